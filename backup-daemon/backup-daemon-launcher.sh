@@ -0,0 +1,16 @@
+#!/bin/bash
+#
+# a launcher which records how it was run and then runs the rest of its command
+# line, for trying out launcher settings; it logs to the file named by
+# BACKUP_DAEMON_LAUNCHER_LOG, or backup-daemon-launcher.log
+
+log="${BACKUP_DAEMON_LAUNCHER_LOG:-backup-daemon-launcher.log}"
+
+{
+	printf "args:"
+	printf " %s" "$@"
+	printf "\n"
+	env | grep "^BACKUP_DAEMON_" | grep -v "^BACKUP_DAEMON_LAUNCHER_LOG=" | sort
+} >> "$log"
+
+exec "$@"
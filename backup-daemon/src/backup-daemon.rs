@@ -1,16 +1,27 @@
 #![crate_name = "backup_daemon"]
 #![crate_type = "bin"]
 
+extern crate libc;
 extern crate rustc_serialize;
 extern crate time;
+extern crate toml;
+extern crate yaml_rust;
 
 use std::env;
+use std::fs;
 
 use std::path::Path;
+use std::rc::Rc;
 
+use wbs::backup::clock::*;
 use wbs::backup::config::*;
-use wbs::backup::state::*;
+use wbs::backup::format::*;
+use wbs::backup::log::*;
 use wbs::backup::main::*;
+use wbs::backup::report::*;
+use wbs::backup::run::*;
+use wbs::backup::state::*;
+use wbs::backup::status::*;
 
 mod wbs {
 
@@ -18,11 +29,22 @@ mod wbs {
 
 		pub mod log;
 
+		pub mod clock;
 		pub mod config;
+		pub mod exec;
+		pub mod format;
 		pub mod main;
+		pub mod queue;
+		pub mod report;
 		pub mod run;
 		pub mod state;
+		pub mod status;
 		pub mod time;
+		pub mod window;
+		pub mod zone;
+
+		#[cfg (test)]
+		pub mod test;
 
 	}
 
@@ -35,6 +57,95 @@ fn main () {
 	let args: Vec <String> =
 		env::args ().collect ();
 
+	if args.len () == 3 && args [1] == "status" {
+
+		let (config, state) =
+			read_quietly (& args [2]);
+
+		print! (
+			"{}",
+			format_status (& config, & state, time::get_time ()));
+
+		return;
+
+	}
+
+	if (args.len () == 3 || args.len () == 4) && args [1] == "history" {
+
+		let (config, state) =
+			read_quietly (& args [2]);
+
+		print! (
+			"{}",
+			format_history (
+				& config,
+				& state,
+				args.get (3).map (|job_name| job_name.as_str ())));
+
+		return;
+
+	}
+
+	if args.len () == 3 && args [1] == "fleet-report" {
+
+		let reports =
+			read_reports (
+				Path::new (& args [2]),
+			).unwrap_or_else (
+				|err|
+				panic! ("{}", err)
+			);
+
+		print! (
+			"{}",
+			format_fleet_report (
+				& reports,
+				time::get_time ()));
+
+		return;
+
+	}
+
+	// wakes a running daemon through its control socket, to check for due work
+	// at once
+
+	if args.len () == 3 && args [1] == "wake" {
+
+		let config =
+			Config::read (
+				Path::new (& args [2]));
+
+		let control_socket =
+			config.control_socket.unwrap_or_else (
+				|| panic! ("no control_socket in config {}", args [2]));
+
+		send_command (
+			& control_socket,
+			"wake",
+		).unwrap_or_else (
+			|err|
+
+			panic! (
+				"error sending to control socket {}: {}",
+				control_socket,
+				err)
+
+		);
+
+		return;
+
+	}
+
+	if args.len () == 4 && args [1] == "convert-config" {
+
+		convert_config (
+			Path::new (& args [2]),
+			Path::new (& args [3]));
+
+		return;
+
+	}
+
 	if args.len () != 2 {
 		println! ("Syntax error");
 		return;
@@ -51,15 +162,138 @@ fn main () {
 	let config =
 		Config::read (& config_path);
 
+	let mut clock =
+		SystemClock::new ();
+
+	clock.wake_on_signals ();
+
+	if let Some (ref control_socket) = config.control_socket {
+
+		clock.listen_for_commands (
+			control_socket,
+		).unwrap_or_else (
+			|err|
+
+			panic! (
+				"error opening control socket {}: {}",
+				control_socket,
+				err)
+
+		);
+
+	}
+
+	let clock =
+		Rc::new (clock);
+
+	let logger =
+		Logger::from_config (
+			& config,
+			clock.clone ());
+
+	log! (
+		logger,
+		Level::Info,
+		"loaded config {}",
+		config_path.display ());
+
 	let mut state =
-		Global::read (& config);
+		Global::read (& config, & logger);
 
 	// run program
 
+	let context = Context {
+		clock: & * clock,
+		runner: & ProcessScriptRunner,
+		logger: & logger,
+	};
+
 	state.write_state (& config);
 
-	main_loop (& config, &mut state);
+	main_loop (& context, & config, &mut state);
 
 	// (never reach here)
 
 }
+
+// reads the config and state for a report, without the daemon's log output
+
+fn read_quietly (
+	config_path_str: &str,
+) -> (Config, Global) {
+
+	let config =
+		Config::read (
+			& Path::new (config_path_str));
+
+	let logger =
+		Logger::new (
+			Level::Warning,
+			Box::new (TextSink),
+			Rc::new (SystemClock::new ()));
+
+	let state =
+		Global::read (& config, & logger);
+
+	(config, state)
+
+}
+
+// translates a config file between formats, chosen by extension. only the file
+// itself is converted, so templates and includes are kept as they are, but it
+// must load as a valid config first.
+
+fn convert_config (
+	source_path: & Path,
+	target_path: & Path,
+) {
+
+	let config_value =
+		read_value (
+			source_path,
+		).unwrap_or_else (
+			|err|
+			panic! ("error reading config {}", err)
+		);
+
+	Config::load_value (
+		source_path,
+		config_value.clone (),
+	).unwrap_or_else (
+		|err|
+
+		panic! (
+			"error reading config {}: {}",
+			source_path.display (),
+			err)
+
+	);
+
+	let target_source =
+		format_value (
+			ConfigFormat::from_path (target_path),
+			& config_value,
+		).unwrap_or_else (
+			|err|
+
+			panic! (
+				"error converting config {}: {}",
+				source_path.display (),
+				err)
+
+		);
+
+	fs::write (
+		target_path,
+		target_source,
+	).unwrap_or_else (
+		|err|
+
+		panic! (
+			"error writing config {}: {}",
+			target_path.display (),
+			err)
+
+	);
+
+}
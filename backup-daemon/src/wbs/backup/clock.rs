@@ -0,0 +1,433 @@
+extern crate libc;
+extern crate time;
+
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::ptr;
+use std::sync::atomic::AtomicIsize;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use time::Timespec;
+
+// ######################################## interface
+
+pub enum Wakeup {
+	Deadline,
+	Interrupted,
+	ClockJump,
+	Command (String),
+}
+
+pub trait Clock {
+
+	fn now (
+		& self,
+	) -> Timespec;
+
+	fn sleep_until (
+		& self,
+		deadline: Timespec,
+	) -> Wakeup;
+
+}
+
+// Sleeps on a realtime timerfd, which the kernel cancels when the wall clock
+// is set, alongside a self-pipe which signal handlers write to, and optionally
+// a control socket which commands arrive on, one per datagram. Clock jumps
+// that the kernel does not report, such as a suspended virtual machine, are
+// caught by comparing elapsed wall-clock and monotonic time.
+
+pub struct SystemClock {
+	wake_read: libc::c_int,
+	wake_write: libc::c_int,
+	control: Option <UnixDatagram>,
+}
+
+const CLOCK_JUMP_TOLERANCE: i64 = 2;
+
+static SIGNAL_WAKE_WRITE: AtomicIsize =
+	AtomicIsize::new (-1);
+
+// ######################################## implementation
+
+impl SystemClock {
+
+	pub fn new (
+	) -> SystemClock {
+
+		let mut fds: [libc::c_int; 2] =
+			[-1; 2];
+
+		if unsafe {
+			libc::pipe2 (
+				fds.as_mut_ptr (),
+				libc::O_CLOEXEC | libc::O_NONBLOCK)
+		} != 0 {
+
+			panic! (
+				"error creating wake pipe: {}",
+				io::Error::last_os_error ());
+
+		}
+
+		SystemClock {
+			wake_read: fds [0],
+			wake_write: fds [1],
+			control: None,
+		}
+
+	}
+
+	// a socket left by a daemon which has since stopped is replaced
+
+	pub fn listen_for_commands (
+		&mut self,
+		path: &str,
+	) -> io::Result <()> {
+
+		let _ = fs::remove_file (path);
+
+		let socket =
+			try! (
+				UnixDatagram::bind (path));
+
+		try! (
+			socket.set_nonblocking (true));
+
+		self.control = Some (socket);
+
+		Ok (())
+
+	}
+
+	// SIGHUP and SIGUSR1 wake the main loop to check for due work at once
+
+	pub fn wake_on_signals (
+		& self,
+	) {
+
+		SIGNAL_WAKE_WRITE.store (
+			self.wake_write as isize,
+			Ordering::SeqCst);
+
+		for signal in [libc::SIGHUP, libc::SIGUSR1].iter () {
+
+			unsafe {
+
+				let mut action: libc::sigaction =
+					::std::mem::zeroed ();
+
+				action.sa_sigaction =
+					signal_handler as extern "C" fn (libc::c_int) as libc::sighandler_t;
+
+				action.sa_flags =
+					libc::SA_RESTART;
+
+				libc::sigemptyset (
+					&mut action.sa_mask);
+
+				if libc::sigaction (
+					* signal,
+					& action,
+					ptr::null_mut ()) != 0 {
+
+					panic! (
+						"error installing signal handler: {}",
+						io::Error::last_os_error ());
+
+				}
+
+			}
+
+		}
+
+	}
+
+	fn drain_wake_pipe (
+		& self,
+	) {
+
+		let mut buffer: [u8; 64] =
+			[0; 64];
+
+		while unsafe {
+			libc::read (
+				self.wake_read,
+				buffer.as_mut_ptr () as * mut libc::c_void,
+				buffer.len ())
+		} > 0 {}
+
+	}
+
+	// takes one command from the control socket; any others wake the next
+	// sleep at once
+
+	fn receive_command (
+		& self,
+	) -> Option <String> {
+
+		let mut buffer: [u8; 256] =
+			[0; 256];
+
+		self.control.as_ref ().and_then (
+			|control| control.recv (& mut buffer).ok ()
+		).map (
+			|size|
+			String::from_utf8_lossy (
+				& buffer [0 .. size],
+			).trim ().to_string ()
+		)
+
+	}
+
+	fn wait (
+		& self,
+		deadline: Timespec,
+	) -> io::Result <Wakeup> {
+
+		let timer_fd =
+			unsafe {
+				libc::timerfd_create (
+					libc::CLOCK_REALTIME,
+					libc::TFD_CLOEXEC)
+			};
+
+		if timer_fd < 0 {
+			return Err (io::Error::last_os_error ());
+		}
+
+		let result =
+			self.wait_timer (
+				timer_fd,
+				deadline);
+
+		unsafe {
+			libc::close (timer_fd);
+		}
+
+		result
+
+	}
+
+	fn wait_timer (
+		& self,
+		timer_fd: libc::c_int,
+		deadline: Timespec,
+	) -> io::Result <Wakeup> {
+
+		let timer_spec =
+			libc::itimerspec {
+				it_interval: libc::timespec {
+					tv_sec: 0,
+					tv_nsec: 0,
+				},
+				it_value: libc::timespec {
+					tv_sec: deadline.sec as libc::time_t,
+					tv_nsec: deadline.nsec as libc::c_long,
+				},
+			};
+
+		if unsafe {
+			libc::timerfd_settime (
+				timer_fd,
+				libc::TFD_TIMER_ABSTIME | libc::TFD_TIMER_CANCEL_ON_SET,
+				& timer_spec,
+				ptr::null_mut ())
+		} != 0 {
+			return Err (io::Error::last_os_error ());
+		}
+
+		let mut poll_fds = vec! [
+			libc::pollfd {
+				fd: timer_fd,
+				events: libc::POLLIN,
+				revents: 0,
+			},
+			libc::pollfd {
+				fd: self.wake_read,
+				events: libc::POLLIN,
+				revents: 0,
+			},
+		];
+
+		if let Some (ref control) = self.control {
+
+			poll_fds.push (
+				libc::pollfd {
+					fd: control.as_raw_fd (),
+					events: libc::POLLIN,
+					revents: 0,
+				});
+
+		}
+
+		loop {
+
+			let ready =
+				unsafe {
+					libc::poll (
+						poll_fds.as_mut_ptr (),
+						poll_fds.len () as libc::nfds_t,
+						-1)
+				};
+
+			if ready >= 0 {
+				break;
+			}
+
+			let error =
+				io::Error::last_os_error ();
+
+			if error.kind () != io::ErrorKind::Interrupted {
+				return Err (error);
+			}
+
+		}
+
+		if poll_fds [1].revents != 0 {
+
+			self.drain_wake_pipe ();
+
+			return Ok (Wakeup::Interrupted);
+
+		}
+
+		if poll_fds.len () > 2 && poll_fds [2].revents != 0 {
+
+			return Ok (
+				self.receive_command ().map_or (
+					Wakeup::Interrupted,
+					Wakeup::Command));
+
+		}
+
+		let mut expirations: u64 = 0;
+
+		if unsafe {
+			libc::read (
+				timer_fd,
+				&mut expirations as * mut u64 as * mut libc::c_void,
+				8)
+		} < 0 {
+
+			let error =
+				io::Error::last_os_error ();
+
+			if error.raw_os_error () == Some (libc::ECANCELED) {
+				return Ok (Wakeup::ClockJump);
+			}
+
+			return Err (error);
+
+		}
+
+		Ok (Wakeup::Deadline)
+
+	}
+
+}
+
+impl Clock for SystemClock {
+
+	fn now (
+		& self,
+	) -> Timespec {
+
+		time::get_time ()
+
+	}
+
+	fn sleep_until (
+		& self,
+		deadline: Timespec,
+	) -> Wakeup {
+
+		let real_start =
+			time::get_time ();
+
+		if real_start >= deadline {
+			return Wakeup::Deadline;
+		}
+
+		let monotonic_start =
+			Instant::now ();
+
+		let wakeup =
+			self.wait (
+				deadline,
+			).unwrap_or_else (
+				|err|
+
+				panic! (
+					"error waiting for next job: {}",
+					err)
+
+			);
+
+		let real_elapsed =
+			(time::get_time () - real_start).num_seconds ();
+
+		let monotonic_elapsed =
+			monotonic_start.elapsed ().as_secs () as i64;
+
+		if (real_elapsed - monotonic_elapsed).abs () > CLOCK_JUMP_TOLERANCE {
+			return Wakeup::ClockJump;
+		}
+
+		wakeup
+
+	}
+
+}
+
+// sends a command to a daemon's control socket
+
+pub fn send_command (
+	path: &str,
+	command: &str,
+) -> io::Result <()> {
+
+	let socket =
+		try! (
+			UnixDatagram::unbound ());
+
+	try! (
+		socket.send_to (
+			command.as_bytes (),
+			path));
+
+	Ok (())
+
+}
+
+fn write_wake_byte (
+	wake_write: libc::c_int,
+) {
+
+	// the pipe is non-blocking, and a full pipe means a wakeup is pending
+
+	unsafe {
+		libc::write (
+			wake_write,
+			b"w".as_ptr () as * const libc::c_void,
+			1);
+	}
+
+}
+
+extern "C" fn signal_handler (
+	_signal: libc::c_int,
+) {
+
+	let wake_write =
+		SIGNAL_WAKE_WRITE.load (
+			Ordering::SeqCst);
+
+	if wake_write >= 0 {
+		write_wake_byte (wake_write as libc::c_int);
+	}
+
+}
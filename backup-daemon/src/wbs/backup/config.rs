@@ -1,17 +1,40 @@
 extern crate time;
 
+use rustc_serialize::Decodable;
 use rustc_serialize::json;
+use rustc_serialize::json::Json;
 
+use std::collections::BTreeMap;
+use std::fs;
 use std::io::Read;
 use std::fs::File;
 use std::path::Path;
+use std::path::PathBuf;
 
+use wbs::backup::exec::*;
+use wbs::backup::format::*;
+use wbs::backup::report::*;
 use wbs::backup::time::*;
+use wbs::backup::window::*;
+use wbs::backup::zone::*;
+
+#[derive (RustcEncodable, RustcDecodable)]
+pub struct WindowConfig {
+
+	pub days: Option <Vec <String>>,
+	pub start: String,
+	pub end: String,
+
+	pub stages: Option <Vec <String>>,
+	pub must_finish_by: Option <bool>,
+
+}
 
 #[derive (RustcEncodable, RustcDecodable)]
 pub struct JobConfig {
 
 	pub name: String,
+	pub timezone: Option <String>,
 
 	pub sync_script: Option <String>,
 	pub sync_log: Option <String>,
@@ -21,34 +44,111 @@ pub struct JobConfig {
 
 	pub send_script: Option <String>,
 	pub send_log: Option <String>,
+	pub send_priority: Option <i64>,
+
+	pub export_script: Option <String>,
+	pub export_log: Option <String>,
+	pub export_schedule: Option <String>,
+
+	pub verify_script: Option <String>,
+	pub verify_restore_test: Option <String>,
+	pub verify_log: Option <String>,
+	pub verify_schedule: Option <String>,
+	pub verify_pick: Option <String>,
+	pub verify_alert_days: Option <u64>,
+
+	pub blackouts: Option <Vec <WindowConfig>>,
+
+	pub working_directory: Option <String>,
+	pub user: Option <String>,
+	pub group: Option <String>,
+	pub umask: Option <String>,
+	pub nice: Option <i32>,
+	pub ionice_class: Option <String>,
+	pub ionice_priority: Option <u32>,
+
+	pub cgroup: Option <String>,
+	pub cgroup_memory_max: Option <String>,
+	pub cgroup_io_max: Option <Vec <String>>,
+
+	pub launcher: Option <Vec <String>>,
 
 }
 
+// A launcher is a command prefix which each of the job's scripts is run under,
+// such as a chroot or container runtime. Its arguments may use ${job}, ${stage}
+// and ${script}; an argument of ${command} stands for the script and its
+// arguments, which otherwise follow the launcher, and one of ${environment}
+// stands for NAME=VALUE arguments for the variables the daemon sets.
+
+pub const LAUNCHER_COMMAND: & 'static str = "${command}";
+pub const LAUNCHER_ENVIRONMENT: & 'static str = "${environment}";
+
+// Instead of a verify script, a job can name the zbackup backup each snapshot
+// was sent to, and have it checked with tar-filter restore-test. The path may
+// use ${job} and ${time}, which is the snapshot's day as given to scripts.
+
+pub const RESTORE_TEST_PROGRAM: & 'static str = "tar-filter";
+
 #[derive (RustcEncodable, RustcDecodable)]
 pub struct Config {
 
 	pub state: String,
 	pub lock: String,
+	pub control_socket: Option <String>,
+	pub timezone: Option <String>,
+
+	pub log_level: Option <String>,
+	pub log_sink: Option <String>,
+	pub syslog_path: Option <String>,
+
+	pub blackouts: Option <Vec <WindowConfig>>,
+
+	pub send_bandwidth: Option <u64>,
+	pub send_burst: Option <u64>,
+
+	pub host_name: Option <String>,
+	pub report_directory: Option <String>,
+	pub report_url: Option <String>,
+	pub report_interval: Option <u64>,
 
 	pub jobs: Vec <JobConfig>,
 
 }
 
+// which sent snapshot the verify stage restores: the latest, or one picked at
+// random so that older snapshots are tested too
+
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum VerifyPick {
+	Latest,
+	Random,
+}
+
+impl VerifyPick {
+
+	pub fn from_string (
+		str: &str,
+	) -> Result <VerifyPick, String> {
+
+		match str {
+			"latest" => Ok (VerifyPick::Latest),
+			"random" => Ok (VerifyPick::Random),
+			_ => Err (format! ("unknown verify pick: {}", str)),
+		}
+
+	}
+
+}
+
 impl Config {
 
 	pub fn read (
 		config_path: & Path,
 	) -> Config {
 
-		log! ("loading config");
-
-		let mut config_json: String =
-			String::new ();
-
-		File::open (
-			& config_path,
-		).unwrap ().read_to_string (
-			&mut config_json,
+		Config::load (
+			config_path,
 		).unwrap_or_else (
 			|err|
 
@@ -57,23 +157,833 @@ impl Config {
 				config_path.display (),
 				err)
 
-		);
+		)
+
+	}
+
+	// jobs may name one of the config's templates, which supplies any fields
+	// the job leaves out, and "include" lists files or directories of further
+	// jobs, relative to the config file. string fields then have ${field}
+	// replaced with the job's own value of that field, such as its name.
+
+	pub fn load (
+		config_path: & Path,
+	) -> Result <Config, String> {
+
+		Config::load_value (
+			config_path,
+			try! (read_value (config_path)))
+
+	}
+
+	pub fn load_value (
+		config_path: & Path,
+		config_value: Json,
+	) -> Result <Config, String> {
+
+		let mut config_object =
+			match config_value {
+
+			Json::Object (config_object) => config_object,
+
+			_ => return Err (
+				"expected an object".to_string ()),
+
+		};
+
+		let templates =
+			match config_object.remove ("templates") {
+
+			Some (Json::Object (templates)) => templates,
+
+			None => BTreeMap::new (),
+
+			Some (_) => return Err (
+				"templates must be an object".to_string ()),
+
+		};
+
+		let mut sources: Vec <(String, Json)> =
+			vec! [];
+
+		match config_object.remove ("jobs") {
+
+			Some (Json::Array (jobs)) => {
+
+				for job in jobs {
+
+					sources.push (
+						(config_path.display ().to_string (), job));
+
+				}
+
+			},
+
+			None => (),
+
+			Some (_) => return Err (
+				"jobs must be a list".to_string ()),
+
+		}
+
+		for include_path in try! (include_paths (config_path, & mut config_object)) {
+
+			for job in try! (read_jobs (& include_path)) {
+
+				sources.push (
+					(include_path.display ().to_string (), job));
+
+			}
+
+		}
+
+		config_object.insert (
+			"jobs".to_string (),
+			Json::Array (vec! []));
+
+		let mut config: Config =
+			try! (
+				decode_value (
+					Json::Object (config_object)));
+
+		try! (
+			validate_windows (
+				& config.blackouts,
+			).map_err (
+				|err|
+				format! (
+					"blackouts: {}",
+					err)
+			));
+
+		if config.report_interval == Some (0) {
+			return Err ("report_interval: must be positive".to_string ());
+		}
+
+		if let Some (ref report_url) = config.report_url {
+
+			try! (
+				parse_http_url (
+					report_url,
+				).map (
+					|_| ()
+				).map_err (
+					|err|
+					format! (
+						"report_url: {}",
+						err)
+				));
+
+		}
+
+		for (job_index, (source, job)) in sources.into_iter ().enumerate () {
+
+			let job_config =
+				try! (
+					expand_job (
+						& templates,
+						job,
+					).map_err (
+						|err|
+						format! (
+							"job {} from {}: {}",
+							job_index + 1,
+							source,
+							err)
+					));
+
+			if let Some (other_index) = config.jobs.iter ().position (
+				|other| other.name == job_config.name) {
+
+				return Err (
+					format! (
+						"job {} from {}: duplicate job name {}, also job {}",
+						job_index + 1,
+						source,
+						job_config.name,
+						other_index + 1));
+
+			}
+
+			try! (
+				validate_job (
+					& job_config,
+				).map_err (
+					|err|
+					format! (
+						"job {} from {}: {}",
+						job_config.name,
+						source,
+						err)
+				));
+
+			config.jobs.push (
+				job_config);
+
+		}
+
+		Ok (config)
+
+	}
+
+	pub fn export_period (
+		& self,
+		job_config: & JobConfig,
+	) -> Period {
+
+		Period::from_string (
+			job_config.export_schedule.as_ref ().map_or ("weekly", |schedule| schedule),
+		).unwrap_or_else (
+			|err|
+
+			panic! (
+				"error in export schedule for {}: {}",
+				job_config.name,
+				err)
+
+		)
+
+	}
+
+	pub fn verify_period (
+		& self,
+		job_config: & JobConfig,
+	) -> Period {
+
+		Period::from_string (
+			job_config.verify_schedule.as_ref ().map_or ("weekly", |schedule| schedule),
+		).unwrap_or_else (
+			|err|
+
+			panic! (
+				"error in verify schedule for {}: {}",
+				job_config.name,
+				err)
+
+		)
+
+	}
+
+	pub fn verify_enabled (
+		& self,
+		job_config: & JobConfig,
+	) -> bool {
+
+		job_config.verify_script.is_some ()
+			|| job_config.verify_restore_test.is_some ()
+
+	}
+
+	pub fn verify_pick (
+		& self,
+		job_config: & JobConfig,
+	) -> VerifyPick {
+
+		VerifyPick::from_string (
+			job_config.verify_pick.as_ref ().map_or ("latest", |pick| pick),
+		).unwrap_or_else (
+			|err|
+
+			panic! (
+				"error in verify pick for {}: {}",
+				job_config.name,
+				err)
+
+		)
+
+	}
+
+	// a report which has not changed is still exported this often, in seconds,
+	// so that a host which has stopped can be told from one with nothing new
+
+	pub fn report_interval (
+		& self,
+	) -> i64 {
+
+		self.report_interval.map_or (
+			DEFAULT_REPORT_INTERVAL,
+			|report_interval| report_interval as i64)
+
+	}
+
+	// without a successful verification for this long a job raises an alert,
+	// which by default allows one verification to be missed
+
+	pub fn verify_alert_seconds (
+		& self,
+		job_config: & JobConfig,
+	) -> i64 {
+
+		job_config.verify_alert_days.map_or (
+			2 * self.verify_period (job_config).days () * 86400,
+			|days| days as i64 * 86400)
+
+	}
+
+	// blackouts from the config apply to every job, alongside the job's own;
+	// they are checked when the config is loaded
+
+	pub fn job_windows (
+		& self,
+		job_config: & JobConfig,
+	) -> Vec <Window> {
+
+		self.blackouts.iter ().chain (
+			job_config.blackouts.iter (),
+		).flat_map (
+			|window_configs| window_configs.iter ()
+		).map (
+			|window_config|
+
+			Window::parse (
+				window_config,
+			).unwrap_or_else (
+				|err|
+
+				panic! (
+					"error in blackouts for {}: {}",
+					job_config.name,
+					err)
 
-		let config: Config =
-			json::decode (
-				& config_json,
+			)
+
+		).collect ()
+
+	}
+
+	pub fn job_zone (
+		& self,
+		job_config: & JobConfig,
+	) -> Zone {
+
+		match job_config.timezone.as_ref ().or (
+			self.timezone.as_ref ()) {
+
+			None => Zone::utc (),
+
+			Some (timezone) => Zone::load (
+				timezone,
 			).unwrap_or_else (
 				|err|
 
 				panic! (
-					"error reading config {}: {}",
-					config_path.display (),
+					"error loading timezone for {}: {}",
+					job_config.name,
+					err)
+
+			),
+
+		}
+
+	}
+
+}
+
+pub fn read_value (
+	path: & Path,
+) -> Result <Json, String> {
+
+	let mut value_json: String =
+		String::new ();
+
+	try! (
+		File::open (
+			path,
+		).and_then (
+			|mut file|
+			file.read_to_string (
+				&mut value_json)
+		).map_err (
+			|err|
+			format! (
+				"{}: {}",
+				path.display (),
+				err)
+		));
+
+	parse_value (
+		ConfigFormat::from_path (path),
+		& value_json,
+	).map_err (
+		|err|
+		format! (
+			"{}: {}",
+			path.display (),
+			err)
+	)
+
+}
+
+fn include_paths (
+	config_path: & Path,
+	config_object: &mut BTreeMap <String, Json>,
+) -> Result <Vec <PathBuf>, String> {
+
+	let base_path =
+		config_path.parent ().unwrap_or (Path::new ("."));
+
+	let includes =
+		match config_object.remove ("include") {
+
+		None => vec! [],
+
+		Some (Json::String (include)) => vec! [include],
+
+		Some (Json::Array (includes)) => try! (
+			includes.into_iter ().map (
+				|include|
+				match include {
+					Json::String (include) => Ok (include),
+					_ => Err ("include must be a path or list of paths".to_string ()),
+				}
+			).collect ()),
+
+		Some (_) => return Err (
+			"include must be a path or list of paths".to_string ()),
+
+	};
+
+	let mut include_paths: Vec <PathBuf> =
+		vec! [];
+
+	for include in includes {
+
+		let include_path =
+			base_path.join (& include);
+
+		let metadata =
+			try! (
+				fs::metadata (
+					& include_path,
+				).map_err (
+					|err|
+					format! (
+						"include {}: {}",
+						include_path.display (),
+						err)
+				));
+
+		if ! metadata.is_dir () {
+
+			include_paths.push (
+				include_path);
+
+			continue;
+
+		}
+
+		// only json, toml and yaml files are read from a directory, in name order,
+		// so that editor backups and the like are left alone
+
+		let mut dir_paths: Vec <PathBuf> =
+			try! (
+				fs::read_dir (
+					& include_path,
+				).and_then (
+					|entries|
+					entries.map (
+						|entry| entry.map (|entry| entry.path ())
+					).collect ()
+				).map_err (
+					|err|
+					format! (
+						"include {}: {}",
+						include_path.display (),
+						err)
+				));
+
+		dir_paths.retain (
+			|dir_path|
+			ConfigFormat::from_extension (dir_path).is_some ());
+
+		dir_paths.sort ();
+
+		include_paths.extend (
+			dir_paths);
+
+	}
+
+	Ok (include_paths)
+
+}
+
+// an included file holds a single job or a list of them
+
+fn read_jobs (
+	path: & Path,
+) -> Result <Vec <Json>, String> {
+
+	match try! (read_value (path)) {
+
+		Json::Array (jobs) => Ok (jobs),
+
+		job @ Json::Object (_) => Ok (vec! [job]),
+
+		_ => Err (
+			format! (
+				"{}: expected a job or list of jobs",
+				path.display ())),
+
+	}
+
+}
+
+fn expand_job (
+	templates: & BTreeMap <String, Json>,
+	job: Json,
+) -> Result <JobConfig, String> {
+
+	let mut job_object =
+		match job {
+			Json::Object (job_object) => job_object,
+			_ => return Err ("expected an object".to_string ()),
+		};
+
+	let mut merged =
+		match job_object.remove ("template") {
+
+		None => BTreeMap::new (),
+
+		Some (Json::String (template_name)) => {
+
+			match templates.get (& template_name) {
+
+				Some (& Json::Object (ref template)) => template.clone (),
+
+				Some (_) => return Err (
+					format! (
+						"template {} must be an object",
+						template_name)),
+
+				None => return Err (
+					format! (
+						"unknown template {}",
+						template_name)),
+
+			}
+
+		},
+
+		Some (_) => return Err (
+			"template must be a template name".to_string ()),
+
+	};
+
+	merged.extend (
+		job_object);
+
+	let variables: BTreeMap <String, String> =
+		merged.iter ().filter_map (
+			|(key, value)|
+			value.as_string ().map (
+				|value| (key.clone (), value.to_string ()))
+		).collect ();
+
+	let mut expanded: BTreeMap <String, Json> =
+		BTreeMap::new ();
+
+	for (key, value) in merged {
+
+		// the launcher and restore test are expanded each time they run instead
+
+		if key == "launcher" || key == "verify_restore_test" {
+			expanded.insert (key, value);
+			continue;
+		}
+
+		expanded.insert (
+			key.clone (),
+			try! (
+				interpolate_value (
+					& variables,
+					value,
+				).map_err (
+					|err|
+					format! (
+						"{}: {}",
+						key,
+						err)
+				)));
+
+	}
+
+	decode_value (
+		Json::Object (expanded))
+
+}
+
+fn interpolate_value (
+	variables: & BTreeMap <String, String>,
+	value: Json,
+) -> Result <Json, String> {
+
+	match value {
+
+		Json::String (string) =>
+			interpolate (variables, & string).map (Json::String),
+
+		Json::Array (values) =>
+			values.into_iter ().map (
+				|value| interpolate_value (variables, value)
+			).collect::<Result <Vec <Json>, String>> ().map (Json::Array),
+
+		value => Ok (value),
+
+	}
+
+}
+
+pub fn interpolate (
+	variables: & BTreeMap <String, String>,
+	string: &str,
+) -> Result <String, String> {
+
+	let mut output =
+		String::new ();
+
+	let mut rest =
+		string;
+
+	while let Some (start) = rest.find ("${") {
+
+		output.push_str (
+			& rest [.. start]);
+
+		let end =
+			try! (
+				rest [start ..].find ('}').ok_or_else (
+					|| format! ("unterminated ${{ in {}", string)));
+
+		let variable =
+			& rest [start + 2 .. start + end];
+
+		let value =
+			try! (
+				variables.get (variable).ok_or_else (
+					|| format! ("unknown variable ${{{}}}", variable)));
+
+		if value.contains ("${") {
+
+			return Err (
+				format! (
+					"variable ${{{}}} is itself templated",
+					variable));
+
+		}
+
+		output.push_str (
+			value);
+
+		rest =
+			& rest [start + end + 1 ..];
+
+	}
+
+	output.push_str (
+		rest);
+
+	Ok (output)
+
+}
+
+pub fn launcher_variables (
+	job: &str,
+	stage: &str,
+	script: &str,
+) -> BTreeMap <String, String> {
+
+	let mut variables =
+		BTreeMap::new ();
+
+	variables.insert ("job".to_string (), job.to_string ());
+	variables.insert ("stage".to_string (), stage.to_string ());
+	variables.insert ("script".to_string (), script.to_string ());
+
+	variables
+
+}
+
+pub fn restore_test_variables (
+	job: &str,
+	time: &str,
+) -> BTreeMap <String, String> {
+
+	let mut variables =
+		BTreeMap::new ();
+
+	variables.insert ("job".to_string (), job.to_string ());
+	variables.insert ("time".to_string (), time.to_string ());
+
+	variables
+
+}
+
+fn validate_job (
+	job_config: & JobConfig,
+) -> Result <(), String> {
+
+	if job_config.name.is_empty () {
+		return Err ("name is empty".to_string ());
+	}
+
+	if let Some (ref verify_restore_test) = job_config.verify_restore_test {
+
+		if job_config.verify_script.is_some () {
+			return Err ("verify_script and verify_restore_test are exclusive".to_string ());
+		}
+
+		if job_config.verify_log.is_none () {
+			return Err ("verify_restore_test needs a verify_log".to_string ());
+		}
+
+		try! (
+			interpolate (
+				& restore_test_variables (& job_config.name, "time"),
+				verify_restore_test,
+			).map_err (
+				|err|
+				format! (
+					"verify_restore_test: {}",
 					err)
+			));
+
+	}
+
+	for & (stage, ref script, ref log) in [
+		("sync", job_config.sync_script.as_ref (), job_config.sync_log.as_ref ()),
+		("snapshot", job_config.snapshot_script.as_ref (), job_config.snapshot_log.as_ref ()),
+		("send", job_config.send_script.as_ref (), job_config.send_log.as_ref ()),
+		("export", job_config.export_script.as_ref (), job_config.export_log.as_ref ()),
+		("verify", job_config.verify_script.as_ref ().or (job_config.verify_restore_test.as_ref ()), job_config.verify_log.as_ref ()),
+	].iter () {
+
+		if script.is_some () != log.is_some () {
+
+			return Err (
+				format! (
+					"{}_script and {}_log must be given together",
+					stage,
+					stage));
+
+		}
+
+	}
 
-			);
+	try! (
+		validate_windows (
+			& job_config.blackouts,
+		).map_err (
+			|err|
+			format! (
+				"blackouts: {}",
+				err)
+		));
+
+	if let Some (ref export_schedule) = job_config.export_schedule {
+
+		try! (
+			Period::from_string (
+				export_schedule,
+			).map (|_| ()));
+
+	}
+
+	if let Some (ref launcher) = job_config.launcher {
+
+		if launcher.is_empty () {
+			return Err ("launcher is empty".to_string ());
+		}
+
+		let variables =
+			launcher_variables (& job_config.name, "sync", "script");
+
+		for argument in launcher.iter () {
+
+			if argument != LAUNCHER_COMMAND && argument != LAUNCHER_ENVIRONMENT {
+
+				try! (
+					interpolate (
+						& variables,
+						argument,
+					).map_err (
+						|err|
+						format! (
+							"launcher: {}",
+							err)
+					));
+
+			}
+
+		}
+
+	}
+
+	if let Some (ref verify_schedule) = job_config.verify_schedule {
+
+		try! (
+			Period::from_string (
+				verify_schedule,
+			).map (|_| ()));
+
+	}
 
-		config
+	if let Some (ref verify_pick) = job_config.verify_pick {
+
+		try! (
+			VerifyPick::from_string (
+				verify_pick,
+			).map (|_| ()));
+
+	}
+
+	try! (
+		validate_settings (
+			job_config));
+
+	Ok (())
+
+}
+
+fn validate_windows (
+	window_configs: & Option <Vec <WindowConfig>>,
+) -> Result <(), String> {
+
+	for window_config in window_configs.iter ().flat_map (|window_configs| window_configs.iter ()) {
+
+		try! (
+			Window::parse (
+				window_config,
+			).map (|_| ()));
 
 	}
 
+	Ok (())
+
+}
+
+pub fn decode_value <Type: Decodable> (
+	value: Json,
+) -> Result <Type, String> {
+
+	Decodable::decode (
+		&mut json::Decoder::new (value),
+	).map_err (
+		|err|
+		match err {
+
+			json::DecoderError::MissingFieldError (field) =>
+				format! ("missing field {}", field),
+
+			json::DecoderError::ExpectedError (expected, found) =>
+				format! ("expected {} but found {}", expected, found),
+
+			json::DecoderError::ParseError (err) =>
+				parser_error (& err),
+
+			err =>
+				format! ("{:?}", err),
+
+		}
+	)
+
 }
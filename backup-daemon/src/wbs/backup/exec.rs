@@ -0,0 +1,664 @@
+extern crate libc;
+
+use std::env;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use wbs::backup::config::*;
+
+// Process settings applied to a job's scripts between fork and exec. The
+// child joins its cgroup and lowers its priority before dropping to the
+// configured user, so that these steps still have the daemon's privileges.
+
+struct ExecSettings {
+	cgroup_procs: Option <CString>,
+	nice: Option <libc::c_int>,
+	ioprio: Option <libc::c_int>,
+	umask: Option <libc::mode_t>,
+	gid: Option <libc::gid_t>,
+	uid: Option <libc::uid_t>,
+}
+
+struct UserEntry {
+	uid: libc::uid_t,
+	gid: libc::gid_t,
+	name: Option <String>,
+	home: Option <String>,
+}
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+// the environment is set on whatever is run, and a launcher which does not
+// pass it on can be given it as arguments instead
+
+pub fn script_command (
+	job_config: & JobConfig,
+	stage: &str,
+	script: &str,
+	arguments: & [String],
+	environment: & [(String, String)],
+) -> Result <process::Command, String> {
+
+	let script =
+		match job_config.working_directory {
+			Some (_) => try! (script_path (script)),
+			None => PathBuf::from (script),
+		};
+
+	let command_line: Vec <String> =
+		match job_config.launcher {
+
+		Some (ref launcher) =>
+			try! (
+				launcher_command_line (
+					job_config,
+					launcher,
+					stage,
+					& script.to_string_lossy (),
+					arguments,
+					environment)),
+
+		None =>
+			Some (script.to_string_lossy ().into_owned ()).into_iter ().chain (
+				arguments.iter ().cloned (),
+			).collect (),
+
+	};
+
+	// the launcher itself is found the same way as the script
+
+	let program =
+		match job_config.working_directory {
+			Some (_) if job_config.launcher.is_some () => try! (script_path (& command_line [0])),
+			_ => PathBuf::from (& command_line [0]),
+		};
+
+	let mut command =
+		process::Command::new (
+			program);
+
+	command
+		.args (& command_line [1 ..])
+		.envs (environment.iter ().cloned ());
+
+	if let Some (ref working_directory) = job_config.working_directory {
+
+		command.current_dir (
+			working_directory);
+
+	}
+
+	// a script run as another user sees that user's home, as it would under
+	// su; numeric ids outside the password database have neither
+
+	if let Some (ref user) = job_config.user {
+
+		let user_entry =
+			try! (lookup_user (user));
+
+		for & (name, ref value) in [
+			("HOME", & user_entry.home),
+			("USER", & user_entry.name),
+			("LOGNAME", & user_entry.name),
+		].iter () {
+
+			match * value {
+				Some (ref value) => command.env (name, value),
+				None => command.env_remove (name),
+			};
+
+		}
+
+	}
+
+	let settings =
+		try! (exec_settings (job_config));
+
+	unsafe {
+
+		command.pre_exec (
+			move || apply_settings (& settings));
+
+	}
+
+	Ok (command)
+
+}
+
+fn launcher_command_line (
+	job_config: & JobConfig,
+	launcher: & [String],
+	stage: &str,
+	script: &str,
+	arguments: & [String],
+	environment: & [(String, String)],
+) -> Result <Vec <String>, String> {
+
+	if launcher.is_empty () {
+
+		return Err (
+			format! (
+				"invalid launcher for {}: launcher is empty",
+				job_config.name));
+
+	}
+
+	let variables =
+		launcher_variables (
+			& job_config.name,
+			stage,
+			script);
+
+	let mut command_line: Vec <String> =
+		vec! [];
+
+	let mut command_given =
+		false;
+
+	for argument in launcher.iter () {
+
+		if argument == LAUNCHER_COMMAND {
+
+			command_line.push (script.to_string ());
+			command_line.extend (arguments.iter ().cloned ());
+
+			command_given = true;
+
+		} else if argument == LAUNCHER_ENVIRONMENT {
+
+			command_line.extend (
+				environment.iter ().map (
+					|& (ref name, ref value)|
+					format! ("{}={}", name, value)));
+
+		} else {
+
+			command_line.push (
+				try! (
+					interpolate (
+						& variables,
+						argument,
+					).map_err (
+						|err|
+						format! (
+							"invalid launcher for {}: {}",
+							job_config.name,
+							err)
+					)));
+
+		}
+
+	}
+
+	if ! command_given {
+
+		command_line.push (script.to_string ());
+		command_line.extend (arguments.iter ().cloned ());
+
+	}
+
+	Ok (command_line)
+
+}
+
+// a relative script path is resolved against the daemon's directory, not the
+// job's working directory
+
+fn script_path (
+	script: &str,
+) -> Result <PathBuf, String> {
+
+	let path =
+		Path::new (script);
+
+	if path.is_absolute () || ! script.contains ('/') {
+		return Ok (path.to_path_buf ());
+	}
+
+	env::current_dir ().map (
+		|current_dir|
+		current_dir.join (path)
+	).map_err (
+		|err|
+		format! (
+			"error resolving script {}: {}",
+			script,
+			err)
+	)
+
+}
+
+// checks the settings when the config is loaded, so that mistakes are not
+// only found when a script is due
+
+pub fn validate_settings (
+	job_config: & JobConfig,
+) -> Result <(), String> {
+
+	if let Some (nice) = job_config.nice {
+
+		if nice < -20 || nice > 19 {
+
+			return Err (
+				format! (
+					"invalid nice: {}",
+					nice));
+
+		}
+
+	}
+
+	try! (ioprio (job_config));
+	try! (umask (job_config));
+
+	if let Some (ref user) = job_config.user {
+		try! (lookup_user (user));
+	}
+
+	if let Some (ref group) = job_config.group {
+		try! (lookup_group (group));
+	}
+
+	if let Some (ref cgroup) = job_config.cgroup {
+		try! (cgroup_path (cgroup));
+	}
+
+	Ok (())
+
+}
+
+fn exec_settings (
+	job_config: & JobConfig,
+) -> Result <ExecSettings, String> {
+
+	let (uid, user_gid) =
+		match job_config.user {
+
+		Some (ref user) => {
+			let user_entry = try! (lookup_user (user));
+			(Some (user_entry.uid), Some (user_entry.gid))
+		},
+
+		None => (None, None),
+
+	};
+
+	let gid =
+		match job_config.group {
+			Some (ref group) => Some (try! (lookup_group (group))),
+			None => user_gid,
+		};
+
+	let cgroup_procs =
+		match job_config.cgroup {
+
+		Some (ref cgroup) => {
+
+			let cgroup_path =
+				try! (prepare_cgroup (job_config, cgroup));
+
+			Some (try! (
+				CString::new (
+					cgroup_path.join ("cgroup.procs").as_os_str ().as_bytes (),
+				).map_err (
+					|_| format! ("invalid cgroup path: {}", cgroup)
+				)))
+
+		},
+
+		None => None,
+
+	};
+
+	Ok (ExecSettings {
+		cgroup_procs: cgroup_procs,
+		nice: job_config.nice,
+		ioprio: try! (ioprio (job_config)),
+		umask: try! (umask (job_config)),
+		gid: gid,
+		uid: uid,
+	})
+
+}
+
+fn ioprio (
+	job_config: & JobConfig,
+) -> Result <Option <libc::c_int>, String> {
+
+	let class =
+		match job_config.ionice_class.as_ref ().map (|class| class.as_str ()) {
+
+		None => {
+
+			if job_config.ionice_priority.is_some () {
+				2
+			} else {
+				return Ok (None);
+			}
+
+		},
+
+		Some ("realtime") => 1,
+		Some ("best-effort") => 2,
+		Some ("idle") => 3,
+
+		Some (class) => return Err (
+			format! (
+				"invalid ionice_class: {}",
+				class)),
+
+	};
+
+	let priority =
+		match job_config.ionice_priority {
+
+		Some (priority) if priority > 7 => return Err (
+			format! (
+				"invalid ionice_priority: {}",
+				priority)),
+
+		Some (priority) => priority as libc::c_int,
+
+		None => 4,
+
+	};
+
+	// the idle class has no priority levels
+
+	Ok (Some (
+		(class << IOPRIO_CLASS_SHIFT)
+			| if class == 3 { 0 } else { priority }))
+
+}
+
+fn umask (
+	job_config: & JobConfig,
+) -> Result <Option <libc::mode_t>, String> {
+
+	match job_config.umask {
+
+		Some (ref umask) => libc::mode_t::from_str_radix (
+			umask,
+			8,
+		).ok ().and_then (
+			|umask|
+			if umask <= 0o777 { Some (Some (umask)) } else { None }
+		).ok_or_else (
+			||
+			format! (
+				"invalid umask: {}",
+				umask)
+		),
+
+		None => Ok (None),
+
+	}
+
+}
+
+fn lookup_user (
+	user: &str,
+) -> Result <UserEntry, String> {
+
+	let user_c =
+		try! (
+			CString::new (user).map_err (
+				|_| format! ("invalid user: {}", user)));
+
+	let passwd =
+		unsafe { libc::getpwnam (user_c.as_ptr ()) };
+
+	if ! passwd.is_null () {
+
+		return Ok (unsafe {
+			UserEntry {
+				uid: (* passwd).pw_uid,
+				gid: (* passwd).pw_gid,
+				name: Some (c_string ((* passwd).pw_name)),
+				home: Some (c_string ((* passwd).pw_dir)),
+			}
+		});
+
+	}
+
+	// numeric ids need not exist in the password database
+
+	match user.parse::<libc::uid_t> () {
+
+		Ok (uid) => Ok (UserEntry {
+			uid: uid,
+			gid: uid as libc::gid_t,
+			name: None,
+			home: None,
+		}),
+
+		Err (_) => Err (format! ("unknown user: {}", user)),
+
+	}
+
+}
+
+unsafe fn c_string (
+	pointer: * const libc::c_char,
+) -> String {
+
+	CStr::from_ptr (pointer).to_string_lossy ().into_owned ()
+
+}
+
+fn lookup_group (
+	group: &str,
+) -> Result <libc::gid_t, String> {
+
+	let group_c =
+		try! (
+			CString::new (group).map_err (
+				|_| format! ("invalid group: {}", group)));
+
+	let group_entry =
+		unsafe { libc::getgrnam (group_c.as_ptr ()) };
+
+	if ! group_entry.is_null () {
+		return Ok (unsafe { (* group_entry).gr_gid });
+	}
+
+	match group.parse::<libc::gid_t> () {
+		Ok (gid) => Ok (gid),
+		Err (_) => Err (format! ("unknown group: {}", group)),
+	}
+
+}
+
+// cgroups are named relative to the cgroup filesystem, and may not leave it
+
+fn cgroup_path (
+	cgroup: &str,
+) -> Result <PathBuf, String> {
+
+	let relative_path =
+		Path::new (cgroup.trim_start_matches ('/'));
+
+	if cgroup.contains ('\0')
+	|| relative_path.as_os_str ().is_empty ()
+	|| relative_path.components ().any (
+		|component|
+		match component {
+			Component::Normal (_) => false,
+			_ => true,
+		}) {
+
+		return Err (
+			format! (
+				"invalid cgroup: {}",
+				cgroup));
+
+	}
+
+	Ok (Path::new ("/sys/fs/cgroup").join (relative_path))
+
+}
+
+fn prepare_cgroup (
+	job_config: & JobConfig,
+	cgroup: &str,
+) -> Result <PathBuf, String> {
+
+	let cgroup_path =
+		try! (cgroup_path (cgroup));
+
+	try! (
+		fs::create_dir_all (
+			& cgroup_path,
+		).map_err (
+			|err|
+			format! (
+				"error creating cgroup {}: {}",
+				cgroup_path.display (),
+				err)
+		));
+
+	if let Some (ref memory_max) = job_config.cgroup_memory_max {
+
+		try! (
+			write_cgroup_file (
+				& cgroup_path,
+				"memory.max",
+				memory_max));
+
+	}
+
+	if let Some (ref io_max) = job_config.cgroup_io_max {
+
+		for io_max_line in io_max.iter () {
+
+			try! (
+				write_cgroup_file (
+					& cgroup_path,
+					"io.max",
+					io_max_line));
+
+		}
+
+	}
+
+	Ok (cgroup_path)
+
+}
+
+fn write_cgroup_file (
+	cgroup_path: & Path,
+	name: &str,
+	value: &str,
+) -> Result <(), String> {
+
+	let file_path =
+		cgroup_path.join (name);
+
+	OpenOptions::new ()
+		.write (true)
+		.open (& file_path)
+		.and_then (
+			|mut file|
+			file.write_all (value.as_bytes ()))
+		.map_err (
+			|err|
+			format! (
+				"error writing {} to {}: {}",
+				value,
+				file_path.display (),
+				err))
+
+}
+
+// runs in the forked child, so only async-signal-safe calls are allowed here
+
+fn apply_settings (
+	settings: & ExecSettings,
+) -> io::Result <()> {
+
+	unsafe {
+
+		if let Some (ref cgroup_procs) = settings.cgroup_procs {
+
+			let fd =
+				libc::open (
+					cgroup_procs.as_ptr (),
+					libc::O_WRONLY);
+
+			if fd < 0 {
+				return Err (io::Error::last_os_error ());
+			}
+
+			let written =
+				libc::write (
+					fd,
+					b"0".as_ptr () as * const libc::c_void,
+					1);
+
+			libc::close (fd);
+
+			if written != 1 {
+				return Err (io::Error::last_os_error ());
+			}
+
+		}
+
+		if let Some (nice) = settings.nice {
+
+			if libc::setpriority (libc::PRIO_PROCESS, 0, nice) != 0 {
+				return Err (io::Error::last_os_error ());
+			}
+
+		}
+
+		if let Some (ioprio) = settings.ioprio {
+
+			if libc::syscall (
+				libc::SYS_ioprio_set,
+				IOPRIO_WHO_PROCESS,
+				0,
+				ioprio) != 0 {
+
+				return Err (io::Error::last_os_error ());
+
+			}
+
+		}
+
+		if let Some (umask) = settings.umask {
+			libc::umask (umask);
+		}
+
+		if let Some (gid) = settings.gid {
+
+			if libc::setgroups (1, & gid) != 0
+			|| libc::setgid (gid) != 0 {
+				return Err (io::Error::last_os_error ());
+			}
+
+		}
+
+		if let Some (uid) = settings.uid {
+
+			if libc::setuid (uid) != 0 {
+				return Err (io::Error::last_os_error ());
+			}
+
+		}
+
+	}
+
+	Ok (())
+
+}
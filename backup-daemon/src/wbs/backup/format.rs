@@ -0,0 +1,384 @@
+extern crate toml;
+extern crate yaml_rust;
+
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use yaml_rust::Yaml;
+use yaml_rust::YamlEmitter;
+use yaml_rust::YamlLoader;
+
+// Config files in any format are read into a json value, so that templates,
+// includes and validation behave the same whichever format they came from.
+
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+	Json,
+	Toml,
+	Yaml,
+}
+
+impl ConfigFormat {
+
+	pub fn from_extension (
+		path: & Path,
+	) -> Option <ConfigFormat> {
+
+		match path.extension ().and_then (|extension| extension.to_str ()) {
+			Some ("json") => Some (ConfigFormat::Json),
+			Some ("toml") => Some (ConfigFormat::Toml),
+			Some ("yaml") | Some ("yml") => Some (ConfigFormat::Yaml),
+			_ => None,
+		}
+
+	}
+
+	// the main config file has always been json whatever its name
+
+	pub fn from_path (
+		path: & Path,
+	) -> ConfigFormat {
+
+		ConfigFormat::from_extension (
+			path,
+		).unwrap_or (
+			ConfigFormat::Json)
+
+	}
+
+}
+
+pub fn parse_value (
+	format: ConfigFormat,
+	source: &str,
+) -> Result <Json, String> {
+
+	match format {
+
+		ConfigFormat::Json =>
+			Json::from_str (
+				source,
+			).map_err (
+				|err|
+				parser_error (& err)
+			),
+
+		ConfigFormat::Toml => {
+
+			let mut parser =
+				toml::Parser::new (source);
+
+			match parser.parse () {
+
+				Some (table) =>
+					Ok (toml_to_json (toml::Value::Table (table))),
+
+				None => {
+
+					let error =
+						& parser.errors [0];
+
+					let (line, column) =
+						parser.to_linecol (error.lo);
+
+					Err (
+						format! (
+							"{} at line {} column {}",
+							error.desc,
+							line + 1,
+							column + 1))
+
+				},
+
+			}
+
+		},
+
+		ConfigFormat::Yaml => {
+
+			let mut documents =
+				try! (
+					YamlLoader::load_from_str (
+						source,
+					).map_err (
+						|err|
+						err.to_string ()
+					));
+
+			if documents.len () != 1 {
+
+				return Err (
+					format! (
+						"expected one yaml document but found {}",
+						documents.len ()));
+
+			}
+
+			yaml_to_json (
+				documents.remove (0))
+
+		},
+
+	}
+
+}
+
+pub fn format_value (
+	format: ConfigFormat,
+	value: & Json,
+) -> Result <String, String> {
+
+	match format {
+
+		ConfigFormat::Json =>
+			Ok (format! (
+				"{}\n",
+				json::as_pretty_json (value).indent (1))),
+
+		ConfigFormat::Toml => {
+
+			match try! (json_to_toml (value)) {
+
+				Some (table @ toml::Value::Table (_)) =>
+					Ok (table.to_string ()),
+
+				_ => Err (
+					"expected an object".to_string ()),
+
+			}
+
+		},
+
+		ConfigFormat::Yaml => {
+
+			let mut output =
+				String::new ();
+
+			try! (
+				YamlEmitter::new (
+					&mut output,
+				).dump (
+					& json_to_yaml (value),
+				).map_err (
+					|err|
+					format! ("{:?}", err)
+				));
+
+			output.push ('\n');
+
+			Ok (output)
+
+		},
+
+	}
+
+}
+
+pub fn parser_error (
+	err: & json::ParserError,
+) -> String {
+
+	match * err {
+
+		json::ParserError::SyntaxError (ref code, line, column) =>
+			format! (
+				"{:?} at line {} column {}",
+				code,
+				line,
+				column),
+
+		json::ParserError::IoError (ref err) =>
+			err.to_string (),
+
+	}
+
+}
+
+fn toml_to_json (
+	value: toml::Value,
+) -> Json {
+
+	match value {
+
+		toml::Value::String (string) => Json::String (string),
+		toml::Value::Integer (integer) => Json::I64 (integer),
+		toml::Value::Float (float) => Json::F64 (float),
+		toml::Value::Boolean (boolean) => Json::Boolean (boolean),
+		toml::Value::Datetime (datetime) => Json::String (datetime),
+
+		toml::Value::Array (values) =>
+			Json::Array (
+				values.into_iter ().map (toml_to_json).collect ()),
+
+		toml::Value::Table (table) =>
+			Json::Object (
+				table.into_iter ().map (
+					|(key, value)| (key, toml_to_json (value))
+				).collect ()),
+
+	}
+
+}
+
+// toml has no null, so null fields are left out, and arrays must not mix
+// tables with other values
+
+fn json_to_toml (
+	value: & Json,
+) -> Result <Option <toml::Value>, String> {
+
+	Ok (Some (match * value {
+
+		Json::Null => return Ok (None),
+
+		Json::String (ref string) => toml::Value::String (string.clone ()),
+		Json::I64 (integer) => toml::Value::Integer (integer),
+		Json::U64 (integer) => toml::Value::Integer (integer as i64),
+		Json::F64 (float) => toml::Value::Float (float),
+		Json::Boolean (boolean) => toml::Value::Boolean (boolean),
+
+		Json::Array (ref values) => {
+
+			let mut array: Vec <toml::Value> =
+				vec! [];
+
+			for value in values.iter () {
+
+				match try! (json_to_toml (value)) {
+
+					Some (value) => array.push (value),
+
+					None => return Err (
+						"toml arrays cannot hold null".to_string ()),
+
+				}
+
+			}
+
+			let tables =
+				array.iter ().filter (
+					|value|
+					match ** value {
+						toml::Value::Table (_) => true,
+						_ => false,
+					}
+				).count ();
+
+			if tables != 0 && tables != array.len () {
+
+				return Err (
+					"toml arrays cannot mix tables with other values".to_string ());
+
+			}
+
+			toml::Value::Array (array)
+
+		},
+
+		Json::Object (ref object) => {
+
+			let mut table: BTreeMap <String, toml::Value> =
+				BTreeMap::new ();
+
+			for (key, value) in object.iter () {
+
+				if let Some (value) = try! (json_to_toml (value)) {
+					table.insert (key.clone (), value);
+				}
+
+			}
+
+			toml::Value::Table (table)
+
+		},
+
+	}))
+
+}
+
+fn yaml_to_json (
+	value: Yaml,
+) -> Result <Json, String> {
+
+	Ok (match value {
+
+		Yaml::Null => Json::Null,
+		Yaml::String (string) => Json::String (string),
+		Yaml::Integer (integer) => Json::I64 (integer),
+		Yaml::Boolean (boolean) => Json::Boolean (boolean),
+
+		Yaml::Real (real) => Json::F64 (
+			try! (
+				real.parse ().map_err (
+					|_| format! ("invalid number {}", real)))),
+
+		Yaml::Array (values) => Json::Array (
+			try! (
+				values.into_iter ().map (
+					yaml_to_json
+				).collect ())),
+
+		Yaml::Hash (hash) => {
+
+			let mut object: BTreeMap <String, Json> =
+				BTreeMap::new ();
+
+			for (key, value) in hash {
+
+				let key =
+					match key {
+						Yaml::String (key) => key,
+						Yaml::Integer (key) => key.to_string (),
+						_ => return Err ("yaml keys must be strings".to_string ()),
+					};
+
+				object.insert (
+					key,
+					try! (yaml_to_json (value)));
+
+			}
+
+			Json::Object (object)
+
+		},
+
+		Yaml::Alias (_) => return Err (
+			"yaml aliases are not supported".to_string ()),
+
+		Yaml::BadValue => return Err (
+			"invalid yaml value".to_string ()),
+
+	})
+
+}
+
+fn json_to_yaml (
+	value: & Json,
+) -> Yaml {
+
+	match * value {
+
+		Json::Null => Yaml::Null,
+		Json::String (ref string) => Yaml::String (string.clone ()),
+		Json::I64 (integer) => Yaml::Integer (integer),
+		Json::U64 (integer) => Yaml::Integer (integer as i64),
+		Json::F64 (float) => Yaml::Real (float.to_string ()),
+		Json::Boolean (boolean) => Yaml::Boolean (boolean),
+
+		Json::Array (ref values) =>
+			Yaml::Array (
+				values.iter ().map (json_to_yaml).collect ()),
+
+		Json::Object (ref object) =>
+			Yaml::Hash (
+				object.iter ().map (
+					|(key, value)|
+					(Yaml::String (key.clone ()), json_to_yaml (value))
+				).collect ()),
+
+	}
+
+}
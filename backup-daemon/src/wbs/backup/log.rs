@@ -2,16 +2,384 @@
 
 extern crate time;
 
+use rustc_serialize::json::Json;
+
+use std::collections::BTreeMap;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::rc::Rc;
+
+use time::Timespec;
+
+use wbs::backup::clock::*;
+use wbs::backup::config::*;
+use wbs::backup::time::*;
+
 #[macro_export]
 macro_rules! log {
 
-	($($arg:tt)*) => {
+	($logger:expr, $level:expr, [ $( $key:ident = $value:expr ),* ], $($arg:tt)*) => {
 
-		println! (
-			"{}: {}",
-			time_format_pretty (time::get_time ()),
+		$logger.log (
+			$level,
+			vec! [ $( (stringify! ($key), {
+				use ::rustc_serialize::json::ToJson;
+				($value).to_json ()
+			}) ),* ],
 			format! ($($arg)*))
 
+	};
+
+	($logger:expr, $level:expr, $($arg:tt)*) => {
+
+		$logger.log (
+			$level,
+			vec! [],
+			format! ($($arg)*))
+
+	};
+
+}
+
+// ######################################## interface
+
+#[derive (Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+	Debug,
+	Info,
+	Warning,
+	Error,
+}
+
+pub struct LogRecord {
+
+	pub time: Timespec,
+	pub level: Level,
+	pub message: String,
+
+	pub fields: Vec <(& 'static str, Json)>,
+
+}
+
+pub trait LogSink {
+
+	fn write (
+		& self,
+		record: & LogRecord,
+	);
+
+}
+
+pub struct TextSink;
+
+pub struct JsonSink;
+
+pub struct SyslogSink {
+	socket: UnixDatagram,
+	path: String,
+	ident: String,
+}
+
+// records are timed by the same clock as the scheduler, so that they agree
+// with it when it is simulated
+
+pub struct Logger {
+	level: Level,
+	sink: Box <LogSink>,
+	clock: Rc <Clock>,
+}
+
+// ######################################## implementation
+
+// ---------- level
+
+impl Level {
+
+	pub fn from_string (
+		str: &str,
+	) -> Result <Level, String> {
+
+		match str {
+			"debug" => Ok (Level::Debug),
+			"info" => Ok (Level::Info),
+			"warning" => Ok (Level::Warning),
+			"error" => Ok (Level::Error),
+			_ => Err (format! ("unknown log level: {}", str)),
+		}
+
+	}
+
+	fn syslog_severity (
+		& self,
+	) -> u8 {
+
+		match * self {
+			Level::Debug => 7,
+			Level::Info => 6,
+			Level::Warning => 4,
+			Level::Error => 3,
+		}
+
+	}
+
+}
+
+impl ToString for Level {
+
+	fn to_string (& self) -> String {
+
+		match * self {
+			Level::Debug => { "debug".to_string () }
+			Level::Info => { "info".to_string () }
+			Level::Warning => { "warning".to_string () }
+			Level::Error => { "error".to_string () }
+		}
+
+	}
+
+}
+
+// ---------- formatting
+
+pub fn format_text (
+	record: & LogRecord,
+) -> String {
+
+	match record.level {
+
+		Level::Info => format! (
+			"{}: {}",
+			time_format_pretty (record.time),
+			record.message),
+
+		_ => format! (
+			"{}: {}: {}",
+			time_format_pretty (record.time),
+			record.level.to_string (),
+			record.message),
+
+	}
+
+}
+
+pub fn format_json (
+	record: & LogRecord,
+) -> String {
+
+	let mut object: BTreeMap <String, Json> =
+		BTreeMap::new ();
+
+	for & (key, ref value) in record.fields.iter () {
+		object.insert (key.to_string (), value.clone ());
+	}
+
+	object.insert (
+		"time".to_string (),
+		Json::String (time_format_iso (record.time)));
+
+	object.insert (
+		"level".to_string (),
+		Json::String (record.level.to_string ()));
+
+	object.insert (
+		"message".to_string (),
+		Json::String (record.message.clone ()));
+
+	Json::Object (object).to_string ()
+
+}
+
+fn format_fields (
+	record: & LogRecord,
+) -> String {
+
+	record.fields.iter ().map (
+		|& (key, ref value)|
+
+		match * value {
+			Json::String (ref value) => format! (" {}={}", key, value),
+			ref value => format! (" {}={}", key, value),
+		}
+
+	).collect ()
+
+}
+
+// ---------- sinks
+
+impl LogSink for TextSink {
+
+	fn write (
+		& self,
+		record: & LogRecord,
+	) {
+
+		println! (
+			"{}",
+			format_text (record));
+
+	}
+
+}
+
+impl LogSink for JsonSink {
+
+	fn write (
+		& self,
+		record: & LogRecord,
+	) {
+
+		println! (
+			"{}",
+			format_json (record));
+
+	}
+
+}
+
+impl SyslogSink {
+
+	pub fn new (
+		path: &str,
+		ident: &str,
+	) -> Result <SyslogSink, String> {
+
+		let socket =
+			try! (
+				UnixDatagram::unbound ().map_err (
+					|err|
+					format! (
+						"error creating syslog socket: {}",
+						err)
+				));
+
+		Ok (SyslogSink {
+			socket: socket,
+			path: path.to_string (),
+			ident: ident.to_string (),
+		})
+
+	}
+
+}
+
+impl LogSink for SyslogSink {
+
+	fn write (
+		& self,
+		record: & LogRecord,
+	) {
+
+		// facility 3 is "daemon"
+
+		let message =
+			format! (
+				"<{}>{}[{}]: {}{}",
+				3 * 8 + record.level.syslog_severity (),
+				self.ident,
+				process::id (),
+				record.message,
+				format_fields (record));
+
+		if let Err (err) =
+			self.socket.send_to (
+				message.as_bytes (),
+				& self.path) {
+
+			eprintln! (
+				"error writing to syslog {}: {}",
+				self.path,
+				err);
+
+			eprintln! (
+				"{}",
+				format_text (record));
+
+		}
+
+	}
+
+}
+
+// ---------- logger
+
+impl Logger {
+
+	pub fn new (
+		level: Level,
+		sink: Box <LogSink>,
+		clock: Rc <Clock>,
+	) -> Logger {
+
+		Logger {
+			level: level,
+			sink: sink,
+			clock: clock,
+		}
+
+	}
+
+	pub fn from_config (
+		config: & Config,
+		clock: Rc <Clock>,
+	) -> Logger {
+
+		let level =
+			Level::from_string (
+				config.log_level.as_ref ().map_or ("info", |level| level),
+			).unwrap_or_else (
+				|err|
+				panic! ("error in config: {}", err)
+			);
+
+		let sink: Box <LogSink> =
+			match config.log_sink.as_ref ().map_or ("text", |sink| sink) {
+
+			"text" => Box::new (TextSink),
+
+			"json" => Box::new (JsonSink),
+
+			"syslog" => Box::new (
+				SyslogSink::new (
+					config.syslog_path.as_ref ().map_or ("/dev/log", |path| path),
+					"backup-daemon",
+				).unwrap_or_else (
+					|err|
+					panic! ("{}", err)
+				)),
+
+			sink => panic! (
+				"error in config: unknown log sink: {}",
+				sink),
+
+		};
+
+		Logger::new (
+			level,
+			sink,
+			clock)
+
+	}
+
+	pub fn log (
+		& self,
+		level: Level,
+		fields: Vec <(& 'static str, Json)>,
+		message: String,
+	) {
+
+		if level < self.level {
+			return;
+		}
+
+		self.sink.write (
+			& LogRecord {
+				time: self.clock.now (),
+				level: level,
+				message: message,
+				fields: fields,
+			});
+
 	}
 
 }
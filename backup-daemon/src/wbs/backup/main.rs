@@ -1,52 +1,96 @@
 extern crate time;
 
 use std::cmp::Ordering;
-use std::thread;
-use std::time::Duration;
 
+use time::Timespec;
+
+use wbs::backup::clock::*;
 use wbs::backup::config::*;
+use wbs::backup::log::*;
+use wbs::backup::report::*;
 use wbs::backup::run::*;
 use wbs::backup::state::*;
 use wbs::backup::time::*;
+use wbs::backup::window::*;
+use wbs::backup::zone::*;
+
+pub struct Context <'a> {
+	pub clock: & 'a Clock,
+	pub runner: & 'a ScriptRunner,
+	pub logger: & 'a Logger,
+}
+
+// how far a job got in a pass: sends are queued across all jobs, and the
+// export and verify follow once the sends have had their turn
+
+enum Progress {
+	Blocked,
+	SendDue (Timespec),
+	SendDone,
+}
+
+// runs each stage which is due, in order. a stage already run in this period
+// lets the next one be checked, so that a stage deferred by a blackout carries
+// on when it ends, while a stage which is deferred holds back those after it.
 
 fn loop_job (
+	context: & Context,
 	config: & Config,
 	state: &mut Global,
 	job_index: usize,
-) {
+) -> Progress {
 
-	let now = time::get_time ();
-	let last_hour = round_down_hour (now);
-	let last_day = round_down_day (now);
+	let now = context.clock.now ();
+
+	let (last_hour, last_day) = {
+
+		let zone =
+			& state.jobs [job_index].zone;
+
+		(round_down_hour (zone, now), round_down_day (zone, now))
+
+	};
 
 	match state.jobs [job_index].last_sync {
 
 		None => {
+
+			if deferred (context, config, state, job_index, "sync") {
+				return Progress::Blocked
+			}
+
 			do_sync (
+				context,
 				config,
 				state,
 				job_index,
 				last_hour,
 			)
+
 		}
 
 		Some (last_sync) => match last_sync.cmp (& last_hour) {
 
 			Ordering::Less => {
+
+				if deferred (context, config, state, job_index, "sync") {
+					return Progress::Blocked
+				}
+
 				do_sync (
+					context,
 					config,
 					state,
 					job_index,
 					last_hour,
 				)
-			}
 
-			Ordering::Equal => {
-				return
 			}
 
+			Ordering::Equal => {}
+
 			Ordering::Greater => {
-				panic! ("last sync is in future")
+				in_future (context, config, job_index, "sync", last_sync);
 			}
 
 		}
@@ -56,65 +100,213 @@ fn loop_job (
 	match state.jobs [job_index].last_snapshot {
 
 		None => {
+
+			if deferred (context, config, state, job_index, "snapshot") {
+				return Progress::Blocked
+			}
+
 			do_snapshot (
+				context,
 				config,
 				state,
 				job_index,
 				last_day,
 			)
+
 		}
 
 		Some (last_snapshot) => match last_snapshot.cmp (&last_day) {
 
 			Ordering::Less => {
+
+				if deferred (context, config, state, job_index, "snapshot") {
+					return Progress::Blocked
+				}
+
 				do_snapshot (
+					context,
 					config,
 					state,
 					job_index,
 					last_day,
 				)
+
+			}
+
+			Ordering::Equal => {}
+
+			Ordering::Greater => {
+				in_future (context, config, job_index, "snapshot", last_snapshot);
+			}
+
+		}
+
+	}
+
+	match state.jobs [job_index].last_send {
+
+		None => {
+
+			if deferred (context, config, state, job_index, "send") {
+				return Progress::Blocked
+			}
+
+			Progress::SendDue (last_day)
+
+		}
+
+		Some (last_send) => match last_send.cmp (&last_day) {
+
+			Ordering::Less => {
+
+				if deferred (context, config, state, job_index, "send") {
+					return Progress::Blocked
+				}
+
+				Progress::SendDue (last_day)
+
 			}
 
 			Ordering::Equal => {
-				return
+				Progress::SendDone
 			}
 
 			Ordering::Greater => {
-				panic! ("last snapshot is in future")
+				in_future (context, config, job_index, "send", last_send);
+				Progress::SendDone
 			}
 
 		}
 
 	}
 
-	match state.jobs [job_index].last_send {
+}
+
+fn loop_job_export (
+	context: & Context,
+	config: & Config,
+	state: &mut Global,
+	job_index: usize,
+) {
+
+	let now = context.clock.now ();
+
+	// the export only copies snapshots which have been sent, so a send which
+	// is held back does not hold back the export
+
+	let last_period =
+		round_down_period (
+			& state.jobs [job_index].zone,
+			config.export_period (& config.jobs [job_index]),
+			now);
+
+	match state.jobs [job_index].last_export {
 
 		None => {
-			do_send (
+
+			if deferred (context, config, state, job_index, "export") {
+				return
+			}
+
+			do_export (
+				context,
 				config,
 				state,
 				job_index,
-				last_day,
+				last_period,
 			)
+
 		}
 
-		Some (last_send) => match last_send.cmp (&last_day) {
+		Some (last_export) => match last_export.cmp (&last_period) {
 
 			Ordering::Less => {
-				do_send (
+
+				if deferred (context, config, state, job_index, "export") {
+					return
+				}
+
+				do_export (
+					context,
 					config,
 					state,
 					job_index,
-					last_day,
+					last_period,
 				)
+
 			}
 
-			Ordering::Equal => {
+			Ordering::Equal => {}
+
+			Ordering::Greater => {
+				in_future (context, config, job_index, "export", last_export);
+			}
+
+		}
+
+	}
+
+}
+
+// verification runs on its own schedule and, like the export, only picks from
+// snapshots which have been sent, so a send which is held back does not hold
+// it back either
+
+fn loop_job_verify (
+	context: & Context,
+	config: & Config,
+	state: &mut Global,
+	job_index: usize,
+) {
+
+	let now = context.clock.now ();
+
+	let last_period =
+		round_down_period (
+			& state.jobs [job_index].zone,
+			config.verify_period (& config.jobs [job_index]),
+			now);
+
+	match state.jobs [job_index].last_verify {
+
+		None => {
+
+			if deferred (context, config, state, job_index, "verify") {
 				return
 			}
 
+			do_verify (
+				context,
+				config,
+				state,
+				job_index,
+				last_period,
+			)
+
+		}
+
+		Some (last_verify) => match last_verify.cmp (&last_period) {
+
+			Ordering::Less => {
+
+				if deferred (context, config, state, job_index, "verify") {
+					return
+				}
+
+				do_verify (
+					context,
+					config,
+					state,
+					job_index,
+					last_period,
+				)
+
+			}
+
+			Ordering::Equal => {}
+
 			Ordering::Greater => {
-				panic! ("last send is in future")
+				in_future (context, config, job_index, "verify", last_verify);
 			}
 
 		}
@@ -123,28 +315,310 @@ fn loop_job (
 
 }
 
-fn loop_once (
+// a stage which last ran after the start of the current period means the wall
+// clock has gone back. it counts as done, so that the period is not run twice,
+// and the next period starts once the clock has caught up.
+
+fn in_future (
+	context: & Context,
+	config: & Config,
+	job_index: usize,
+	stage: &str,
+	last: Timespec,
+) {
+
+	let job_config = & config.jobs [job_index];
+
+	log! (
+		context.logger,
+		Level::Warning,
+		[
+			job = job_config.name,
+			stage = stage,
+			last = time_format_iso (last)
+		],
+		"last {} for {} at {} is in the future, waiting for the clock",
+		stage,
+		job_config.name,
+		time_format_pretty (last));
+
+}
+
+// a stage which is due while blacked out is left for a later pass, and later
+// stages wait behind it; the deferral is logged once and kept in the state so
+// that it shows in the status
+
+fn deferred (
+	context: & Context,
+	config: & Config,
+	state: &mut Global,
+	job_index: usize,
+	stage: &str,
+) -> bool {
+
+	let job_config = & config.jobs [job_index];
+
+	let until =
+		blackout_end (
+			& config.job_windows (job_config),
+			& state.jobs [job_index].zone,
+			stage,
+			context.clock.now ());
+
+	match until {
+
+		None => {
+
+			if state.jobs [job_index].deferred_stage.as_ref ().map (
+				|deferred_stage| deferred_stage.as_str ()
+			) == Some (stage) {
+
+				state.jobs [job_index].deferred_stage = None;
+				state.jobs [job_index].deferred_until = None;
+
+				state.write_state (config);
+
+			}
+
+			false
+
+		},
+
+		Some (until) => {
+
+			if state.jobs [job_index].deferred_stage.as_ref ().map (
+				|deferred_stage| deferred_stage.as_str ()
+			) != Some (stage)
+			|| state.jobs [job_index].deferred_until != Some (until) {
+
+				log! (
+					context.logger,
+					Level::Info,
+					[
+						job = job_config.name,
+						stage = stage,
+						deferred_until = time_format_iso (until)
+					],
+					"{} deferred for {} until {}",
+					stage,
+					job_config.name,
+					time_format_pretty (until));
+
+				state.jobs [job_index].deferred_stage = Some (stage.to_string ());
+				state.jobs [job_index].deferred_until = Some (until);
+
+				state.write_state (config);
+
+			}
+
+			true
+
+		},
+
+	}
+
+}
+
+pub fn loop_once (
+	context: & Context,
 	config: & Config,
 	state: &mut Global,
 ) {
 
-	for i in 0 .. state.jobs.len () {
-		loop_job (config, state, i)
+	let progress: Vec <Progress> =
+		(0 .. state.jobs.len ()).map (
+			|job_index|
+			loop_job (context, config, state, job_index)
+		).collect ();
+
+	let send_jobs: Vec <(usize, Timespec)> =
+		progress.iter ().enumerate ().filter_map (
+			|(job_index, job_progress)|
+			match * job_progress {
+				Progress::SendDue (send_time) => Some ((job_index, send_time)),
+				_ => None,
+			}
+		).collect ();
+
+	do_send_queue (
+		context,
+		config,
+		state,
+		& send_jobs);
+
+	for (job_index, job_progress) in progress.iter ().enumerate () {
+
+		match * job_progress {
+
+			Progress::Blocked => (),
+
+			Progress::SendDue (_) | Progress::SendDone => {
+				loop_job_export (context, config, state, job_index);
+				loop_job_verify (context, config, state, job_index);
+			},
+
+		}
+
+		check_verify_overdue (context, config, state, job_index);
+
+	}
+
+}
+
+pub fn next_due_time (
+	config: & Config,
+	state: & Global,
+	now: Timespec,
+) -> Timespec {
+
+	let mut next_due: Option <Timespec> =
+		None;
+
+	for (job_config, job) in config.jobs.iter ().zip (state.jobs.iter ()) {
+
+		let mut stage_times: Vec <Timespec> =
+			vec! [ next_hour (& job.zone, now) ];
+
+		if job_config.snapshot_script.is_some ()
+		|| job_config.send_script.is_some () {
+
+			stage_times.push (
+				next_day (& job.zone, now));
+
+		}
+
+		if let Some (deferred_until) = job.deferred_until {
+
+			stage_times.push (
+				deferred_until);
+
+		}
+
+		if job_config.export_script.is_some () {
+
+			stage_times.push (
+				next_period (
+					& job.zone,
+					config.export_period (job_config),
+					now));
+
+		}
+
+		if config.verify_enabled (job_config) {
+
+			stage_times.push (
+				next_period (
+					& job.zone,
+					config.verify_period (job_config),
+					now));
+
+		}
+
+		for stage_time in stage_times {
+
+			if next_due.map_or (true, |next_due| stage_time < next_due) {
+				next_due = Some (stage_time);
+			}
+
+		}
+
 	}
 
+	next_due.unwrap_or (
+		next_hour (& Zone::utc (), now))
+
 }
 
 pub fn main_loop (
+	context: & Context,
 	config: & Config,
 	state: &mut Global,
 ) {
 
+	let mut report_exporter =
+		ReportExporter::new ();
+
 	loop {
 
-		loop_once (config, state);
+		loop_once (context, config, state);
+
+		for err in report_exporter.export (
+			config,
+			state,
+			context.clock.now ()) {
+
+			log! (
+				context.logger,
+				Level::Warning,
+				"{}",
+				err);
+
+		}
+
+		let next_due = {
+
+			let next_due =
+				next_due_time (
+					config,
+					state,
+					context.clock.now ());
+
+			match report_exporter.next_due (config) {
+				Some (report_due) if report_due < next_due => report_due,
+				_ => next_due,
+			}
+
+		};
+
+		log! (
+			context.logger,
+			Level::Debug,
+			"sleeping until {}",
+			time_format_pretty (next_due));
+
+		match context.clock.sleep_until (next_due) {
 
-		thread::sleep (
-			Duration::from_millis (1000));
+			Wakeup::Deadline => (),
+
+			Wakeup::Interrupted => {
+
+				log! (
+					context.logger,
+					Level::Info,
+					"woken by signal");
+
+			},
+
+			Wakeup::Command (ref command) if command == "wake" => {
+
+				log! (
+					context.logger,
+					Level::Info,
+					"woken by control command");
+
+			},
+
+			Wakeup::Command (command) => {
+
+				log! (
+					context.logger,
+					Level::Warning,
+					[ command = command ],
+					"unknown control command: {}",
+					command);
+
+			},
+
+			Wakeup::ClockJump => {
+
+				log! (
+					context.logger,
+					Level::Warning,
+					"wall clock changed, recomputing schedule");
+
+			},
+
+		}
 
 	}
 
@@ -0,0 +1,153 @@
+extern crate time;
+
+use time::Timespec;
+
+use wbs::backup::config::*;
+use wbs::backup::state::*;
+
+// ######################################## interface
+
+// Sends from every job share one queue. Jobs with a higher send priority go
+// first, and otherwise the oldest snapshots, so that a job's own snapshots
+// always go out in order.
+
+pub struct QueueEntry {
+	pub job_index: usize,
+	pub snapshot_index: usize,
+	pub priority: i64,
+}
+
+// Limits the bytes sent across all jobs, using the sizes scripts report. The
+// bucket starts full, and a send may start unless it is overdrawn,
+// so one which overdraws it delays those after it until the debt is repaid.
+
+pub struct TokenBucket {
+	rate: u64,
+	capacity: f64,
+	tokens: f64,
+	updated: Timespec,
+}
+
+// ######################################## implementation
+
+pub fn send_queue (
+	config: & Config,
+	state: & Global,
+	job_indexes: & [usize],
+) -> Vec <QueueEntry> {
+
+	let mut entries: Vec <QueueEntry> =
+		vec! [];
+
+	for & job_index in job_indexes.iter () {
+
+		let job_config = & config.jobs [job_index];
+
+		if job_config.send_script.is_none () {
+			continue;
+		}
+
+		for (snapshot_index, snapshot)
+			in state.jobs [job_index].snapshots.iter ().enumerate () {
+
+			match snapshot.state {
+
+				SnapshotState::Snapshotted | SnapshotState::Sending => {
+
+					entries.push (
+						QueueEntry {
+							job_index: job_index,
+							snapshot_index: snapshot_index,
+							priority: job_config.send_priority.unwrap_or (0),
+						});
+
+				},
+
+				_ => {},
+
+			}
+
+		}
+
+	}
+
+	entries.sort_by_key (
+		|entry| (
+			- entry.priority,
+			state.jobs [entry.job_index].snapshots [entry.snapshot_index].snapshot_time,
+			entry.job_index,
+		));
+
+	entries
+
+}
+
+impl TokenBucket {
+
+	pub fn new (
+		rate: u64,
+		capacity: u64,
+		now: Timespec,
+	) -> TokenBucket {
+
+		TokenBucket {
+			rate: rate,
+			capacity: capacity as f64,
+			tokens: capacity as f64,
+			updated: now,
+		}
+
+	}
+
+	fn refill (
+		&mut self,
+		now: Timespec,
+	) {
+
+		if now > self.updated {
+
+			let elapsed =
+				(now - self.updated).num_milliseconds () as f64 / 1000.0;
+
+			self.tokens =
+				(self.tokens + elapsed * self.rate as f64).min (self.capacity);
+
+			self.updated = now;
+
+		}
+
+	}
+
+	// when the bucket is overdrawn, the time at which it will have been repaid
+
+	pub fn wait_until (
+		&mut self,
+		now: Timespec,
+	) -> Option <Timespec> {
+
+		self.refill (now);
+
+		if self.tokens >= 0.0 || self.rate == 0 {
+			return None;
+		}
+
+		let seconds =
+			(- self.tokens / self.rate as f64).ceil () as i64;
+
+		Some (Timespec::new (now.sec + seconds.max (1), 0))
+
+	}
+
+	pub fn consume (
+		&mut self,
+		bytes: u64,
+		now: Timespec,
+	) {
+
+		self.refill (now);
+
+		self.tokens -= bytes as f64;
+
+	}
+
+}
@@ -0,0 +1,784 @@
+extern crate libc;
+extern crate time;
+
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+
+use std::ffi::CStr;
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use time::Timespec;
+
+use wbs::backup::config::*;
+use wbs::backup::format::*;
+use wbs::backup::run::*;
+use wbs::backup::state::*;
+use wbs::backup::time::*;
+
+// ######################################## interface
+
+// A compact summary of the daemon's state, written to a directory or posted to
+// a url whenever it changes, and at least once an interval, so that the reports
+// from many hosts can be gathered into one table. Times are in utc, like the
+// state file; the interval is in seconds, and missing from older reports.
+
+#[derive (RustcEncodable, RustcDecodable)]
+pub struct Report {
+	pub host: String,
+	pub generated: String,
+	pub interval: Option <u64>,
+	pub jobs: Vec <ReportJob>,
+}
+
+#[derive (RustcEncodable, RustcDecodable)]
+pub struct ReportJob {
+
+	pub name: String,
+	pub state: String,
+
+	pub last_sync: Option <String>,
+	pub last_snapshot: Option <String>,
+	pub last_send: Option <String>,
+	pub last_export: Option <String>,
+	pub last_verified: Option <String>,
+
+	pub last_failure: Option <String>,
+	pub last_failure_stage: Option <String>,
+
+	pub deferred_stage: Option <String>,
+	pub verify_overdue: bool,
+
+	pub snapshots: u64,
+	pub unsent: u64,
+	pub latest_snapshot: Option <String>,
+
+}
+
+// a report which is several intervals old is from a host which has stopped
+
+pub const DEFAULT_REPORT_INTERVAL: i64 = 3600;
+
+pub const REPORT_STALE_INTERVALS: i64 = 3;
+
+// snapshots are sent daily, so this allows for one day to be missed
+
+pub const SEND_STALE_SECONDS: i64 = 2 * 86400;
+
+// failures in the day before a report are flagged
+
+pub const FAILURE_RECENT_SECONDS: i64 = 86400;
+
+const HTTP_TIMEOUT_SECONDS: u64 = 30;
+
+// ######################################## implementation
+
+pub fn build_report (
+	config: & Config,
+	state: & Global,
+	host: &str,
+	now: Timespec,
+) -> Report {
+
+	Report {
+
+		host: host.to_string (),
+		generated: time_format_pretty (now),
+		interval: Some (config.report_interval () as u64),
+
+		jobs: config.jobs.iter ().zip (state.jobs.iter ()).map (
+			|(job_config, job)|
+
+			ReportJob {
+
+				name: job_config.name.clone (),
+				state: job.state.to_string (),
+
+				last_sync: time_format_pretty_opt (job.last_sync),
+				last_snapshot: time_format_pretty_opt (job.last_snapshot),
+				last_send: time_format_pretty_opt (job.last_send),
+				last_export: time_format_pretty_opt (job.last_export),
+				last_verified: time_format_pretty_opt (job.last_verified ()),
+
+				last_failure: time_format_pretty_opt (job.last_failure),
+				last_failure_stage: job.last_failure_stage.clone (),
+
+				deferred_stage: job.deferred_stage.clone (),
+				verify_overdue: verify_overdue (config, job_config, job, now),
+
+				snapshots: job.snapshots.len () as u64,
+
+				unsent: job.snapshots.iter ().filter (
+					|snapshot|
+					match snapshot.state {
+						SnapshotState::Sent => false,
+						_ => true,
+					}
+				).count () as u64,
+
+				latest_snapshot: time_format_pretty_opt (
+					job.snapshots.iter ().map (
+						|snapshot| snapshot.snapshot_time
+					).max ()),
+
+			}
+
+		).collect (),
+
+	}
+
+}
+
+// The daemon exports its report from the main loop, but only when the jobs in
+// it have changed or an interval has passed. Posts are made by a thread of
+// their own, so that a slow collector does not hold up the schedule; a report
+// still waiting to be posted is replaced by a newer one, and failures are
+// returned by a later export.
+
+pub struct ReportExporter {
+	last_jobs: Option <String>,
+	last_export: Option <Timespec>,
+	poster: Option <ReportPoster>,
+}
+
+struct ReportPoster {
+	reports: mpsc::Sender <String>,
+	errors: mpsc::Receiver <String>,
+}
+
+impl ReportExporter {
+
+	pub fn new (
+	) -> ReportExporter {
+
+		ReportExporter {
+			last_jobs: None,
+			last_export: None,
+			poster: None,
+		}
+
+	}
+
+	pub fn export (
+		&mut self,
+		config: & Config,
+		state: & Global,
+		now: Timespec,
+	) -> Vec <String> {
+
+		let mut errors: Vec <String> =
+			self.poster.iter ().flat_map (
+				|poster| poster.errors.try_iter ()
+			).collect ();
+
+		if config.report_directory.is_none () && config.report_url.is_none () {
+			return errors;
+		}
+
+		match self.export_changed (config, state, now) {
+			Ok (()) => (),
+			Err (err) => errors.push (err),
+		}
+
+		errors
+
+	}
+
+	// when the next export is due even if nothing changes
+
+	pub fn next_due (
+		& self,
+		config: & Config,
+	) -> Option <Timespec> {
+
+		if config.report_directory.is_none () && config.report_url.is_none () {
+			return None;
+		}
+
+		self.last_export.map (
+			|last_export|
+			Timespec::new (last_export.sec + config.report_interval (), 0))
+
+	}
+
+	fn export_changed (
+		&mut self,
+		config: & Config,
+		state: & Global,
+		now: Timespec,
+	) -> Result <(), String> {
+
+		let host =
+			try! (host_name (config));
+
+		let report =
+			build_report (config, state, & host, now);
+
+		let jobs_json =
+			try! (
+				json::encode (
+					& report.jobs,
+				).map_err (
+					|err|
+					format! (
+						"error encoding report: {}",
+						err)
+				));
+
+		if self.last_jobs.as_ref () == Some (& jobs_json)
+		&& self.last_export.map_or (
+			false,
+			|last_export| now.sec - last_export.sec < config.report_interval ()) {
+
+			return Ok (());
+
+		}
+
+		let report_json =
+			try! (encode_report (& report));
+
+		try! (
+			write_report (
+				config,
+				& host,
+				& report_json));
+
+		if let Some (ref report_url) = config.report_url {
+
+			let poster =
+				self.poster.get_or_insert_with (
+					|| ReportPoster::start (report_url));
+
+			let _ = poster.reports.send (report_json);
+
+		}
+
+		self.last_jobs = Some (jobs_json);
+		self.last_export = Some (now);
+
+		Ok (())
+
+	}
+
+}
+
+impl ReportPoster {
+
+	fn start (
+		report_url: &str,
+	) -> ReportPoster {
+
+		let (reports_sender, reports_receiver) =
+			mpsc::channel::<String> ();
+
+		let (errors_sender, errors_receiver) =
+			mpsc::channel ();
+
+		let report_url =
+			report_url.to_string ();
+
+		thread::spawn (
+			move || {
+
+			while let Ok (mut report_json) = reports_receiver.recv () {
+
+				while let Ok (newer_json) = reports_receiver.try_recv () {
+					report_json = newer_json;
+				}
+
+				if let Err (err) = post_report (& report_url, & report_json) {
+
+					let _ = errors_sender.send (
+						format! (
+							"error posting report to {}: {}",
+							report_url,
+							err));
+
+				}
+
+			}
+
+		});
+
+		ReportPoster {
+			reports: reports_sender,
+			errors: errors_receiver,
+		}
+
+	}
+
+}
+
+fn encode_report (
+	report: & Report,
+) -> Result <String, String> {
+
+	json::encode (
+		report,
+	).map_err (
+		|err|
+		format! (
+			"error encoding report: {}",
+			err)
+	)
+
+}
+
+// the report is written to a temporary name and renamed, so that a reader
+// never sees part of one
+
+fn write_report (
+	config: & Config,
+	host: &str,
+	report_json: &str,
+) -> Result <(), String> {
+
+	if let Some (ref report_directory) = config.report_directory {
+
+		let report_path =
+			Path::new (report_directory).join (
+				format! ("{}.json", host));
+
+		let temp_path =
+			Path::new (report_directory).join (
+				format! (".{}.json.temp", host));
+
+		try! (
+			fs::write (
+				& temp_path,
+				report_json,
+			).and_then (
+				|()|
+				fs::rename (
+					& temp_path,
+					& report_path)
+			).map_err (
+				|err|
+				format! (
+					"error writing report {}: {}",
+					report_path.display (),
+					err)
+			));
+
+	}
+
+	Ok (())
+
+}
+
+pub fn host_name (
+	config: & Config,
+) -> Result <String, String> {
+
+	if let Some (ref host_name) = config.host_name {
+		return Ok (host_name.clone ());
+	}
+
+	let mut buffer: [libc::c_char; 256] =
+		[0; 256];
+
+	if unsafe {
+		libc::gethostname (
+			buffer.as_mut_ptr (),
+			buffer.len () - 1)
+	} != 0 {
+
+		return Err (
+			"error reading host name".to_string ());
+
+	}
+
+	Ok (
+		unsafe { CStr::from_ptr (buffer.as_ptr ()) }
+			.to_string_lossy ()
+			.into_owned ())
+
+}
+
+// reads every json file in the directory, keeping those which cannot be read
+// so that they show in the fleet report
+
+pub fn read_reports (
+	report_directory: & Path,
+) -> Result <Vec <(String, Result <Report, String>)>, String> {
+
+	let mut report_paths: Vec <_> =
+		try! (
+			fs::read_dir (
+				report_directory,
+			).and_then (
+				|entries|
+				entries.map (
+					|entry| entry.map (|entry| entry.path ())
+				).collect::<Result <Vec <_>, _>> ()
+			).map_err (
+				|err|
+				format! (
+					"error reading {}: {}",
+					report_directory.display (),
+					err)
+			));
+
+	report_paths.retain (
+		|report_path|
+		report_path.extension ().map_or (false, |extension| extension == "json"));
+
+	report_paths.sort ();
+
+	Ok (report_paths.into_iter ().map (
+		|report_path| {
+
+		let file_name =
+			report_path.file_name ().unwrap ().to_string_lossy ().into_owned ();
+
+		let report =
+			fs::read_to_string (
+				& report_path,
+			).map_err (
+				|err|
+				err.to_string ()
+			).and_then (
+				|report_json|
+				Json::from_str (& report_json).map_err (
+					|err|
+					parser_error (& err))
+			).and_then (
+				decode_value::<Report>
+			);
+
+		(file_name, report)
+
+	}).collect ())
+
+}
+
+// one line per job on each host, with flags for anything which needs looking
+// at, and the rows for stale hosts first
+
+pub fn format_fleet_report (
+	reports: & [(String, Result <Report, String>)],
+	now: Timespec,
+) -> String {
+
+	let mut rows: Vec <Vec <String>> =
+		vec! [];
+
+	for & (ref file_name, ref report) in reports.iter () {
+
+		let report =
+			match * report {
+
+			Ok (ref report) => report,
+
+			Err (ref err) => {
+
+				rows.push (vec! [
+					file_name.clone (),
+					"-".to_string (),
+					"-".to_string (),
+					"-".to_string (),
+					"-".to_string (),
+					"-".to_string (),
+					format! ("unreadable: {}", err),
+				]);
+
+				continue;
+
+			},
+
+		};
+
+		let generated =
+			parse_report_time (& Some (report.generated.clone ()));
+
+		let interval =
+			report.interval.map_or (
+				DEFAULT_REPORT_INTERVAL,
+				|interval| interval as i64);
+
+		let stale =
+			generated.map_or (
+				true,
+				|generated| now.sec - generated.sec > REPORT_STALE_INTERVALS * interval);
+
+		if report.jobs.is_empty () {
+
+			rows.push (vec! [
+				report.host.clone (),
+				"-".to_string (),
+				"-".to_string (),
+				"-".to_string (),
+				"-".to_string (),
+				"-".to_string (),
+				if stale { "stale".to_string () } else { "".to_string () },
+			]);
+
+		}
+
+		for job in report.jobs.iter () {
+
+			let mut flags: Vec <String> =
+				vec! [];
+
+			if stale {
+				flags.push ("stale".to_string ());
+			}
+
+			let report_time =
+				generated.unwrap_or (now);
+
+			if parse_report_time (& job.last_send).map_or (
+				true,
+				|last_send| report_time.sec - last_send.sec > SEND_STALE_SECONDS) {
+
+				flags.push ("send-overdue".to_string ());
+
+			}
+
+			if job.verify_overdue {
+				flags.push ("verify-overdue".to_string ());
+			}
+
+			if parse_report_time (& job.last_failure).map_or (
+				false,
+				|last_failure| report_time.sec - last_failure.sec <= FAILURE_RECENT_SECONDS) {
+
+				flags.push (
+					format! (
+						"failed:{}",
+						job.last_failure_stage.as_ref ().map_or ("unknown", |stage| stage)));
+
+			}
+
+			if let Some (ref deferred_stage) = job.deferred_stage {
+
+				flags.push (
+					format! (
+						"deferred:{}",
+						deferred_stage));
+
+			}
+
+			rows.push (vec! [
+				report.host.clone (),
+				job.name.clone (),
+				job.state.clone (),
+				job.last_send.clone ().unwrap_or ("never".to_string ()),
+				job.last_verified.clone ().unwrap_or ("never".to_string ()),
+				format! ("{}/{}", job.unsent, job.snapshots),
+				flags.join (" "),
+			]);
+
+		}
+
+	}
+
+	rows.sort_by_key (
+		|row| ! row [6].split (' ').any (
+			|flag| flag == "stale" || flag.starts_with ("unreadable")));
+
+	format_table (
+		& [ "HOST", "JOB", "STATE", "LAST SEND", "LAST VERIFIED", "UNSENT", "FLAGS" ],
+		& rows)
+
+}
+
+fn format_table (
+	headings: & [&str],
+	rows: & [Vec <String>],
+) -> String {
+
+	let mut widths: Vec <usize> =
+		headings.iter ().map (|heading| heading.len ()).collect ();
+
+	for row in rows.iter () {
+
+		for (width, cell) in widths.iter_mut ().zip (row.iter ()) {
+			* width = (* width).max (cell.len ());
+		}
+
+	}
+
+	let mut output =
+		String::new ();
+
+	let heading_row: Vec <String> =
+		headings.iter ().map (|heading| heading.to_string ()).collect ();
+
+	for row in Some (& heading_row).into_iter ().chain (rows.iter ()) {
+
+		let mut line =
+			String::new ();
+
+		for (cell, width) in row.iter ().zip (widths.iter ()) {
+			line.push_str (& format! ("{:1$}  ", cell, width));
+		}
+
+		output.push_str (line.trim_end ());
+		output.push ('\n');
+
+	}
+
+	output
+
+}
+
+fn parse_report_time (
+	time_str: & Option <String>,
+) -> Option <Timespec> {
+
+	time_str.as_ref ().and_then (
+		|time_str|
+		time::strptime (
+			time_str,
+			"%Y-%m-%d %H:%M:%S",
+		).ok ()
+	).map (
+		|tm| tm.to_timespec ()
+	)
+
+}
+
+// only plain http is supported, which is enough for a collector on the local
+// network or behind a proxy
+
+pub fn parse_http_url (
+	url: &str,
+) -> Result <(String, u16, String), String> {
+
+	if ! url.starts_with ("http://") {
+
+		return Err (
+			format! (
+				"unsupported url {}, expected http://",
+				url));
+
+	}
+
+	let rest =
+		& url ["http://".len () ..];
+
+	let (authority, path) =
+		match rest.find ('/') {
+			Some (slash) => (& rest [.. slash], & rest [slash ..]),
+			None => (rest, "/"),
+		};
+
+	let (host, port) =
+		match authority.rfind (':') {
+
+		Some (colon) => (
+			& authority [.. colon],
+			try! (
+				authority [colon + 1 ..].parse::<u16> ().map_err (
+					|_| format! ("invalid port in url {}", url))),
+		),
+
+		None => (authority, 80),
+
+	};
+
+	if host.is_empty () {
+
+		return Err (
+			format! (
+				"missing host in url {}",
+				url));
+
+	}
+
+	Ok ((host.to_string (), port, path.to_string ()))
+
+}
+
+fn post_report (
+	url: &str,
+	body: &str,
+) -> Result <(), String> {
+
+	let (host, port, path) =
+		try! (parse_http_url (url));
+
+	let timeout =
+		Duration::from_secs (HTTP_TIMEOUT_SECONDS);
+
+	let address =
+		try! (
+			(host.as_str (), port).to_socket_addrs ().map_err (
+				|err| err.to_string ()
+			).and_then (
+				|mut addresses|
+				addresses.next ().ok_or_else (
+					|| format! ("no address for {}", host))
+			));
+
+	let mut stream =
+		try! (
+			TcpStream::connect_timeout (
+				& address,
+				timeout,
+			).map_err (
+				|err| err.to_string ()
+			));
+
+	try! (
+		stream.set_read_timeout (
+			Some (timeout),
+		).and_then (
+			|()|
+			stream.set_write_timeout (
+				Some (timeout))
+		).map_err (
+			|err| err.to_string ()
+		));
+
+	try! (
+		write! (
+			stream,
+			concat! (
+				"POST {} HTTP/1.0\r\n",
+				"Host: {}\r\n",
+				"Content-Type: application/json\r\n",
+				"Content-Length: {}\r\n",
+				"\r\n",
+				"{}"),
+			path,
+			if port == 80 { host.clone () } else { format! ("{}:{}", host, port) },
+			body.len (),
+			body,
+		).map_err (
+			|err| err.to_string ()
+		));
+
+	let mut response =
+		String::new ();
+
+	try! (
+		stream.read_to_string (
+			&mut response,
+		).map_err (
+			|err| err.to_string ()
+		));
+
+	let status_line =
+		response.lines ().next ().unwrap_or ("");
+
+	match status_line.split (' ').nth (1) {
+
+		Some (status) if status.starts_with ('2') => Ok (()),
+
+		_ => Err (
+			format! (
+				"unexpected response: {}",
+				status_line)),
+
+	}
+
+}
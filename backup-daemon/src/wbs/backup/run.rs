@@ -1,37 +1,268 @@
+extern crate libc;
 extern crate time;
 
+use rustc_serialize::json;
+
+use std::cmp;
+use std::env;
+use std::fs;
 use std::fs::File;
-use std::io::Result;
+use std::io;
+use std::io::Read;
 use std::io::Write;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::process;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use time::Timespec;
 
+use wbs::backup::clock::*;
 use wbs::backup::config::*;
+use wbs::backup::exec::*;
+use wbs::backup::log::*;
+use wbs::backup::main::*;
+use wbs::backup::queue::*;
 use wbs::backup::state::*;
 use wbs::backup::time::*;
+use wbs::backup::window::*;
+
+// scripts which report on a snapshot write a json object to the file named by
+// this variable; see SnapshotMetadata for the fields
+
+pub const RESULT_ENV: & 'static str = "BACKUP_DAEMON_RESULT";
+
+// send scripts are given the bandwidth budget, in bytes per second, when one is
+// configured
+
+pub const BANDWIDTH_ENV: & 'static str = "BACKUP_DAEMON_BANDWIDTH";
+
+// a script given a deadline is stopped if it is still running then, and its
+// output marked as stopped. a script which can't be started, or whose output
+// can't be kept, is an error for the stage instead, which stays due.
+
+pub struct ScriptOutput {
+	pub status: process::ExitStatus,
+	pub result: Result <Option <String>, String>,
+	pub stopped: bool,
+}
+
+const STOP_GRACE_SECONDS: u64 = 10;
+
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum SendOutcome {
+	Sent,
+	Failed,
+	Stopped,
+	NotStarted,
+}
+
+pub trait ScriptRunner {
+
+	fn run_script (
+		& self,
+		clock: & Clock,
+		job_config: & JobConfig,
+		name: &str,
+		script: &str,
+		log: &str,
+		time: &str,
+		arguments: & [String],
+		environment: & [(String, String)],
+		deadline: Option <Timespec>,
+	) -> Result <ScriptOutput, String>;
+
+	// runs a program with exactly the arguments given, for the stages the
+	// daemon provides itself
+
+	fn run_command (
+		& self,
+		clock: & Clock,
+		job_config: & JobConfig,
+		name: &str,
+		program: &str,
+		log: &str,
+		time: &str,
+		arguments: & [String],
+		environment: & [(String, String)],
+		deadline: Option <Timespec>,
+	) -> Result <ScriptOutput, String>;
+
+}
+
+pub struct ProcessScriptRunner;
+
+impl ScriptRunner for ProcessScriptRunner {
+
+	fn run_script (
+		& self,
+		clock: & Clock,
+		job_config: & JobConfig,
+		name: &str,
+		script: &str,
+		log: &str,
+		time: &str,
+		arguments: & [String],
+		environment: & [(String, String)],
+		deadline: Option <Timespec>,
+	) -> Result <ScriptOutput, String> {
+
+		run_script (
+			clock,
+			job_config,
+			name,
+			script,
+			log,
+			time,
+			arguments,
+			environment,
+			deadline)
+
+	}
+
+	fn run_command (
+		& self,
+		clock: & Clock,
+		job_config: & JobConfig,
+		name: &str,
+		program: &str,
+		log: &str,
+		time: &str,
+		arguments: & [String],
+		environment: & [(String, String)],
+		deadline: Option <Timespec>,
+	) -> Result <ScriptOutput, String> {
+
+		run_command (
+			clock,
+			job_config,
+			name,
+			program,
+			log,
+			time,
+			arguments,
+			environment,
+			deadline)
+
+	}
+
+}
+
+// scripts are given the time they run for, an hour or a day, as their first
+// argument
 
 pub fn run_script (
+	clock: & Clock,
+	job_config: & JobConfig,
 	name: &str,
 	script: &str,
 	log: &str,
 	time: &str,
-) -> process::ExitStatus {
+	arguments: & [String],
+	environment: & [(String, String)],
+	deadline: Option <Timespec>,
+) -> Result <ScriptOutput, String> {
+
+	let arguments: Vec <String> =
+		Some (time.to_string ()).into_iter ().chain (
+			arguments.iter ().cloned (),
+		).collect ();
+
+	run_command (
+		clock,
+		job_config,
+		name,
+		script,
+		log,
+		time,
+		& arguments,
+		environment,
+		deadline)
 
-	let process_output =
-		process::Command::new (script)
-		.arg (time)
-		.output ()
-		.unwrap_or_else (
-			|err|
+}
 
-			panic! (
+pub fn run_command (
+	clock: & Clock,
+	job_config: & JobConfig,
+	name: &str,
+	script: &str,
+	log: &str,
+	time: &str,
+	arguments: & [String],
+	environment: & [(String, String)],
+	deadline: Option <Timespec>,
+) -> Result <ScriptOutput, String> {
+
+	let result_path =
+		try! (
+			env::current_dir ().map (
+				|current_dir|
+				current_dir.join (
+					format! (
+						"{}-{}.result",
+						log,
+						time))
+			).map_err (
+				|err|
+				format! (
+					"error running script {}: {}",
+					script,
+					err)
+			));
+
+	remove_result (
+		& result_path);
+
+	let environment: Vec <(String, String)> =
+		environment.iter ().cloned ().chain (
+			Some ((
+				RESULT_ENV.to_string (),
+				result_path.to_string_lossy ().into_owned (),
+			)).into_iter (),
+		).collect ();
+
+	let mut command =
+		try! (
+			script_command (
+				job_config,
+				name,
+				script,
+				arguments,
+				& environment,
+			).map_err (
+				|err|
+				format! (
+					"error running script {}: {}",
+					script,
+					err)
+			));
+
+	// the process settings are applied in the child, so a cgroup, priority or
+	// user which is refused there shows up as an error starting the script
+
+	let (process_output, stopped) =
+		try! (
+			match deadline {
+
+			Some (deadline) =>
+				output_until (
+					&mut command,
+					clock,
+					deadline),
+
+			None =>
+				command.output ().map (
+					|process_output| (process_output, false)),
+
+		}.map_err (
+			|err|
+			format! (
 				"error running script {}: {}",
 				script,
 				err)
-
-		);
+		));
 
 	let output_path_str =
 		format! (
@@ -43,40 +274,214 @@ pub fn run_script (
 		Path::new (& output_path_str);
 
 	let mut output_file =
-		File::create (
-			& output_path
-		).unwrap_or_else (
+		try! (
+			File::create (
+				& output_path,
+			).map_err (
+				|err|
+				format! (
+					"error creating {} log {}: {}",
+					name,
+					output_path.display (),
+					err)
+			));
+
+	try! (
+		write_process_output (
+			&mut output_file,
+			& process_output,
+		).map_err (
 			|err|
-
-			panic! (
-				"error creating {} log {}: {}",
-				name,
-				output_path.display (),
+			format! (
+				"error writing script output {}: {}",
+				script,
 				err)
+		));
+
+	// the script has run by now, so a result which can't be read is left for
+	// the stage to log
 
+	let result =
+		read_result (
+			& result_path,
+		).map_err (
+			|err|
+			format! (
+				"error reading script result {}: {}",
+				result_path.display (),
+				err)
 		);
 
-	write_process_output (
-		&mut output_file,
-		& process_output,
-	).unwrap_or_else (
-		|err|
+	remove_result (
+		& result_path);
 
-		panic! (
-			"error writing script output {}: {}",
-			script,
-			err)
+	Ok (ScriptOutput {
+		status: process_output.status,
+		result: result,
+		stopped: stopped,
+	})
+
+}
+
+// runs the script in its own process group, so that everything it starts can
+// be stopped together at the deadline: first politely, then not. the deadline
+// is on the daemon's clock, which may be simulated or set while the script
+// runs, so the time left is counted down on the monotonic clock.
+
+fn output_until (
+	command: &mut process::Command,
+	clock: & Clock,
+	deadline: Timespec,
+) -> io::Result <(process::Output, bool)> {
+
+	let stop_at =
+		Instant::now () + Duration::from_secs (
+			cmp::max (deadline.sec - clock.now ().sec, 0) as u64);
 
-	);
+	unsafe {
 
-	process_output.status
+		command.pre_exec (
+			|| {
+				if libc::setpgid (0, 0) != 0 {
+					return Err (io::Error::last_os_error ());
+				}
+				Ok (())
+			});
+
+	}
+
+	let mut child =
+		try! (
+			command
+				.stdin (process::Stdio::null ())
+				.stdout (process::Stdio::piped ())
+				.stderr (process::Stdio::piped ())
+				.spawn ());
+
+	let stdout_reader =
+		read_in_background (
+			child.stdout.take ().unwrap ());
+
+	let stderr_reader =
+		read_in_background (
+			child.stderr.take ().unwrap ());
+
+	let mut stopped = false;
+	let mut killed = false;
+	let mut stop_started = Instant::now ();
+
+	let status =
+		loop {
+
+		if let Some (status) = try! (child.try_wait ()) {
+			break status;
+		}
+
+		if ! stopped && Instant::now () >= stop_at {
+
+			signal_group (& child, libc::SIGTERM);
+
+			stopped = true;
+			stop_started = Instant::now ();
+
+		}
+
+		if stopped && ! killed
+		&& stop_started.elapsed () >= Duration::from_secs (STOP_GRACE_SECONDS) {
+
+			signal_group (& child, libc::SIGKILL);
+
+			killed = true;
+
+		}
+
+		thread::sleep (
+			Duration::from_millis (100));
+
+	};
+
+	Ok ((
+		process::Output {
+			status: status,
+			stdout: stdout_reader.join ().unwrap_or_default (),
+			stderr: stderr_reader.join ().unwrap_or_default (),
+		},
+		stopped,
+	))
+
+}
+
+fn read_in_background <Source: Read + Send + 'static> (
+	mut source: Source,
+) -> thread::JoinHandle <Vec <u8>> {
+
+	thread::spawn (
+		move || {
+			let mut buffer: Vec <u8> = vec! [];
+			let _ = source.read_to_end (&mut buffer);
+			buffer
+		})
+
+}
+
+fn signal_group (
+	child: & process::Child,
+	signal: libc::c_int,
+) {
+
+	unsafe {
+		libc::kill (
+			- (child.id () as libc::pid_t),
+			signal);
+	}
+
+}
+
+fn read_result (
+	result_path: & Path,
+) -> io::Result <Option <String>> {
+
+	let mut result_file =
+		match File::open (result_path) {
+
+		Ok (result_file) => result_file,
+
+		Err (ref err) if err.kind () == io::ErrorKind::NotFound =>
+			return Ok (None),
+
+		Err (err) =>
+			return Err (err),
+
+	};
+
+	// a result which is not valid utf-8 is passed on as near as it can be, for
+	// the json decoder to report on
+
+	let mut result: Vec <u8> =
+		vec! [];
+
+	try! (
+		result_file.read_to_end (
+			&mut result));
+
+	Ok (Some (
+		String::from_utf8_lossy (& result).into_owned ()))
+
+}
+
+fn remove_result (
+	result_path: & Path,
+) {
+
+	let _ = fs::remove_file (
+		result_path);
 
 }
 
 pub fn write_process_output (
 	output_file: &mut File,
 	process_output: & process::Output,
-) -> Result<()> {
+) -> io::Result<()> {
 
 	try! (write! (
 		output_file,
@@ -101,6 +506,7 @@ pub fn write_process_output (
 }
 
 pub fn do_sync (
+	context: & Context,
 	config: & Config,
 	state: &mut Global,
 	job_index: usize,
@@ -112,6 +518,13 @@ pub fn do_sync (
 	if job_config.sync_script.is_some () {
 
 		log! (
+			context.logger,
+			Level::Info,
+			[
+				job = job_config.name,
+				stage = "sync",
+				sync_time = time_format_iso (sync_time)
+			],
 			"sync started for {} {}",
 			job_config.name,
 			time_format_pretty (sync_time));
@@ -127,18 +540,68 @@ pub fn do_sync (
 		let sync_log =
 			job_config.sync_log.clone ().unwrap ();
 
-		let exit_status =
-			run_script (
+		let started =
+			Instant::now ();
+
+		let script_output =
+			context.runner.run_script (
+				context.clock,
+				job_config,
 				"sync",
 				& sync_script,
 				& sync_log,
-				& time_format_hour (sync_time));
+				& time_format_hour (
+					& state.jobs [job_index].zone,
+					sync_time),
+				& [],
+				& [],
+				None);
+
+		let exit_status =
+			match script_output {
+
+			Ok (script_output) =>
+				script_output.status,
+
+			Err (err) => {
+
+				not_started (
+					context,
+					job_config,
+					state,
+					job_index,
+					"sync",
+					& err);
+
+				state.write_state (config);
+
+				return;
+
+			},
+
+		};
 
 		log! (
+			context.logger,
+			exit_level (exit_status),
+			[
+				job = job_config.name,
+				stage = "sync",
+				sync_time = time_format_iso (sync_time),
+				exit_code = exit_status.code (),
+				duration = duration_seconds (started)
+			],
 			"sync for {} {}",
 			job_config.name,
 			exit_report (exit_status));
 
+		record_failure (
+			context,
+			state,
+			job_index,
+			"sync",
+			exit_status);
+
 		state.jobs [job_index].state =
 			JobState::Idle;
 
@@ -150,6 +613,13 @@ pub fn do_sync (
 	} else {
 
 		log! (
+			context.logger,
+			Level::Info,
+			[
+				job = job_config.name,
+				stage = "sync",
+				sync_time = time_format_iso (sync_time)
+			],
 			"sync skipped for {} {}",
 			job_config.name,
 			time_format_pretty (sync_time));
@@ -164,6 +634,7 @@ pub fn do_sync (
 }
 
 pub fn do_snapshot (
+	context: & Context,
 	config: & Config,
 	state: &mut Global,
 	job_index: usize,
@@ -175,6 +646,13 @@ pub fn do_snapshot (
 	if job_config.snapshot_script.is_some () {
 
 		log! (
+			context.logger,
+			Level::Info,
+			[
+				job = job_config.name,
+				stage = "snapshot",
+				snapshot_time = time_format_iso (snapshot_time)
+			],
 			"snapshot started for {} {}",
 			job_config.name,
 			time_format_pretty (snapshot_time));
@@ -190,6 +668,11 @@ pub fn do_snapshot (
 				state: SnapshotState::Snapshotting,
 				snapshot_time: snapshot_time,
 				send_time: None,
+				export_state: None,
+				export_time: None,
+				verify_state: None,
+				verify_time: None,
+				metadata: None,
 			}
 		);
 
@@ -201,18 +684,83 @@ pub fn do_snapshot (
 		let snapshot_log =
 			job_config.snapshot_log.clone ().unwrap ();
 
-		let exit_status =
-			run_script (
+		let started =
+			Instant::now ();
+
+		let script_output =
+			context.runner.run_script (
+				context.clock,
+				job_config,
 				"snapshot",
 				& snapshot_script,
 				& snapshot_log,
-				& time_format_day (snapshot_time));
+				& time_format_day (
+					& state.jobs [job_index].zone,
+					snapshot_time),
+				& [],
+				& [],
+				None);
+
+		// a snapshot whose script never ran was not taken
+
+		let script_output =
+			match script_output {
+
+			Ok (script_output) =>
+				script_output,
+
+			Err (err) => {
+
+				not_started (
+					context,
+					job_config,
+					state,
+					job_index,
+					"snapshot",
+					& err);
+
+				state.jobs [job_index].snapshots.remove (
+					snapshot_index);
+
+				state.write_state (config);
+
+				return;
+
+			},
+
+		};
+
+		let exit_status =
+			script_output.status;
 
 		log! (
+			context.logger,
+			exit_level (exit_status),
+			[
+				job = job_config.name,
+				stage = "snapshot",
+				snapshot_time = time_format_iso (snapshot_time),
+				exit_code = exit_status.code (),
+				duration = duration_seconds (started)
+			],
 			"snapshot for {} {}",
 			job_config.name,
 			exit_report (exit_status));
 
+		record_failure (
+			context,
+			state,
+			job_index,
+			"snapshot",
+			exit_status);
+
+		record_result (
+			context,
+			job_config,
+			"snapshot",
+			&mut state.jobs [job_index].snapshots [snapshot_index],
+			script_output.result);
+
 		state.jobs [job_index].state =
 			JobState::Idle;
 
@@ -227,6 +775,13 @@ pub fn do_snapshot (
 	} else {
 
 		log! (
+			context.logger,
+			Level::Info,
+			[
+				job = job_config.name,
+				stage = "snapshot",
+				snapshot_time = time_format_iso (snapshot_time)
+			],
 			"snapshot skipped for {} {}",
 			job_config.name,
 			time_format_pretty (snapshot_time));
@@ -240,30 +795,518 @@ pub fn do_snapshot (
 
 }
 
-pub fn do_send (
+// sends the snapshots of every job whose send is due through the one queue, see
+// send_queue, each against the previous one as its incremental parent. a
+// snapshot left sending by a crash is sent again, and the first failure stops
+// the rest of that job's sends so that the chain of parents stays intact. while
+// the bandwidth budget is overdrawn, the remaining sends wait as if deferred.
+
+pub fn do_send_queue (
+	context: & Context,
+	config: & Config,
+	state: &mut Global,
+	send_jobs: & [(usize, Timespec)],
+) {
+
+	let mut queued_jobs: Vec <usize> = vec! [];
+
+	for & (job_index, send_time) in send_jobs.iter () {
+
+		let job_config = & config.jobs [job_index];
+
+		if job_config.send_script.is_some () {
+
+			queued_jobs.push (job_index);
+
+		} else {
+
+			log! (
+				context.logger,
+				Level::Info,
+				[
+					job = job_config.name,
+					stage = "send",
+					send_time = time_format_iso (send_time)
+				],
+				"send skipped for {} {}",
+				job_config.name,
+				time_format_pretty (send_time));
+
+			state.jobs [job_index].last_send =
+				Some (send_time);
+
+			state.write_state (config);
+
+		}
+
+	}
+
+	let queue =
+		send_queue (
+			config,
+			state,
+			& queued_jobs);
+
+	if let Some (rate) = config.send_bandwidth {
+
+		let now = context.clock.now ();
+
+		state.send_bucket.get_or_insert_with (
+			|| TokenBucket::new (
+				rate,
+				config.send_burst.unwrap_or (rate * 3600),
+				now));
+
+	}
+
+	let mut parents: Vec <Option <usize>> =
+		(0 .. state.jobs.len ()).map (
+			|job_index| latest_sent (state, job_index)
+		).collect ();
+
+	let mut started_jobs: Vec <usize> = vec! [];
+	let mut failed_jobs: Vec <usize> = vec! [];
+	let mut held_jobs: Vec <usize> = vec! [];
+
+	for (queue_index, entry) in queue.iter ().enumerate () {
+
+		let job_index = entry.job_index;
+		let job_config = & config.jobs [job_index];
+
+		if failed_jobs.contains (& job_index)
+		|| held_jobs.contains (& job_index) {
+			continue;
+		}
+
+		let send_time =
+			send_jobs.iter ().find (
+				|& & (send_job_index, _)| send_job_index == job_index
+			).unwrap ().1;
+
+		let bandwidth_wait =
+			state.send_bucket.as_mut ().and_then (
+				|send_bucket| send_bucket.wait_until (context.clock.now ()));
+
+		if let Some (until) = bandwidth_wait {
+
+			for entry in queue [queue_index ..].iter () {
+
+				if failed_jobs.contains (& entry.job_index)
+				|| held_jobs.contains (& entry.job_index) {
+					continue;
+				}
+
+				held_jobs.push (entry.job_index);
+
+				wait_for_bandwidth (
+					context,
+					config,
+					state,
+					entry.job_index,
+					until);
+
+			}
+
+			break;
+
+		}
+
+		// a job's sends are only logged as started once its first snapshot is
+		// sent, not while the bandwidth budget holds it back. each snapshot's
+		// send is logged on its own as well.
+
+		if ! started_jobs.contains (& job_index) {
+
+			let queued =
+				queue [queue_index ..].iter ().filter (
+					|entry| entry.job_index == job_index
+				).count ();
+
+			log! (
+				context.logger,
+				Level::Info,
+				[
+					job = job_config.name,
+					stage = "send",
+					send_time = time_format_iso (send_time),
+					queued = queued
+				],
+				"sends for {} {} started with {} snapshots queued",
+				job_config.name,
+				time_format_pretty (send_time),
+				queued);
+
+			started_jobs.push (job_index);
+
+		}
+
+		let outcome =
+			do_send_snapshot (
+				context,
+				config,
+				state,
+				job_index,
+				entry.snapshot_index,
+				parents [job_index],
+				send_time);
+
+		match outcome {
+
+			SendOutcome::Sent => {
+
+				parents [job_index] =
+					Some (entry.snapshot_index);
+
+				let size =
+					state.jobs [job_index].snapshots [entry.snapshot_index].metadata.as_ref ()
+						.and_then (|metadata| metadata.size);
+
+				if let (Some (send_bucket), Some (size))
+					= (state.send_bucket.as_mut (), size) {
+
+					send_bucket.consume (
+						size,
+						context.clock.now ());
+
+				}
+
+			},
+
+			SendOutcome::Failed
+			| SendOutcome::Stopped
+			| SendOutcome::NotStarted => {
+
+				let remaining =
+					queue [queue_index + 1 ..].iter ().filter (
+						|entry| entry.job_index == job_index
+					).count ();
+
+				log! (
+					context.logger,
+					Level::Warning,
+					[
+						job = job_config.name,
+						stage = "send",
+						send_time = time_format_iso (send_time),
+						pending = remaining
+					],
+					"send stopped for {} with {} later snapshots pending",
+					job_config.name,
+					remaining);
+
+				// a send stopped by a blackout is still due, to carry on once
+				// the blackout ends, as is one whose script could not start
+
+				if outcome != SendOutcome::Failed {
+					held_jobs.push (job_index);
+				} else {
+					failed_jobs.push (job_index);
+				}
+
+			},
+
+		}
+
+	}
+
+	for & job_index in queued_jobs.iter () {
+
+		if held_jobs.contains (& job_index) {
+			continue;
+		}
+
+		state.jobs [job_index].last_send =
+			send_jobs.iter ().find (
+				|& & (send_job_index, _)| send_job_index == job_index
+			).map (
+				|& (_, send_time)| send_time
+			);
+
+	}
+
+	state.write_state (config);
+
+}
+
+fn latest_sent (
+	state: & Global,
+	job_index: usize,
+) -> Option <usize> {
+
+	state.jobs [job_index].snapshots.iter ().enumerate ().filter (
+		|& (_, snapshot)|
+		match snapshot.state {
+			SnapshotState::Sent => true,
+			_ => false,
+		}
+	).max_by_key (
+		|& (_, snapshot)| snapshot.snapshot_time
+	).map (
+		|(snapshot_index, _)| snapshot_index
+	)
+
+}
+
+fn wait_for_bandwidth (
+	context: & Context,
 	config: & Config,
 	state: &mut Global,
 	job_index: usize,
-	send_time: Timespec,
+	until: Timespec,
 ) {
 
 	let job_config = & config.jobs [job_index];
 
-	if job_config.send_script.is_some () {
+	if state.jobs [job_index].deferred_until == Some (until) {
+		return;
+	}
+
+	log! (
+		context.logger,
+		Level::Info,
+		[
+			job = job_config.name,
+			stage = "send",
+			deferred_until = time_format_iso (until)
+		],
+		"send deferred for {} until {} by bandwidth budget",
+		job_config.name,
+		time_format_pretty (until));
+
+	state.jobs [job_index].deferred_stage = Some ("send".to_string ());
+	state.jobs [job_index].deferred_until = Some (until);
+
+}
+
+// runs the send script with the snapshot's own day and, when there is one, the
+// day of the parent snapshot to send incrementally against. the script is
+// stopped if it runs into a blackout which sends must finish by.
+
+pub fn do_send_snapshot (
+	context: & Context,
+	config: & Config,
+	state: &mut Global,
+	job_index: usize,
+	snapshot_index: usize,
+	parent_index: Option <usize>,
+	send_time: Timespec,
+) -> SendOutcome {
+
+	let job_config = & config.jobs [job_index];
+
+	let snapshot_time =
+		state.jobs [job_index].snapshots [snapshot_index].snapshot_time;
+
+	let parent_time =
+		parent_index.map (
+			|parent_index|
+			state.jobs [job_index].snapshots [parent_index].snapshot_time);
+
+	log! (
+		context.logger,
+		Level::Info,
+		[
+			job = job_config.name,
+			stage = "send",
+			send_time = time_format_iso (send_time),
+			snapshot_time = time_format_iso (snapshot_time),
+			parent_time = parent_time.map (time_format_iso)
+		],
+		"send started for {} {}",
+		job_config.name,
+		time_format_pretty (snapshot_time));
+
+	state.jobs [job_index].state =
+		JobState::Sending;
+
+	state.jobs [job_index].snapshots [snapshot_index].state =
+		SnapshotState::Sending;
+
+	state.jobs [job_index].snapshots [snapshot_index].send_time =
+		Some (send_time);
+
+	state.write_state (config);
+
+	let send_script =
+		job_config.send_script.clone ().unwrap ();
+
+	let send_log =
+		job_config.send_log.clone ().unwrap ();
+
+	let arguments: Vec <String> =
+		parent_time.map (
+			|parent_time|
+			time_format_day (
+				& state.jobs [job_index].zone,
+				parent_time)
+		).into_iter ().collect ();
+
+	let environment: Vec <(String, String)> =
+		config.send_bandwidth.map (
+			|rate|
+			(BANDWIDTH_ENV.to_string (), rate.to_string ())
+		).into_iter ().collect ();
+
+	let deadline =
+		next_deadline (
+			& config.job_windows (job_config),
+			& state.jobs [job_index].zone,
+			"send",
+			context.clock.now ());
+
+	let started =
+		Instant::now ();
+
+	let script_output =
+		context.runner.run_script (
+			context.clock,
+			job_config,
+			"send",
+			& send_script,
+			& send_log,
+			& time_format_day (
+				& state.jobs [job_index].zone,
+				snapshot_time),
+			& arguments,
+			& environment,
+			deadline);
+
+	let script_output =
+		match script_output {
+
+		Ok (script_output) =>
+			script_output,
+
+		Err (err) => {
+
+			not_started (
+				context,
+				job_config,
+				state,
+				job_index,
+				"send",
+				& err);
+
+			state.jobs [job_index].snapshots [snapshot_index].state =
+				SnapshotState::Snapshotted;
+
+			state.jobs [job_index].snapshots [snapshot_index].send_time =
+				None;
+
+			state.write_state (config);
+
+			return SendOutcome::NotStarted;
+
+		},
+
+	};
+
+	let exit_status =
+		script_output.status;
+
+	log! (
+		context.logger,
+		exit_level (exit_status),
+		[
+			job = job_config.name,
+			stage = "send",
+			send_time = time_format_iso (send_time),
+			snapshot_time = time_format_iso (snapshot_time),
+			parent_time = parent_time.map (time_format_iso),
+			exit_code = exit_status.code (),
+			duration = duration_seconds (started)
+		],
+		"send completed for {} {}",
+		job_config.name,
+		exit_report (exit_status));
+
+	if script_output.stopped {
 
 		log! (
-			"send started for {} {}",
+			context.logger,
+			Level::Warning,
+			[
+				job = job_config.name,
+				stage = "send",
+				snapshot_time = time_format_iso (snapshot_time),
+				deadline = deadline.map (time_format_iso)
+			],
+			"send for {} stopped at blackout {}",
 			job_config.name,
-			time_format_pretty (send_time));
+			time_format_pretty (deadline.unwrap ()));
+
+	} else {
+
+		record_failure (
+			context,
+			state,
+			job_index,
+			"send",
+			exit_status);
+
+	}
+
+	record_result (
+		context,
+		job_config,
+		"send",
+		&mut state.jobs [job_index].snapshots [snapshot_index],
+		script_output.result);
+
+	state.jobs [job_index].state =
+		JobState::Idle;
+
+	if exit_status.success () && ! script_output.stopped {
+
+		state.jobs [job_index].snapshots [snapshot_index].state =
+			SnapshotState::Sent;
+
+	} else {
+
+		state.jobs [job_index].snapshots [snapshot_index].state =
+			SnapshotState::Snapshotted;
+
+		state.jobs [job_index].snapshots [snapshot_index].send_time =
+			None;
+
+	}
+
+	state.write_state (config);
+
+	if script_output.stopped {
+		SendOutcome::Stopped
+	} else if exit_status.success () {
+		SendOutcome::Sent
+	} else {
+		SendOutcome::Failed
+	}
+
+}
+
+// exports every sent snapshot which has not yet been exported, oldest first,
+// including those whose previous export failed
+
+pub fn do_export (
+	context: & Context,
+	config: & Config,
+	state: &mut Global,
+	job_index: usize,
+	export_time: Timespec,
+) {
+
+	let job_config = & config.jobs [job_index];
+
+	if job_config.export_script.is_some () {
 
 		let mut snapshot_indexes: Vec <usize> = vec! [];
 
 		for (snapshot_index, snapshot)
 			in state.jobs [job_index].snapshots.iter ().enumerate () {
 
-			match snapshot.state {
+			match (& snapshot.state, & snapshot.export_state) {
 
-				SnapshotState::Snapshotted => {
+				(& SnapshotState::Sent, & None)
+				| (& SnapshotState::Sent, & Some (ExportState::Failed)) => {
 
 					snapshot_indexes.push (snapshot_index)
 
@@ -275,26 +1318,48 @@ pub fn do_send (
 
 		}
 
+		snapshot_indexes.sort_by_key (
+			|& snapshot_index|
+			state.jobs [job_index].snapshots [snapshot_index].snapshot_time);
+
 		for snapshot_index in snapshot_indexes {
 
-			do_send_snapshot (
-				config,
-				state,
-				job_index,
-				snapshot_index,
-				send_time);
+			let started =
+				do_export_snapshot (
+					context,
+					config,
+					state,
+					job_index,
+					snapshot_index,
+					export_time);
+
+			if ! started {
+				return;
+			}
 
 		}
 
+		state.jobs [job_index].last_export =
+			Some (export_time);
+
+		state.write_state (config);
+
 	} else {
 
 		log! (
-			"send skipped for {} {}",
+			context.logger,
+			Level::Info,
+			[
+				job = job_config.name,
+				stage = "export",
+				export_time = time_format_iso (export_time)
+			],
+			"export skipped for {} {}",
 			job_config.name,
-			time_format_pretty (send_time));
+			time_format_pretty (export_time));
 
-		state.jobs [job_index].last_send =
-			Some (send_time);
+		state.jobs [job_index].last_export =
+			Some (export_time);
 
 		state.write_state (config);
 
@@ -302,72 +1367,244 @@ pub fn do_send (
 
 }
 
-pub fn do_send_snapshot (
+pub fn do_export_snapshot (
+	context: & Context,
 	config: & Config,
 	state: &mut Global,
 	job_index: usize,
 	snapshot_index: usize,
-	send_time: Timespec,
+	export_time: Timespec,
+) -> bool {
+
+	let job_config = & config.jobs [job_index];
+
+	let snapshot_time =
+		state.jobs [job_index].snapshots [snapshot_index].snapshot_time;
+
+	log! (
+		context.logger,
+		Level::Info,
+		[
+			job = job_config.name,
+			stage = "export",
+			export_time = time_format_iso (export_time),
+			snapshot_time = time_format_iso (snapshot_time)
+		],
+		"export started for {} {}",
+		job_config.name,
+		time_format_pretty (snapshot_time));
+
+	state.jobs [job_index].state =
+		JobState::Exporting;
+
+	let previous_export_state =
+		state.jobs [job_index].snapshots [snapshot_index].export_state.take ();
+
+	state.jobs [job_index].snapshots [snapshot_index].export_state =
+		Some (ExportState::Exporting);
+
+	state.write_state (config);
+
+	let export_script =
+		job_config.export_script.clone ().unwrap ();
+
+	let export_log =
+		job_config.export_log.clone ().unwrap ();
+
+	let started =
+		Instant::now ();
+
+	let script_output =
+		context.runner.run_script (
+			context.clock,
+			job_config,
+			"export",
+			& export_script,
+			& export_log,
+			& time_format_day (
+				& state.jobs [job_index].zone,
+				snapshot_time),
+			& [],
+			& [],
+			None);
+
+	let script_output =
+		match script_output {
+
+		Ok (script_output) =>
+			script_output,
+
+		Err (err) => {
+
+			not_started (
+				context,
+				job_config,
+				state,
+				job_index,
+				"export",
+				& err);
+
+			state.jobs [job_index].snapshots [snapshot_index].export_state =
+				previous_export_state;
+
+			state.write_state (config);
+
+			return false;
+
+		},
+
+	};
+
+	let exit_status =
+		script_output.status;
+
+	log! (
+		context.logger,
+		exit_level (exit_status),
+		[
+			job = job_config.name,
+			stage = "export",
+			export_time = time_format_iso (export_time),
+			snapshot_time = time_format_iso (snapshot_time),
+			exit_code = exit_status.code (),
+			duration = duration_seconds (started)
+		],
+		"export completed for {} {}",
+		job_config.name,
+		exit_report (exit_status));
+
+	record_failure (
+		context,
+		state,
+		job_index,
+		"export",
+		exit_status);
+
+	record_result (
+		context,
+		job_config,
+		"export",
+		&mut state.jobs [job_index].snapshots [snapshot_index],
+		script_output.result);
+
+	state.jobs [job_index].state =
+		JobState::Idle;
+
+	state.jobs [job_index].snapshots [snapshot_index].export_state =
+		Some (
+			if exit_status.success () {
+				ExportState::Exported
+			} else {
+				ExportState::Failed
+			});
+
+	state.jobs [job_index].snapshots [snapshot_index].export_time =
+		Some (export_time);
+
+	state.write_state (config);
+
+	true
+
+}
+
+// restores one sent snapshot, either the latest or one picked at random, and
+// raises an alert if the job has gone too long without a successful check
+
+pub fn do_verify (
+	context: & Context,
+	config: & Config,
+	state: &mut Global,
+	job_index: usize,
+	verify_time: Timespec,
 ) {
 
 	let job_config = & config.jobs [job_index];
 
-	if job_config.send_script.is_some () {
+	if config.verify_enabled (job_config) {
 
-		log! (
-			"send started for {} {}",
-			job_config.name,
-			time_format_pretty (send_time));
+		let mut snapshot_indexes: Vec <usize> =
+			state.jobs [job_index].snapshots.iter ().enumerate ().filter (
+				|& (_, snapshot)|
+				match snapshot.state {
+					SnapshotState::Sent => true,
+					_ => false,
+				}
+			).map (
+				|(snapshot_index, _)| snapshot_index
+			).collect ();
 
-		state.jobs [job_index].state =
-			JobState::Sending;
+		snapshot_indexes.sort_by_key (
+			|& snapshot_index|
+			state.jobs [job_index].snapshots [snapshot_index].snapshot_time);
 
-		state.jobs [job_index].snapshots [snapshot_index].state =
-			SnapshotState::Sending;
+		let picked =
+			match config.verify_pick (job_config) {
 
-		state.jobs [job_index].snapshots [snapshot_index].send_time =
-			Some (send_time);
+			VerifyPick::Latest =>
+				snapshot_indexes.last ().cloned (),
 
-		state.write_state (config);
+			VerifyPick::Random if ! snapshot_indexes.is_empty () =>
+				Some (snapshot_indexes [
+					pick_index (verify_time, snapshot_indexes.len ())]),
 
-		let send_script =
-			job_config.send_script.clone ().unwrap ();
+			VerifyPick::Random =>
+				None,
 
-		let send_log =
-			job_config.send_log.clone ().unwrap ();
+		};
 
-		let exit_status =
-			run_script (
-				"send",
-				& send_script,
-				& send_log,
-				& time_format_day (send_time));
+		match picked {
 
-		log! (
-			"send completed for {} {}",
-			job_config.name,
-			exit_report (exit_status));
+			Some (snapshot_index) => {
 
-		state.jobs [job_index].state =
-			JobState::Idle;
+				let started =
+					do_verify_snapshot (
+						context,
+						config,
+						state,
+						job_index,
+						snapshot_index);
 
-		state.jobs [job_index].last_send =
-			Some (send_time);
+				if ! started {
+					return;
+				}
 
-		state.jobs [job_index].snapshots [snapshot_index].state =
-			SnapshotState::Sent;
+			},
+
+			None =>
+				log! (
+					context.logger,
+					Level::Info,
+					[
+						job = job_config.name,
+						stage = "verify",
+						verify_time = time_format_iso (verify_time)
+					],
+					"verify skipped for {}, no sent snapshots",
+					job_config.name),
+
+		}
+
+		state.jobs [job_index].last_verify =
+			Some (verify_time);
 
 		state.write_state (config);
 
 	} else {
 
 		log! (
-			"send skipped for {} {}",
+			context.logger,
+			Level::Info,
+			[
+				job = job_config.name,
+				stage = "verify",
+				verify_time = time_format_iso (verify_time)
+			],
+			"verify skipped for {} {}",
 			job_config.name,
-			time_format_pretty (send_time));
+			time_format_pretty (verify_time));
 
-		state.jobs [job_index].last_send =
-			Some (send_time);
+		state.jobs [job_index].last_verify =
+			Some (verify_time);
 
 		state.write_state (config);
 
@@ -375,6 +1612,416 @@ pub fn do_send_snapshot (
 
 }
 
+pub fn do_verify_snapshot (
+	context: & Context,
+	config: & Config,
+	state: &mut Global,
+	job_index: usize,
+	snapshot_index: usize,
+) -> bool {
+
+	let job_config = & config.jobs [job_index];
+
+	let snapshot_time =
+		state.jobs [job_index].snapshots [snapshot_index].snapshot_time;
+
+	log! (
+		context.logger,
+		Level::Info,
+		[
+			job = job_config.name,
+			stage = "verify",
+			snapshot_time = time_format_iso (snapshot_time)
+		],
+		"verify started for {} {}",
+		job_config.name,
+		time_format_pretty (snapshot_time));
+
+	state.jobs [job_index].state =
+		JobState::Verifying;
+
+	let previous_verify_state =
+		state.jobs [job_index].snapshots [snapshot_index].verify_state.take ();
+
+	state.jobs [job_index].snapshots [snapshot_index].verify_state =
+		Some (VerifyState::Verifying);
+
+	state.write_state (config);
+
+	let verify_log =
+		job_config.verify_log.clone ().unwrap ();
+
+	let snapshot_day =
+		time_format_day (
+			& state.jobs [job_index].zone,
+			snapshot_time);
+
+	let started =
+		Instant::now ();
+
+	let script_output =
+		match job_config.verify_restore_test {
+
+		Some (ref verify_restore_test) => {
+
+			let backup_path =
+				interpolate (
+					& restore_test_variables (& job_config.name, & snapshot_day),
+					verify_restore_test,
+				).unwrap ();
+
+			context.runner.run_command (
+				context.clock,
+				job_config,
+				"verify",
+				RESTORE_TEST_PROGRAM,
+				& verify_log,
+				& snapshot_day,
+				& [ "restore-test".to_string (), backup_path ],
+				& [],
+				None)
+
+		},
+
+		None =>
+			context.runner.run_script (
+				context.clock,
+				job_config,
+				"verify",
+				job_config.verify_script.as_ref ().unwrap (),
+				& verify_log,
+				& snapshot_day,
+				& [],
+				& [],
+				None),
+
+	};
+
+	let script_output =
+		match script_output {
+
+		Ok (script_output) =>
+			script_output,
+
+		Err (err) => {
+
+			not_started (
+				context,
+				job_config,
+				state,
+				job_index,
+				"verify",
+				& err);
+
+			state.jobs [job_index].snapshots [snapshot_index].verify_state =
+				previous_verify_state;
+
+			state.write_state (config);
+
+			return false;
+
+		},
+
+	};
+
+	let exit_status =
+		script_output.status;
+
+	log! (
+		context.logger,
+		exit_level (exit_status),
+		[
+			job = job_config.name,
+			stage = "verify",
+			snapshot_time = time_format_iso (snapshot_time),
+			exit_code = exit_status.code (),
+			duration = duration_seconds (started)
+		],
+		"verify completed for {} {}",
+		job_config.name,
+		exit_report (exit_status));
+
+	record_failure (
+		context,
+		state,
+		job_index,
+		"verify",
+		exit_status);
+
+	record_result (
+		context,
+		job_config,
+		"verify",
+		&mut state.jobs [job_index].snapshots [snapshot_index],
+		script_output.result);
+
+	state.jobs [job_index].state =
+		JobState::Idle;
+
+	state.jobs [job_index].snapshots [snapshot_index].verify_state =
+		Some (
+			if exit_status.success () {
+				VerifyState::Verified
+			} else {
+				VerifyState::Failed
+			});
+
+	state.jobs [job_index].snapshots [snapshot_index].verify_time =
+		Some (context.clock.now ());
+
+	state.write_state (config);
+
+	true
+
+}
+
+// a job is overdue once its last successful verification, or its first
+// snapshot if it has never had one, is older than the alert period
+
+pub fn verify_overdue (
+	config: & Config,
+	job_config: & JobConfig,
+	job: & Job,
+	now: Timespec,
+) -> bool {
+
+	if ! config.verify_enabled (job_config) {
+		return false;
+	}
+
+	job.last_verified ().or_else (
+		|| job.snapshots.iter ().map (|snapshot| snapshot.snapshot_time).min ()
+	).map_or (
+		false,
+		|since| now.sec - since.sec > config.verify_alert_seconds (job_config))
+
+}
+
+// checked on every pass, so that a job whose verification is held back still
+// raises the alert; it is logged once each time the job becomes overdue
+
+pub fn check_verify_overdue (
+	context: & Context,
+	config: & Config,
+	state: &mut Global,
+	job_index: usize,
+) {
+
+	let job_config = & config.jobs [job_index];
+
+	let overdue =
+		verify_overdue (
+			config,
+			job_config,
+			& state.jobs [job_index],
+			context.clock.now ());
+
+	if overdue == state.jobs [job_index].verify_alerted {
+		return;
+	}
+
+	if overdue {
+
+		let last_verified =
+			state.jobs [job_index].last_verified ();
+
+		log! (
+			context.logger,
+			Level::Error,
+			[
+				job = job_config.name,
+				stage = "verify",
+				alert = "verify-overdue",
+				last_verified = last_verified.map (time_format_iso)
+			],
+			"no successful verification for {} since {}",
+			job_config.name,
+			last_verified.map_or (
+				"it started".to_string (),
+				time_format_pretty));
+
+	}
+
+	state.jobs [job_index].verify_alerted =
+		overdue;
+
+	state.write_state (config);
+
+}
+
+// spreads random picks over the snapshots without a source of randomness, by
+// mixing the time of the verify period into an index
+
+fn pick_index (
+	verify_time: Timespec,
+	count: usize,
+) -> usize {
+
+	let mixed =
+		(verify_time.sec as u64).wrapping_mul (
+			6364136223846793005,
+		).wrapping_add (
+			1442695040888963407);
+
+	((mixed >> 33) % count as u64) as usize
+
+}
+
+// the latest stage to fail is kept for the status and the fleet report
+
+fn record_failure (
+	context: & Context,
+	state: &mut Global,
+	job_index: usize,
+	stage: &str,
+	exit_status: process::ExitStatus,
+) {
+
+	if ! exit_status.success () {
+
+		state.jobs [job_index].last_failure_stage =
+			Some (stage.to_string ());
+
+		state.jobs [job_index].last_failure =
+			Some (context.clock.now ());
+
+	}
+
+}
+
+// a script which could not be started fails its stage, which is left due so
+// that it is tried again on a later pass, while the other jobs carry on
+
+fn not_started (
+	context: & Context,
+	job_config: & JobConfig,
+	state: &mut Global,
+	job_index: usize,
+	stage: &str,
+	err: &str,
+) {
+
+	log! (
+		context.logger,
+		Level::Error,
+		[
+			job = job_config.name,
+			stage = stage,
+			error = err
+		],
+		"{} for {} not started: {}",
+		stage,
+		job_config.name,
+		err);
+
+	state.jobs [job_index].last_failure_stage =
+		Some (stage.to_string ());
+
+	state.jobs [job_index].last_failure =
+		Some (context.clock.now ());
+
+	state.jobs [job_index].state =
+		JobState::Idle;
+
+}
+
+// a result which cannot be read is logged and otherwise ignored, since the
+// script itself has already run
+
+fn record_result (
+	context: & Context,
+	job_config: & JobConfig,
+	stage: &str,
+	snapshot: &mut Snapshot,
+	result: Result <Option <String>, String>,
+) {
+
+	let result =
+		match result {
+
+		Ok (Some (result)) =>
+			result,
+
+		Ok (None) =>
+			return,
+
+		Err (err) => {
+
+			log! (
+				context.logger,
+				Level::Warning,
+				[
+					job = job_config.name,
+					stage = stage,
+					snapshot_time = time_format_iso (snapshot.snapshot_time)
+				],
+				"unreadable result from {} script for {}: {}",
+				stage,
+				job_config.name,
+				err);
+
+			return;
+
+		},
+
+	};
+
+	match json::decode::<SnapshotMetadata> (& result) {
+
+		Ok (metadata) => {
+
+			snapshot.metadata.get_or_insert_with (
+				SnapshotMetadata::default,
+			).merge (
+				metadata);
+
+		},
+
+		Err (err) => {
+
+			log! (
+				context.logger,
+				Level::Warning,
+				[
+					job = job_config.name,
+					stage = stage,
+					snapshot_time = time_format_iso (snapshot.snapshot_time)
+				],
+				"invalid result from {} script for {}: {}",
+				stage,
+				job_config.name,
+				err);
+
+		},
+
+	}
+
+}
+
+fn exit_level (
+	exit_status: process::ExitStatus,
+) -> Level {
+
+	if exit_status.success () {
+		Level::Info
+	} else {
+		Level::Error
+	}
+
+}
+
+fn duration_seconds (
+	started: Instant,
+) -> f64 {
+
+	let duration =
+		started.elapsed ();
+
+	duration.as_secs () as f64
+		+ duration.subsec_nanos () as f64 / 1_000_000_000.0
+
+}
+
 fn exit_report (
 	exit_status: process::ExitStatus,
 ) -> String {
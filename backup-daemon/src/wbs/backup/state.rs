@@ -2,6 +2,7 @@ extern crate time;
 
 use rustc_serialize::json;
 
+use std::collections::BTreeMap;
 use std::io::Read;
 use std::io::Result;
 use std::io::Write;
@@ -14,7 +15,10 @@ use std::path::Path;
 use time::Timespec;
 
 use wbs::backup::config::*;
+use wbs::backup::log::*;
+use wbs::backup::queue::*;
 use wbs::backup::time::*;
+use wbs::backup::zone::*;
 
 // ######################################## interface
 
@@ -28,6 +32,7 @@ pub enum JobState {
 	Snapshotting,
 	Sending,
 	Exporting,
+	Verifying,
 }
 
 // ---------- snapshot state
@@ -39,6 +44,35 @@ pub enum SnapshotState {
 	Sent,
 }
 
+// ---------- export state
+
+pub enum ExportState {
+	Exporting,
+	Exported,
+	Failed,
+}
+
+// ---------- verify state
+
+pub enum VerifyState {
+	Verifying,
+	Verified,
+	Failed,
+}
+
+// ---------- snapshot metadata
+
+// what a snapshot, send or export script reports about the snapshot, read from
+// the json file it is pointed at by the result environment variable
+
+#[derive (Clone, Default, RustcEncodable, RustcDecodable)]
+pub struct SnapshotMetadata {
+	pub size: Option <u64>,
+	pub checksum: Option <String>,
+	pub remote_id: Option <String>,
+	pub tags: Option <BTreeMap <String, String>>,
+}
+
 // ---------- snapshot
 
 pub struct Snapshot {
@@ -48,6 +82,14 @@ pub struct Snapshot {
 	pub snapshot_time: Timespec,
 	pub send_time: Option <Timespec>,
 
+	pub export_state: Option <ExportState>,
+	pub export_time: Option <Timespec>,
+
+	pub verify_state: Option <VerifyState>,
+	pub verify_time: Option <Timespec>,
+
+	pub metadata: Option <SnapshotMetadata>,
+
 }
 
 // ---------- job
@@ -56,10 +98,21 @@ pub struct Job {
 
 	pub name: String,
 	pub state: JobState,
+	pub zone: Zone,
 
 	pub last_sync: Option <Timespec>,
 	pub last_snapshot: Option <Timespec>,
 	pub last_send: Option <Timespec>,
+	pub last_export: Option <Timespec>,
+	pub last_verify: Option <Timespec>,
+
+	pub last_failure: Option <Timespec>,
+	pub last_failure_stage: Option <String>,
+
+	pub deferred_stage: Option <String>,
+	pub deferred_until: Option <Timespec>,
+
+	pub verify_alerted: bool,
 
 	pub snapshots: Vec <Snapshot>,
 
@@ -67,8 +120,11 @@ pub struct Job {
 
 // ---------- global
 
+// the send bucket only lives as long as the daemon, and starts full
+
 pub struct Global {
 	pub jobs: Vec <Job>,
+	pub send_bucket: Option <TokenBucket>,
 }
 
 // ==================== disk state
@@ -81,6 +137,14 @@ struct DiskSnapshot {
 	pub snapshot_time: String,
 	pub send_time: Option <String>,
 
+	pub export_state: Option <String>,
+	pub export_time: Option <String>,
+
+	pub verify_state: Option <String>,
+	pub verify_time: Option <String>,
+
+	pub metadata: Option <SnapshotMetadata>,
+
 }
 
 #[derive (RustcEncodable, RustcDecodable)]
@@ -92,6 +156,16 @@ struct DiskJob {
 	pub last_sync: Option <String>,
 	pub last_snapshot: Option <String>,
 	pub last_send: Option <String>,
+	pub last_export: Option <String>,
+	pub last_verify: Option <String>,
+
+	pub last_failure: Option <String>,
+	pub last_failure_stage: Option <String>,
+
+	pub deferred_stage: Option <String>,
+	pub deferred_until: Option <String>,
+
+	pub verify_alerted: Option <bool>,
 
 	pub snapshots: Option <Vec <DiskSnapshot>>,
 
@@ -120,6 +194,7 @@ impl JobState {
 			"snapshotting" => { JobState::Snapshotting }
 			"sending" => { JobState::Sending }
 			"exporting" => { JobState::Exporting }
+			"verifying" => { JobState::Verifying }
 			_ => { panic! ("err") }
 		}
 
@@ -137,6 +212,7 @@ impl ToString for JobState {
 			JobState::Snapshotting => { "snapshotting".to_string () }
 			JobState::Sending => { "sending".to_string () }
 			JobState::Exporting => { "exporting".to_string () }
+			JobState::Verifying => { "verifying".to_string () }
 		}
 
 	}
@@ -176,27 +252,163 @@ impl ToString for SnapshotState {
 
 }
 
+// ---------- export state
+
+impl ExportState {
+
+	fn from_string (str: &str) -> ExportState {
+
+		match str {
+			"exporting" => { ExportState::Exporting }
+			"exported" => { ExportState::Exported }
+			"failed" => { ExportState::Failed }
+			_ => { panic! ("err") }
+		}
+
+	}
+
+}
+
+impl ToString for ExportState {
+
+	fn to_string (& self) -> String {
+
+		match * self {
+			ExportState::Exporting => { "exporting".to_string () }
+			ExportState::Exported => { "exported".to_string () }
+			ExportState::Failed => { "failed".to_string () }
+		}
+
+	}
+
+}
+
+// ---------- verify state
+
+impl VerifyState {
+
+	fn from_string (str: &str) -> VerifyState {
+
+		match str {
+			"verifying" => { VerifyState::Verifying }
+			"verified" => { VerifyState::Verified }
+			"failed" => { VerifyState::Failed }
+			_ => { panic! ("err") }
+		}
+
+	}
+
+}
+
+impl ToString for VerifyState {
+
+	fn to_string (& self) -> String {
+
+		match * self {
+			VerifyState::Verifying => { "verifying".to_string () }
+			VerifyState::Verified => { "verified".to_string () }
+			VerifyState::Failed => { "failed".to_string () }
+		}
+
+	}
+
+}
+
+// ---------- snapshot metadata
+
+impl SnapshotMetadata {
+
+	// fields reported by a later stage replace those from an earlier one, and
+	// tags are combined
+
+	pub fn merge (
+		&mut self,
+		other: SnapshotMetadata,
+	) {
+
+		if other.size.is_some () {
+			self.size = other.size;
+		}
+
+		if other.checksum.is_some () {
+			self.checksum = other.checksum;
+		}
+
+		if other.remote_id.is_some () {
+			self.remote_id = other.remote_id;
+		}
+
+		if let Some (other_tags) = other.tags {
+
+			self.tags.get_or_insert_with (
+				BTreeMap::new,
+			).extend (
+				other_tags);
+
+		}
+
+	}
+
+}
+
+// ---------- job
+
+impl Job {
+
+	// the time of the latest successful verification of any snapshot
+
+	pub fn last_verified (
+		& self,
+	) -> Option <Timespec> {
+
+		self.snapshots.iter ().filter (
+			|snapshot|
+			match snapshot.verify_state {
+				Some (VerifyState::Verified) => true,
+				_ => false,
+			}
+		).filter_map (
+			|snapshot| snapshot.verify_time
+		).max ()
+
+	}
+
+}
+
 // ---------- global state
 
 impl Global {
 
 	fn read_job (
+		config: & Config,
 		disk_state: & DiskState,
 		job_config: & JobConfig,
 	) -> Job {
 
-		match disk_state.jobs.iter ().find (
-			|elem| elem.name == job_config.name
-		) {
+		let zone =
+			config.job_zone (job_config);
+
+		let mut job =
+			match disk_state.jobs.iter ().find (
+				|elem| elem.name == job_config.name
+			) {
 
 			None => {
 
 				Job {
 					name: job_config.name.clone (),
 					state: JobState::Idle,
+					zone: zone,
 					last_sync: None,
 					last_snapshot: None,
 					last_send: None,
+					last_export: None,
+					last_verify: None,
+					last_failure: None,
+					last_failure_stage: None,
+					deferred_stage: None,
+					deferred_until: None,
+					verify_alerted: false,
 					snapshots: vec! [],
 				}
 
@@ -211,6 +423,8 @@ impl Global {
 					state: JobState::from_string (
 						& disk_job.state),
 
+					zone: zone,
+
 					last_sync: time_parse_opt (
 						& disk_job.last_sync),
 
@@ -220,6 +434,24 @@ impl Global {
 					last_send: time_parse_opt (
 						& disk_job.last_send),
 
+					last_export: time_parse_opt (
+						& disk_job.last_export),
+
+					last_verify: time_parse_opt (
+						& disk_job.last_verify),
+
+					last_failure: time_parse_opt (
+						& disk_job.last_failure),
+
+					last_failure_stage: disk_job.last_failure_stage.clone (),
+
+					deferred_stage: disk_job.deferred_stage.clone (),
+
+					deferred_until: time_parse_opt (
+						& disk_job.deferred_until),
+
+					verify_alerted: disk_job.verify_alerted.unwrap_or (false),
+
 					snapshots: match & disk_job.snapshots {
 
 						& Some (ref disk_snapshots) => {
@@ -242,7 +474,37 @@ impl Global {
 
 			}
 
-		}
+		};
+
+		// boundaries are kept in utc, as found in the zone the job had when
+		// they were written. rounding them down again in its zone now means
+		// that after the zone changes, a stage counts as done for the local
+		// period it ran in, instead of being in the future.
+
+		job.last_sync = job.last_sync.map (
+			|last_sync| round_down_hour (& job.zone, last_sync));
+
+		job.last_snapshot = job.last_snapshot.map (
+			|last_snapshot| round_down_day (& job.zone, last_snapshot));
+
+		job.last_send = job.last_send.map (
+			|last_send| round_down_day (& job.zone, last_send));
+
+		job.last_export = job.last_export.map (
+			|last_export|
+			round_down_period (
+				& job.zone,
+				config.export_period (job_config),
+				last_export));
+
+		job.last_verify = job.last_verify.map (
+			|last_verify|
+			round_down_period (
+				& job.zone,
+				config.verify_period (job_config),
+				last_verify));
+
+		job
 
 	}
 
@@ -261,6 +523,22 @@ impl Global {
 			send_time: time_parse_opt (
 				& disk_snapshot.send_time),
 
+			export_state: disk_snapshot.export_state.as_ref ().map (
+				|export_state|
+				ExportState::from_string (export_state)),
+
+			export_time: time_parse_opt (
+				& disk_snapshot.export_time),
+
+			verify_state: disk_snapshot.verify_state.as_ref ().map (
+				|verify_state|
+				VerifyState::from_string (verify_state)),
+
+			verify_time: time_parse_opt (
+				& disk_snapshot.verify_time),
+
+			metadata: disk_snapshot.metadata.clone (),
+
 		}
 
 	}
@@ -283,6 +561,24 @@ impl Global {
 			last_send: time_format_pretty_opt (
 				job.last_send),
 
+			last_export: time_format_pretty_opt (
+				job.last_export),
+
+			last_verify: time_format_pretty_opt (
+				job.last_verify),
+
+			last_failure: time_format_pretty_opt (
+				job.last_failure),
+
+			last_failure_stage: job.last_failure_stage.clone (),
+
+			deferred_stage: job.deferred_stage.clone (),
+
+			deferred_until: time_format_pretty_opt (
+				job.deferred_until),
+
+			verify_alerted: Some (job.verify_alerted),
+
 			snapshots: Some (job.snapshots.iter ().map (
 				|snapshot|
 
@@ -309,6 +605,22 @@ impl Global {
 			send_time: time_format_pretty_opt (
 				snapshot.send_time),
 
+			export_state: snapshot.export_state.as_ref ().map (
+				|export_state|
+				export_state.to_string ()),
+
+			export_time: time_format_pretty_opt (
+				snapshot.export_time),
+
+			verify_state: snapshot.verify_state.as_ref ().map (
+				|verify_state|
+				verify_state.to_string ()),
+
+			verify_time: time_format_pretty_opt (
+				snapshot.verify_time),
+
+			metadata: snapshot.metadata.clone (),
+
 		}
 
 	}
@@ -353,6 +665,7 @@ impl Global {
 				|job_config|
 
 				Global::read_job (
+					config,
 					& disk_state,
 					job_config)
 
@@ -360,6 +673,7 @@ impl Global {
 
 		Global {
 			jobs: jobs_temp,
+			send_bucket: None,
 		}
 
 	}
@@ -374,9 +688,17 @@ impl Global {
 			Job {
 				name: job_config.name.clone (),
 				state: JobState::Idle,
+				zone: config.job_zone (job_config),
 				last_sync: None,
 				last_snapshot: None,
 				last_send: None,
+				last_export: None,
+				last_verify: None,
+				last_failure: None,
+				last_failure_stage: None,
+				deferred_stage: None,
+				deferred_until: None,
+				verify_alerted: false,
 				snapshots: vec! [],
 			}
 
@@ -384,12 +706,14 @@ impl Global {
 
 		Global {
 			jobs: jobs_temp,
+			send_bucket: None,
 		}
 
 	}
 
 	pub fn read (
 		config: & Config,
+		logger: & Logger,
 	) -> Global {
 
 		// load state
@@ -404,7 +728,7 @@ impl Global {
 
 			Ok (_) => {
 
-				log! ("load existing state");
+				log! (logger, Level::Info, "load existing state");
 
 				Global::read_state (
 					config,
@@ -415,7 +739,7 @@ impl Global {
 
 			Err (_) => {
 
-				log! ("no existing state");
+				log! (logger, Level::Info, "no existing state");
 
 				Global::new_state (
 					config,
@@ -0,0 +1,268 @@
+extern crate time;
+
+use time::Timespec;
+
+use wbs::backup::config::*;
+use wbs::backup::queue::*;
+use wbs::backup::run::*;
+use wbs::backup::state::*;
+use wbs::backup::time::*;
+
+// ######################################## interface
+
+pub fn format_status (
+	config: & Config,
+	state: & Global,
+	now: Timespec,
+) -> String {
+
+	let mut output =
+		String::new ();
+
+	for (job_config, job) in config.jobs.iter ().zip (state.jobs.iter ()) {
+
+		output.push_str (& format! (
+			"{}: {}\n",
+			job_config.name,
+			job.state.to_string ()));
+
+		output.push_str (& format! (
+			"  last sync: {}\n",
+			format_time_opt (job.last_sync)));
+
+		output.push_str (& format! (
+			"  last snapshot: {}\n",
+			format_time_opt (job.last_snapshot)));
+
+		output.push_str (& format! (
+			"  last send: {}\n",
+			format_time_opt (job.last_send)));
+
+		if job_config.export_script.is_some () {
+
+			output.push_str (& format! (
+				"  last export: {}\n",
+				format_time_opt (job.last_export)));
+
+		}
+
+		if config.verify_enabled (job_config) {
+
+			output.push_str (& format! (
+				"  last verify: {}\n",
+				format_time_opt (job.last_verify)));
+
+			output.push_str (& format! (
+				"  last verified: {}\n",
+				format_time_opt (job.last_verified ())));
+
+			if verify_overdue (config, job_config, job, now) {
+
+				output.push_str (& format! (
+					"  alert: no successful verification since {}\n",
+					job.last_verified ().map_or (
+						"it started".to_string (),
+						time_format_pretty)));
+
+			}
+
+		}
+
+		if let (Some (last_failure), & Some (ref last_failure_stage))
+			= (job.last_failure, & job.last_failure_stage) {
+
+			output.push_str (& format! (
+				"  last failure: {} at {}\n",
+				last_failure_stage,
+				time_format_pretty (last_failure)));
+
+		}
+
+		if let (& Some (ref deferred_stage), Some (deferred_until))
+			= (& job.deferred_stage, job.deferred_until) {
+
+			output.push_str (& format! (
+				"  deferred: {} until {}\n",
+				deferred_stage,
+				time_format_pretty (deferred_until)));
+
+		}
+
+		let unsent =
+			job.snapshots.iter ().filter (
+				|snapshot|
+				match snapshot.state {
+					SnapshotState::Sent => false,
+					_ => true,
+				}
+			).count ();
+
+		output.push_str (& format! (
+			"  snapshots: {} ({} unsent)\n",
+			job.snapshots.len (),
+			unsent));
+
+		if let Some (snapshot) = job.snapshots.iter ().max_by_key (
+			|snapshot| snapshot.snapshot_time) {
+
+			output.push_str (& format! (
+				"  latest: {}\n",
+				format_snapshot (snapshot)));
+
+		}
+
+	}
+
+	let queue =
+		send_queue (
+			config,
+			state,
+			& (0 .. state.jobs.len ()).collect::<Vec <usize>> ());
+
+	if ! queue.is_empty () {
+
+		output.push_str ("send queue:\n");
+
+		for (queue_index, entry) in queue.iter ().enumerate () {
+
+			output.push_str (& format! (
+				"  {}. {} {} priority {}\n",
+				queue_index + 1,
+				config.jobs [entry.job_index].name,
+				time_format_pretty (
+					state.jobs [entry.job_index].snapshots [entry.snapshot_index].snapshot_time),
+				entry.priority));
+
+		}
+
+	}
+
+	if let Some (send_bandwidth) = config.send_bandwidth {
+
+		output.push_str (& format! (
+			"send bandwidth: {} bytes per second\n",
+			send_bandwidth));
+
+	}
+
+	output
+
+}
+
+pub fn format_history (
+	config: & Config,
+	state: & Global,
+	job_name: Option <&str>,
+) -> String {
+
+	let mut output =
+		String::new ();
+
+	for (job_config, job) in config.jobs.iter ().zip (state.jobs.iter ()) {
+
+		if job_name.map_or (false, |job_name| job_name != job_config.name) {
+			continue;
+		}
+
+		let mut snapshots: Vec <& Snapshot> =
+			job.snapshots.iter ().collect ();
+
+		snapshots.sort_by_key (
+			|snapshot| snapshot.snapshot_time);
+
+		for snapshot in snapshots {
+
+			output.push_str (& format! (
+				"{} {}\n",
+				job_config.name,
+				format_snapshot (snapshot)));
+
+		}
+
+	}
+
+	output
+
+}
+
+// ######################################## implementation
+
+fn format_snapshot (
+	snapshot: & Snapshot,
+) -> String {
+
+	let mut output =
+		format! (
+			"{} {}",
+			time_format_pretty (snapshot.snapshot_time),
+			snapshot.state.to_string ());
+
+	if let Some (send_time) = snapshot.send_time {
+
+		output.push_str (& format! (
+			" sent={}",
+			time_format_iso (send_time)));
+
+	}
+
+	if let Some (ref export_state) = snapshot.export_state {
+
+		output.push_str (& format! (
+			" export={}",
+			export_state.to_string ()));
+
+	}
+
+	if let Some (ref verify_state) = snapshot.verify_state {
+
+		output.push_str (& format! (
+			" verify={}",
+			verify_state.to_string ()));
+
+	}
+
+	if let Some (verify_time) = snapshot.verify_time {
+
+		output.push_str (& format! (
+			" verified={}",
+			time_format_iso (verify_time)));
+
+	}
+
+	if let Some (ref metadata) = snapshot.metadata {
+
+		if let Some (size) = metadata.size {
+			output.push_str (& format! (" size={}", size));
+		}
+
+		if let Some (ref checksum) = metadata.checksum {
+			output.push_str (& format! (" checksum={}", checksum));
+		}
+
+		if let Some (ref remote_id) = metadata.remote_id {
+			output.push_str (& format! (" remote_id={}", remote_id));
+		}
+
+		if let Some (ref tags) = metadata.tags {
+
+			for (key, value) in tags.iter () {
+				output.push_str (& format! (" tag:{}={}", key, value));
+			}
+
+		}
+
+	}
+
+	output
+
+}
+
+fn format_time_opt (
+	time: Option <Timespec>,
+) -> String {
+
+	time.map_or (
+		"never".to_string (),
+		time_format_pretty)
+
+}
@@ -0,0 +1,2794 @@
+extern crate libc;
+extern crate time;
+
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::ExitStatusExt;
+use std::process;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use time::Timespec;
+
+use wbs::backup::clock::*;
+use wbs::backup::config::*;
+use wbs::backup::format::*;
+use wbs::backup::log::*;
+use wbs::backup::main::*;
+use wbs::backup::report::*;
+use wbs::backup::run::*;
+use wbs::backup::state::*;
+use wbs::backup::status::*;
+use wbs::backup::time::*;
+use wbs::backup::window::*;
+use wbs::backup::zone::*;
+
+// ######################################## harness
+
+// ---------- fake clock
+
+struct FakeClock {
+	now: Cell <Timespec>,
+}
+
+impl FakeClock {
+
+	fn new (
+		now: & str,
+	) -> FakeClock {
+
+		FakeClock {
+			now: Cell::new (time_parse (now)),
+		}
+
+	}
+
+	fn set (
+		& self,
+		now: & str,
+	) {
+
+		self.now.set (
+			time_parse (now));
+
+	}
+
+}
+
+impl Clock for FakeClock {
+
+	fn now (
+		& self,
+	) -> Timespec {
+
+		self.now.get ()
+
+	}
+
+	fn sleep_until (
+		& self,
+		deadline: Timespec,
+	) -> Wakeup {
+
+		if deadline > self.now.get () {
+			self.now.set (deadline);
+		}
+
+		Wakeup::Deadline
+
+	}
+
+}
+
+// ---------- fake script runner
+
+struct FakeRunner {
+	invocations: RefCell <Vec <(String, String)>>,
+	failing: RefCell <Vec <String>>,
+	results: RefCell <Vec <(String, String)>>,
+	slow: RefCell <Vec <String>>,
+	unstartable: RefCell <Vec <String>>,
+	deadlines: RefCell <Vec <Option <Timespec>>>,
+	environments: RefCell <Vec <Vec <(String, String)>>>,
+}
+
+impl FakeRunner {
+
+	fn new (
+	) -> FakeRunner {
+
+		FakeRunner {
+			invocations: RefCell::new (vec! []),
+			failing: RefCell::new (vec! []),
+			results: RefCell::new (vec! []),
+			slow: RefCell::new (vec! []),
+			unstartable: RefCell::new (vec! []),
+			deadlines: RefCell::new (vec! []),
+			environments: RefCell::new (vec! []),
+		}
+
+	}
+
+	fn fail (
+		& self,
+		script: & str,
+	) {
+
+		self.failing.borrow_mut ().push (
+			script.to_string ());
+
+	}
+
+	fn succeed (
+		& self,
+		script: & str,
+	) {
+
+		self.failing.borrow_mut ().retain (
+			|failing| failing != script);
+
+	}
+
+	// an unstartable script fails as if its process settings were refused
+
+	fn unstartable (
+		& self,
+		script: & str,
+	) {
+
+		self.unstartable.borrow_mut ().push (
+			script.to_string ());
+
+	}
+
+	// a slow script is still running at any deadline it is given
+
+	fn slow (
+		& self,
+		script: & str,
+	) {
+
+		self.slow.borrow_mut ().push (
+			script.to_string ());
+
+	}
+
+	fn result (
+		& self,
+		script: & str,
+		result: & str,
+	) {
+
+		self.results.borrow_mut ().push (
+			(script.to_string (), result.to_string ()));
+
+	}
+
+	fn take (
+		& self,
+	) -> Vec <(String, String)> {
+
+		self.invocations.borrow_mut ().drain (..).collect ()
+
+	}
+
+}
+
+impl ScriptRunner for FakeRunner {
+
+	fn run_script (
+		& self,
+		clock: & Clock,
+		job_config: & JobConfig,
+		name: & str,
+		script: & str,
+		log: & str,
+		time: & str,
+		arguments: & [String],
+		environment: & [(String, String)],
+		deadline: Option <Timespec>,
+	) -> Result <ScriptOutput, String> {
+
+		let arguments: Vec <String> =
+			Some (time.to_string ()).into_iter ().chain (
+				arguments.iter ().cloned (),
+			).collect ();
+
+		self.run_command (
+			clock,
+			job_config,
+			name,
+			script,
+			log,
+			time,
+			& arguments,
+			environment,
+			deadline)
+
+	}
+
+	fn run_command (
+		& self,
+		_clock: & Clock,
+		_job_config: & JobConfig,
+		_name: & str,
+		script: & str,
+		_log: & str,
+		_time: & str,
+		arguments: & [String],
+		environment: & [(String, String)],
+		deadline: Option <Timespec>,
+	) -> Result <ScriptOutput, String> {
+
+		self.deadlines.borrow_mut ().push (
+			deadline);
+
+		self.environments.borrow_mut ().push (
+			environment.to_vec ());
+
+		let stopped =
+			deadline.is_some ()
+			&& self.slow.borrow ().iter ().any (|slow| slow == script);
+
+		let command_line =
+			arguments.join (" ");
+
+		self.invocations.borrow_mut ().push (
+			invocation (script, & command_line));
+
+		if self.unstartable.borrow ().iter ().any (|unstartable| unstartable == script) {
+
+			return Err (
+				format! (
+					"error running script {}: Operation not permitted (os error 1)",
+					script));
+
+		}
+
+		let status =
+			if self.failing.borrow ().iter ().any (
+				|failing| failing == script
+			) {
+				process::ExitStatus::from_raw (1 << 8)
+			} else {
+				process::ExitStatus::from_raw (0)
+			};
+
+		let result =
+			self.results.borrow ().iter ().find (
+				|& & (ref result_script, _)| result_script == script
+			).map (
+				|& (_, ref result)| result.clone ()
+			);
+
+		Ok (ScriptOutput {
+			status: if stopped { process::ExitStatus::from_raw (15) } else { status },
+			result: Ok (result),
+			stopped: stopped,
+		})
+
+	}
+
+}
+
+// ---------- logger
+
+struct MemorySink {
+	lines: Rc <RefCell <Vec <String>>>,
+}
+
+impl LogSink for MemorySink {
+
+	fn write (
+		& self,
+		record: & LogRecord,
+	) {
+
+		self.lines.borrow_mut ().push (
+			format_json (record));
+
+	}
+
+}
+
+fn test_logger (
+	clock: Rc <Clock>,
+) -> Logger {
+
+	memory_logger (Level::Debug, clock).0
+
+}
+
+fn memory_logger (
+	level: Level,
+	clock: Rc <Clock>,
+) -> (Logger, Rc <RefCell <Vec <String>>>) {
+
+	let lines =
+		Rc::new (RefCell::new (vec! []));
+
+	let logger =
+		Logger::new (
+			level,
+			Box::new (MemorySink {
+				lines: lines.clone (),
+			}),
+			clock);
+
+	(logger, lines)
+
+}
+
+// ---------- config
+
+static NEXT_STATE: AtomicUsize =
+	AtomicUsize::new (0);
+
+struct TestConfig {
+	config: Config,
+}
+
+impl Drop for TestConfig {
+
+	fn drop (
+		&mut self,
+	) {
+
+		let _ = fs::remove_file (
+			& self.config.state);
+
+	}
+
+}
+
+fn test_config (
+) -> TestConfig {
+
+	let state_path =
+		env::temp_dir ().join (
+			format! (
+				"backup-daemon-test-{}-{}.state",
+				process::id (),
+				NEXT_STATE.fetch_add (1, Ordering::SeqCst)));
+
+	let _ = fs::remove_file (& state_path);
+
+	TestConfig {
+		config: Config {
+
+			state: state_path.to_str ().unwrap ().to_string (),
+			lock: "backup-daemon.lock".to_string (),
+			control_socket: None,
+			timezone: None,
+
+			log_level: None,
+			log_sink: None,
+			syslog_path: None,
+
+			blackouts: None,
+
+			send_bandwidth: None,
+			send_burst: None,
+
+			host_name: None,
+			report_directory: None,
+			report_url: None,
+			report_interval: None,
+
+			jobs: vec! [
+				test_job_config ("job1"),
+				test_job_config ("job2"),
+			],
+
+		},
+	}
+
+}
+
+fn test_job_config (
+	name: & str,
+) -> JobConfig {
+
+	JobConfig {
+
+		name: name.to_string (),
+		timezone: None,
+
+		sync_script: Some (format! ("{}-sync", name)),
+		sync_log: Some (format! ("{}-sync", name)),
+
+		snapshot_script: Some (format! ("{}-snapshot", name)),
+		snapshot_log: Some (format! ("{}-snapshot", name)),
+
+		send_script: Some (format! ("{}-send", name)),
+		send_log: Some (format! ("{}-send", name)),
+		send_priority: None,
+
+		export_script: None,
+		export_log: None,
+		export_schedule: None,
+
+		verify_script: None,
+		verify_restore_test: None,
+		verify_log: None,
+		verify_schedule: None,
+		verify_pick: None,
+		verify_alert_days: None,
+
+		blackouts: None,
+
+		working_directory: None,
+		user: None,
+		group: None,
+		umask: None,
+		nice: None,
+		ionice_class: None,
+		ionice_priority: None,
+
+		cgroup: None,
+		cgroup_memory_max: None,
+		cgroup_io_max: None,
+
+		launcher: None,
+
+	}
+
+}
+
+fn invocation (
+	script: & str,
+	command_line: & str,
+) -> (String, String) {
+
+	(script.to_string (), command_line.to_string ())
+
+}
+
+fn run_for (
+	context: & Context,
+	clock: & FakeClock,
+	config: & Config,
+	state: &mut Global,
+	seconds: i64,
+) {
+
+	let end =
+		Timespec::new (clock.now ().sec + seconds, 0);
+
+	while clock.now () < end {
+
+		loop_once (context, config, state);
+
+		let next_due =
+			next_due_time (
+				config,
+				state,
+				clock.now ());
+
+		clock.sleep_until (
+			if next_due < end { next_due } else { end });
+
+	}
+
+}
+
+// ######################################## tests
+
+#[test]
+fn first_run_syncs_snapshots_and_sends () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:30:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! [
+		invocation ("job1-sync", "2016-10-22-00"),
+		invocation ("job1-snapshot", "2016-10-22"),
+		invocation ("job2-sync", "2016-10-22-00"),
+		invocation ("job2-snapshot", "2016-10-22"),
+		invocation ("job1-send", "2016-10-22"),
+		invocation ("job2-send", "2016-10-22"),
+	]);
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! []);
+
+}
+
+#[test]
+fn simulated_days_sync_hourly_and_snapshot_daily () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	run_for (& context, & clock, config, &mut state, 3 * 86400);
+
+	let invocations = runner.take ();
+
+	let count = |script: & str| invocations.iter ().filter (
+		|& & (ref invocation_script, _)| invocation_script == script
+	).count ();
+
+	assert_eq! (count ("job1-sync"), 72);
+	assert_eq! (count ("job1-snapshot"), 3);
+	assert_eq! (count ("job1-send"), 3);
+
+	assert_eq! (count ("job2-sync"), 72);
+	assert_eq! (count ("job2-snapshot"), 3);
+	assert_eq! (count ("job2-send"), 3);
+
+	assert_eq! (state.jobs [0].snapshots.len (), 3);
+
+	assert! (state.jobs [0].snapshots.iter ().all (
+		|snapshot| match snapshot.state {
+			SnapshotState::Sent => true,
+			_ => false,
+		}));
+
+	assert_eq! (
+		time_format_pretty_opt (state.jobs [0].last_sync),
+		Some ("2016-10-24 23:00:00".to_string ()));
+
+}
+
+#[test]
+fn failed_script_is_retried_next_period () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 10:15:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	runner.fail ("job1-sync");
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take () [0], invocation ("job1-sync", "2016-10-22-10"));
+
+	run_for (& context, & clock, config, &mut state, 30 * 60);
+
+	assert_eq! (runner.take (), vec! []);
+
+	clock.set ("2016-10-22 11:00:00");
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! [
+		invocation ("job1-sync", "2016-10-22-11"),
+		invocation ("job2-sync", "2016-10-22-11"),
+	]);
+
+}
+
+#[test]
+fn unstartable_script_fails_stage_and_leaves_it_due () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 10:15:00"));
+	let runner = FakeRunner::new ();
+	let (logger, lines) = memory_logger (Level::Error, clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	runner.unstartable ("job1-sync");
+	runner.unstartable ("job1-send");
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! [
+		invocation ("job1-sync", "2016-10-22-10"),
+		invocation ("job1-snapshot", "2016-10-22"),
+		invocation ("job2-sync", "2016-10-22-10"),
+		invocation ("job2-snapshot", "2016-10-22"),
+		invocation ("job1-send", "2016-10-22"),
+		invocation ("job2-send", "2016-10-22"),
+	]);
+
+	assert! (state.jobs [0].last_sync.is_none ());
+	assert! (state.jobs [0].last_send.is_none ());
+	assert_eq! (state.jobs [0].last_failure_stage, Some ("send".to_string ()));
+	assert_eq! (state.jobs [0].snapshots [0].state.to_string (), "snapshotted");
+	assert_eq! (state.jobs [1].snapshots [0].state.to_string (), "sent");
+
+	let records: Vec <Json> =
+		lines.borrow ().iter ().map (
+			|line| Json::from_str (line).unwrap ()
+		).collect ();
+
+	assert_eq! (records.len (), 2);
+	assert_eq! (records [0] ["stage"], Json::String ("sync".to_string ()));
+
+	assert_eq! (
+		records [0] ["message"],
+		Json::String (
+			"sync for job1 not started: error running script job1-sync: \
+			Operation not permitted (os error 1)".to_string ()));
+
+	// both stages are tried again on the next pass
+
+	runner.unstartable.borrow_mut ().clear ();
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! [
+		invocation ("job1-sync", "2016-10-22-10"),
+		invocation ("job1-send", "2016-10-22"),
+	]);
+
+	assert_eq! (state.jobs [0].snapshots [0].state.to_string (), "sent");
+
+}
+
+#[test]
+fn restart_resumes_from_persisted_state () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 10:15:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+
+	{
+
+		let mut state = Global::read (config, & logger);
+
+		loop_once (& context, config, &mut state);
+
+		assert_eq! (runner.take ().len (), 6);
+
+	}
+
+	// restart within the same hour does nothing
+
+	clock.set ("2016-10-22 10:45:00");
+
+	let mut state = Global::read (config, & logger);
+
+	assert_eq! (state.jobs [0].snapshots.len (), 1);
+
+	assert_eq! (
+		time_format_pretty (state.jobs [0].snapshots [0].snapshot_time),
+		"2016-10-22 00:00:00");
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! []);
+
+	// restart on the next day snapshots again
+
+	clock.set ("2016-10-23 02:10:00");
+
+	let mut state = Global::read (config, & logger);
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! [
+		invocation ("job1-sync", "2016-10-23-02"),
+		invocation ("job1-snapshot", "2016-10-23"),
+		invocation ("job2-sync", "2016-10-23-02"),
+		invocation ("job2-snapshot", "2016-10-23"),
+		invocation ("job1-send", "2016-10-23 2016-10-22"),
+		invocation ("job2-send", "2016-10-23 2016-10-22"),
+	]);
+
+}
+
+#[test]
+fn clock_skew_within_period_is_ignored () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 10:45:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	loop_once (& context, config, &mut state);
+
+	runner.take ();
+
+	clock.set ("2016-10-22 10:05:00");
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! []);
+
+}
+
+#[test]
+fn clock_skew_before_last_run_waits_for_clock () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 10:45:00"));
+	let runner = FakeRunner::new ();
+	let (logger, lines) = memory_logger (Level::Warning, clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	loop_once (& context, config, &mut state);
+
+	runner.take ();
+
+	// the clock going back past the last sync runs nothing and says so
+
+	clock.set ("2016-10-22 09:55:00");
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! []);
+
+	let warnings: Vec <Json> =
+		lines.borrow ().iter ().map (
+			|line| Json::from_str (line).unwrap ()
+		).collect ();
+
+	assert! (warnings.iter ().any (
+		|record|
+			record ["job"] == Json::String ("job1".to_string ())
+			&& record ["stage"] == Json::String ("sync".to_string ())
+			&& record ["last"] == Json::String ("2016-10-22T10:00:00Z".to_string ())));
+
+	// and work resumes once it has caught up
+
+	run_for (& context, & clock, config, &mut state, 70 * 60);
+
+	assert_eq! (runner.take (), vec! [
+		invocation ("job1-sync", "2016-10-22-11"),
+		invocation ("job2-sync", "2016-10-22-11"),
+	]);
+
+}
+
+// ---------- stages
+
+#[test]
+fn sends_are_ordered_and_stop_at_first_failure () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (job_invocations (& runner, "job1-send"), vec! [
+		invocation ("job1-send", "2016-10-22"),
+	]);
+
+	// two days of failures leave both snapshots waiting
+
+	runner.fail ("job1-send");
+
+	run_for (& context, & clock, config, &mut state, 2 * 86400 + 3600);
+
+	assert_eq! (job_invocations (& runner, "job1-send"), vec! [
+		invocation ("job1-send", "2016-10-23 2016-10-22"),
+		invocation ("job1-send", "2016-10-23 2016-10-22"),
+	]);
+
+	let snapshot_states: Vec <String> =
+		state.jobs [0].snapshots.iter ().map (
+			|snapshot| snapshot.state.to_string ()
+		).collect ();
+
+	assert_eq! (snapshot_states, vec! ["sent", "snapshotted", "snapshotted"]);
+	assert! (state.jobs [0].snapshots [1].send_time.is_none ());
+
+	// the next send catches up in order, each against the one before
+
+	runner.succeed ("job1-send");
+
+	run_for (& context, & clock, config, &mut state, 86400);
+
+	assert_eq! (job_invocations (& runner, "job1-send"), vec! [
+		invocation ("job1-send", "2016-10-23 2016-10-22"),
+		invocation ("job1-send", "2016-10-24 2016-10-23"),
+		invocation ("job1-send", "2016-10-25 2016-10-24"),
+	]);
+
+}
+
+#[test]
+fn script_results_are_recorded_on_snapshots () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	runner.result (
+		"job1-snapshot",
+		r#"{ "size": 1048576, "checksum": "sha256:abc", "tags": { "host": "db1" } }"#);
+
+	runner.result (
+		"job1-send",
+		r#"{ "remote_id": "vault-17", "tags": { "tier": "cold" } }"#);
+
+	runner.result (
+		"job2-snapshot",
+		"not json");
+
+	loop_once (& context, config, &mut state);
+
+	// the send result is merged into the snapshot result, and survives a restart
+
+	let state = Global::read (config, & logger);
+
+	assert_eq! (
+		format_history (config, & state, None),
+		concat! (
+			"job1 2016-10-22 00:00:00 sent sent=2016-10-22T00:00:00Z size=1048576 ",
+			"checksum=sha256:abc remote_id=vault-17 tag:host=db1 tag:tier=cold\n",
+			"job2 2016-10-22 00:00:00 sent sent=2016-10-22T00:00:00Z\n"));
+
+	assert! (format_status (config, & state, clock.now ()).contains (
+		"  latest: 2016-10-22 00:00:00 sent sent=2016-10-22T00:00:00Z size=1048576 "));
+
+}
+
+#[test]
+fn weekly_export_copies_sent_snapshots_oldest_first () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-19 00:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.jobs [0].export_script = Some ("job1-export".to_string ());
+	test_config.config.jobs [0].export_log = Some ("job1-export".to_string ());
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	run_for (& context, & clock, config, &mut state, 10 * 86400);
+
+	assert_eq! (job_invocations (& runner, "job1-export"), vec! [
+		invocation ("job1-export", "2016-10-19"),
+		invocation ("job1-export", "2016-10-20"),
+		invocation ("job1-export", "2016-10-21"),
+		invocation ("job1-export", "2016-10-22"),
+		invocation ("job1-export", "2016-10-23"),
+		invocation ("job1-export", "2016-10-24"),
+	]);
+
+	let export_states: Vec <Option <String>> =
+		state.jobs [0].snapshots.iter ().map (
+			|snapshot|
+			snapshot.export_state.as_ref ().map (
+				|export_state| export_state.to_string ())
+		).collect ();
+
+	assert_eq! (export_states.len (), 10);
+	assert! (export_states [0 .. 6].iter ().all (
+		|export_state| export_state == & Some ("exported".to_string ())));
+	assert! (export_states [6 ..].iter ().all (
+		|export_state| export_state.is_none ()));
+
+	assert_eq! (
+		time_format_pretty_opt (state.jobs [0].last_export),
+		Some ("2016-10-24 00:00:00".to_string ()));
+
+	assert_eq! (
+		time_format_pretty_opt (state.jobs [0].snapshots [1].export_time),
+		Some ("2016-10-24 00:00:00".to_string ()));
+
+}
+
+#[test]
+fn failed_export_is_retried_next_period () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-19 00:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.jobs [0].export_script = Some ("job1-export".to_string ());
+	test_config.config.jobs [0].export_log = Some ("job1-export".to_string ());
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	runner.fail ("job1-export");
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (job_invocations (& runner, "job1-export"), vec! [
+		invocation ("job1-export", "2016-10-19"),
+	]);
+
+	assert_eq! (
+		state.jobs [0].snapshots [0].export_state.as_ref ().map (
+			|export_state| export_state.to_string ()),
+		Some ("failed".to_string ()));
+
+	// a restart keeps the failure and retries it at the start of next week
+
+	let mut state = Global::read (config, & logger);
+
+	runner.succeed ("job1-export");
+
+	run_for (& context, & clock, config, &mut state, 5 * 86400 + 3600);
+
+	assert_eq! (job_invocations (& runner, "job1-export"), vec! [
+		invocation ("job1-export", "2016-10-19"),
+		invocation ("job1-export", "2016-10-20"),
+		invocation ("job1-export", "2016-10-21"),
+		invocation ("job1-export", "2016-10-22"),
+		invocation ("job1-export", "2016-10-23"),
+		invocation ("job1-export", "2016-10-24"),
+	]);
+
+	assert! (state.jobs [0].snapshots.iter ().all (
+		|snapshot| match snapshot.export_state {
+			Some (ExportState::Exported) => true,
+			_ => false,
+		}));
+
+}
+
+#[test]
+fn verify_restores_a_sent_snapshot_and_alerts_when_overdue () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-19 00:00:00"));
+	let runner = FakeRunner::new ();
+	let (logger, lines) = memory_logger (Level::Error, clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.jobs [0].verify_script = Some ("job1-verify".to_string ());
+	test_config.config.jobs [0].verify_log = Some ("job1-verify".to_string ());
+	test_config.config.jobs [1].verify_script = Some ("job2-verify".to_string ());
+	test_config.config.jobs [1].verify_log = Some ("job2-verify".to_string ());
+	test_config.config.jobs [1].verify_pick = Some ("random".to_string ());
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	// each week the latest sent snapshot is restored, and failures build up
+	// until there has been no success for two weeks
+
+	runner.fail ("job1-verify");
+
+	run_for (& context, & clock, config, &mut state, 20 * 86400);
+
+	let invocations =
+		runner.take ();
+
+	let verifies =
+		|script: & str|
+		invocations.iter ().filter (
+			|& & (ref invocation_script, _)| invocation_script == script
+		).cloned ().collect::<Vec <(String, String)>> ();
+
+	assert_eq! (verifies ("job1-verify"), vec! [
+		invocation ("job1-verify", "2016-10-19"),
+		invocation ("job1-verify", "2016-10-24"),
+		invocation ("job1-verify", "2016-10-31"),
+		invocation ("job1-verify", "2016-11-07"),
+	]);
+
+	assert_eq! (
+		state.jobs [0].snapshots [5].verify_state.as_ref ().map (
+			|verify_state| verify_state.to_string ()),
+		Some ("failed".to_string ()));
+
+	let alerts: Vec <Json> =
+		lines.borrow ().iter ().map (
+			|line| Json::from_str (line).unwrap ()
+		).filter (
+			|record| record.find ("alert").is_some ()
+		).collect ();
+
+	assert_eq! (alerts.len (), 1);
+	assert_eq! (alerts [0] ["job"], Json::String ("job1".to_string ()));
+	assert_eq! (alerts [0] ["message"], Json::String (
+		"no successful verification for job1 since it started".to_string ()));
+
+	assert! (format_status (config, & state, clock.now ()).contains (concat! (
+		"  last verify: 2016-11-07 00:00:00\n",
+		"  last verified: never\n",
+		"  alert: no successful verification since it started\n")));
+
+	// a random pick is always one of the snapshots already sent
+
+	let job2_verifies =
+		verifies ("job2-verify");
+
+	assert_eq! (job2_verifies.len (), 4);
+
+	assert! (job2_verifies.iter ().zip (
+		["2016-10-19", "2016-10-24", "2016-10-31", "2016-11-07"].iter (),
+	).all (
+		|(& (_, ref snapshot_day), verify_day)|
+		"2016-10-19" <= snapshot_day.as_str () && snapshot_day.as_str () <= * verify_day));
+
+	// a success clears the alert, and is recorded on the snapshot
+
+	runner.succeed ("job1-verify");
+
+	run_for (& context, & clock, config, &mut state, 7 * 86400);
+
+	let status =
+		format_status (config, & state, clock.now ());
+
+	assert! (status.contains ("  last verified: 2016-11-14 00:00:00\n"));
+	assert! (! status.contains ("  alert: "));
+
+	let state = Global::read (config, & logger);
+
+	assert! (format_history (config, & state, Some ("job1")).contains (
+		"job1 2016-11-14 00:00:00 sent sent=2016-11-14T00:00:00Z verify=verified verified=2016-11-14T00:00:00Z\n"));
+
+}
+
+#[test]
+fn held_back_send_does_not_hold_back_export_or_verify () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-19 00:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.jobs [0].export_script = Some ("job1-export".to_string ());
+	test_config.config.jobs [0].export_log = Some ("job1-export".to_string ());
+	test_config.config.jobs [0].verify_restore_test = Some ("/zbackup/backups/${job}/${time}".to_string ());
+	test_config.config.jobs [0].verify_log = Some ("job1-verify".to_string ());
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	run_for (& context, & clock, config, &mut state, 5 * 86400 - 3600);
+
+	runner.take ();
+
+	// the send for the new week fails, but the export and the restore test
+	// still run, on the snapshots which were sent
+
+	runner.fail ("job1-send");
+
+	run_for (& context, & clock, config, &mut state, 2 * 3600);
+
+	let invocations =
+		runner.take ();
+
+	assert! (invocations.contains (& invocation ("job1-send", "2016-10-24 2016-10-23")));
+
+	assert_eq! (
+		invocations.iter ().filter (
+			|& & (ref script, _)| script == "job1-export" || script == "tar-filter"
+		).cloned ().collect::<Vec <(String, String)>> (),
+		vec! [
+			invocation ("job1-export", "2016-10-20"),
+			invocation ("job1-export", "2016-10-21"),
+			invocation ("job1-export", "2016-10-22"),
+			invocation ("job1-export", "2016-10-23"),
+			invocation ("tar-filter", "restore-test /zbackup/backups/job1/2016-10-23"),
+		]);
+
+	assert_eq! (
+		state.jobs [0].snapshots [5].export_state.as_ref ().map (
+			|export_state| export_state.to_string ()),
+		None);
+
+}
+
+#[test]
+fn verify_overdue_alert_is_raised_between_verifies () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-19 00:00:00"));
+	let runner = FakeRunner::new ();
+	let (logger, lines) = memory_logger (Level::Error, clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.jobs [0].verify_script = Some ("job1-verify".to_string ());
+	test_config.config.jobs [0].verify_log = Some ("job1-verify".to_string ());
+	test_config.config.jobs [0].verify_alert_days = Some (2);
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	runner.fail ("job1-verify");
+
+	run_for (& context, & clock, config, &mut state, 5 * 86400);
+
+	// the alert is raised as soon as the job is overdue, and only once
+
+	let alerts = || -> Vec <Json> {
+		lines.borrow ().iter ().map (
+			|line| Json::from_str (line).unwrap ()
+		).filter (
+			|record| record.find ("alert").is_some ()
+		).collect ()
+	};
+
+	assert_eq! (alerts ().len (), 1);
+	assert_eq! (alerts () [0] ["time"], Json::String ("2016-10-21T01:00:00Z".to_string ()));
+	assert! (state.jobs [0].verify_alerted);
+
+	// a success clears it
+
+	runner.succeed ("job1-verify");
+
+	run_for (& context, & clock, config, &mut state, 3600);
+
+	assert! (! state.jobs [0].verify_alerted);
+	assert_eq! (alerts ().len (), 1);
+
+}
+
+// ---------- idle loop
+
+#[test]
+fn next_due_time_is_earliest_local_boundary () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let logger = test_logger (clock.clone ());
+
+	let mut test_config = test_config ();
+	test_config.config.jobs [1].timezone = Some ("Asia/Kolkata".to_string ());
+	let config = & test_config.config;
+	let state = Global::read (config, & logger);
+
+	// job2 is at 15:45 local time, so its next hour starts before job1's
+
+	assert_eq! (
+		time_format_pretty (
+			next_due_time (config, & state, time_parse ("2016-10-22 10:15:00"))),
+		"2016-10-22 10:30:00");
+
+	assert_eq! (
+		time_format_pretty (
+			next_due_time (config, & state, time_parse ("2016-10-22 10:30:00"))),
+		"2016-10-22 11:00:00");
+
+}
+
+#[test]
+fn next_hour_skips_repeated_and_missing_hours () {
+
+	let zone = Zone::load ("Europe/London").unwrap ();
+
+	// first 01:30 bst, then 01:30 gmt, both move on to 02:00 gmt
+
+	assert_eq! (
+		time_format_pretty (next_hour (& zone, time_parse ("2016-10-30 00:30:00"))),
+		"2016-10-30 02:00:00");
+
+	assert_eq! (
+		time_format_pretty (next_hour (& zone, time_parse ("2016-10-30 01:30:00"))),
+		"2016-10-30 02:00:00");
+
+	// 00:30 gmt moves on to 02:00 bst, as 01:00 is skipped
+
+	assert_eq! (
+		time_format_pretty (next_hour (& zone, time_parse ("2016-03-27 00:30:00"))),
+		"2016-03-27 01:00:00");
+
+	assert_eq! (
+		time_format_pretty (next_day (& zone, time_parse ("2016-10-29 12:00:00"))),
+		"2016-10-29 23:00:00");
+
+}
+
+#[test]
+fn system_clock_sleeps_until_deadline_or_signal () {
+
+	let clock = SystemClock::new ();
+
+	clock.wake_on_signals ();
+
+	let deadline =
+		Timespec::new (clock.now ().sec + 2, 0);
+
+	match clock.sleep_until (deadline) {
+		Wakeup::Deadline => (),
+		_ => panic! ("expected deadline"),
+	}
+
+	assert! (clock.now () >= deadline);
+
+	let signaller =
+		thread::spawn (|| {
+			thread::sleep (Duration::from_millis (100));
+			unsafe { libc::kill (libc::getpid (), libc::SIGUSR1) };
+		});
+
+	match clock.sleep_until (Timespec::new (clock.now ().sec + 60, 0)) {
+		Wakeup::Interrupted => (),
+		_ => panic! ("expected interruption"),
+	}
+
+	signaller.join ().unwrap ();
+
+}
+
+#[test]
+fn system_clock_wakes_on_control_command () {
+
+	let socket_path =
+		env::temp_dir ().join (
+			format! (
+				"backup-daemon-test-{}.control",
+				process::id ()));
+
+	let socket_path =
+		socket_path.to_str ().unwrap ().to_string ();
+
+	let mut clock = SystemClock::new ();
+
+	clock.listen_for_commands (& socket_path).unwrap ();
+
+	let sender = {
+		let socket_path = socket_path.clone ();
+		thread::spawn (move || {
+			thread::sleep (Duration::from_millis (100));
+			send_command (& socket_path, "wake").unwrap ();
+		})
+	};
+
+	match clock.sleep_until (Timespec::new (clock.now ().sec + 60, 0)) {
+		Wakeup::Command (ref command) if command == "wake" => (),
+		_ => panic! ("expected wake command"),
+	}
+
+	sender.join ().unwrap ();
+
+	fs::remove_file (& socket_path).unwrap ();
+
+}
+
+// ---------- timezones
+
+fn job_invocations (
+	runner: & FakeRunner,
+	job: & str,
+) -> Vec <(String, String)> {
+
+	runner.take ().into_iter ().filter (
+		|& (ref script, _)| script.starts_with (job)
+	).collect ()
+
+}
+
+#[test]
+fn zone_offsets_follow_transitions_and_rule () {
+
+	let zone = Zone::load ("Europe/London").unwrap ();
+
+	assert_eq! (zone.offset_at (time_parse ("2016-07-01 12:00:00").sec), 3600);
+	assert_eq! (zone.offset_at (time_parse ("2016-12-01 12:00:00").sec), 0);
+	assert_eq! (zone.offset_at (time_parse ("2050-07-01 12:00:00").sec), 3600);
+	assert_eq! (zone.offset_at (time_parse ("2050-12-01 12:00:00").sec), 0);
+
+}
+
+#[test]
+fn zone_resolves_gaps_and_overlaps () {
+
+	let zone = Zone::load ("Europe/London").unwrap ();
+
+	// 01:30 does not exist on 27 march, it becomes 02:30 bst
+
+	assert_eq! (
+		zone.from_local (time_parse ("2016-03-27 01:30:00").sec),
+		time_parse ("2016-03-27 01:30:00").sec);
+
+	// 01:30 happens twice on 30 october, the first is 01:30 bst
+
+	assert_eq! (
+		zone.from_local (time_parse ("2016-10-30 01:30:00").sec),
+		time_parse ("2016-10-30 00:30:00").sec);
+
+}
+
+#[test]
+fn zone_rejects_invalid_names () {
+
+	assert! (Zone::load ("../etc/passwd").is_err ());
+	assert! (Zone::load ("Nowhere/Special").is_err ());
+
+}
+
+#[test]
+fn local_schedule_across_clocks_going_back () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-29 22:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.timezone = Some ("Europe/London".to_string ());
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	run_for (& context, & clock, config, &mut state, 6 * 3600);
+
+	assert_eq! (job_invocations (& runner, "job1"), vec! [
+		invocation ("job1-sync", "2016-10-29-23"),
+		invocation ("job1-snapshot", "2016-10-29"),
+		invocation ("job1-send", "2016-10-29"),
+		invocation ("job1-sync", "2016-10-30-00"),
+		invocation ("job1-snapshot", "2016-10-30"),
+		invocation ("job1-send", "2016-10-30 2016-10-29"),
+		invocation ("job1-sync", "2016-10-30-01"),
+		invocation ("job1-sync", "2016-10-30-02"),
+		invocation ("job1-sync", "2016-10-30-03"),
+	]);
+
+	// persisted state stays in utc
+
+	assert_eq! (
+		time_format_pretty (state.jobs [0].snapshots [1].snapshot_time),
+		"2016-10-29 23:00:00");
+
+	assert_eq! (
+		time_format_pretty_opt (Global::read (config, & logger).jobs [0].last_sync),
+		Some ("2016-10-30 03:00:00".to_string ()));
+
+}
+
+#[test]
+fn local_schedule_across_clocks_going_forward () {
+
+	let clock = Rc::new (FakeClock::new ("2016-03-26 23:30:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.jobs [0].timezone = Some ("Europe/London".to_string ());
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	run_for (& context, & clock, config, &mut state, 3 * 3600 + 1800);
+
+	assert_eq! (job_invocations (& runner, "job1"), vec! [
+		invocation ("job1-sync", "2016-03-26-23"),
+		invocation ("job1-snapshot", "2016-03-26"),
+		invocation ("job1-send", "2016-03-26"),
+		invocation ("job1-sync", "2016-03-27-00"),
+		invocation ("job1-snapshot", "2016-03-27"),
+		invocation ("job1-send", "2016-03-27 2016-03-26"),
+		invocation ("job1-sync", "2016-03-27-02"),
+		invocation ("job1-sync", "2016-03-27-03"),
+	]);
+
+}
+
+#[test]
+fn changing_timezone_keeps_existing_state () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 02:15:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+
+	{
+		let mut state = Global::read (& test_config.config, & logger);
+		loop_once (& context, & test_config.config, &mut state);
+		runner.take ();
+	}
+
+	// the utc day's snapshot falls in new york's previous day, and the hour in
+	// kolkata starts at half past, so neither is in the future
+
+	test_config.config.jobs [0].timezone = Some ("America/New_York".to_string ());
+	test_config.config.jobs [1].timezone = Some ("Asia/Kolkata".to_string ());
+	let config = & test_config.config;
+
+	clock.set ("2016-10-22 02:20:00");
+
+	let mut state = Global::read (config, & logger);
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (runner.take (), vec! []);
+
+	// snapshot names are the local day of their utc time, so the parent is now
+	// named for the day it falls in in new york
+
+	run_for (& context, & clock, config, &mut state, 2 * 3600);
+
+	assert_eq! (job_invocations (& runner, "job1"), vec! [
+		invocation ("job1-sync", "2016-10-21-23"),
+		invocation ("job1-sync", "2016-10-22-00"),
+		invocation ("job1-snapshot", "2016-10-22"),
+		invocation ("job1-send", "2016-10-22 2016-10-21"),
+	]);
+
+}
+
+// ---------- logging
+
+#[test]
+fn log_records_carry_structured_fields () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 10:15:00"));
+	let runner = FakeRunner::new ();
+	let (logger, lines) = memory_logger (Level::Info, clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let test_config = test_config ();
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	runner.fail ("job1-snapshot");
+
+	loop_once (& context, config, &mut state);
+
+	let records: Vec <Json> =
+		lines.borrow ().iter ().map (
+			|line| Json::from_str (line).unwrap ()
+		).collect ();
+
+	let snapshot_ended =
+		records.iter ().find (
+			|record|
+			record.find ("job") == Some (& Json::String ("job1".to_string ()))
+			&& record.find ("stage") == Some (& Json::String ("snapshot".to_string ()))
+			&& record.find ("exit_code").is_some ()
+		).unwrap ();
+
+	assert_eq! (snapshot_ended ["level"], Json::String ("error".to_string ()));
+	assert_eq! (snapshot_ended ["exit_code"].as_i64 (), Some (1));
+	assert_eq! (snapshot_ended ["snapshot_time"], Json::String ("2016-10-22T00:00:00Z".to_string ()));
+	assert_eq! (snapshot_ended ["message"], Json::String ("snapshot for job1 ended with status 1".to_string ()));
+	assert! (snapshot_ended ["duration"].is_f64 ());
+
+}
+
+#[test]
+fn log_level_filters_records () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let (logger, lines) = memory_logger (Level::Warning, clock.clone ());
+
+	log! (logger, Level::Info, "hidden");
+	log! (logger, Level::Error, [job = "job1"], "shown {}", 1);
+
+	let lines = lines.borrow ();
+
+	assert_eq! (lines.len (), 1);
+
+	let record = Json::from_str (& lines [0]).unwrap ();
+
+	assert_eq! (record ["message"], Json::String ("shown 1".to_string ()));
+	assert_eq! (record ["job"], Json::String ("job1".to_string ()));
+	assert_eq! (record ["time"], Json::String ("2016-10-22T00:00:00Z".to_string ()));
+
+}
+
+#[test]
+fn syslog_sink_writes_to_socket () {
+
+	let socket_path =
+		env::temp_dir ().join (
+			format! (
+				"backup-daemon-test-{}.syslog",
+				process::id ()));
+
+	let _ = fs::remove_file (& socket_path);
+
+	let socket =
+		UnixDatagram::bind (& socket_path).unwrap ();
+
+	let logger =
+		Logger::new (
+			Level::Info,
+			Box::new (
+				SyslogSink::new (
+					socket_path.to_str ().unwrap (),
+					"backup-daemon",
+				).unwrap ()),
+			Rc::new (FakeClock::new ("2016-10-22 00:00:00")));
+
+	log! (logger, Level::Warning, [job = "job1", exit_code = 2], "sync failed");
+
+	let mut buffer = [0u8; 256];
+	let size = socket.recv (&mut buffer).unwrap ();
+
+	assert_eq! (
+		String::from_utf8_lossy (& buffer [.. size]).into_owned (),
+		format! (
+			"<28>backup-daemon[{}]: sync failed job=job1 exit_code=2",
+			process::id ()));
+
+	fs::remove_file (& socket_path).unwrap ();
+
+}
+
+// ---------- process settings
+
+#[test]
+fn process_runner_applies_job_settings () {
+
+	let test_dir =
+		env::temp_dir ().join (
+			format! (
+				"backup-daemon-test-{}-exec",
+				process::id ()));
+
+	let work_dir =
+		test_dir.join ("work");
+
+	fs::create_dir_all (& work_dir).unwrap ();
+
+	let script_path =
+		test_dir.join ("settings.sh");
+
+	fs::write (
+		& script_path,
+		"#!/bin/sh\npwd\numask\nnice\necho '{\"size\": 42}' > \"$BACKUP_DAEMON_RESULT\"\n",
+	).unwrap ();
+
+	fs::set_permissions (
+		& script_path,
+		fs::Permissions::from_mode (0o755),
+	).unwrap ();
+
+	let mut job_config =
+		test_job_config ("job1");
+
+	job_config.working_directory =
+		Some (work_dir.to_str ().unwrap ().to_string ());
+
+	job_config.umask = Some ("027".to_string ());
+	job_config.nice = Some (5);
+	job_config.ionice_class = Some ("idle".to_string ());
+
+	let log_prefix =
+		test_dir.join ("job1-sync");
+
+	let clock = FakeClock::new ("2016-10-22 10:00:00");
+
+	let script_output =
+		ProcessScriptRunner.run_script (
+			& clock,
+			& job_config,
+			"sync",
+			script_path.to_str ().unwrap (),
+			log_prefix.to_str ().unwrap (),
+			"2016-10-22-10",
+			& [],
+			& [],
+			None).unwrap ();
+
+	assert! (script_output.status.success ());
+
+	assert_eq! (
+		script_output.result,
+		Ok (Some ("{\"size\": 42}\n".to_string ())));
+
+	assert! (! test_dir.join ("job1-sync-2016-10-22-10.result").exists ());
+
+	let output =
+		fs::read_to_string (
+			test_dir.join ("job1-sync-2016-10-22-10.log"),
+		).unwrap ();
+
+	assert_eq! (
+		output,
+		format! (
+			"STDOUT:\n{}\n0027\n5\n\nSTDERR:\n\n",
+			work_dir.display ()));
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+#[test]
+fn result_which_is_not_utf8_is_logged_and_ignored () {
+
+	let test_dir =
+		config_test_dir ("not-utf8");
+
+	let script_path =
+		test_dir.join ("snapshot.sh");
+
+	fs::write (
+		& script_path,
+		"#!/bin/sh\nprintf '{\"size\": \"\\377\"}' > \"$BACKUP_DAEMON_RESULT\"\n",
+	).unwrap ();
+
+	fs::set_permissions (
+		& script_path,
+		fs::Permissions::from_mode (0o755),
+	).unwrap ();
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 10:15:00"));
+	let (logger, lines) = memory_logger (Level::Warning, clock.clone ());
+	let context = Context { clock: & * clock, runner: & ProcessScriptRunner, logger: & logger };
+
+	let mut test_config = test_config ();
+
+	{
+
+		let job_config = &mut test_config.config.jobs [0];
+
+		job_config.sync_script = None;
+		job_config.snapshot_script = Some (script_path.to_str ().unwrap ().to_string ());
+		job_config.snapshot_log = Some (test_dir.join ("job1-snapshot").to_str ().unwrap ().to_string ());
+		job_config.send_script = None;
+
+	}
+
+	test_config.config.jobs.truncate (1);
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (state.jobs [0].snapshots [0].state.to_string (), "snapshotted");
+	assert! (state.jobs [0].snapshots [0].metadata.is_none ());
+	assert! (state.jobs [0].last_failure_stage.is_none ());
+
+	let records: Vec <Json> =
+		lines.borrow ().iter ().map (
+			|line| Json::from_str (line).unwrap ()
+		).collect ();
+
+	assert_eq! (records.len (), 1);
+
+	assert! (
+		records [0] ["message"].as_string ().unwrap ().starts_with (
+			"invalid result from snapshot script for job1: "));
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+#[test]
+fn process_runner_returns_error_when_script_cannot_start () {
+
+	let test_dir =
+		config_test_dir ("unstartable");
+
+	let clock = FakeClock::new ("2016-10-22 10:00:00");
+
+	let error =
+		ProcessScriptRunner.run_script (
+			& clock,
+			& test_job_config ("job1"),
+			"sync",
+			test_dir.join ("missing.sh").to_str ().unwrap (),
+			test_dir.join ("job1-sync").to_str ().unwrap (),
+			"2016-10-22-10",
+			& [],
+			& [],
+			None).err ().unwrap ();
+
+	assert! (
+		error.starts_with (
+			& format! (
+				"error running script {}: ",
+				test_dir.join ("missing.sh").display ())));
+
+	assert! (! test_dir.join ("job1-sync-2016-10-22-10.log").exists ());
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+// dropping to another user needs privileges, so this only runs as root
+
+#[test]
+fn process_runner_sets_home_for_user () {
+
+	if unsafe { libc::geteuid () } != 0 {
+		return;
+	}
+
+	let test_dir =
+		env::temp_dir ().join (
+			format! (
+				"backup-daemon-test-{}-user",
+				process::id ()));
+
+	fs::create_dir_all (& test_dir).unwrap ();
+
+	fs::set_permissions (
+		& test_dir,
+		fs::Permissions::from_mode (0o777),
+	).unwrap ();
+
+	let script_path =
+		test_dir.join ("user.sh");
+
+	fs::write (
+		& script_path,
+		"#!/bin/sh\nid -u\necho \"$HOME $USER $LOGNAME\"\n",
+	).unwrap ();
+
+	fs::set_permissions (
+		& script_path,
+		fs::Permissions::from_mode (0o755),
+	).unwrap ();
+
+	let mut job_config =
+		test_job_config ("job1");
+
+	job_config.user = Some ("nobody".to_string ());
+
+	let log_prefix =
+		test_dir.join ("job1-sync");
+
+	let clock = FakeClock::new ("2016-10-22 10:00:00");
+
+	let script_output =
+		ProcessScriptRunner.run_script (
+			& clock,
+			& job_config,
+			"sync",
+			script_path.to_str ().unwrap (),
+			log_prefix.to_str ().unwrap (),
+			"2016-10-22-10",
+			& [],
+			& [],
+			None).unwrap ();
+
+	assert! (script_output.status.success ());
+
+	let (uid, home) = unsafe {
+		let passwd = libc::getpwnam (b"nobody\0".as_ptr () as * const libc::c_char);
+		(
+			(* passwd).pw_uid,
+			CStr::from_ptr ((* passwd).pw_dir).to_string_lossy ().into_owned (),
+		)
+	};
+
+	let output =
+		fs::read_to_string (
+			test_dir.join ("job1-sync-2016-10-22-10.log"),
+		).unwrap ();
+
+	assert_eq! (
+		output,
+		format! (
+			"STDOUT:\n{}\n{} nobody nobody\n\nSTDERR:\n\n",
+			uid,
+			home));
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+#[test]
+fn process_runner_wraps_script_in_launcher () {
+
+	let test_dir =
+		config_test_dir ("launcher");
+
+	let script_path =
+		test_dir.join ("launched.sh");
+
+	fs::write (
+		& script_path,
+		"#!/bin/sh\necho \"$* $JOB $STAGE\"\necho '{}' > \"$BACKUP_DAEMON_RESULT\"\n",
+	).unwrap ();
+
+	fs::set_permissions (
+		& script_path,
+		fs::Permissions::from_mode (0o755),
+	).unwrap ();
+
+	let launcher_log =
+		test_dir.join ("launcher.log");
+
+	let mut job_config =
+		test_job_config ("job1");
+
+	job_config.launcher = Some (vec! [
+		concat! (env! ("CARGO_MANIFEST_DIR"), "/backup-daemon-launcher.sh").to_string (),
+		"env".to_string (),
+		"${environment}".to_string (),
+		"JOB=${job}".to_string (),
+		"STAGE=${stage}".to_string (),
+		"${command}".to_string (),
+	]);
+
+	let log_prefix =
+		test_dir.join ("job1-send");
+
+	let result_path =
+		env::current_dir ().unwrap ().join (
+			format! ("{}-2016-10-22.result", log_prefix.display ()));
+
+	let clock = FakeClock::new ("2016-10-22 10:00:00");
+
+	let script_output =
+		ProcessScriptRunner.run_script (
+			& clock,
+			& job_config,
+			"send",
+			script_path.to_str ().unwrap (),
+			log_prefix.to_str ().unwrap (),
+			"2016-10-22",
+			& [ "2016-10-21".to_string () ],
+			& [
+				(BANDWIDTH_ENV.to_string (), "1000".to_string ()),
+				("BACKUP_DAEMON_LAUNCHER_LOG".to_string (), launcher_log.to_str ().unwrap ().to_string ()),
+			],
+			None).unwrap ();
+
+	assert! (script_output.status.success ());
+	assert_eq! (script_output.result, Ok (Some ("{}\n".to_string ())));
+
+	// the launcher gets the same environment as the script, and the script
+	// gets it again through the arguments
+
+	assert_eq! (
+		fs::read_to_string (& launcher_log).unwrap (),
+		format! (
+			concat! (
+				"args: env BACKUP_DAEMON_BANDWIDTH=1000 BACKUP_DAEMON_LAUNCHER_LOG={} ",
+				"BACKUP_DAEMON_RESULT={} JOB=job1 STAGE=send {} 2016-10-22 2016-10-21\n",
+				"BACKUP_DAEMON_BANDWIDTH=1000\n",
+				"BACKUP_DAEMON_RESULT={}\n"),
+			launcher_log.display (),
+			result_path.display (),
+			script_path.display (),
+			result_path.display ()));
+
+	assert_eq! (
+		fs::read_to_string (
+			test_dir.join ("job1-send-2016-10-22.log"),
+		).unwrap (),
+		"STDOUT:\n2016-10-22 2016-10-21 job1 send\n\nSTDERR:\n\n");
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+#[test]
+fn process_runner_stops_script_at_deadline () {
+
+	let test_dir =
+		config_test_dir ("deadline");
+
+	let script_path =
+		test_dir.join ("slow.sh");
+
+	fs::write (
+		& script_path,
+		"#!/bin/sh\necho started\nsleep 30\necho finished\n",
+	).unwrap ();
+
+	fs::set_permissions (
+		& script_path,
+		fs::Permissions::from_mode (0o755),
+	).unwrap ();
+
+	let started =
+		::std::time::Instant::now ();
+
+	let clock = FakeClock::new ("2016-10-22 10:00:00");
+
+	let script_output =
+		ProcessScriptRunner.run_script (
+			& clock,
+			& test_job_config ("job1"),
+			"send",
+			script_path.to_str ().unwrap (),
+			test_dir.join ("job1-send").to_str ().unwrap (),
+			"2016-10-22",
+			& [],
+			& [],
+			Some (Timespec::new (clock.now ().sec + 1, 0))).unwrap ();
+
+	assert! (script_output.stopped);
+	assert! (! script_output.status.success ());
+	assert! (started.elapsed () < Duration::from_secs (10));
+
+	assert_eq! (
+		fs::read_to_string (
+			test_dir.join ("job1-send-2016-10-22.log"),
+		).unwrap (),
+		"STDOUT:\nstarted\n\nSTDERR:\n\n");
+
+	// the deadline is on the daemon's clock, which here is simulated and far
+	// behind the real one, so a script which ends well before it runs to the end
+
+	let quick_path =
+		test_dir.join ("quick.sh");
+
+	fs::write (
+		& quick_path,
+		"#!/bin/sh\nsleep 1\necho finished\n",
+	).unwrap ();
+
+	fs::set_permissions (
+		& quick_path,
+		fs::Permissions::from_mode (0o755),
+	).unwrap ();
+
+	let quick_output =
+		ProcessScriptRunner.run_script (
+			& clock,
+			& test_job_config ("job1"),
+			"send",
+			quick_path.to_str ().unwrap (),
+			test_dir.join ("job1-quick").to_str ().unwrap (),
+			"2016-10-22",
+			& [],
+			& [],
+			Some (Timespec::new (clock.now ().sec + 3600, 0))).unwrap ();
+
+	assert! (! quick_output.stopped);
+	assert! (quick_output.status.success ());
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+// ---------- reports
+
+#[test]
+fn reports_are_exported_and_gathered_into_fleet_report () {
+
+	let test_dir =
+		config_test_dir ("report");
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let other_config = test_config ();
+
+	let mut test_config = test_config ();
+	test_config.config.host_name = Some ("host1".to_string ());
+	test_config.config.report_directory = Some (test_dir.to_str ().unwrap ().to_string ());
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	runner.fail ("job1-send");
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (
+		ReportExporter::new ().export (config, & state, clock.now ()),
+		Vec::<String>::new ());
+
+	let report: Report =
+		json::decode (
+			& fs::read_to_string (
+				test_dir.join ("host1.json"),
+			).unwrap (),
+		).unwrap ();
+
+	assert_eq! (report.host, "host1");
+	assert_eq! (report.generated, "2016-10-22 00:00:00");
+	assert_eq! (report.jobs.len (), 2);
+	assert_eq! (report.jobs [0].last_failure, Some ("2016-10-22 00:00:00".to_string ()));
+	assert_eq! (report.jobs [0].last_failure_stage, Some ("send".to_string ()));
+	assert_eq! ((report.jobs [0].unsent, report.jobs [0].snapshots), (1, 1));
+	assert_eq! (report.jobs [1].last_failure, None);
+
+	assert! (format_status (config, & state, clock.now ()).contains (
+		"  last failure: send at 2016-10-22 00:00:00\n"));
+
+	// a host which stopped reporting and a report which cannot be read come first
+
+	let other_state = Global::read (& other_config.config, & logger);
+
+	fs::write (
+		test_dir.join ("host2.json"),
+		json::encode (
+			& build_report (config, & other_state, "host2", time_parse ("2016-10-20 12:00:00")),
+		).unwrap (),
+	).unwrap ();
+
+	fs::write (test_dir.join ("broken.json"), "not json").unwrap ();
+	fs::write (test_dir.join ("notes.txt"), "ignored").unwrap ();
+
+	let fleet_report =
+		format_fleet_report (
+			& read_reports (& test_dir).unwrap (),
+			time_parse ("2016-10-22 01:00:00"));
+
+	assert_eq! (
+		fleet_report.lines ().collect::<Vec <&str>> (),
+		vec! [
+			"HOST         JOB   STATE  LAST SEND            LAST VERIFIED  UNSENT  FLAGS",
+			"broken.json  -     -      -                    -              -       unreadable: \"invalid syntax\" at line 1 column 2",
+			"host2        job1  idle   never                never          0/0     stale send-overdue",
+			"host2        job2  idle   never                never          0/0     stale send-overdue",
+			"host1        job1  idle   2016-10-22 00:00:00  never          1/1     failed:send",
+			"host1        job2  idle   2016-10-22 00:00:00  never          0/1",
+		]);
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+#[test]
+fn report_is_exported_when_changed_or_interval_passes () {
+
+	let test_dir =
+		config_test_dir ("report-interval");
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.host_name = Some ("host1".to_string ());
+	test_config.config.report_directory = Some (test_dir.to_str ().unwrap ().to_string ());
+	test_config.config.report_interval = Some (1800);
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	let mut report_exporter =
+		ReportExporter::new ();
+
+	let generated = || -> String {
+		let report: Report =
+			json::decode (
+				& fs::read_to_string (
+					test_dir.join ("host1.json"),
+				).unwrap (),
+			).unwrap ();
+		report.generated
+	};
+
+	loop_once (& context, config, &mut state);
+
+	report_exporter.export (config, & state, clock.now ());
+
+	assert_eq! (generated (), "2016-10-22 00:00:00");
+
+	assert_eq! (
+		time_format_pretty_opt (report_exporter.next_due (config)),
+		Some ("2016-10-22 00:30:00".to_string ()));
+
+	// nothing has changed, so nothing is written until the interval passes
+
+	clock.set ("2016-10-22 00:20:00");
+	loop_once (& context, config, &mut state);
+	report_exporter.export (config, & state, clock.now ());
+
+	assert_eq! (generated (), "2016-10-22 00:00:00");
+
+	clock.set ("2016-10-22 00:30:00");
+	loop_once (& context, config, &mut state);
+	report_exporter.export (config, & state, clock.now ());
+
+	assert_eq! (generated (), "2016-10-22 00:30:00");
+
+	// a new sync changes the report
+
+	clock.set ("2016-10-22 01:00:00");
+	loop_once (& context, config, &mut state);
+	report_exporter.export (config, & state, clock.now ());
+
+	assert_eq! (generated (), "2016-10-22 01:00:00");
+
+	// the fleet report takes a host as stopped after three of its intervals
+
+	let reports =
+		read_reports (& test_dir).unwrap ();
+
+	assert! (! format_fleet_report (& reports, time_parse ("2016-10-22 02:30:00")).contains ("stale"));
+	assert! (format_fleet_report (& reports, time_parse ("2016-10-22 02:31:00")).contains ("stale"));
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+#[test]
+fn report_is_posted_to_http_endpoint () {
+
+	let listener =
+		TcpListener::bind ("127.0.0.1:0").unwrap ();
+
+	let port =
+		listener.local_addr ().unwrap ().port ();
+
+	let server =
+		thread::spawn (
+			move || {
+
+			let (mut stream, _) =
+				listener.accept ().unwrap ();
+
+			let mut request = vec! [];
+			let mut buffer = [0u8; 4096];
+
+			// the request ends with the body, whose length is in the header
+
+			loop {
+
+				let size = stream.read (&mut buffer).unwrap ();
+				request.extend_from_slice (& buffer [.. size]);
+
+				let text = String::from_utf8_lossy (& request).into_owned ();
+
+				if let Some (header_end) = text.find ("\r\n\r\n") {
+
+					let length: usize =
+						text.lines ().find (
+							|line| line.starts_with ("Content-Length: ")
+						).unwrap () ["Content-Length: ".len () ..].parse ().unwrap ();
+
+					if request.len () >= header_end + 4 + length {
+						break;
+					}
+
+				}
+
+			}
+
+			stream.write_all (b"HTTP/1.0 204 No Content\r\n\r\n").unwrap ();
+
+			String::from_utf8 (request).unwrap ()
+
+		});
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let logger = test_logger (clock.clone ());
+
+	let mut test_config = test_config ();
+	test_config.config.host_name = Some ("host1".to_string ());
+	test_config.config.report_url = Some (format! ("http://127.0.0.1:{}/reports/host1", port));
+
+	let config = & test_config.config;
+	let state = Global::read (config, & logger);
+
+	// the post is made in the background, so the export returns at once
+
+	let mut report_exporter =
+		ReportExporter::new ();
+
+	assert_eq! (
+		report_exporter.export (config, & state, time_parse ("2016-10-22 00:00:00")),
+		Vec::<String>::new ());
+
+	let request =
+		server.join ().unwrap ();
+
+	assert! (request.starts_with (& format! (
+		"POST /reports/host1 HTTP/1.0\r\nHost: 127.0.0.1:{}\r\n",
+		port)));
+
+	let report: Report =
+		json::decode (
+			& request [request.find ("\r\n\r\n").unwrap () + 4 ..],
+		).unwrap ();
+
+	assert_eq! (report.host, "host1");
+
+	assert_eq! (
+		parse_http_url ("https://example.com/").err (),
+		Some ("unsupported url https://example.com/, expected http://".to_string ()));
+
+}
+
+// ---------- config files
+
+fn config_test_dir (
+	name: & str,
+) -> ::std::path::PathBuf {
+
+	let test_dir =
+		env::temp_dir ().join (
+			format! (
+				"backup-daemon-test-{}-{}",
+				process::id (),
+				name));
+
+	let _ = fs::remove_dir_all (& test_dir);
+
+	fs::create_dir_all (
+		test_dir.join ("conf.d"),
+	).unwrap ();
+
+	test_dir
+
+}
+
+#[test]
+fn config_templates_and_includes_are_merged () {
+
+	let test_dir =
+		config_test_dir ("config-merge");
+
+	fs::write (
+		test_dir.join ("backup-daemon.config"),
+		r#"{
+			"state": "backup-daemon.state",
+			"lock": "backup-daemon.lock",
+			"templates": {
+				"default": {
+					"sync_script": "./${name}-sync.sh",
+					"sync_log": "./logs/${name}-sync",
+					"cgroup_io_max": [ "8:0 wbps=${rate}" ],
+					"rate": "1000"
+				}
+			},
+			"include": "conf.d",
+			"jobs": [
+				{ "name": "job1", "template": "default" }
+			]
+		}"#,
+	).unwrap ();
+
+	fs::write (
+		test_dir.join ("conf.d/20-job3.json"),
+		r#"{ "name": "job3", "template": "default", "sync_log": "/var/log/${name}" }"#,
+	).unwrap ();
+
+	fs::write (
+		test_dir.join ("conf.d/10-job2.json"),
+		r#"[ { "name": "job2", "template": "default", "rate": "50" } ]"#,
+	).unwrap ();
+
+	fs::write (
+		test_dir.join ("conf.d/30-job4.json~"),
+		"not json",
+	).unwrap ();
+
+	let config =
+		Config::load (
+			& test_dir.join ("backup-daemon.config"),
+		).unwrap ();
+
+	let jobs: Vec <(& str, Option <& str>, Option <& str>, Option <& str>)> =
+		config.jobs.iter ().map (
+			|job_config| (
+				job_config.name.as_str (),
+				job_config.sync_script.as_ref ().map (|script| script.as_str ()),
+				job_config.sync_log.as_ref ().map (|log| log.as_str ()),
+				job_config.cgroup_io_max.as_ref ().map (|io_max| io_max [0].as_str ()),
+			)
+		).collect ();
+
+	assert_eq! (jobs, vec! [
+		("job1", Some ("./job1-sync.sh"), Some ("./logs/job1-sync"), Some ("8:0 wbps=1000")),
+		("job2", Some ("./job2-sync.sh"), Some ("./logs/job2-sync"), Some ("8:0 wbps=50")),
+		("job3", Some ("./job3-sync.sh"), Some ("/var/log/job3"), Some ("8:0 wbps=1000")),
+	]);
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+#[test]
+fn config_errors_name_the_job_and_source () {
+
+	let test_dir =
+		config_test_dir ("config-errors");
+
+	let config_path =
+		test_dir.join ("backup-daemon.config");
+
+	let job_path =
+		test_dir.join ("conf.d/job.json");
+
+	let load_error = |jobs: & str, included: & str| {
+
+		fs::write (
+			& config_path,
+			format! (
+				r#"{{
+					"state": "backup-daemon.state",
+					"lock": "backup-daemon.lock",
+					"templates": {{ "default": {{ "sync_script": "./${{name}}-sync.sh" }} }},
+					"include": [ "conf.d" ],
+					"jobs": [ {} ]
+				}}"#,
+				jobs),
+		).unwrap ();
+
+		fs::write (
+			& job_path,
+			included,
+		).unwrap ();
+
+		match Config::load (& config_path) {
+			Ok (_) => panic! ("config loaded"),
+			Err (err) => err.replace (& test_dir.display ().to_string (), "DIR"),
+		}
+
+	};
+
+	assert_eq! (
+		load_error (
+			r#"{ "name": "job1", "sync_script": "a", "sync_log": "a" }"#,
+			r#"{ "name": "job1", "sync_script": "b", "sync_log": "b" }"#),
+		"job 2 from DIR/conf.d/job.json: duplicate job name job1, also job 1");
+
+	assert_eq! (
+		load_error (
+			"",
+			r#"{ "name": "job1", "template": "missing" }"#),
+		"job 1 from DIR/conf.d/job.json: unknown template missing");
+
+	assert_eq! (
+		load_error (
+			"",
+			r#"{ "name": "job1", "template": "default", "sync_log": "${job}" }"#),
+		"job 1 from DIR/conf.d/job.json: sync_log: unknown variable ${job}");
+
+	assert_eq! (
+		load_error (
+			"",
+			r#"{ "name": "job1", "template": "default" }"#),
+		"job job1 from DIR/conf.d/job.json: sync_script and sync_log must be given together");
+
+	assert_eq! (
+		load_error (
+			"",
+			r#"{ "name": "job1", "launcher": [ "chroot", "${root}", "${command}" ] }"#),
+		"job job1 from DIR/conf.d/job.json: launcher: unknown variable ${root}");
+
+	assert_eq! (
+		load_error (
+			"",
+			r#"{ "name": "job1", "verify_restore_test": "/zbackup/backups/${name}/${time}", "verify_log": "v" }"#),
+		"job job1 from DIR/conf.d/job.json: verify_restore_test: unknown variable ${name}");
+
+	for & (settings, error) in [
+		(r#""nice": 20"#, "invalid nice: 20"),
+		(r#""ionice_class": "fast""#, "invalid ionice_class: fast"),
+		(r#""ionice_priority": 8"#, "invalid ionice_priority: 8"),
+		(r#""umask": "0800""#, "invalid umask: 0800"),
+		(r#""user": "no-such-user""#, "unknown user: no-such-user"),
+		(r#""group": "no-such-group""#, "unknown group: no-such-group"),
+		(r#""cgroup": "backup/../../escape""#, "invalid cgroup: backup/../../escape"),
+	].iter () {
+
+		assert_eq! (
+			load_error (
+				"",
+				& format! (r#"{{ "name": "job1", {} }}"#, settings)),
+			format! ("job job1 from DIR/conf.d/job.json: {}", error));
+
+	}
+
+	assert_eq! (
+		load_error (
+			r#"{ "sync_script": "a" }"#,
+			"[]"),
+		"job 1 from DIR/backup-daemon.config: missing field name");
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+#[test]
+fn config_formats_load_alike_and_convert () {
+
+	let test_dir =
+		config_test_dir ("config-formats");
+
+	fs::write (
+		test_dir.join ("backup-daemon.json"),
+		r#"{
+			"state": "backup-daemon.state",
+			"lock": "backup-daemon.lock",
+			"templates": { "default": { "sync_script": "./${name}-sync.sh", "sync_log": "./${name}-sync" } },
+			"include": "conf.d",
+			"jobs": [ { "name": "job1", "template": "default", "nice": 5, "umask": "027" } ]
+		}"#,
+	).unwrap ();
+
+	fs::write (
+		test_dir.join ("backup-daemon.toml"),
+		concat! (
+			"# comments are allowed here\n",
+			"state = \"backup-daemon.state\"\n",
+			"lock = \"backup-daemon.lock\"\n",
+			"include = \"conf.d\"\n",
+			"\n",
+			"[templates.default]\n",
+			"sync_script = \"./${name}-sync.sh\"\n",
+			"sync_log = \"./${name}-sync\"\n",
+			"\n",
+			"[[jobs]]\n",
+			"name = \"job1\"\n",
+			"template = \"default\"\n",
+			"nice = 5\n",
+			"umask = \"027\"\n"),
+	).unwrap ();
+
+	fs::write (
+		test_dir.join ("backup-daemon.yaml"),
+		concat! (
+			"# and here\n",
+			"state: backup-daemon.state\n",
+			"lock: backup-daemon.lock\n",
+			"include: conf.d\n",
+			"templates:\n",
+			"  default:\n",
+			"    sync_script: ./${name}-sync.sh\n",
+			"    sync_log: ./${name}-sync\n",
+			"jobs:\n",
+			"  - name: job1\n",
+			"    template: default\n",
+			"    nice: 5\n",
+			"    umask: \"027\"\n"),
+	).unwrap ();
+
+	fs::write (
+		test_dir.join ("conf.d/job2.yml"),
+		"name: job2\ntemplate: default\n",
+	).unwrap ();
+
+	let summary = |config: Config| -> Vec <String> {
+		config.jobs.iter ().map (
+			|job_config|
+			format! (
+				"{} {:?} {:?} {:?} {:?}",
+				job_config.name,
+				job_config.sync_script,
+				job_config.sync_log,
+				job_config.nice,
+				job_config.umask)
+		).collect ()
+	};
+
+	let expected =
+		summary (
+			Config::load (
+				& test_dir.join ("backup-daemon.json"),
+			).unwrap ());
+
+	assert_eq! (expected.len (), 2);
+
+	for name in [ "backup-daemon.toml", "backup-daemon.yaml" ].iter () {
+
+		assert_eq! (
+			summary (Config::load (& test_dir.join (name)).unwrap ()),
+			expected);
+
+	}
+
+	// converted files load the same as the original
+
+	for name in [ "converted.toml", "converted.yaml", "converted.json" ].iter () {
+
+		let converted_path =
+			test_dir.join (name);
+
+		fs::write (
+			& converted_path,
+			format_value (
+				ConfigFormat::from_path (& converted_path),
+				& read_value (& test_dir.join ("backup-daemon.json")).unwrap (),
+			).unwrap (),
+		).unwrap ();
+
+		assert_eq! (
+			summary (Config::load (& converted_path).unwrap ()),
+			expected);
+
+	}
+
+	// and all formats report the same errors
+
+	fs::write (
+		test_dir.join ("conf.d/job2.yml"),
+		"name: job1\n",
+	).unwrap ();
+
+	for name in [ "backup-daemon.json", "backup-daemon.toml", "backup-daemon.yaml" ].iter () {
+
+		assert_eq! (
+			Config::load (
+				& test_dir.join (name),
+			).err ().unwrap ().replace (& test_dir.display ().to_string (), "DIR"),
+			"job 2 from DIR/conf.d/job2.yml: duplicate job name job1, also job 1");
+
+	}
+
+	fs::remove_dir_all (& test_dir).unwrap ();
+
+}
+
+// ---------- blackouts
+
+fn blackout (
+	days: & [& str],
+	start: & str,
+	end: & str,
+	stages: & [& str],
+	must_finish_by: bool,
+) -> WindowConfig {
+
+	WindowConfig {
+		days: if days.is_empty () { None } else {
+			Some (days.iter ().map (|day| day.to_string ()).collect ())
+		},
+		start: start.to_string (),
+		end: end.to_string (),
+		stages: if stages.is_empty () { None } else {
+			Some (stages.iter ().map (|stage| stage.to_string ()).collect ())
+		},
+		must_finish_by: Some (must_finish_by),
+	}
+
+}
+
+#[test]
+fn blackout_windows_wrap_midnight_and_chain () {
+
+	let windows: Vec <Window> = vec! [
+		blackout (& ["fri"], "22:00", "02:00", & [], false),
+		blackout (& ["sat"], "02:00", "04:00", & ["send"], true),
+	].iter ().map (|window_config| Window::parse (window_config).unwrap ()).collect ();
+
+	let zone = Zone::utc ();
+
+	let end = |stage: & str, now: & str|
+		blackout_end (& windows, & zone, stage, time_parse (now)).map (time_format_pretty);
+
+	assert_eq! (end ("send", "2016-10-21 23:00:00"), Some ("2016-10-22 04:00:00".to_string ()));
+	assert_eq! (end ("sync", "2016-10-21 23:00:00"), Some ("2016-10-22 02:00:00".to_string ()));
+	assert_eq! (end ("sync", "2016-10-22 01:00:00"), Some ("2016-10-22 02:00:00".to_string ()));
+	assert_eq! (end ("sync", "2016-10-22 23:00:00"), None);
+	assert_eq! (end ("send", "2016-10-21 21:59:00"), None);
+
+	assert_eq! (
+		next_deadline (& windows, & zone, "send", time_parse ("2016-10-20 12:00:00"))
+			.map (time_format_pretty),
+		Some ("2016-10-22 02:00:00".to_string ()));
+
+	assert_eq! (
+		next_deadline (& windows, & zone, "sync", time_parse ("2016-10-20 12:00:00")),
+		None);
+
+	assert_eq! (
+		Window::parse (& blackout (& ["mon"], "9:00", "25:00", & [], false)).err (),
+		Some ("invalid time 25:00, expected HH:MM".to_string ()));
+
+	assert_eq! (
+		Window::parse (& blackout (& ["monday"], "9:00", "17:00", & [], false)).err (),
+		Some ("unknown day monday".to_string ()));
+
+}
+
+#[test]
+fn blackouts_defer_stages_until_they_end () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let runner = FakeRunner::new ();
+	let (logger, lines) = memory_logger (Level::Info, clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.jobs [0].blackouts = Some (vec! [
+		blackout (& [], "00:00", "01:30", & ["snapshot"], false),
+	]);
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (job_invocations (& runner, "job1"), vec! [
+		invocation ("job1-sync", "2016-10-22-00"),
+	]);
+
+	assert! (format_status (config, & state, clock.now ()).contains (
+		"  deferred: snapshot until 2016-10-22 01:30:00\n"));
+
+	// the end of the blackout wakes the scheduler, and the send waits behind
+	// the snapshot
+
+	run_for (& context, & clock, config, &mut state, 6300);
+
+	assert_eq! (job_invocations (& runner, "job1"), vec! [
+		invocation ("job1-sync", "2016-10-22-01"),
+		invocation ("job1-snapshot", "2016-10-22"),
+		invocation ("job1-send", "2016-10-22"),
+	]);
+
+	assert! (state.jobs [0].deferred_stage.is_none ());
+
+	let deferrals =
+		lines.borrow ().iter ().filter (
+			|line| line.contains ("snapshot deferred for job1")
+		).count ();
+
+	assert_eq! (deferrals, 1);
+
+}
+
+#[test]
+fn send_running_into_blackout_is_stopped_and_resumed () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-24 00:00:00"));
+	let runner = FakeRunner::new ();
+	let logger = test_logger (clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.jobs [0].blackouts = Some (vec! [
+		blackout (& ["mon", "tue", "wed", "thu", "fri"], "09:00", "17:00", & ["send"], true),
+	]);
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	runner.slow ("job1-send");
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (
+		runner.deadlines.borrow ().iter ().map (
+			|deadline| deadline.map (time_format_pretty)
+		).collect::<Vec <_>> (),
+		vec! [
+			None,
+			None,
+			None,
+			None,
+			Some ("2016-10-24 09:00:00".to_string ()),
+			None,
+		]);
+
+	assert_eq! (state.jobs [0].snapshots [0].state.to_string (), "snapshotted");
+	assert! (state.jobs [0].last_send.is_none ());
+
+	// during the blackout the send is deferred, and it carries on afterwards
+
+	runner.slow.borrow_mut ().clear ();
+	runner.take ();
+
+	clock.set ("2016-10-24 10:00:00");
+
+	run_for (& context, & clock, config, &mut state, 7 * 3600 + 1800);
+
+	assert_eq! (
+		job_invocations (& runner, "job1-send"),
+		vec! [ invocation ("job1-send", "2016-10-24") ]);
+
+	assert_eq! (state.jobs [0].snapshots [0].state.to_string (), "sent");
+
+	assert_eq! (
+		time_format_pretty_opt (state.jobs [0].last_send),
+		Some ("2016-10-24 00:00:00".to_string ()));
+
+}
+
+// ---------- send queue
+
+#[test]
+fn send_queue_follows_priority_and_bandwidth () {
+
+	let clock = Rc::new (FakeClock::new ("2016-10-22 00:00:00"));
+	let runner = FakeRunner::new ();
+	let (logger, lines) = memory_logger (Level::Info, clock.clone ());
+	let context = Context { clock: & * clock, runner: & runner, logger: & logger };
+
+	let mut test_config = test_config ();
+	test_config.config.send_bandwidth = Some (1000);
+	test_config.config.send_burst = Some (3000);
+	test_config.config.jobs [1].send_priority = Some (10);
+
+	let config = & test_config.config;
+	let mut state = Global::read (config, & logger);
+
+	runner.result ("job1-snapshot", r#"{ "size": 4000 }"#);
+	runner.result ("job2-snapshot", r#"{ "size": 4000 }"#);
+
+	// the higher priority job goes first, and overdraws the bucket
+
+	loop_once (& context, config, &mut state);
+
+	assert_eq! (
+		runner.take ().into_iter ().filter (
+			|& (ref script, _)| script.ends_with ("-send")
+		).collect::<Vec <_>> (),
+		vec! [ invocation ("job2-send", "2016-10-22") ]);
+
+	assert_eq! (
+		runner.environments.borrow ().last ().unwrap (),
+		& vec! [ ("BACKUP_DAEMON_BANDWIDTH".to_string (), "1000".to_string ()) ]);
+
+	let status =
+		format_status (config, & state, clock.now ());
+
+	assert! (status.contains ("  deferred: send until 2016-10-22 00:00:01\n"));
+
+	let job1_records = |prefix: & str| {
+		lines.borrow ().iter ().map (
+			|line| Json::from_str (line).unwrap ()
+		).filter (
+			|record|
+			record.find ("job") == Some (& Json::String ("job1".to_string ()))
+			&& record ["message"].as_string ().unwrap ().starts_with (prefix)
+		).collect::<Vec <Json>> ()
+	};
+
+	assert_eq! (job1_records ("sends for").len (), 0);
+	assert_eq! (job1_records ("send started").len (), 0);
+
+	assert! (status.contains (concat! (
+		"send queue:\n",
+		"  1. job1 2016-10-22 00:00:00 priority 0\n",
+		"send bandwidth: 1000 bytes per second\n")));
+
+	// the other job waits until the debt is repaid
+
+	run_for (& context, & clock, config, &mut state, 10);
+
+	assert_eq! (
+		runner.take (),
+		vec! [ invocation ("job1-send", "2016-10-22") ]);
+
+	// the job's sends and its one snapshot's send are told apart
+
+	let job1_sends = job1_records ("sends for");
+	let job1_snapshot_sends = job1_records ("send started");
+
+	assert_eq! (job1_sends.len (), 1);
+	assert_eq! (job1_sends [0] ["queued"], Json::U64 (1));
+	assert! (job1_sends [0].find ("snapshot_time").is_none ());
+
+	assert_eq! (job1_snapshot_sends.len (), 1);
+	assert! (job1_snapshot_sends [0].find ("snapshot_time").is_some ());
+
+	assert_eq! (state.jobs [0].snapshots [0].state.to_string (), "sent");
+	assert! (state.jobs [0].deferred_stage.is_none ());
+
+}
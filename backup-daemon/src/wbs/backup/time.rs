@@ -3,30 +3,242 @@ extern crate time;
 use time::Timespec;
 use time::Tm;
 
+use wbs::backup::zone::*;
+
+pub fn at_local (
+	zone: & Zone,
+	when: Timespec,
+) -> Tm {
+
+	time::at_utc (
+		Timespec::new (
+			zone.to_local (when.sec),
+			0))
+
+}
+
+pub fn from_local (
+	zone: & Zone,
+	local: Tm,
+) -> Timespec {
+
+	Timespec::new (
+		zone.from_local (
+			local.to_timespec ().sec),
+		0)
+
+}
+
 pub fn round_down_hour (
+	zone: & Zone,
 	now: Timespec,
 ) -> Timespec {
 
-	Tm {
+	from_local (zone, Tm {
 		tm_min: 0,
 		tm_sec: 0,
 		tm_nsec: 0,
-		..time::at_utc (now)
-	}.to_timespec ()
+		..at_local (zone, now)
+	})
 
 }
 
 pub fn round_down_day (
+	zone: & Zone,
 	now: Timespec,
 ) -> Timespec {
 
-	Tm {
+	from_local (zone, Tm {
 		tm_hour: 0,
 		tm_min: 0,
 		tm_sec: 0,
 		tm_nsec: 0,
-		..time::at_utc (now)
-	}.to_timespec ()
+		..at_local (zone, now)
+	})
+
+}
+
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+	Daily,
+	Weekly,
+	Monthly,
+}
+
+impl Period {
+
+	pub fn from_string (
+		str: &str,
+	) -> Result <Period, String> {
+
+		match str {
+			"daily" => Ok (Period::Daily),
+			"weekly" => Ok (Period::Weekly),
+			"monthly" => Ok (Period::Monthly),
+			_ => Err (format! ("unknown schedule: {}", str)),
+		}
+
+	}
+
+	// the longest the period can be, in days
+
+	pub fn days (
+		& self,
+	) -> i64 {
+
+		match * self {
+			Period::Daily => 1,
+			Period::Weekly => 7,
+			Period::Monthly => 31,
+		}
+
+	}
+
+}
+
+// periods are whole days, so that they begin on the pass which snapshots and
+// sends; weeks start on monday, months on the first, both at local midnight
+
+pub fn round_down_period (
+	zone: & Zone,
+	period: Period,
+	now: Timespec,
+) -> Timespec {
+
+	match period {
+
+		Period::Daily =>
+			round_down_day (zone, now),
+
+		Period::Weekly => {
+
+			let local =
+				at_local (zone, now);
+
+			let days_since_monday =
+				(local.tm_wday + 6) % 7;
+
+			let day =
+				round_down_day (zone, now);
+
+			round_down_day (
+				zone,
+				Timespec::new (
+					day.sec - days_since_monday as i64 * 86400 + 43200,
+					0))
+
+		},
+
+		Period::Monthly => {
+
+			from_local (zone, Tm {
+				tm_mday: 1,
+				tm_hour: 0,
+				tm_min: 0,
+				tm_sec: 0,
+				tm_nsec: 0,
+				..at_local (zone, now)
+			})
+
+		},
+
+	}
+
+}
+
+pub fn next_period (
+	zone: & Zone,
+	period: Period,
+	now: Timespec,
+) -> Timespec {
+
+	match period {
+
+		Period::Daily =>
+			next_day (zone, now),
+
+		Period::Weekly => {
+
+			let week_start =
+				round_down_period (zone, period, now);
+
+			round_down_day (
+				zone,
+				Timespec::new (
+					week_start.sec + 7 * 86400 + 43200,
+					0))
+
+		},
+
+		Period::Monthly => {
+
+			let local =
+				at_local (zone, now);
+
+			let (year, month) =
+				if local.tm_mon == 11 {
+					(local.tm_year + 1, 0)
+				} else {
+					(local.tm_year, local.tm_mon + 1)
+				};
+
+			from_local (zone, Tm {
+				tm_year: year,
+				tm_mon: month,
+				tm_mday: 1,
+				tm_hour: 0,
+				tm_min: 0,
+				tm_sec: 0,
+				tm_nsec: 0,
+				..local
+			})
+
+		},
+
+	}
+
+}
+
+pub fn next_hour (
+	zone: & Zone,
+	now: Timespec,
+) -> Timespec {
+
+	next_boundary (zone, now, 3600)
+
+}
+
+pub fn next_day (
+	zone: & Zone,
+	now: Timespec,
+) -> Timespec {
+
+	next_boundary (zone, now, 86400)
+
+}
+
+fn next_boundary (
+	zone: & Zone,
+	now: Timespec,
+	period: i64,
+) -> Timespec {
+
+	let local =
+		zone.to_local (now.sec);
+
+	let next_local =
+		local - ((local % period) + period) % period + period;
+
+	let next =
+		zone.from_local (next_local);
+
+	// a boundary can only land before now inside a repeated hour
+
+	if next > now.sec {
+		Timespec::new (next, 0)
+	} else {
+		Timespec::new (now.sec + period, 0)
+	}
 
 }
 
@@ -41,6 +253,17 @@ pub fn time_format_pretty (
 
 }
 
+pub fn time_format_iso (
+	when: Timespec,
+) -> String {
+
+	time::strftime (
+		"%Y-%m-%dT%H:%M:%SZ",
+		& time::at_utc (when),
+	).unwrap ()
+
+}
+
 pub fn time_format_pretty_opt (
 	when_opt: Option <Timespec>,
 ) -> Option<String> {
@@ -53,23 +276,25 @@ pub fn time_format_pretty_opt (
 }
 
 pub fn time_format_day (
+	zone: & Zone,
 	when: Timespec,
 ) -> String {
 
 	time::strftime (
 		"%Y-%m-%d",
-		& time::at_utc (when),
+		& at_local (zone, when),
 	).unwrap ()
 
 }
 
 pub fn time_format_hour (
+	zone: & Zone,
 	when: Timespec,
 ) -> String {
 
 	time::strftime (
 		"%Y-%m-%d-%H",
-		& time::at_utc (when),
+		& at_local (zone, when),
 	).unwrap ()
 
 }
@@ -0,0 +1,271 @@
+extern crate time;
+
+use time::Timespec;
+
+use wbs::backup::config::*;
+use wbs::backup::zone::*;
+
+// ######################################## interface
+
+// A blackout window keeps some or all stages from starting between two local
+// times on the given days. A window whose end is not after its start runs past
+// midnight into the next day, and belongs to the day on which it starts.
+
+pub struct Window {
+	days: [bool; 7],
+	start: i64,
+	end: i64,
+	stages: Option <Vec <String>>,
+	must_finish_by: bool,
+}
+
+pub const STAGES: [& 'static str; 5] =
+	["sync", "snapshot", "send", "export", "verify"];
+
+const DAYS: [& 'static str; 7] =
+	["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// ######################################## implementation
+
+impl Window {
+
+	pub fn parse (
+		window_config: & WindowConfig,
+	) -> Result <Window, String> {
+
+		let days =
+			match window_config.days {
+
+			None => [true; 7],
+
+			Some (ref day_names) => {
+
+				let mut days = [false; 7];
+
+				for day_name in day_names.iter () {
+
+					let day =
+						try! (
+							DAYS.iter ().position (
+								|day| day == & day_name.as_str (),
+							).ok_or_else (
+								|| format! ("unknown day {}", day_name)));
+
+					days [day] = true;
+
+				}
+
+				days
+
+			},
+
+		};
+
+		if let Some (ref stages) = window_config.stages {
+
+			for stage in stages.iter () {
+
+				if ! STAGES.contains (& stage.as_str ()) {
+
+					return Err (
+						format! (
+							"unknown stage {}",
+							stage));
+
+				}
+
+			}
+
+		}
+
+		Ok (Window {
+			days: days,
+			start: try! (parse_time_of_day (& window_config.start)),
+			end: try! (parse_time_of_day (& window_config.end)),
+			stages: window_config.stages.clone (),
+			must_finish_by: window_config.must_finish_by.unwrap_or (false),
+		})
+
+	}
+
+	fn applies_to (
+		& self,
+		stage: &str,
+	) -> bool {
+
+		self.stages.as_ref ().map_or (
+			true,
+			|stages| stages.iter ().any (|window_stage| window_stage == stage))
+
+	}
+
+	// the local time at which the window ends, if it contains the given local
+	// time
+
+	fn end_containing (
+		& self,
+		local: i64,
+	) -> Option <i64> {
+
+		let day =
+			local - local.rem_euclid (86400);
+
+		let time_of_day =
+			local - day;
+
+		if self.start < self.end {
+
+			if self.days [week_day (day)]
+			&& self.start <= time_of_day
+			&& time_of_day < self.end {
+				return Some (day + self.end);
+			}
+
+		} else {
+
+			if self.days [week_day (day)]
+			&& self.start <= time_of_day {
+				return Some (day + 86400 + self.end);
+			}
+
+			if self.days [week_day (day - 86400)]
+			&& time_of_day < self.end {
+				return Some (day + self.end);
+			}
+
+		}
+
+		None
+
+	}
+
+	fn next_start (
+		& self,
+		local: i64,
+	) -> Option <i64> {
+
+		let today =
+			local - local.rem_euclid (86400);
+
+		(0 .. 9).map (
+			|days| today + days * 86400
+		).filter (
+			|& day| self.days [week_day (day)] && day + self.start > local
+		).map (
+			|day| day + self.start
+		).next ()
+
+	}
+
+}
+
+// when the stage is blacked out now, the time at which it is next allowed to
+// start, following on through any windows which adjoin or overlap
+
+pub fn blackout_end (
+	windows: & [Window],
+	zone: & Zone,
+	stage: &str,
+	now: Timespec,
+) -> Option <Timespec> {
+
+	let mut local =
+		zone.to_local (now.sec);
+
+	let mut blacked_out =
+		false;
+
+	// a week of back to back windows is as far as it is worth following
+
+	for _ in 0 .. 16 {
+
+		match windows.iter ().filter (
+			|window| window.applies_to (stage)
+		).filter_map (
+			|window| window.end_containing (local)
+		).max () {
+
+			Some (end) => {
+				blacked_out = true;
+				local = end;
+			},
+
+			None => break,
+
+		}
+
+	}
+
+	if blacked_out {
+		Some (Timespec::new (zone.from_local (local), 0))
+	} else {
+		None
+	}
+
+}
+
+// the start of the next window which a running stage must finish before
+
+pub fn next_deadline (
+	windows: & [Window],
+	zone: & Zone,
+	stage: &str,
+	now: Timespec,
+) -> Option <Timespec> {
+
+	let local =
+		zone.to_local (now.sec);
+
+	windows.iter ().filter (
+		|window| window.must_finish_by && window.applies_to (stage)
+	).filter_map (
+		|window| window.next_start (local)
+	).min ().map (
+		|start| Timespec::new (zone.from_local (start), 0)
+	)
+
+}
+
+fn parse_time_of_day (
+	time_of_day: &str,
+) -> Result <i64, String> {
+
+	let parts: Vec <&str> =
+		time_of_day.split (':').collect ();
+
+	let parsed =
+		if parts.len () == 2 {
+			parts [0].parse::<i64> ().ok ().and_then (
+				|hours| parts [1].parse::<i64> ().ok ().map (
+					|minutes| (hours, minutes)))
+		} else {
+			None
+		};
+
+	match parsed {
+
+		Some ((hours, minutes))
+			if (0 .. 24).contains (& hours) && (0 .. 60).contains (& minutes) =>
+				Ok (hours * 3600 + minutes * 60),
+
+		Some ((24, 0)) =>
+			Ok (86400),
+
+		_ => Err (
+			format! (
+				"invalid time {}, expected HH:MM",
+				time_of_day)),
+
+	}
+
+}
+
+// the epoch fell on a thursday
+
+fn week_day (
+	day: i64,
+) -> usize {
+
+	((day / 86400 + 4).rem_euclid (7)) as usize
+
+}
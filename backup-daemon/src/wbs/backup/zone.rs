@@ -0,0 +1,865 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// ######################################## interface
+
+// A time zone loaded from the system's compiled tz database. Boundaries are
+// computed on local wall-clock time, with two rules for the edge cases:
+//
+// * ambiguous wall-clock times, which occur twice when clocks go back, resolve
+//   to their earliest occurrence, so a repeated hour only runs once;
+// * nonexistent wall-clock times, which are skipped when clocks go forward,
+//   are shifted forward by the length of the gap.
+
+pub struct Zone {
+
+	transitions: Vec <Transition>,
+	initial_offset: i64,
+
+	rule: Option <Rule>,
+
+}
+
+struct Transition {
+	time: i64,
+	offset: i64,
+}
+
+// ---------- posix tz rule, used after the last transition
+
+struct Rule {
+	std_offset: i64,
+	dst: Option <DstRule>,
+}
+
+struct DstRule {
+	dst_offset: i64,
+	start: RuleDate,
+	start_time: i64,
+	end: RuleDate,
+	end_time: i64,
+}
+
+enum RuleDate {
+	Julian (i64),
+	ZeroJulian (i64),
+	MonthWeekDay (i64, i64, i64),
+}
+
+// ######################################## implementation
+
+impl Zone {
+
+	pub fn utc (
+	) -> Zone {
+
+		Zone {
+			transitions: vec! [],
+			initial_offset: 0,
+			rule: None,
+		}
+
+	}
+
+	pub fn load (
+		name: & str,
+	) -> Result <Zone, String> {
+
+		if name == "UTC" {
+			return Ok (Zone::utc ());
+		}
+
+		if name.starts_with ("/")
+		|| name.split ('/').any (|part| part == ".." || part.is_empty ()) {
+
+			return Err (
+				format! (
+					"invalid timezone name: {}",
+					name));
+
+		}
+
+		let zoneinfo_path =
+			env::var ("TZDIR").unwrap_or (
+				"/usr/share/zoneinfo".to_string ());
+
+		let zone_path =
+			Path::new (& zoneinfo_path).join (name);
+
+		let mut zone_bytes: Vec <u8> =
+			vec! [];
+
+		try! (
+			File::open (
+				& zone_path,
+			).and_then (
+				|mut file|
+				file.read_to_end (& mut zone_bytes)
+			).map_err (
+				|err|
+				format! (
+					"error reading {}: {}",
+					zone_path.display (),
+					err)
+			));
+
+		Zone::parse (
+			& zone_bytes,
+		).map_err (
+			|err|
+			format! (
+				"error reading {}: {}",
+				zone_path.display (),
+				err)
+		)
+
+	}
+
+	pub fn offset_at (
+		& self,
+		utc: i64,
+	) -> i64 {
+
+		match self.transitions.iter ().rposition (
+			|transition| transition.time <= utc
+		) {
+
+			None =>
+				self.initial_offset,
+
+			Some (index) if index + 1 < self.transitions.len () =>
+				self.transitions [index].offset,
+
+			Some (index) => match self.rule {
+				Some (ref rule) => rule.offset_at (utc),
+				None => self.transitions [index].offset,
+			},
+
+		}
+
+	}
+
+	pub fn to_local (
+		& self,
+		utc: i64,
+	) -> i64 {
+
+		utc + self.offset_at (utc)
+
+	}
+
+	pub fn from_local (
+		& self,
+		local: i64,
+	) -> i64 {
+
+		let offset_before =
+			self.offset_at (local - 86400);
+
+		let offset_after =
+			self.offset_at (local + 86400);
+
+		let mut valid: Vec <i64> =
+			[offset_before, offset_after].iter ().map (
+				|offset| local - offset
+			).filter (
+				|utc| self.to_local (* utc) == local
+			).collect ();
+
+		valid.sort ();
+
+		match valid.first () {
+			Some (utc) => * utc,
+			None => local - offset_before,
+		}
+
+	}
+
+	fn parse (
+		bytes: & [u8],
+	) -> Result <Zone, String> {
+
+		let mut reader =
+			ZoneReader {
+				bytes: bytes,
+				position: 0,
+			};
+
+		let (version, counts) =
+			try! (reader.read_header ());
+
+		// version 2 files repeat the data with 64 bit times
+
+		let (counts, time_size) =
+			if version >= b'2' {
+
+				try! (reader.skip_data (& counts, 4));
+
+				let (_, counts) =
+					try! (reader.read_header ());
+
+				(counts, 8)
+
+			} else {
+
+				(counts, 4)
+
+			};
+
+		let (is_ut_count, is_std_count, leap_count, time_count, type_count, char_count) =
+			counts;
+
+		if type_count == 0 {
+			return Err ("no local time types".to_string ());
+		}
+
+		let mut times: Vec <i64> =
+			vec! [];
+
+		for _ in 0 .. time_count {
+			times.push (try! (reader.read_int (time_size)));
+		}
+
+		let mut type_indexes: Vec <usize> =
+			vec! [];
+
+		for _ in 0 .. time_count {
+			type_indexes.push (try! (reader.read_int (1)) as usize);
+		}
+
+		let mut type_offsets: Vec <i64> =
+			vec! [];
+
+		for _ in 0 .. type_count {
+			type_offsets.push (try! (reader.read_int (4)));
+			try! (reader.skip (2));
+		}
+
+		try! (reader.skip (
+			char_count
+			+ leap_count * (time_size + 4)
+			+ is_std_count
+			+ is_ut_count));
+
+		let mut transitions: Vec <Transition> =
+			vec! [];
+
+		for (time, type_index) in times.iter ().zip (type_indexes.iter ()) {
+
+			if * type_index >= type_count {
+				return Err ("invalid local time type".to_string ());
+			}
+
+			transitions.push (
+				Transition {
+					time: * time,
+					offset: type_offsets [* type_index],
+				});
+
+		}
+
+		let rule =
+			if version >= b'2' {
+				try! (reader.read_footer ())
+			} else {
+				None
+			};
+
+		Ok (Zone {
+			transitions: transitions,
+			initial_offset: type_offsets [0],
+			rule: rule,
+		})
+
+	}
+
+}
+
+// ---------- tzif reader
+
+type ZoneCounts =
+	(usize, usize, usize, usize, usize, usize);
+
+struct ZoneReader <'a> {
+	bytes: & 'a [u8],
+	position: usize,
+}
+
+impl <'a> ZoneReader <'a> {
+
+	fn read_header (
+		&mut self,
+	) -> Result <(u8, ZoneCounts), String> {
+
+		if self.bytes.len () < self.position + 44
+		|| & self.bytes [self.position .. self.position + 4] != b"TZif" {
+			return Err ("not a tzif file".to_string ());
+		}
+
+		let version =
+			self.bytes [self.position + 4];
+
+		try! (self.skip (20));
+
+		let mut counts: Vec <usize> =
+			vec! [];
+
+		for _ in 0 .. 6 {
+			counts.push (try! (self.read_int (4)) as usize);
+		}
+
+		Ok ((version, (
+			counts [0],
+			counts [1],
+			counts [2],
+			counts [3],
+			counts [4],
+			counts [5],
+		)))
+
+	}
+
+	fn skip_data (
+		&mut self,
+		counts: & ZoneCounts,
+		time_size: usize,
+	) -> Result <(), String> {
+
+		let & (is_ut_count, is_std_count, leap_count, time_count, type_count, char_count) =
+			counts;
+
+		self.skip (
+			time_count * (time_size + 1)
+			+ type_count * 6
+			+ char_count
+			+ leap_count * (time_size + 4)
+			+ is_std_count
+			+ is_ut_count)
+
+	}
+
+	fn skip (
+		&mut self,
+		size: usize,
+	) -> Result <(), String> {
+
+		if self.bytes.len () < self.position + size {
+			return Err ("truncated tzif file".to_string ());
+		}
+
+		self.position += size;
+
+		Ok (())
+
+	}
+
+	fn read_int (
+		&mut self,
+		size: usize,
+	) -> Result <i64, String> {
+
+		if self.bytes.len () < self.position + size {
+			return Err ("truncated tzif file".to_string ());
+		}
+
+		let mut value: i64 =
+			if size > 1 && self.bytes [self.position] & 0x80 != 0 {
+				-1
+			} else {
+				0
+			};
+
+		for byte in & self.bytes [self.position .. self.position + size] {
+			value = (value << 8) | (* byte as i64);
+		}
+
+		self.position += size;
+
+		Ok (value)
+
+	}
+
+	fn read_footer (
+		&mut self,
+	) -> Result <Option <Rule>, String> {
+
+		let footer =
+			& self.bytes [self.position ..];
+
+		if footer.len () < 2 || footer [0] != b'\n' {
+			return Ok (None);
+		}
+
+		let end =
+			match footer [1 ..].iter ().position (|byte| * byte == b'\n') {
+				Some (end) => end + 1,
+				None => return Err ("invalid tzif footer".to_string ()),
+			};
+
+		let tz_string =
+			try! (
+				String::from_utf8 (
+					footer [1 .. end].to_vec (),
+				).map_err (
+					|_| "invalid tzif footer".to_string ()
+				));
+
+		if tz_string.is_empty () {
+			return Ok (None);
+		}
+
+		Rule::parse (& tz_string).map (Some)
+
+	}
+
+}
+
+// ---------- posix tz rule
+
+impl Rule {
+
+	fn parse (
+		tz_string: & str,
+	) -> Result <Rule, String> {
+
+		let mut parser =
+			RuleParser {
+				chars: tz_string.chars ().collect (),
+				position: 0,
+			};
+
+		let error =
+			|| format! ("invalid tz rule: {}", tz_string);
+
+		try! (parser.name ().ok_or_else (& error));
+
+		let std_offset =
+			- try! (parser.offset ().ok_or_else (& error));
+
+		if parser.done () {
+
+			return Ok (Rule {
+				std_offset: std_offset,
+				dst: None,
+			});
+
+		}
+
+		try! (parser.name ().ok_or_else (& error));
+
+		let dst_offset =
+			if parser.peek () == Some (',') {
+				std_offset + 3600
+			} else {
+				- try! (parser.offset ().ok_or_else (& error))
+			};
+
+		if ! parser.eat (',') {
+			return Err (error ());
+		}
+
+		let start =
+			try! (parser.date ().ok_or_else (& error));
+
+		let start_time =
+			try! (parser.rule_time ().ok_or_else (& error));
+
+		if ! parser.eat (',') {
+			return Err (error ());
+		}
+
+		let end =
+			try! (parser.date ().ok_or_else (& error));
+
+		let end_time =
+			try! (parser.rule_time ().ok_or_else (& error));
+
+		if ! parser.done () {
+			return Err (error ());
+		}
+
+		Ok (Rule {
+			std_offset: std_offset,
+			dst: Some (DstRule {
+				dst_offset: dst_offset,
+				start: start,
+				start_time: start_time,
+				end: end,
+				end_time: end_time,
+			}),
+		})
+
+	}
+
+	fn offset_at (
+		& self,
+		utc: i64,
+	) -> i64 {
+
+		let dst =
+			match self.dst {
+				Some (ref dst) => dst,
+				None => return self.std_offset,
+			};
+
+		let (year, _, _) =
+			civil_from_days (
+				div_floor (utc + self.std_offset, 86400));
+
+		let start =
+			dst.start.day (year) * 86400
+				+ dst.start_time
+				- self.std_offset;
+
+		let end =
+			dst.end.day (year) * 86400
+				+ dst.end_time
+				- dst.dst_offset;
+
+		let in_dst =
+			if start < end {
+				start <= utc && utc < end
+			} else {
+				utc < end || start <= utc
+			};
+
+		if in_dst {
+			dst.dst_offset
+		} else {
+			self.std_offset
+		}
+
+	}
+
+}
+
+impl RuleDate {
+
+	fn day (
+		& self,
+		year: i64,
+	) -> i64 {
+
+		let year_start =
+			days_from_civil (year, 1, 1);
+
+		match * self {
+
+			RuleDate::Julian (day) => {
+
+				let leap_day =
+					if is_leap_year (year) && day >= 60 { 1 } else { 0 };
+
+				year_start + day - 1 + leap_day
+
+			},
+
+			RuleDate::ZeroJulian (day) =>
+				year_start + day,
+
+			RuleDate::MonthWeekDay (month, week, weekday) => {
+
+				let month_start =
+					days_from_civil (year, month, 1);
+
+				let first_weekday =
+					modulo (month_start + 4, 7);
+
+				let mut day =
+					modulo (weekday - first_weekday, 7)
+						+ (week - 1) * 7;
+
+				while day >= month_length (year, month) {
+					day -= 7;
+				}
+
+				month_start + day
+
+			},
+
+		}
+
+	}
+
+}
+
+struct RuleParser {
+	chars: Vec <char>,
+	position: usize,
+}
+
+impl RuleParser {
+
+	fn peek (
+		& self,
+	) -> Option <char> {
+
+		self.chars.get (self.position).cloned ()
+
+	}
+
+	fn done (
+		& self,
+	) -> bool {
+
+		self.position == self.chars.len ()
+
+	}
+
+	fn eat (
+		&mut self,
+		expected: char,
+	) -> bool {
+
+		if self.peek () == Some (expected) {
+			self.position += 1;
+			true
+		} else {
+			false
+		}
+
+	}
+
+	fn name (
+		&mut self,
+	) -> Option <()> {
+
+		if self.eat ('<') {
+
+			while self.peek ().map_or (false, |char| char != '>') {
+				self.position += 1;
+			}
+
+			if self.eat ('>') { Some (()) } else { None }
+
+		} else {
+
+			let start =
+				self.position;
+
+			while self.peek ().map_or (false, |char| char.is_alphabetic ()) {
+				self.position += 1;
+			}
+
+			if self.position - start >= 3 { Some (()) } else { None }
+
+		}
+
+	}
+
+	fn number (
+		&mut self,
+	) -> Option <i64> {
+
+		let start =
+			self.position;
+
+		while self.peek ().map_or (false, |char| char.is_digit (10)) {
+			self.position += 1;
+		}
+
+		if self.position == start {
+			return None;
+		}
+
+		self.chars [start .. self.position].iter ().cloned ().collect::<String> ()
+			.parse ().ok ()
+
+	}
+
+	fn offset (
+		&mut self,
+	) -> Option <i64> {
+
+		let sign =
+			if self.eat ('-') {
+				-1
+			} else {
+				self.eat ('+');
+				1
+			};
+
+		let mut seconds =
+			match self.number () {
+				Some (hours) => hours * 3600,
+				None => return None,
+			};
+
+		for multiplier in [60, 1].iter () {
+
+			if ! self.eat (':') {
+				break;
+			}
+
+			match self.number () {
+				Some (value) => seconds += value * multiplier,
+				None => return None,
+			}
+
+		}
+
+		Some (sign * seconds)
+
+	}
+
+	fn date (
+		&mut self,
+	) -> Option <RuleDate> {
+
+		if self.eat ('J') {
+
+			self.number ().map (RuleDate::Julian)
+
+		} else if self.eat ('M') {
+
+			let mut fields: Vec <i64> =
+				vec! [];
+
+			for index in 0 .. 3 {
+
+				if index > 0 && ! self.eat ('.') {
+					return None;
+				}
+
+				match self.number () {
+					Some (value) => fields.push (value),
+					None => return None,
+				}
+
+			}
+
+			let (month, week, weekday) =
+				(fields [0], fields [1], fields [2]);
+
+			if month < 1 || month > 12
+			|| week < 1 || week > 5
+			|| weekday > 6 {
+				return None;
+			}
+
+			Some (RuleDate::MonthWeekDay (month, week, weekday))
+
+		} else {
+
+			self.number ().map (RuleDate::ZeroJulian)
+
+		}
+
+	}
+
+	fn rule_time (
+		&mut self,
+	) -> Option <i64> {
+
+		if self.eat ('/') {
+			self.offset ()
+		} else {
+			Some (7200)
+		}
+
+	}
+
+}
+
+// ---------- calendar arithmetic
+
+fn modulo (
+	value: i64,
+	divisor: i64,
+) -> i64 {
+
+	((value % divisor) + divisor) % divisor
+
+}
+
+fn div_floor (
+	value: i64,
+	divisor: i64,
+) -> i64 {
+
+	(value - modulo (value, divisor)) / divisor
+
+}
+
+fn is_leap_year (
+	year: i64,
+) -> bool {
+
+	(year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+
+}
+
+fn month_length (
+	year: i64,
+	month: i64,
+) -> i64 {
+
+	match month {
+		2 => if is_leap_year (year) { 29 } else { 28 },
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
+	}
+
+}
+
+fn days_from_civil (
+	year: i64,
+	month: i64,
+	day: i64,
+) -> i64 {
+
+	let year =
+		if month <= 2 { year - 1 } else { year };
+
+	let era =
+		div_floor (year, 400);
+
+	let year_of_era =
+		year - era * 400;
+
+	let day_of_year =
+		(153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+
+	let day_of_era =
+		year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+	era * 146097 + day_of_era - 719468
+
+}
+
+fn civil_from_days (
+	days: i64,
+) -> (i64, i64, i64) {
+
+	let days =
+		days + 719468;
+
+	let era =
+		div_floor (days, 146097);
+
+	let day_of_era =
+		days - era * 146097;
+
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+
+	let day_of_year =
+		day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+
+	let month_index =
+		(5 * day_of_year + 2) / 153;
+
+	let day =
+		day_of_year - (153 * month_index + 2) / 5 + 1;
+
+	let month =
+		if month_index < 10 { month_index + 3 } else { month_index - 9 };
+
+	let year =
+		year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	(year, month, day)
+
+}
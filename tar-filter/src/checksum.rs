@@ -0,0 +1,84 @@
+// crc32c, the castagnoli polynomial also used by iscsi and ext4, which checks
+// the blocks and tables in a pack
+
+const POLYNOMIAL: u32 = 0x82f63b78;
+
+#[ derive (Clone) ]
+pub struct Crc32c {
+	table: [u32; 256],
+	state: u32,
+}
+
+impl Crc32c {
+
+	pub fn new () -> Crc32c {
+
+		let mut table: [u32; 256] =
+			[0; 256];
+
+		for index in 0 .. 256 {
+
+			let mut value =
+				index as u32;
+
+			for _bit in 0 .. 8 {
+
+				value =
+					if value & 1 != 0 {
+						(value >> 1) ^ POLYNOMIAL
+					} else {
+						value >> 1
+					};
+
+			}
+
+			table [index] =
+				value;
+
+		}
+
+		Crc32c {
+			table: table,
+			state: 0xffffffff,
+		}
+
+	}
+
+	pub fn update (
+		& mut self,
+		bytes: & [u8],
+	) {
+
+		for byte in bytes.iter () {
+
+			self.state =
+				self.table [((self.state ^ * byte as u32) & 0xff) as usize]
+				^ (self.state >> 8);
+
+		}
+
+	}
+
+	pub fn sum (
+		& self,
+	) -> u32 {
+
+		self.state ^ 0xffffffff
+
+	}
+
+}
+
+pub fn crc32c (
+	bytes: & [u8],
+) -> u32 {
+
+	let mut checksum =
+		Crc32c::new ();
+
+	checksum.update (
+		bytes);
+
+	checksum.sum ()
+
+}
@@ -0,0 +1,280 @@
+// Encoding of the WBS pack format. Every number is little-endian, whatever the
+// host, and every line is 16 bytes, padded with nuls.
+//
+//   header       "WBS PACK", "HEADER START", "VERSION n", "TARPACK 0",
+//                "HEADER END"
+//   content      the blocks, in the order they were written, with padding to
+//                the pack's alignment where the packer asked for it
+//   padding      up to the alignment again
+//   table        "BLOCKS START", one reference per block in unpacked order,
+//                "BLOCKS END"
+//   location     offset of "BLOCKS START" (u64), number of references (u64)
+//   checksums    version 1 only: unpacked size (u64), unpacked crc32c (u32),
+//                crc32c of the header, table, location and the two fields
+//                before it (u32)
+//   end          "WBS PACK END"
+//
+// A block reference is its offset (u64) and size (u64), then in version 1 its
+// crc32c (u32) and four reserved zero bytes.
+
+use misc::*;
+
+pub const LINE_SIZE: usize = 16;
+
+pub const PACK_START: & [u8; 16] = b"WBS PACK\0\0\0\0\0\0\0\0";
+pub const HEADER_START: & [u8; 16] = b"HEADER START\0\0\0\0";
+pub const VERSION_0: & [u8; 16] = b"VERSION 0\0\0\0\0\0\0\0";
+pub const VERSION_1: & [u8; 16] = b"VERSION 1\0\0\0\0\0\0\0";
+pub const TARPACK_0: & [u8; 16] = b"TARPACK 0\0\0\0\0\0\0\0";
+pub const HEADER_END: & [u8; 16] = b"HEADER END\0\0\0\0\0\0";
+pub const BLOCKS_START: & [u8; 16] = b"BLOCKS START\0\0\0\0";
+pub const BLOCKS_END: & [u8; 16] = b"BLOCKS END\0\0\0\0\0\0";
+pub const PACK_END: & [u8; 16] = b"WBS PACK END\0\0\0\0";
+
+#[ derive (Clone, Copy, Debug, PartialEq) ]
+pub struct BlockReference {
+
+	pub offset: u64,
+	pub size: u64,
+	pub checksum: Option <u32>,
+
+}
+
+#[ derive (Clone, Copy, Debug, PartialEq) ]
+pub struct BlocksLocation {
+
+	pub offset: u64,
+	pub length: u64,
+
+}
+
+#[ derive (Clone, Copy, Debug, PartialEq) ]
+pub struct Checksums {
+
+	pub output_size: u64,
+	pub output_checksum: u32,
+	pub table_checksum: u32,
+
+}
+
+// the size of the footer after the table, which depends on the version
+
+pub fn trailer_size (
+	version: u64,
+) -> u64 {
+
+	if version >= 1 { 48 } else { 32 }
+
+}
+
+pub fn block_reference_size (
+	version: u64,
+) -> u64 {
+
+	if version >= 1 { 24 } else { 16 }
+
+}
+
+pub fn version_line (
+	version: u64,
+) -> Result <& 'static [u8; 16], TfError> {
+
+	match version {
+
+		0 => Ok (VERSION_0),
+		1 => Ok (VERSION_1),
+
+		_ => Err (TfError {
+			error_message: format! (
+				"Unknown WBS pack file version {}",
+				version),
+		}),
+
+	}
+
+}
+
+pub fn encode_u64 (
+	value: u64,
+	bytes: & mut Vec <u8>,
+) {
+
+	for index in 0 .. 8 {
+		bytes.push ((value >> (index * 8)) as u8);
+	}
+
+}
+
+pub fn encode_u32 (
+	value: u32,
+	bytes: & mut Vec <u8>,
+) {
+
+	for index in 0 .. 4 {
+		bytes.push ((value >> (index * 8)) as u8);
+	}
+
+}
+
+pub fn decode_u64 (
+	bytes: & [u8],
+) -> u64 {
+
+	let mut value: u64 = 0;
+
+	for index in 0 .. 8 {
+		value |= (bytes [index] as u64) << (index * 8);
+	}
+
+	value
+
+}
+
+pub fn decode_u32 (
+	bytes: & [u8],
+) -> u32 {
+
+	let mut value: u32 = 0;
+
+	for index in 0 .. 4 {
+		value |= (bytes [index] as u32) << (index * 8);
+	}
+
+	value
+
+}
+
+pub fn encode_block_reference (
+	block_reference: & BlockReference,
+	version: u64,
+) -> Vec <u8> {
+
+	let mut bytes: Vec <u8> =
+		Vec::with_capacity (
+			block_reference_size (version) as usize);
+
+	encode_u64 (
+		block_reference.offset,
+		& mut bytes);
+
+	encode_u64 (
+		block_reference.size,
+		& mut bytes);
+
+	if version >= 1 {
+
+		encode_u32 (
+			block_reference.checksum.unwrap_or (0),
+			& mut bytes);
+
+		encode_u32 (
+			0,
+			& mut bytes);
+
+	}
+
+	bytes
+
+}
+
+pub fn decode_block_reference (
+	bytes: & [u8],
+	version: u64,
+) -> BlockReference {
+
+	BlockReference {
+
+		offset: decode_u64 (
+			& bytes [0 .. 8]),
+
+		size: decode_u64 (
+			& bytes [8 .. 16]),
+
+		checksum: if version >= 1 {
+			Some (
+				decode_u32 (
+					& bytes [16 .. 20]))
+		} else { None },
+
+	}
+
+}
+
+pub fn encode_blocks_location (
+	blocks_location: & BlocksLocation,
+) -> Vec <u8> {
+
+	let mut bytes: Vec <u8> =
+		Vec::with_capacity (
+			LINE_SIZE);
+
+	encode_u64 (
+		blocks_location.offset,
+		& mut bytes);
+
+	encode_u64 (
+		blocks_location.length,
+		& mut bytes);
+
+	bytes
+
+}
+
+pub fn decode_blocks_location (
+	bytes: & [u8],
+) -> BlocksLocation {
+
+	BlocksLocation {
+
+		offset: decode_u64 (
+			& bytes [0 .. 8]),
+
+		length: decode_u64 (
+			& bytes [8 .. 16]),
+
+	}
+
+}
+
+// the table checksum covers the first twelve bytes, so these are encoded
+// separately from it
+
+pub fn encode_output_checksums (
+	output_size: u64,
+	output_checksum: u32,
+) -> Vec <u8> {
+
+	let mut bytes: Vec <u8> =
+		Vec::with_capacity (
+			12);
+
+	encode_u64 (
+		output_size,
+		& mut bytes);
+
+	encode_u32 (
+		output_checksum,
+		& mut bytes);
+
+	bytes
+
+}
+
+pub fn decode_checksums (
+	bytes: & [u8],
+) -> Checksums {
+
+	Checksums {
+
+		output_size: decode_u64 (
+			& bytes [0 .. 8]),
+
+		output_checksum: decode_u32 (
+			& bytes [8 .. 12]),
+
+		table_checksum: decode_u32 (
+			& bytes [12 .. 16]),
+
+	}
+
+}
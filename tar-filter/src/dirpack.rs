@@ -0,0 +1,824 @@
+use libc;
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::ptr;
+
+use checksum::*;
+use misc::*;
+
+use extract;
+use tar;
+use wbspack;
+
+// file contents are written in blocks of this size, so a large file need not
+// be held in memory
+
+const CHUNK_SIZE: usize = 0x100000;
+
+struct DirPacker <'a, 'b: 'a> {
+	packer: & 'a mut wbspack::Packer <'b>,
+	filter: & 'a extract::Filter,
+	one_file_system: bool,
+	root_device: u64,
+	deferred: Vec <wbspack::Deferred>,
+	hard_links: HashMap <(u64, u64), Vec <u8>>,
+	user_names: HashMap <u32, Vec <u8>>,
+	group_names: HashMap <u32, Vec <u8>>,
+	offset: u64,
+	checksum: Crc32c,
+	problems: Vec <Problem>,
+}
+
+// the size and checksum of the tar the pack unpacks to, which go in the pack's
+// footer
+
+pub struct Packed {
+	pub problems: Vec <Problem>,
+	pub size: u64,
+	pub checksum: u32,
+}
+
+// a file which could not be read, and was left out or, if it changed while
+// being read, padded to the size in its header
+
+pub struct Problem {
+	pub name: Vec <u8>,
+	pub error: TfError,
+}
+
+// packs a directory tree as a gnu tar, as "tar c" would with the directory's
+// own name, without the tar ever being written. entries are visited in order of
+// their names' bytes, so that the same tree always gives the same pack.
+
+pub fn pack (
+	path: & Path,
+	packer: & mut wbspack::Packer,
+	filter: & extract::Filter,
+	one_file_system: bool,
+) -> Result <Packed, TfError> {
+
+	// the directory itself is followed if it is a symlink, but nothing in it is
+
+	let metadata =
+		try! (
+			fs::metadata (
+				path,
+			).map_err (
+				|error| io_error (path, error)));
+
+	if ! metadata.is_dir () {
+
+		return Err (TfError {
+			error_message: format! (
+				"Not a directory: {}",
+				path.display ()),
+		});
+
+	}
+
+	let name =
+		match path.file_name () {
+
+		Some (name) =>
+			name.as_bytes ().to_vec (),
+
+		None =>
+			match try! (
+				fs::canonicalize (
+					path,
+				).map_err (
+					|error| io_error (path, error))
+			).file_name () {
+				Some (name) => name.as_bytes ().to_vec (),
+				None => b".".to_vec (),
+			},
+
+	};
+
+	let mut dir_packer =
+		DirPacker {
+			packer: packer,
+			filter: filter,
+			one_file_system: one_file_system,
+			root_device: metadata.dev (),
+			deferred: vec! (),
+			hard_links: HashMap::new (),
+			user_names: HashMap::new (),
+			group_names: HashMap::new (),
+			offset: 0,
+			checksum: Crc32c::new (),
+			problems: vec! (),
+		};
+
+	try! (
+		dir_packer.pack_directory (
+			path,
+			name,
+			& metadata));
+
+	try! (
+		dir_packer.write_deferred ());
+
+	try! (
+		dir_packer.write_nulls ());
+
+	Ok (Packed {
+		size: dir_packer.offset,
+		checksum: dir_packer.checksum.sum (),
+		problems: dir_packer.problems,
+	})
+
+}
+
+impl <'a, 'b> DirPacker <'a, 'b> {
+
+	fn pack_directory (
+		& mut self,
+		path: & Path,
+		name: Vec <u8>,
+		metadata: & fs::Metadata,
+	) -> Result <(), TfError> {
+
+		let mut directory_name =
+			name.clone ();
+
+		directory_name.push (b'/');
+
+		let header =
+			self.header (
+				directory_name,
+				tar::Type::Directory,
+				metadata);
+
+		try! (
+			self.defer_header (
+				header));
+
+		// like tar --one-file-system, a mount point is kept but not what is
+		// mounted there
+
+		if self.one_file_system && metadata.dev () != self.root_device {
+			return Ok (());
+		}
+
+		let read_dir =
+			match fs::read_dir (path) {
+
+			Ok (read_dir) => read_dir,
+
+			Err (error) => {
+				self.record_problem (& name, io_error (path, error));
+				return Ok (());
+			},
+
+		};
+
+		let mut children: Vec <(Vec <u8>, fs::DirEntry)> =
+			vec! ();
+
+		for dir_entry in read_dir {
+
+			match dir_entry {
+
+				Ok (dir_entry) =>
+					children.push ((
+						dir_entry.file_name ().as_bytes ().to_vec (),
+						dir_entry,
+					)),
+
+				Err (error) =>
+					self.record_problem (& name, io_error (path, error)),
+
+			}
+
+		}
+
+		children.sort_by (
+			|& (ref left, _), & (ref right, _)| left.cmp (right));
+
+		for & (ref child_name, ref dir_entry) in children.iter () {
+
+			let mut full_name =
+				name.clone ();
+
+			full_name.push (b'/');
+			full_name.extend_from_slice (child_name);
+
+			if ! self.filter.matches (& full_name) {
+				continue;
+			}
+
+			try! (
+				self.pack_entry (
+					& dir_entry.path (),
+					full_name));
+
+		}
+
+		Ok (())
+
+	}
+
+	fn pack_entry (
+		& mut self,
+		path: & Path,
+		name: Vec <u8>,
+	) -> Result <(), TfError> {
+
+		let metadata =
+			match fs::symlink_metadata (path) {
+
+			Ok (metadata) => metadata,
+
+			Err (error) => {
+				self.record_problem (& name, io_error (path, error));
+				return Ok (());
+			},
+
+		};
+
+		let file_type =
+			metadata.file_type ();
+
+		if file_type.is_dir () {
+
+			return self.pack_directory (
+				path,
+				name,
+				& metadata);
+
+		}
+
+		// a file with other names which were packed already is stored as a hard
+		// link to the first of them
+
+		let hard_link_key =
+			(metadata.dev (), metadata.ino ());
+
+		if metadata.nlink () > 1 {
+
+			if let Some (link_name) =
+				self.hard_links.get (& hard_link_key).cloned () {
+
+				let mut header =
+					self.header (
+						name,
+						tar::Type::Link,
+						& metadata);
+
+				header.linkname = link_name;
+
+				return self.defer_header (
+					header);
+
+			}
+
+		}
+
+		if file_type.is_file () {
+
+			let file =
+				match fs::File::open (path) {
+
+				Ok (file) => file,
+
+				Err (error) => {
+					self.record_problem (& name, io_error (path, error));
+					return Ok (());
+				},
+
+			};
+
+			let mut header =
+				self.header (
+					name.clone (),
+					tar::Type::Regular,
+					& metadata);
+
+			header.size = metadata.len ();
+
+			try! (
+				self.defer_header (
+					header));
+
+			try! (
+				self.write_file (
+					file,
+					& name,
+					metadata.len ()));
+
+		} else if file_type.is_symlink () {
+
+			let link_target =
+				match fs::read_link (path) {
+
+				Ok (link_target) => link_target,
+
+				Err (error) => {
+					self.record_problem (& name, io_error (path, error));
+					return Ok (());
+				},
+
+			};
+
+			let mut header =
+				self.header (
+					name.clone (),
+					tar::Type::SymbolicLink,
+					& metadata);
+
+			header.linkname =
+				link_target.as_os_str ().as_bytes ().to_vec ();
+
+			try! (
+				self.defer_header (
+					header));
+
+		} else if file_type.is_char_device ()
+			|| file_type.is_block_device () {
+
+			let typeflag =
+				if file_type.is_char_device () {
+					tar::Type::CharacterSpecial
+				} else {
+					tar::Type::BlockSpecial
+				};
+
+			let mut header =
+				self.header (
+					name.clone (),
+					typeflag,
+					& metadata);
+
+			let (dev_major, dev_minor) =
+				split_device (
+					metadata.rdev ());
+
+			header.dev_major = dev_major;
+			header.dev_minor = dev_minor;
+
+			try! (
+				self.defer_header (
+					header));
+
+		} else if file_type.is_fifo () {
+
+			let header =
+				self.header (
+					name.clone (),
+					tar::Type::Fifo,
+					& metadata);
+
+			try! (
+				self.defer_header (
+					header));
+
+		} else {
+
+			// like tar, sockets are left out, since they can't be recreated
+			// from an archive
+
+			return Ok (());
+
+		}
+
+		if metadata.nlink () > 1 {
+
+			self.hard_links.insert (
+				hard_link_key,
+				name);
+
+		}
+
+		Ok (())
+
+	}
+
+	// a header for an entry with a file's ownership, mode and time. the times
+	// before 1970 which gnu tar would write as negative numbers are kept as 0.
+
+	fn header (
+		& mut self,
+		name: Vec <u8>,
+		typeflag: tar::Type,
+		metadata: & fs::Metadata,
+	) -> tar::Header {
+
+		tar::Header {
+			name: name,
+			mode: metadata.mode () & 0o7777,
+			uid: metadata.uid (),
+			gid: metadata.gid (),
+			size: 0,
+			blocks: 0,
+			mtime: if metadata.mtime () > 0 { metadata.mtime () as u64 } else { 0 },
+			cksum: 0,
+			typeflag: typeflag,
+			stored_typeflag: None,
+			linkname: vec! (),
+			uname: self.user_name (metadata.uid ()),
+			gname: self.group_name (metadata.gid ()),
+			dev_major: 0,
+			dev_minor: 0,
+			atime: 0,
+			ctime: 0,
+			offset: 0,
+			sparse: vec! (),
+			is_extended: false,
+			real_size: 0,
+			format: tar::Format::Gnu,
+		}
+
+	}
+
+	// defers a header, after long name entries for a name or link name which
+	// doesn't fit in it, as gnu tar writes them
+
+	fn defer_header (
+		& mut self,
+		mut header: tar::Header,
+	) -> Result <(), TfError> {
+
+		if header.linkname.len () > 100 {
+
+			try! (
+				self.defer_long_name (
+					tar::Type::LongLink,
+					& header.linkname));
+
+			header.linkname.truncate (100);
+
+		}
+
+		if header.name.len () > 100 {
+
+			try! (
+				self.defer_long_name (
+					tar::Type::LongName,
+					& header.name));
+
+			header.name.truncate (100);
+
+		}
+
+		let header_bytes =
+			try! (
+				header.write ());
+
+		self.defer (
+			header_bytes)
+
+	}
+
+	fn defer_long_name (
+		& mut self,
+		typeflag: tar::Type,
+		name: & [u8],
+	) -> Result <(), TfError> {
+
+		let mut content_bytes =
+			name.to_vec ();
+
+		content_bytes.push (0);
+
+		let header =
+			tar::Header {
+				name: b"././@LongLink".to_vec (),
+				mode: 0o644,
+				uid: 0,
+				gid: 0,
+				size: content_bytes.len () as u64,
+				blocks: 0,
+				mtime: 0,
+				cksum: 0,
+				typeflag: typeflag,
+				stored_typeflag: None,
+				linkname: vec! (),
+				uname: b"root".to_vec (),
+				gname: b"root".to_vec (),
+				dev_major: 0,
+				dev_minor: 0,
+				atime: 0,
+				ctime: 0,
+				offset: 0,
+				sparse: vec! (),
+				is_extended: false,
+				real_size: 0,
+				format: tar::Format::Gnu,
+			};
+
+		let header_bytes =
+			try! (
+				header.write ());
+
+		try! (
+			self.defer (
+				header_bytes));
+
+		let padded_size =
+			tar::size_blocks (content_bytes.len () as u64) * 512;
+
+		content_bytes.resize (
+			padded_size as usize,
+			0);
+
+		self.defer (
+			content_bytes)
+
+	}
+
+	// blocks are counted towards the size and checksum of the tar in the order
+	// they appear in it, which is the order they are deferred or written
+
+	fn defer (
+		& mut self,
+		bytes: Vec <u8>,
+	) -> Result <(), TfError> {
+
+		self.offset += bytes.len () as u64;
+		self.checksum.update (& bytes);
+
+		self.deferred.push (
+			try! (
+				self.packer.defer (
+					bytes)));
+
+		Ok (())
+
+	}
+
+	fn write (
+		& mut self,
+		bytes: & [u8],
+	) -> Result <(), TfError> {
+
+		self.offset += bytes.len () as u64;
+		self.checksum.update (bytes);
+
+		self.packer.write (
+			bytes)
+
+	}
+
+	// writes a file's content aligned like packalign, along with its padding to
+	// a whole block. the header has been written already, so a file which
+	// shrinks is padded with zeros, as gnu tar does, and one which grows is cut
+	// short.
+
+	fn write_file (
+		& mut self,
+		mut file: fs::File,
+		name: & [u8],
+		size: u64,
+	) -> Result <(), TfError> {
+
+		if size == 0 {
+			return Ok (());
+		}
+
+		try! (
+			self.packer.align ());
+
+		let mut remaining =
+			tar::size_blocks (size) * 512;
+
+		let mut file_remaining =
+			size;
+
+		let mut chunk_bytes: Vec <u8> =
+			vec! [0; CHUNK_SIZE];
+
+		while remaining > 0 {
+
+			let chunk_size =
+				if remaining < CHUNK_SIZE as u64 {
+					remaining as usize
+				} else {
+					CHUNK_SIZE
+				};
+
+			let mut bytes_read: usize =
+				0;
+
+			while bytes_read < chunk_size && file_remaining > 0 {
+
+				let read_size =
+					if file_remaining < (chunk_size - bytes_read) as u64 {
+						file_remaining as usize
+					} else {
+						chunk_size - bytes_read
+					};
+
+				match file.read (
+					& mut chunk_bytes [bytes_read .. bytes_read + read_size]) {
+
+					Ok (0) => {
+
+						self.record_problem (
+							name,
+							TfError {
+								error_message: format! (
+									"File shrank by {} bytes, padded with zeros",
+									file_remaining),
+							});
+
+						file_remaining = 0;
+
+					},
+
+					Ok (bytes) => {
+						bytes_read += bytes;
+						file_remaining -= bytes as u64;
+					},
+
+					Err (ref error)
+						if error.kind () == io::ErrorKind::Interrupted => (),
+
+					Err (error) => {
+
+						self.record_problem (
+							name,
+							TfError {
+								error_message: format! (
+									"{}, padded with zeros",
+									error),
+							});
+
+						file_remaining = 0;
+
+					},
+
+				}
+
+			}
+
+			for byte in chunk_bytes [bytes_read .. chunk_size].iter_mut () {
+				* byte = 0;
+			}
+
+			try! (
+				self.write (
+					& chunk_bytes [0 .. chunk_size]));
+
+			remaining -= chunk_size as u64;
+
+		}
+
+		Ok (())
+
+	}
+
+	fn write_deferred (
+		& mut self,
+	) -> Result <(), TfError> {
+
+		try! (
+			self.packer.align ());
+
+		for one_deferred in self.deferred.iter () {
+
+			try! (
+				self.packer.write_deferred (
+					one_deferred));
+
+		}
+
+		Ok (())
+
+	}
+
+	// the end of archive marker, which follows the deferred headers
+
+	fn write_nulls (
+		& mut self,
+	) -> Result <(), TfError> {
+
+		let null_bytes: [u8; 512] =
+			[0; 512];
+
+		for _null_count in 0 .. 2 {
+
+			try! (
+				self.write (
+					& null_bytes));
+
+		}
+
+		Ok (())
+
+	}
+
+	fn record_problem (
+		& mut self,
+		name: & [u8],
+		error: TfError,
+	) {
+
+		self.problems.push (
+			Problem {
+				name: name.to_vec (),
+				error: error,
+			});
+
+	}
+
+	// names are looked up once for each id, and are left empty for an id with
+	// no name, so that tar uses the id when extracting
+
+	fn user_name (
+		& mut self,
+		uid: u32,
+	) -> Vec <u8> {
+
+		self.user_names.entry (uid).or_insert_with (|| {
+
+			let mut buffer: Vec <libc::c_char> =
+				vec! [0; 0x4000];
+
+			let mut passwd: libc::passwd =
+				unsafe { mem::zeroed () };
+
+			let mut result: * mut libc::passwd =
+				ptr::null_mut ();
+
+			unsafe {
+
+				libc::getpwuid_r (
+					uid,
+					& mut passwd,
+					buffer.as_mut_ptr (),
+					buffer.len (),
+					& mut result);
+
+				if result.is_null () {
+					vec! ()
+				} else {
+					CStr::from_ptr (passwd.pw_name).to_bytes ().to_vec ()
+				}
+
+			}
+
+		}).clone ()
+
+	}
+
+	fn group_name (
+		& mut self,
+		gid: u32,
+	) -> Vec <u8> {
+
+		self.group_names.entry (gid).or_insert_with (|| {
+
+			let mut buffer: Vec <libc::c_char> =
+				vec! [0; 0x4000];
+
+			let mut group: libc::group =
+				unsafe { mem::zeroed () };
+
+			let mut result: * mut libc::group =
+				ptr::null_mut ();
+
+			unsafe {
+
+				libc::getgrgid_r (
+					gid,
+					& mut group,
+					buffer.as_mut_ptr (),
+					buffer.len (),
+					& mut result);
+
+				if result.is_null () {
+					vec! ()
+				} else {
+					CStr::from_ptr (group.gr_name).to_bytes ().to_vec ()
+				}
+
+			}
+
+		}).clone ()
+
+	}
+
+}
+
+// the inverse of glibc's makedev, as used by extract
+
+fn split_device (
+	device: u64,
+) -> (u32, u32) {
+
+	(
+		(((device >> 8) & 0x00000fff) | ((device >> 32) & 0xfffff000)) as u32,
+		((device & 0x000000ff) | ((device >> 12) & 0xffffff00)) as u32,
+	)
+
+}
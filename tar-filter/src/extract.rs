@@ -0,0 +1,739 @@
+use libc;
+
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs as unix_fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use misc::*;
+
+use tar;
+use tarindex;
+
+// chooses which entries to extract. an entry is included when it or one of the
+// directories above it matches an include pattern, or when there are none, and
+// excluded in the same way, or when any one of its names matches an exclude
+// pattern without a slash, like tar's --exclude.
+
+pub struct Filter {
+	pub includes: Vec <Vec <u8>>,
+	pub excludes: Vec <Vec <u8>>,
+}
+
+// an entry which was refused or could not be extracted; the rest are still
+// extracted
+
+pub struct Problem {
+	pub name: Vec <u8>,
+	pub error: TfError,
+}
+
+impl Filter {
+
+	pub fn matches (
+		& self,
+		name: & [u8],
+	) -> bool {
+
+		let name =
+			trim_name (
+				name);
+
+		(self.includes.is_empty ()
+			|| self.includes.iter ().any (
+				|pattern| matches_path (pattern, name)))
+
+		&& ! self.excludes.iter ().any (
+			|pattern| matches_path (pattern, name)
+				|| (! pattern.contains (& b'/')
+					&& name.split (|byte| * byte == b'/').any (
+						|part| glob_matches (pattern, part))))
+
+	}
+
+}
+
+fn matches_path (
+	pattern: & [u8],
+	name: & [u8],
+) -> bool {
+
+	let pattern =
+		trim_name (
+			pattern);
+
+	glob_matches (pattern, name)
+
+	|| name.iter ().enumerate ().any (
+		|(index, byte)|
+			* byte == b'/'
+			&& glob_matches (pattern, & name [0 .. index]))
+
+}
+
+fn trim_name (
+	name: & [u8],
+) -> & [u8] {
+
+	let mut name =
+		name;
+
+	while name.starts_with (b"./") {
+		name = & name [2 ..];
+	}
+
+	while name.ends_with (b"/") {
+		name = & name [0 .. name.len () - 1];
+	}
+
+	name
+
+}
+
+// matches shell style patterns, with "*", "?" and "[...]", where any of these
+// may match a slash
+
+pub fn glob_matches (
+	pattern: & [u8],
+	text: & [u8],
+) -> bool {
+
+	match pattern.first () {
+
+		None =>
+			text.is_empty (),
+
+		Some (& b'*') =>
+			(0 .. text.len () + 1).any (
+				|skip| glob_matches (& pattern [1 ..], & text [skip ..])),
+
+		Some (& b'?') =>
+			! text.is_empty ()
+				&& glob_matches (& pattern [1 ..], & text [1 ..]),
+
+		Some (& b'[') if pattern.contains (& b']') => {
+
+			let close =
+				1 + pattern [1 ..].iter ().position (
+					|byte| * byte == b']',
+				).unwrap ();
+
+			! text.is_empty ()
+				&& class_matches (& pattern [1 .. close], text [0])
+				&& glob_matches (& pattern [close + 1 ..], & text [1 ..])
+
+		},
+
+		Some (& byte) =>
+			text.first () == Some (& byte)
+				&& glob_matches (& pattern [1 ..], & text [1 ..]),
+
+	}
+
+}
+
+fn class_matches (
+	class: & [u8],
+	byte: u8,
+) -> bool {
+
+	let (negated, class) =
+		if class.starts_with (b"!") || class.starts_with (b"^") {
+			(true, & class [1 ..])
+		} else {
+			(false, class)
+		};
+
+	let mut index = 0;
+	let mut matched = false;
+
+	while index < class.len () {
+
+		if index + 2 < class.len () && class [index + 1] == b'-' {
+
+			if class [index] <= byte && byte <= class [index + 2] {
+				matched = true;
+			}
+
+			index += 3;
+
+		} else {
+
+			if class [index] == byte {
+				matched = true;
+			}
+
+			index += 1;
+
+		}
+
+	}
+
+	matched != negated
+
+}
+
+// extracts the entries which match the filter into a directory. directory
+// modes and times are set last, since extracting into them changes them.
+
+pub fn extract <Input: Read + Seek> (
+	input: & mut Input,
+	index: & tarindex::Index,
+	target: & Path,
+	filter: & Filter,
+) -> Result <Vec <Problem>, TfError> {
+
+	let mut problems: Vec <Problem> =
+		vec! ();
+
+	let mut directories: Vec <& tarindex::Entry> =
+		vec! ();
+
+	let set_owner =
+		unsafe { libc::geteuid () } == 0;
+
+	for entry in index.entries.iter () {
+
+		if ! filter.matches (& entry.header.name) {
+			continue;
+		}
+
+		let result =
+			extract_entry (
+				input,
+				index,
+				entry,
+				target,
+				set_owner);
+
+		match result {
+
+			Ok (true) =>
+				directories.push (entry),
+
+			Ok (false) => (),
+
+			Err (error) =>
+				problems.push (
+					Problem {
+						name: entry.header.name.clone (),
+						error: error,
+					}),
+
+		}
+
+	}
+
+	// a later entry may have put something else in a directory's place, or a
+	// symlink above it, so only what is still a real directory is changed
+
+	for entry in directories.iter ().rev () {
+
+		let relative_path =
+			try! (
+				safe_path (
+					& entry.header.name));
+
+		let path =
+			target.join (
+				& relative_path);
+
+		let result =
+			check_parents (
+				target,
+				& relative_path,
+			).and_then (|()|
+				match fs::symlink_metadata (& path) {
+
+					Ok (ref metadata)
+						if metadata.is_dir ()
+							&& ! metadata.file_type ().is_symlink () =>
+						set_metadata (
+							& path,
+							& entry.header,
+							set_owner),
+
+					_ => Ok (()),
+
+				}
+			);
+
+		if let Err (error) = result {
+
+			problems.push (
+				Problem {
+					name: entry.header.name.clone (),
+					error: error,
+				});
+
+		}
+
+	}
+
+	Ok (problems)
+
+}
+
+// extracts one entry, returning whether it is a directory, whose metadata
+// must be set later
+
+fn extract_entry <Input: Read + Seek> (
+	input: & mut Input,
+	index: & tarindex::Index,
+	entry: & tarindex::Entry,
+	target: & Path,
+	set_owner: bool,
+) -> Result <bool, TfError> {
+
+	let header =
+		& entry.header;
+
+	let relative_path =
+		try! (
+			safe_path (
+				& header.name));
+
+	let path =
+		target.join (
+			& relative_path);
+
+	try! (
+		check_parents (
+			target,
+			& relative_path));
+
+	if let Some (parent) = path.parent () {
+
+		try! (
+			fs::create_dir_all (
+				parent,
+			).map_err (
+				|error| io_error (parent, error)));
+
+	}
+
+	// directories are reused, but anything else in the way is replaced, so
+	// that a symlink there is not followed
+
+	let is_directory =
+		match header.typeflag {
+			tar::Type::Directory | tar::Type::GnuDirectory => true,
+			_ => false,
+		};
+
+	if let Ok (metadata) = fs::symlink_metadata (& path) {
+
+		if metadata.is_dir () {
+
+			if is_directory {
+				return Ok (true);
+			}
+
+			try! (
+				fs::remove_dir (
+					& path,
+				).map_err (
+					|error| io_error (& path, error)));
+
+		} else {
+
+			try! (
+				fs::remove_file (
+					& path,
+				).map_err (
+					|error| io_error (& path, error)));
+
+		}
+
+	}
+
+	match header.typeflag {
+
+		tar::Type::Directory | tar::Type::GnuDirectory => {
+
+			try! (
+				fs::create_dir (
+					& path,
+				).map_err (
+					|error| io_error (& path, error)));
+
+			return Ok (true);
+
+		},
+
+		tar::Type::Regular | tar::Type::GnuSparse => {
+
+			let mut file =
+				try! (
+					fs::File::create (
+						& path,
+					).map_err (
+						|error| io_error (& path, error)));
+
+			try! (
+				tarindex::copy_entry_content (
+					input,
+					index,
+					entry,
+					& mut file));
+
+		},
+
+		tar::Type::Link => {
+
+			let link_target =
+				try! (
+					safe_path (
+						& header.linkname));
+
+			try! (
+				check_parents (
+					target,
+					& link_target));
+
+			try! (
+				fs::hard_link (
+					target.join (& link_target),
+					& path,
+				).map_err (
+					|error| io_error (& path, error)));
+
+			return Ok (false);
+
+		},
+
+		tar::Type::SymbolicLink => {
+
+			try! (
+				unix_fs::symlink (
+					OsStr::from_bytes (& header.linkname),
+					& path,
+				).map_err (
+					|error| io_error (& path, error)));
+
+		},
+
+		tar::Type::CharacterSpecial
+		| tar::Type::BlockSpecial
+		| tar::Type::Fifo => {
+
+			let file_type =
+				match header.typeflag {
+					tar::Type::CharacterSpecial => libc::S_IFCHR,
+					tar::Type::BlockSpecial => libc::S_IFBLK,
+					_ => libc::S_IFIFO,
+				};
+
+			let path_c =
+				try! (
+					c_path (
+						& path));
+
+			if unsafe {
+				libc::mknod (
+					path_c.as_ptr (),
+					file_type | (header.mode & 0o7777) as libc::mode_t,
+					make_device (
+						header.dev_major,
+						header.dev_minor))
+			} != 0 {
+
+				return Err (
+					io_error (
+						& path,
+						io::Error::last_os_error ()));
+
+			}
+
+		},
+
+		_ => {
+
+			return Err (TfError {
+				error_message: format! (
+					"Unsupported entry type: {:?}",
+					header.typeflag),
+			});
+
+		},
+
+	}
+
+	try! (
+		set_metadata (
+			& path,
+			header,
+			set_owner));
+
+	Ok (false)
+
+}
+
+// names must stay inside the target, so absolute names and any with ".." are
+// refused
+
+fn safe_path (
+	name: & [u8],
+) -> Result <PathBuf, TfError> {
+
+	if name.starts_with (b"/") {
+
+		return Err (TfError {
+			error_message: format! (
+				"Refusing absolute path: {}",
+				String::from_utf8_lossy (name)),
+		});
+
+	}
+
+	let mut path =
+		PathBuf::new ();
+
+	for part in name.split (|byte| * byte == b'/') {
+
+		if part == b".." {
+
+			return Err (TfError {
+				error_message: format! (
+					"Refusing path outside target: {}",
+					String::from_utf8_lossy (name)),
+			});
+
+		}
+
+		if part.is_empty () || part == b"." {
+			continue;
+		}
+
+		path.push (
+			OsStr::from_bytes (part));
+
+	}
+
+	Ok (path)
+
+}
+
+// a symlink extracted earlier could lead outside the target, so none may be
+// followed on the way to an entry
+
+fn check_parents (
+	target: & Path,
+	relative_path: & Path,
+) -> Result <(), TfError> {
+
+	let mut path =
+		target.to_path_buf ();
+
+	let parent =
+		match relative_path.parent () {
+			Some (parent) => parent,
+			None => return Ok (()),
+		};
+
+	for component in parent.components () {
+
+		path.push (
+			component);
+
+		if let Ok (metadata) = fs::symlink_metadata (& path) {
+
+			if metadata.file_type ().is_symlink () {
+
+				return Err (TfError {
+					error_message: format! (
+						"Refusing path through symlink: {}",
+						path.display ()),
+				});
+
+			}
+
+		}
+
+	}
+
+	Ok (())
+
+}
+
+// ownership is only set when running as root, from the numeric ids, and before
+// the mode, since changing it clears the setuid and setgid bits
+
+fn set_metadata (
+	path: & Path,
+	header: & tar::Header,
+	set_owner: bool,
+) -> Result <(), TfError> {
+
+	let path_c =
+		try! (
+			c_path (
+				path));
+
+	let is_symlink =
+		match header.typeflag {
+			tar::Type::SymbolicLink => true,
+			_ => false,
+		};
+
+	let is_directory =
+		match header.typeflag {
+			tar::Type::Directory | tar::Type::GnuDirectory => true,
+			_ => false,
+		};
+
+	if set_owner && unsafe {
+		libc::lchown (
+			path_c.as_ptr (),
+			header.uid,
+			header.gid)
+	} != 0 {
+
+		return Err (
+			io_error (
+				path,
+				io::Error::last_os_error ()));
+
+	}
+
+	// a directory's mode is set through a descriptor opened without following
+	// a symlink, since chmod would follow one put in its place
+
+	if is_directory {
+
+		try! (
+			chmod_directory (
+				path,
+				& path_c,
+				header.mode));
+
+	} else if ! is_symlink && unsafe {
+		libc::chmod (
+			path_c.as_ptr (),
+			(header.mode & 0o7777) as libc::mode_t)
+	} != 0 {
+
+		return Err (
+			io_error (
+				path,
+				io::Error::last_os_error ()));
+
+	}
+
+	let times = [
+		libc::timespec {
+			tv_sec: header.mtime as libc::time_t,
+			tv_nsec: 0,
+		},
+		libc::timespec {
+			tv_sec: header.mtime as libc::time_t,
+			tv_nsec: 0,
+		},
+	];
+
+	if unsafe {
+		libc::utimensat (
+			libc::AT_FDCWD,
+			path_c.as_ptr (),
+			times.as_ptr (),
+			libc::AT_SYMLINK_NOFOLLOW)
+	} != 0 {
+
+		return Err (
+			io_error (
+				path,
+				io::Error::last_os_error ()));
+
+	}
+
+	Ok (())
+
+}
+
+fn chmod_directory (
+	path: & Path,
+	path_c: & CString,
+	mode: u32,
+) -> Result <(), TfError> {
+
+	let fd =
+		unsafe {
+			libc::open (
+				path_c.as_ptr (),
+				libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW
+					| libc::O_CLOEXEC)
+		};
+
+	if fd < 0 {
+
+		return Err (
+			io_error (
+				path,
+				io::Error::last_os_error ()));
+
+	}
+
+	let result =
+		unsafe {
+			libc::fchmod (
+				fd,
+				(mode & 0o7777) as libc::mode_t)
+		};
+
+	let error =
+		io::Error::last_os_error ();
+
+	unsafe {
+		libc::close (fd);
+	}
+
+	if result != 0 {
+
+		return Err (
+			io_error (
+				path,
+				error));
+
+	}
+
+	Ok (())
+
+}
+
+// the same encoding of device numbers as glibc's makedev
+
+fn make_device (
+	major: u32,
+	minor: u32,
+) -> libc::dev_t {
+
+	let major = major as u64;
+	let minor = minor as u64;
+
+	(((major & 0xfffff000) << 32)
+		| ((major & 0x00000fff) << 8)
+		| ((minor & 0xffffff00) << 12)
+		| (minor & 0x000000ff)) as libc::dev_t
+
+}
+
+fn c_path (
+	path: & Path,
+) -> Result <CString, TfError> {
+
+	CString::new (
+		path.as_os_str ().as_bytes (),
+	).map_err (
+		|_error| TfError {
+			error_message: format! (
+				"Path contains nul: {}",
+				path.display ()),
+		})
+
+}
@@ -5,6 +5,10 @@ extern crate rustc_serialize;
 use std::env;
 use std::fs::File;
 use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
 use std::process;
 
 use misc::*;
@@ -13,44 +17,158 @@ use zbackup::ZBackup;
 #[ macro_use ]
 mod misc;
 
+mod checksum;
+mod codec;
 mod compress;
+mod dirpack;
+mod extract;
 mod tar;
+mod tarindex;
 mod tarpack;
 mod wbspack;
 mod zbackup;
 
+#[ cfg (test) ]
+mod test;
+
+const BUFFER_SIZE: usize = 0x100000;
+
 fn pack (
 	alignment: u64,
+	lenient: bool,
 ) -> Result <(), TfError> {
 
-	let mut stdin =
+	let stdin =
 		io::stdin ();
 
-	let mut stdout =
+	let stdout =
 		io::stdout ();
 
-	let mut packer =
+	let mut input =
+		BufReader::with_capacity (
+			BUFFER_SIZE,
+			stdin.lock ());
+
+	let mut output =
+		BufWriter::with_capacity (
+			BUFFER_SIZE,
+			stdout.lock ());
+
+	let packed = {
+
+		let mut packer =
+			try! (
+				wbspack::Packer::new (
+					& mut output,
+					0,
+					alignment));
+
 		try! (
-			wbspack::Packer::new (
-				& mut stdout,
-				0,
-				alignment));
+			packer.write_header ());
 
-	try! (
-		packer.write_header ());
+		let packed =
+			try! (
+				tarpack::pack (
+					& mut input,
+					& mut packer,
+					lenient));
 
-	try! (
-		tarpack::pack (
-			& mut stdin,
-			& mut packer));
+		try! (
+			packer.write_footer (
+				packed.size,
+				packed.checksum));
+
+		packed
+
+	};
 
 	try! (
-		packer.write_footer ());
+		output.flush ());
+
+	// summarise anything kept verbatim in lenient mode
+
+	if ! packed.anomalies.is_empty () {
+
+		stderrln! (
+			"Packed with {} anomalies:",
+			packed.anomalies.len ());
+
+		for anomaly in packed.anomalies.iter () {
+
+			stderrln! (
+				"  {}",
+				tarpack::format_anomaly (anomaly));
+
+		}
+
+	}
 
 	Ok (())
 
 }
 
+// packs a directory tree straight from the filesystem, reporting any files
+// which could not be read and returning whether all of them were
+
+fn pack_dir (
+	path: & str,
+	filter: & extract::Filter,
+	one_file_system: bool,
+) -> Result <bool, TfError> {
+
+	let stdout =
+		io::stdout ();
+
+	let mut output =
+		BufWriter::with_capacity (
+			BUFFER_SIZE,
+			stdout.lock ());
+
+	let packed = {
+
+		let mut packer =
+			try! (
+				wbspack::Packer::new (
+					& mut output,
+					0,
+					0x10000));
+
+		try! (
+			packer.write_header ());
+
+		let packed =
+			try! (
+				dirpack::pack (
+					Path::new (path),
+					& mut packer,
+					filter,
+					one_file_system));
+
+		try! (
+			packer.write_footer (
+				packed.size,
+				packed.checksum));
+
+		packed
+
+	};
+
+	try! (
+		output.flush ());
+
+	for problem in packed.problems.iter () {
+
+		stderrln! (
+			"Error packing {}: {}",
+			String::from_utf8_lossy (& problem.name),
+			problem.error);
+
+	}
+
+	Ok (packed.problems.is_empty ())
+
+}
+
 fn unpack (
 	filename: &str,
 ) -> Result <(), TfError> {
@@ -60,13 +178,21 @@ fn unpack (
 			File::open (
 				filename));
 
-	let mut stdout =
+	let stdout =
 		io::stdout ();
 
+	let mut output =
+		BufWriter::with_capacity (
+			BUFFER_SIZE,
+			stdout.lock ());
+
 	try! (
 		wbspack::unpack (
 			& mut input,
-			& mut stdout));
+			& mut output));
+
+	try! (
+		output.flush ());
 
 	Ok (())
 
@@ -98,13 +224,21 @@ fn zunpack (
 			zbackup.open_backup (
 				backup_name));
 
-	let mut stdout =
+	let stdout =
 		io::stdout ();
 
+	let mut output =
+		BufWriter::with_capacity (
+			BUFFER_SIZE,
+			stdout.lock ());
+
 	try! (
 		wbspack::unpack (
 			& mut input,
-			& mut stdout));
+			& mut output));
+
+	try! (
+		output.flush ());
 
 	Ok (())
 
@@ -170,6 +304,338 @@ fn restore_test (
 
 }
 
+// runs an action on a pack, which is either a file or, for a path containing
+// "/backups/", a backup in a zbackup repository, as for zunpack
+
+fn with_pack <Output, Action> (
+	pack_path: & str,
+	action: Action,
+) -> Result <Output, TfError>
+	where Action: FnOnce (& mut wbspack::PackInput) -> Result <Output, TfError> {
+
+	if pack_path.contains ("/backups/") {
+
+		let backup_split: Vec <& str> =
+			pack_path.splitn (
+				2,
+				"/backups/",
+			).collect ();
+
+		let mut zbackup =
+			try! (
+				ZBackup::open (
+					backup_split [0]));
+
+		let mut input =
+			try! (
+				zbackup.open_backup (
+					backup_split [1]));
+
+		action (
+			& mut input)
+
+	} else {
+
+		let mut input =
+			try! (
+				File::open (
+					pack_path));
+
+		action (
+			& mut input)
+
+	}
+
+}
+
+// checks a pack's structure and the tar headers in it, and optionally the
+// checksum of every block, returning whether no problems were found
+
+fn verify (
+	pack_path: & str,
+	check_data: bool,
+) -> Result <bool, TfError> {
+
+	with_pack (
+		pack_path,
+		|input| {
+
+		let footer =
+			try! (
+				wbspack::read_pack (
+					input));
+
+		let mut problems =
+			wbspack::check_blocks (
+				& footer);
+
+		if problems.is_empty () && check_data {
+
+			if let Err (error) =
+				wbspack::copy_blocks (
+					input,
+					& mut io::sink (),
+					& footer) {
+
+				problems.push (
+					error.error_message);
+
+			}
+
+		}
+
+		for problem in problems.iter () {
+
+			println! (
+				"{}",
+				problem);
+
+		}
+
+		// the headers can't be read if the blocks are out of place
+
+		if ! problems.is_empty () {
+
+			println! (
+				"Checked {} blocks in version {} pack: {} problems",
+				footer.block_references.len (),
+				footer.version,
+				problems.len ());
+
+			return Ok (false);
+
+		}
+
+		let index =
+			try! (
+				read_index (
+					input,
+					& footer));
+
+		for damage in index.damage.iter () {
+
+			println! (
+				"Damaged entry at offset {} ({}): {}, {} blocks",
+				damage.offset,
+				String::from_utf8_lossy (& damage.name),
+				damage.error,
+				damage.blocks);
+
+		}
+
+		println! (
+			"Checked {} blocks and {} entries in version {} pack: {} damaged",
+			footer.block_references.len (),
+			index.entries.len (),
+			footer.version,
+			index.damage.len ());
+
+		Ok (index.damage.is_empty ())
+
+	})
+
+}
+
+fn read_index (
+	input: & mut wbspack::PackInput,
+	footer: & wbspack::Footer,
+) -> Result <tarindex::Index, TfError> {
+
+	let mut unpacked =
+		wbspack::Unpacked::new (
+			input,
+			footer);
+
+	let unpacked_size =
+		unpacked.size ();
+
+	tarindex::read_index (
+		& mut unpacked,
+		unpacked_size)
+
+}
+
+// lists the entries in a pack, from the headers at its end
+
+fn list (
+	pack_path: & str,
+) -> Result <(), TfError> {
+
+	with_pack (
+		pack_path,
+		|input| {
+
+		let footer =
+			try! (
+				wbspack::read_pack (
+					input));
+
+		let index =
+			try! (
+				read_index (
+					input,
+					& footer));
+
+		let stdout =
+			io::stdout ();
+
+		let mut output =
+			BufWriter::new (
+				stdout.lock ());
+
+		for entry in index.entries.iter () {
+
+			try! (
+				writeln! (
+					output,
+					"{}",
+					tarindex::format_entry (
+						entry)));
+
+		}
+
+		for damage in index.damage.iter () {
+
+			stderrln! (
+				"Damaged entry at offset {} ({}): {}",
+				damage.offset,
+				String::from_utf8_lossy (& damage.name),
+				damage.error);
+
+		}
+
+		try! (
+			output.flush ());
+
+		Ok (())
+
+	})
+
+}
+
+// writes the content of one file in a pack, reading only its own blocks
+
+fn cat (
+	pack_path: & str,
+	file_path: & str,
+) -> Result <(), TfError> {
+
+	with_pack (
+		pack_path,
+		|input| {
+
+		let footer =
+			try! (
+				wbspack::read_pack (
+					input));
+
+		let index =
+			try! (
+				read_index (
+					input,
+					& footer));
+
+		let entry =
+			try! (
+				tarindex::find_entry (
+					& index,
+					file_path.as_bytes (),
+				).ok_or_else (
+					|| format! (
+						"Not found in pack: {}",
+						file_path)));
+
+		let stdout =
+			io::stdout ();
+
+		let mut output =
+			BufWriter::with_capacity (
+				BUFFER_SIZE,
+				stdout.lock ());
+
+		let mut unpacked =
+			wbspack::Unpacked::new (
+				input,
+				& footer);
+
+		try! (
+			tarindex::copy_entry_content (
+				& mut unpacked,
+				& index,
+				entry,
+				& mut output));
+
+		try! (
+			output.flush ());
+
+		Ok (())
+
+	})
+
+}
+
+// extracts files from a pack into a directory, reporting any which were
+// refused or failed and returning whether all were extracted
+
+fn extract_pack (
+	pack_path: & str,
+	target_path: & str,
+	filter: & extract::Filter,
+) -> Result <bool, TfError> {
+
+	with_pack (
+		pack_path,
+		|input| {
+
+		let footer =
+			try! (
+				wbspack::read_pack (
+					input));
+
+		let index =
+			try! (
+				read_index (
+					input,
+					& footer));
+
+		let mut unpacked =
+			wbspack::Unpacked::new (
+				input,
+				& footer);
+
+		let problems =
+			try! (
+				extract::extract (
+					& mut unpacked,
+					& index,
+					Path::new (target_path),
+					filter));
+
+		for problem in problems.iter () {
+
+			stderrln! (
+				"Error extracting {}: {}",
+				String::from_utf8_lossy (& problem.name),
+				problem.error);
+
+		}
+
+		for damage in index.damage.iter () {
+
+			stderrln! (
+				"Damaged entry at offset {} ({}): {}",
+				damage.offset,
+				String::from_utf8_lossy (& damage.name),
+				damage.error);
+
+		}
+
+		Ok (problems.is_empty () && index.damage.is_empty ())
+
+	})
+
+}
+
 fn main () {
 
 	let arguments: Vec <String> =
@@ -186,15 +652,22 @@ fn main () {
 
 	if arguments [0] == "pack" {
 
-		if arguments.len () != 1 {
+		let lenient =
+			arguments.len () == 2
+			&& arguments [1] == "--lenient";
+
+		if arguments.len () != 1 && ! lenient {
 
 			stderrln! (
 				"Usage error");
 
+			process::exit (1);
+
 		}
 
 		match pack (
 			0x1,
+			lenient,
 		) {
 
 			Ok (()) => {
@@ -217,15 +690,22 @@ fn main () {
 
 	} else if arguments [0] == "packalign" {
 
-		if arguments.len () != 1 {
+		let lenient =
+			arguments.len () == 2
+			&& arguments [1] == "--lenient";
+
+		if arguments.len () != 1 && ! lenient {
 
 			stderrln! (
 				"Usage error");
 
+			process::exit (1);
+
 		}
 
 		match pack (
 			0x10000,
+			lenient,
 		) {
 
 			Ok (()) => {
@@ -246,6 +726,95 @@ fn main () {
 
 		}
 
+	} else if arguments [0] == "pack-dir" {
+
+		if arguments.len () < 2 {
+
+			stderrln! (
+				"Usage error");
+
+			process::exit (1);
+
+		}
+
+		let mut filter =
+			extract::Filter {
+				includes: vec! (),
+				excludes: vec! (),
+			};
+
+		let mut one_file_system =
+			false;
+
+		let mut rest =
+			arguments [2 ..].iter ();
+
+		while let Some (argument) = rest.next () {
+
+			if argument == "--one-file-system" {
+
+				one_file_system = true;
+
+			} else if argument == "--exclude" {
+
+				match rest.next () {
+
+					Some (pattern) =>
+						filter.excludes.push (
+							pattern.as_bytes ().to_vec ()),
+
+					None => {
+
+						stderrln! (
+							"Usage error");
+
+						process::exit (1);
+
+					},
+
+				}
+
+			} else {
+
+				stderrln! (
+					"Usage error");
+
+				process::exit (1);
+
+			}
+
+		}
+
+		match pack_dir (
+			& arguments [1],
+			& filter,
+			one_file_system,
+		) {
+
+			Ok (true) => {
+
+				process::exit (0)
+
+			},
+
+			Ok (false) => {
+
+				process::exit (1)
+
+			},
+
+			Err (error) => {
+
+				stderrln! (
+					"Error: {}",
+					error);
+
+				process::exit (1)
+
+			},
+
+		}
+
 	} else if arguments [0] == "unpack" {
 
 		if arguments.len () != 2 {
@@ -304,6 +873,197 @@ fn main () {
 
 		}
 
+	} else if arguments [0] == "verify" {
+
+		let check_data =
+			arguments.len () == 3
+			&& arguments [2] == "--data";
+
+		if arguments.len () != 2 && ! check_data {
+
+			stderrln! (
+				"Usage error");
+
+			process::exit (1);
+
+		}
+
+		match verify (
+			& arguments [1],
+			check_data,
+		) {
+
+			Ok (true) => {
+
+				process::exit (0)
+
+			},
+
+			Ok (false) => {
+
+				process::exit (1)
+
+			},
+
+			Err (error) => {
+
+				stderrln! (
+					"Error: {}",
+					error);
+
+				process::exit (1)
+
+			},
+
+		}
+
+	} else if arguments [0] == "ls" {
+
+		if arguments.len () != 2 {
+
+			stderrln! (
+				"Usage error");
+
+			process::exit (1);
+
+		}
+
+		match list (
+			& arguments [1],
+		) {
+
+			Ok (()) => {
+
+				process::exit (0)
+
+			},
+
+			Err (error) => {
+
+				stderrln! (
+					"Error: {}",
+					error);
+
+				process::exit (1)
+
+			},
+
+		}
+
+	} else if arguments [0] == "cat" {
+
+		if arguments.len () != 3 {
+
+			stderrln! (
+				"Usage error");
+
+			process::exit (1);
+
+		}
+
+		match cat (
+			& arguments [1],
+			& arguments [2],
+		) {
+
+			Ok (()) => {
+
+				process::exit (0)
+
+			},
+
+			Err (error) => {
+
+				stderrln! (
+					"Error: {}",
+					error);
+
+				process::exit (1)
+
+			},
+
+		}
+
+	} else if arguments [0] == "extract" {
+
+		if arguments.len () < 3 {
+
+			stderrln! (
+				"Usage error");
+
+			process::exit (1);
+
+		}
+
+		let mut filter =
+			extract::Filter {
+				includes: vec! (),
+				excludes: vec! (),
+			};
+
+		let mut rest =
+			arguments [3 ..].iter ();
+
+		while let Some (argument) = rest.next () {
+
+			if argument == "--exclude" {
+
+				match rest.next () {
+
+					Some (pattern) =>
+						filter.excludes.push (
+							pattern.as_bytes ().to_vec ()),
+
+					None => {
+
+						stderrln! (
+							"Usage error");
+
+						process::exit (1);
+
+					},
+
+				}
+
+			} else {
+
+				filter.includes.push (
+					argument.as_bytes ().to_vec ());
+
+			}
+
+		}
+
+		match extract_pack (
+			& arguments [1],
+			& arguments [2],
+			& filter,
+		) {
+
+			Ok (true) => {
+
+				process::exit (0)
+
+			},
+
+			Ok (false) => {
+
+				process::exit (1)
+
+			},
+
+			Err (error) => {
+
+				stderrln! (
+					"Error: {}",
+					error);
+
+				process::exit (1)
+
+			},
+
+		}
+
 	} else if arguments [0] == "restore" {
 
 		if arguments.len () != 2 {
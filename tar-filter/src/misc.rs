@@ -2,6 +2,7 @@ use std::error::Error;
 use std::fmt;
 use std::io;
 use std::num::ParseIntError;
+use std::path::Path;
 use std::string::FromUtf8Error;
 
 use protobuf;
@@ -88,3 +89,20 @@ impl From <ParseIntError> for TfError {
 	}
 
 }
+
+// an io error along with the path it happened to, since the error alone doesn't
+// say which file it was
+
+pub fn io_error (
+	path: & Path,
+	error: io::Error,
+) -> TfError {
+
+	TfError {
+		error_message: format! (
+			"{}: {}",
+			path.display (),
+			error),
+	}
+
+}
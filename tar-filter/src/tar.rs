@@ -1,3 +1,4 @@
+use std::cmp;
 use std::mem;
 
 use misc::*;
@@ -51,6 +52,7 @@ pub struct Header {
 	pub mtime: u64,
 	pub cksum: u32,
 	pub typeflag: Type,
+	pub stored_typeflag: Option <u8>,
 	pub linkname: Vec <u8>,
 	pub uname: Vec <u8>,
 	pub gname: Vec <u8>,
@@ -60,9 +62,47 @@ pub struct Header {
 	pub ctime: u64,
 	pub offset: u64,
 
+	pub sparse: Vec <SparseEntry>,
+	pub is_extended: bool,
+	pub real_size: u64,
+
+	pub format: Format,
+
+}
+
+// a gnu sparse file stores only the sections of data listed in its map, one
+// after another, with the rest of the map in extension blocks after the header
+// when it does not fit
+
+#[ derive (Clone, Copy, Debug) ]
+pub struct SparseEntry {
+
+	pub offset: u64,
+	pub size: u64,
+
+}
+
+#[ repr (C) ]
+struct BinarySparseExtension {
+
+	sparse: [BinarySparseHeader; 21],
+	isextended: [u8; 1],
+	pad: [u8; 7],
+
+}
+
+// gnu headers keep access and change times where posix ustar headers keep a
+// prefix for long names; the two are told apart by their magic
+
+#[ derive (Clone, Copy, Debug, PartialEq) ]
+pub enum Format {
+
+	Gnu,
+	Ustar,
+
 }
 
-#[ derive (Debug) ]
+#[ derive (Clone, Copy, Debug, PartialEq) ]
 pub enum Type {
 
 	Regular,
@@ -76,8 +116,21 @@ pub enum Type {
 	LongName,
 	LongLink,
 
+	PaxExtended,
+	PaxGlobal,
+
+	GnuSparse,
+	GnuDirectory,
+	GnuMultiVolume,
+
 }
 
+const CKSUM_START: usize = 148;
+const CKSUM_END: usize = 156;
+
+const PREFIX_START: usize = 345;
+const PREFIX_END: usize = 500;
+
 impl Header {
 
 	pub fn read (
@@ -85,7 +138,13 @@ impl Header {
 	) -> Result <Header, TfError> {
 
 		if header_bytes.len () != 512 {
-			panic! ();
+
+			return Err (TfError {
+				error_message: format! (
+					"Header is {} bytes, not 512",
+					header_bytes.len ()),
+			});
+
 		}
 
 		let binary_header =
@@ -94,112 +153,533 @@ impl Header {
 					& header_bytes [0])
 			};
 
-		if binary_header.magic != * b"ustar " {
+		let format =
+			if binary_header.magic == * b"ustar " {
+
+			if binary_header.version != * b" \0" {
+
+				return Err (TfError {
+					error_message: format! (
+						"Unrecognised gnu tar version: {:?}",
+						binary_header.version),
+				});
+
+			}
+
+			Format::Gnu
+
+		} else if binary_header.magic == * b"ustar\0" {
+
+			if binary_header.version != * b"00" {
 
-			Err (TfError {
+				return Err (TfError {
+					error_message: format! (
+						"Unrecognised ustar version: {:?}",
+						binary_header.version),
+				});
+
+			}
+
+			Format::Ustar
+
+		} else if binary_header.magic == [0; 6]
+			&& binary_header.typeflag == * b"M" {
+
+			// gnu tar leaves the magic out of the header which continues a
+			// file at the start of a new volume
+
+			Format::Gnu
+
+		} else {
+
+			return Err (TfError {
 				error_message: format! (
 					"Unrecognised tar format: {:?} {:?}",
 					binary_header.magic,
 					binary_header.version),
-			})
+			});
 
-		} else if binary_header.version != * b" \0" {
+		};
 
-			Err (TfError {
-				error_message: format! (
-					"Unrecognised gnu tar version: {:?}",
-					binary_header.version),
-			})
+		let size =
+			try! (
+				tar_number_u64 (
+					& binary_header.size));
 
-	 	} else {
+		let gnu =
+			format == Format::Gnu;
 
-			let size =
-				try! (
-					tar_number_u64 (
-						& binary_header.size));
+		Ok (Header {
 
-			Ok (Header {
+			name: if gnu {
+				tar_string (
+					& binary_header.name)
+			} else {
+				ustar_name (
+					& binary_header.name,
+					& header_bytes [PREFIX_START .. PREFIX_END])
+			},
 
-				name: tar_string (
-					& binary_header.name),
+			mode: try! (
+				tar_number_u32 (
+					& binary_header.mode)),
 
-				mode: try! (
-					tar_number_u32 (
-						& binary_header.mode)),
+			uid: try! (
+				tar_number_u32 (
+					& binary_header.uid)),
 
-				uid: try! (
-					tar_number_u32 (
-						& binary_header.uid)),
+			gid: try! (
+				tar_number_u32 (
+					& binary_header.gid)),
 
-				gid: try! (
-					tar_number_u32 (
-						& binary_header.gid)),
+			size:
+				size,
 
-				size:
-					size,
+			blocks: size_blocks (
+				size),
 
-				blocks: 0
-					+ (size >> 9)
-					+ (if (size & 0x1ff) != 0 { 1 } else { 0 }),
+			mtime: try! (
+				tar_number_u64 (
+					& binary_header.mtime)),
 
-				mtime: try! (
-					tar_number_u64 (
-						& binary_header.mtime)),
+			cksum: try! (
+				tar_number_u32 (
+					& binary_header.cksum)),
+
+			typeflag: try! (
+				tar_type (
+					& binary_header.typeflag)),
 
-				cksum: try! (
-					tar_number_u32 (
-						& binary_header.cksum)),
+			stored_typeflag: Some (
+				binary_header.typeflag [0]),
 
-				typeflag: tar_type (
-					& binary_header.typeflag),
+			linkname: tar_string (
+				& binary_header.linkname),
 
-				linkname: tar_string (
-					& binary_header.linkname),
+			uname: tar_string (
+				& binary_header.uname),
 
-				uname: tar_string (
-					& binary_header.uname),
+			gname: tar_string (
+				& binary_header.gname),
 
-				gname: tar_string (
-					& binary_header.gname),
+			dev_major: try! (
+				tar_number_u32 (
+					& binary_header.dev_major)),
 
-				dev_major: try! (
-					tar_number_u32 (
-						& binary_header.dev_major)),
+			dev_minor: try! (
+				tar_number_u32 (
+					& binary_header.dev_minor)),
+
+			atime: if gnu {
+				try! (
+					tar_number_u64 (
+						& binary_header.atime))
+			} else { 0 },
 
-				dev_minor: try! (
-					tar_number_u32 (
-						& binary_header.dev_minor)),
+			ctime: if gnu {
+				try! (
+					tar_number_u64 (
+						& binary_header.ctime))
+			} else { 0 },
 
-				atime: try! (
+			offset: if gnu {
+				try! (
 					tar_number_u64 (
-						& binary_header.atime)),
+						& binary_header.offset))
+			} else { 0 },
 
-				ctime: try! (
+			sparse: if gnu {
+				try! (
+					sparse_entries (
+						& binary_header.sparse))
+			} else { vec! () },
+
+			is_extended: gnu
+				&& binary_header.isextended [0] != 0,
+
+			real_size: if gnu {
+				try! (
 					tar_number_u64 (
-						& binary_header.ctime)),
+						& binary_header.realsize))
+			} else { 0 },
+
+			format: format,
+
+		})
+
+	}
+
+	// writes a gnu header for an entry without a sparse map. names too long for
+	// the header must go in a long name entry before it, so are refused here.
+
+	pub fn write (
+		& self,
+	) -> Result <Vec <u8>, TfError> {
+
+		let mut header_bytes: Vec <u8> =
+			vec! [0; 512];
+
+		{
+
+			let binary_header =
+				unsafe {
+					mem::transmute::<& mut u8, & mut BinaryHeader> (
+						& mut header_bytes [0])
+				};
+
+			try! (
+				write_string (
+					& mut binary_header.name,
+					& self.name));
+
+			write_number (
+				& mut binary_header.mode,
+				self.mode as u64);
+
+			write_number (
+				& mut binary_header.uid,
+				self.uid as u64);
+
+			write_number (
+				& mut binary_header.gid,
+				self.gid as u64);
+
+			write_number (
+				& mut binary_header.size,
+				self.size);
+
+			write_number (
+				& mut binary_header.mtime,
+				self.mtime);
+
+			// a typeflag read as another type's, such as a contiguous file's,
+			// is written back as it was
+
+			binary_header.typeflag = [
+				match self.stored_typeflag {
+
+					Some (stored_typeflag)
+						if tar_type (& [stored_typeflag]).ok ()
+							== Some (self.typeflag) =>
+						stored_typeflag,
+
+					_ => type_byte (
+						& self.typeflag),
+
+				},
+			];
+
+			try! (
+				write_string (
+					& mut binary_header.linkname,
+					& self.linkname));
+
+			binary_header.magic = * b"ustar ";
+			binary_header.version = * b" \0";
+
+			write_truncated_string (
+				& mut binary_header.uname,
+				& self.uname);
+
+			write_truncated_string (
+				& mut binary_header.gname,
+				& self.gname);
+
+			match self.typeflag {
+
+				Type::CharacterSpecial | Type::BlockSpecial => {
+
+					write_number (
+						& mut binary_header.dev_major,
+						self.dev_major as u64);
+
+					write_number (
+						& mut binary_header.dev_minor,
+						self.dev_minor as u64);
+
+				},
+
+				_ => (),
+
+			}
+
+		}
+
+		// the checksum is counted with its own field as spaces, and written as
+		// six digits, a nul and a space
+
+		for byte in header_bytes [CKSUM_START .. CKSUM_END].iter_mut () {
+			* byte = b' ';
+		}
+
+		let checksum: u32 =
+			header_bytes.iter ().map (|byte| * byte as u32).sum ();
+
+		header_bytes [CKSUM_START .. CKSUM_END].copy_from_slice (
+			format! ("{:06o}\0 ", checksum).as_bytes ());
+
+		Ok (header_bytes)
+
+	}
+
+}
+
+// checks a header against its checksum, which is the sum of its bytes with the
+// checksum field itself counted as spaces. gnu tar accepts a sum of either
+// signed or unsigned bytes, since some old tars used the former.
+
+pub fn verify_checksum (
+	header_bytes: & [u8],
+) -> Result <(), TfError> {
+
+	let stored =
+		try! (
+			tar_number_u32 (
+				& header_bytes [CKSUM_START .. CKSUM_END]));
+
+	let mut unsigned: u32 = 0;
+	let mut signed: i32 = 0;
+
+	for (index, byte) in header_bytes.iter ().enumerate () {
+
+		let byte =
+			if index >= CKSUM_START && index < CKSUM_END {
+				b' '
+			} else {
+				* byte
+			};
+
+		unsigned += byte as u32;
+		signed += byte as i8 as i32;
+
+	}
+
+	if stored != unsigned && stored as i32 != signed {
+
+		return Err (TfError {
+			error_message: format! (
+				"Header checksum mismatch: stored {:o}, calculated {:o}",
+				stored,
+				unsigned),
+		});
+
+	}
+
+	Ok (())
+
+}
+
+// the name from a header, for reporting one which can't be read
+
+pub fn header_name (
+	header_bytes: & [u8],
+) -> Vec <u8> {
+
+	tar_string (
+		& header_bytes [0 .. 100])
+
+}
+
+// reads one of the blocks which continue a sparse map, returning its entries
+// and whether another block follows
+
+pub fn read_sparse_extension (
+	extension_bytes: & [u8],
+) -> Result <(Vec <SparseEntry>, bool), TfError> {
+
+	if extension_bytes.len () != 512 {
+
+		return Err (TfError {
+			error_message: format! (
+				"Sparse extension is {} bytes, not 512",
+				extension_bytes.len ()),
+		});
+
+	}
+
+	let binary_extension =
+		unsafe {
+			mem::transmute::<& u8, & BinarySparseExtension> (
+				& extension_bytes [0])
+		};
+
+	Ok ((
+		try! (
+			sparse_entries (
+				& binary_extension.sparse)),
+		binary_extension.isextended [0] != 0,
+	))
+
+}
+
+// the map ends at the first empty entry
+
+fn sparse_entries (
+	binary_entries: & [BinarySparseHeader],
+) -> Result <Vec <SparseEntry>, TfError> {
+
+	let mut entries: Vec <SparseEntry> =
+		vec! ();
+
+	for binary_entry in binary_entries.iter () {
+
+		if binary_entry.offset [0] == 0
+			&& binary_entry.numbytes [0] == 0 {
+
+			break;
+
+		}
+
+		entries.push (
+			SparseEntry {
 
 				offset: try! (
 					tar_number_u64 (
-						& binary_header.offset)),
+						& binary_entry.offset)),
+
+				size: try! (
+					tar_number_u64 (
+						& binary_entry.numbytes)),
+
+			});
+
+	}
+
+	Ok (entries)
+
+}
+
+// the number of 512 byte blocks holding an entry's content
+
+pub fn size_blocks (
+	size: u64,
+) -> u64 {
+
+	0
+		+ (size >> 9)
+		+ (if (size & 0x1ff) != 0 { 1 } else { 0 })
+
+}
+
+// splits pax extended header content into its "length key=value\n" records
+
+pub fn pax_records (
+	content: & [u8],
+) -> Result <Vec <(Vec <u8>, Vec <u8>)>, TfError> {
+
+	let mut records: Vec <(Vec <u8>, Vec <u8>)> =
+		vec! ();
+
+	let mut rest =
+		content;
+
+	// the content is padded to a whole block with nuls
+
+	while ! rest.is_empty () && rest [0] != 0 {
+
+		let space =
+			try! (
+				rest.iter ().position (
+					|byte| * byte == b' ',
+				).ok_or_else (
+					|| format! (
+						"Invalid pax record: missing length")));
+
+		let length =
+			try! (
+				usize::from_str_radix (
+					& try! (
+						String::from_utf8 (
+							rest [0 .. space].to_vec ())),
+					10));
 
-/*
-				longnames: [u8; 4],
-				unused: [u8; 1],
-				sparse: [BinarySparseHeader; 4],
-				isextended: [u8; 1],
-				realsize: [u8; 12],
-				pad: [u8; 17],
-*/
+		if length <= space + 1 || length > rest.len ()
+			|| rest [length - 1] != b'\n' {
 
-			})
+			return Err (TfError {
+				error_message: format! (
+					"Invalid pax record length: {}",
+					length),
+			});
+
+		}
+
+		let record =
+			& rest [space + 1 .. length - 1];
+
+		let equals =
+			try! (
+				record.iter ().position (
+					|byte| * byte == b'=',
+				).ok_or_else (
+					|| format! (
+						"Invalid pax record: missing =")));
+
+		records.push ((
+			record [0 .. equals].to_vec (),
+			record [equals + 1 ..].to_vec (),
+		));
+
+		rest =
+			& rest [length ..];
+
+	}
+
+	Ok (records)
+
+}
+
+// the size a pax extended header gives for the following entry, which takes
+// the place of the one in its header, for files too big for that
+
+pub fn pax_size (
+	content: & [u8],
+) -> Result <Option <u64>, TfError> {
+
+	for (key, value) in try! (pax_records (content)) {
+
+		if key == b"size" {
+
+			return Ok (Some (
+				try! (
+					u64::from_str_radix (
+						& try! (String::from_utf8 (value)),
+						10))));
 
 		}
 
 	}
 
+	Ok (None)
+
 }
 
-fn tar_string (
+fn ustar_name (
+	name: & [u8],
+	prefix: & [u8],
+) -> Vec <u8> {
+
+	let prefix =
+		tar_string (prefix);
+
+	let name =
+		tar_string (name);
+
+	if prefix.is_empty () {
+		return name;
+	}
+
+	let mut full_name =
+		prefix;
+
+	full_name.push (b'/');
+	full_name.extend_from_slice (& name);
+
+	full_name
+
+}
+
+pub fn tar_string (
 	slice: & [u8],
 ) -> Vec <u8> {
 
@@ -227,16 +707,42 @@ fn tar_number_u64 (
 
 	} else if slice [0] == 0x80 {
 
-		Ok (u64::from_be (
-			unsafe {
-				* mem::transmute::<& u8, & u64> (
-					& slice [4])
+		let mut number: u64 = 0;
+
+		for byte in slice [1 ..].iter () {
+
+			if number > u64::max_value () >> 8 {
+
+				return Err (TfError {
+					error_message: format! (
+						"Number too large in field of {} bytes",
+						slice.len ()),
+				});
+
 			}
-		))
+
+			number = number << 8 | * byte as u64;
+
+		}
+
+		Ok (number)
 
 	} else if slice [0] == 0xff {
 
-		panic! ()
+		// base-256 with all bits set at the start is negative, as gnu tar writes
+		// times before 1970, which can't be kept here
+
+		let mut number: i64 = -1;
+
+		for byte in slice [1 ..].iter () {
+			number = number.wrapping_shl (8) | * byte as i64;
+		}
+
+		Err (TfError {
+			error_message: format! (
+				"Negative number: {}",
+				number),
+		})
 
 	} else {
 
@@ -248,7 +754,7 @@ fn tar_number_u64 (
 		let number =
 			try! (
 				u64::from_str_radix (
-					& string,
+					string.trim_matches (' '),
 					8));
 
 		Ok (number)
@@ -265,6 +771,28 @@ fn tar_number_u32 (
 
 		Ok (0)
 
+	} else if slice [0] == 0x80 {
+
+		// ids too large for octal are written in base-256, as by gnu tar
+
+		let mut number: u64 = 0;
+
+		for byte in slice [1 ..].iter () {
+			number = number << 8 | * byte as u64;
+		}
+
+		if number > u32::max_value () as u64 {
+
+			return Err (TfError {
+				error_message: format! (
+					"Number too large: {}",
+					number),
+			});
+
+		}
+
+		Ok (number as u32)
+
 	} else {
 
 		let string =
@@ -275,7 +803,7 @@ fn tar_number_u32 (
 		let number =
 			try! (
 				u32::from_str_radix (
-					& string,
+					string.trim_matches (' '),
 					8));
 
 		Ok (number)
@@ -284,13 +812,95 @@ fn tar_number_u32 (
 
 }
 
+// writes a string into a field, with a nul after it when there is room, as
+// tar_string reads it
+
+fn write_string (
+	field: & mut [u8],
+	value: & [u8],
+) -> Result <(), TfError> {
+
+	if value.len () > field.len () {
+
+		return Err (TfError {
+			error_message: format! (
+				"Name too long for tar header: {}",
+				String::from_utf8_lossy (value)),
+		});
+
+	}
+
+	field [0 .. value.len ()].copy_from_slice (
+		value);
+
+	Ok (())
+
+}
+
+// user and group names which don't fit are cut short, as gnu tar does, since
+// the numeric ids are what matter
+
+fn write_truncated_string (
+	field: & mut [u8],
+	value: & [u8],
+) {
+
+	let length =
+		cmp::min (
+			value.len (),
+			field.len ());
+
+	field [0 .. length].copy_from_slice (
+		& value [0 .. length]);
+
+}
+
+// writes a number as octal digits followed by a nul, or when it doesn't fit, in
+// gnu tar's base-256 encoding, which tar_number_u64 reads
+
+fn write_number (
+	field: & mut [u8],
+	value: u64,
+) {
+
+	let digits =
+		field.len () - 1;
+
+	let octal =
+		format! (
+			"{:01$o}",
+			value,
+			digits);
+
+	if octal.len () == digits {
+
+		field [0 .. digits].copy_from_slice (
+			octal.as_bytes ());
+
+		field [digits] = 0;
+
+	} else {
+
+		for (index, byte) in field.iter_mut ().rev ().enumerate () {
+			* byte = if index < 8 { (value >> (index * 8)) as u8 } else { 0 };
+		}
+
+		field [0] = 0x80;
+
+	}
+
+}
+
+// contiguous files, from a few old unix systems, are regular files anywhere
+// else, as gnu tar treats them
+
 fn tar_type (
 	typeflag: & [u8; 1],
-) -> Type {
+) -> Result <Type, TfError> {
 
-	match typeflag [0] {
+	Ok (match typeflag [0] {
 
-		b'0' => Type::Regular,
+		b'0' | b'\0' | b'7' => Type::Regular,
 		b'1' => Type::Link,
 		b'2' => Type::SymbolicLink,
 		b'3' => Type::CharacterSpecial,
@@ -301,14 +911,51 @@ fn tar_type (
 		b'K' => Type::LongLink,
 		b'L' => Type::LongName,
 
+		b'x' => Type::PaxExtended,
+		b'g' => Type::PaxGlobal,
+
+		b'S' => Type::GnuSparse,
+		b'D' => Type::GnuDirectory,
+		b'M' => Type::GnuMultiVolume,
+
 		_ => {
 
-			panic! (
-				"Unrecognised typeflag: {:?}",
-				typeflag [0])
+			return Err (TfError {
+				error_message: format! (
+					"Unrecognised typeflag: {:?}",
+					typeflag [0]),
+			});
 
 		}
 
+	})
+
+}
+
+fn type_byte (
+	typeflag: & Type,
+) -> u8 {
+
+	match * typeflag {
+
+		Type::Regular => b'0',
+		Type::Link => b'1',
+		Type::SymbolicLink => b'2',
+		Type::CharacterSpecial => b'3',
+		Type::BlockSpecial => b'4',
+		Type::Directory => b'5',
+		Type::Fifo => b'6',
+
+		Type::LongLink => b'K',
+		Type::LongName => b'L',
+
+		Type::PaxExtended => b'x',
+		Type::PaxGlobal => b'g',
+
+		Type::GnuSparse => b'S',
+		Type::GnuDirectory => b'D',
+		Type::GnuMultiVolume => b'M',
+
 	}
 
 }
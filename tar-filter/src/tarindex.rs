@@ -0,0 +1,651 @@
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+use misc::*;
+
+use tar;
+
+// an entry in a tar, as read back from the unpacked output of a pack. the name,
+// link name, size and time from any long name or pax extended header before it
+// are applied to its header, and a sparse file's map includes its extension blocks.
+
+pub struct Entry {
+	pub header: tar::Header,
+	pub content_offset: u64,
+	pub sparse: Vec <tar::SparseEntry>,
+}
+
+// a header which could not be read, along with any blocks directly after it
+// which could not be read either
+
+pub struct Damage {
+	pub offset: u64,
+	pub name: Vec <u8>,
+	pub error: TfError,
+	pub blocks: u64,
+}
+
+pub struct Index {
+	pub entries: Vec <Entry>,
+	pub damage: Vec <Damage>,
+}
+
+// the extra headers which apply to the entry after them
+
+#[ derive (Default) ]
+struct Pending {
+	name: Option <Vec <u8>>,
+	linkname: Option <Vec <u8>>,
+	size: Option <u64>,
+	mtime: Option <u64>,
+}
+
+pub fn read_index <Input: Read + Seek> (
+	input: & mut Input,
+	size: u64,
+) -> Result <Index, TfError> {
+
+	let mut index =
+		Index {
+			entries: vec! (),
+			damage: vec! (),
+		};
+
+	let mut pending: Pending =
+		Default::default ();
+
+	let mut offset: u64 =
+		0;
+
+	let mut header_bytes: Vec <u8> =
+		vec! [0; 512];
+
+	while offset + 512 <= size {
+
+		try! (
+			input.seek (
+				SeekFrom::Start (
+					offset)));
+
+		try! (
+			input.read_exact (
+				& mut header_bytes));
+
+		// skip nul blocks, which end an archive, or separate concatenated ones
+
+		if header_bytes.iter ().all (|byte| * byte == 0) {
+
+			offset += 512;
+
+			continue;
+
+		}
+
+		let mut header =
+			match tar::verify_checksum (
+				& header_bytes,
+			).and_then (
+				|()| tar::Header::read (
+					& header_bytes)
+			) {
+
+			Ok (header) => header,
+
+			Err (error) => {
+
+				record_damage (
+					& mut index,
+					offset,
+					tar::header_name (
+						& header_bytes),
+					error);
+
+				offset += 512;
+
+				continue;
+
+			},
+
+		};
+
+		if let Some (pending_size) = pending.size.take () {
+
+			header.size = pending_size;
+			header.blocks = tar::size_blocks (pending_size);
+
+		}
+
+		let mut content_offset =
+			offset + 512;
+
+		let mut sparse =
+			header.sparse.clone ();
+
+		// a sparse file's map continues in the blocks after its header
+
+		let mut is_extended =
+			header.is_extended;
+
+		while is_extended && content_offset + 512 <= size {
+
+			let mut extension_bytes: Vec <u8> =
+				vec! [0; 512];
+
+			try! (
+				input.read_exact (
+					& mut extension_bytes));
+
+			let (extension_sparse, extension_is_extended) =
+				try! (
+					tar::read_sparse_extension (
+						& extension_bytes));
+
+			sparse.extend (
+				extension_sparse);
+
+			is_extended =
+				extension_is_extended;
+
+			content_offset += 512;
+
+		}
+
+		// a size from a pax header can be anything up to the largest number,
+		// so the end of the content may not even be a valid offset
+
+		let next_offset =
+			match header.blocks.checked_mul (512).and_then (
+				|content_size| content_offset.checked_add (content_size)) {
+
+			Some (next_offset) if ! is_extended && next_offset <= size =>
+				next_offset,
+
+			_ => {
+
+				record_damage (
+					& mut index,
+					offset,
+					header.name,
+					TfError {
+						error_message: String::from (
+							"Content extends past the end of the archive"),
+					});
+
+				break;
+
+			},
+
+		};
+
+		match header.typeflag {
+
+			  tar::Type::LongName
+			| tar::Type::LongLink
+			| tar::Type::PaxExtended => {
+
+				let mut content_bytes: Vec <u8> =
+					vec! [0; header.size as usize];
+
+				try! (
+					input.read_exact (
+						& mut content_bytes));
+
+				if let Err (error) =
+					read_pending (
+						& header,
+						& content_bytes,
+						& mut pending) {
+
+					record_damage (
+						& mut index,
+						offset,
+						header.name,
+						error);
+
+				}
+
+			},
+
+			tar::Type::PaxGlobal => (),
+
+			_ => {
+
+				if let Some (name) = pending.name.take () {
+					header.name = name;
+				}
+
+				if let Some (linkname) = pending.linkname.take () {
+					header.linkname = linkname;
+				}
+
+				if let Some (mtime) = pending.mtime.take () {
+					header.mtime = mtime;
+				}
+
+				index.entries.push (
+					Entry {
+						header: header,
+						content_offset: content_offset,
+						sparse: sparse,
+					});
+
+			},
+
+		}
+
+		offset =
+			next_offset;
+
+	}
+
+	Ok (index)
+
+}
+
+fn read_pending (
+	header: & tar::Header,
+	content: & [u8],
+	pending: & mut Pending,
+) -> Result <(), TfError> {
+
+	match header.typeflag {
+
+		tar::Type::LongName =>
+			pending.name = Some (
+				tar::tar_string (
+					content)),
+
+		tar::Type::LongLink =>
+			pending.linkname = Some (
+				tar::tar_string (
+					content)),
+
+		_ => {
+
+			for (key, value) in try! (tar::pax_records (content)) {
+
+				if key == b"path" {
+					pending.name = Some (value);
+				} else if key == b"linkpath" {
+					pending.linkname = Some (value);
+				} else if key == b"size" {
+					pending.size = Some (
+						try! (
+							u64::from_str_radix (
+								& try! (String::from_utf8 (value)),
+								10)));
+				} else if key == b"mtime" {
+
+					// only whole seconds are kept
+
+					let seconds: Vec <u8> =
+						value.into_iter ().take_while (
+							|byte| * byte != b'.',
+						).collect ();
+
+					pending.mtime = Some (
+						try! (
+							u64::from_str_radix (
+								& try! (String::from_utf8 (seconds)),
+								10)));
+
+				}
+
+			}
+
+		},
+
+	}
+
+	Ok (())
+
+}
+
+fn record_damage (
+	index: & mut Index,
+	offset: u64,
+	name: Vec <u8>,
+	error: TfError,
+) {
+
+	if let Some (damage) = index.damage.last_mut () {
+
+		if damage.offset + damage.blocks * 512 == offset {
+
+			damage.blocks += 1;
+
+			return;
+
+		}
+
+	}
+
+	index.damage.push (
+		Damage {
+			offset: offset,
+			name: name,
+			error: error,
+			blocks: 1,
+		});
+
+}
+
+// finds the entry for a path, which is the last one with that name, as when
+// extracting the tar. a leading "./" and trailing "/" are ignored.
+
+pub fn find_entry <'a> (
+	index: & 'a Index,
+	path: & [u8],
+) -> Option <& 'a Entry> {
+
+	let path =
+		normalise_path (
+			path);
+
+	index.entries.iter ().rev ().find (
+		|entry| normalise_path (& entry.header.name) == path)
+
+}
+
+fn normalise_path (
+	path: & [u8],
+) -> & [u8] {
+
+	let mut path =
+		path;
+
+	while path.starts_with (b"./") {
+		path = & path [2 ..];
+	}
+
+	while path.len () > 1 && path.ends_with (b"/") {
+		path = & path [0 .. path.len () - 1];
+	}
+
+	path
+
+}
+
+// writes the content of a file, following a hard link to its target, and
+// filling in the holes in a sparse file
+
+pub fn copy_entry_content <Input: Read + Seek> (
+	input: & mut Input,
+	index: & Index,
+	entry: & Entry,
+	output: & mut Write,
+) -> Result <(), TfError> {
+
+	let entry =
+		match entry.header.typeflag {
+
+		tar::Type::Link =>
+			try! (
+				find_entry (
+					index,
+					& entry.header.linkname,
+				).ok_or_else (
+					|| format! (
+						"Hard link target not found: {}",
+						String::from_utf8_lossy (& entry.header.linkname)))),
+
+		_ => entry,
+
+	};
+
+	try! (
+		input.seek (
+			SeekFrom::Start (
+				entry.content_offset)));
+
+	match entry.header.typeflag {
+
+		tar::Type::Regular => {
+
+			try! (
+				copy_exact (
+					input,
+					output,
+					entry.header.size));
+
+		},
+
+		tar::Type::GnuSparse => {
+
+			let mut position: u64 =
+				0;
+
+			for section in entry.sparse.iter () {
+
+				if section.offset < position {
+
+					return Err (TfError {
+						error_message: format! (
+							"Sparse map out of order: {}",
+							String::from_utf8_lossy (& entry.header.name)),
+					});
+
+				}
+
+				try! (
+					write_zeros (
+						output,
+						section.offset - position));
+
+				try! (
+					copy_exact (
+						input,
+						output,
+						section.size));
+
+				position =
+					section.offset + section.size;
+
+			}
+
+			if entry.header.real_size > position {
+
+				try! (
+					write_zeros (
+						output,
+						entry.header.real_size - position));
+
+			}
+
+		},
+
+		_ => {
+
+			return Err (TfError {
+				error_message: format! (
+					"Not a regular file: {}",
+					String::from_utf8_lossy (& entry.header.name)),
+			});
+
+		},
+
+	}
+
+	Ok (())
+
+}
+
+fn copy_exact <Input: Read> (
+	input: & mut Input,
+	output: & mut Write,
+	size: u64,
+) -> Result <(), TfError> {
+
+	let bytes_copied =
+		try! (
+			io::copy (
+				& mut Read::take (
+					input,
+					size),
+				output));
+
+	if bytes_copied != size {
+
+		return Err (TfError {
+			error_message: format! (
+				"Content truncated: expected {} bytes, got {}",
+				size,
+				bytes_copied),
+		});
+
+	}
+
+	Ok (())
+
+}
+
+fn write_zeros (
+	output: & mut Write,
+	size: u64,
+) -> Result <(), TfError> {
+
+	try! (
+		io::copy (
+			& mut io::repeat (0).take (
+				size),
+			output));
+
+	Ok (())
+
+}
+
+// a line for an entry, like tar's verbose listing, with the size of a sparse
+// file being the size it expands to
+
+pub fn format_entry (
+	entry: & Entry,
+) -> String {
+
+	let header =
+		& entry.header;
+
+	let type_char =
+		match header.typeflag {
+			tar::Type::Link => 'h',
+			tar::Type::SymbolicLink => 'l',
+			tar::Type::CharacterSpecial => 'c',
+			tar::Type::BlockSpecial => 'b',
+			tar::Type::Directory | tar::Type::GnuDirectory => 'd',
+			tar::Type::Fifo => 'p',
+			tar::Type::GnuMultiVolume => 'M',
+			_ => '-',
+		};
+
+	let size =
+		match header.typeflag {
+			tar::Type::GnuSparse => header.real_size,
+			_ => header.size,
+		};
+
+	let mut line =
+		format! (
+			"{}{} {:>10} {} {}",
+			type_char,
+			format_mode (header.mode),
+			size,
+			format_time (header.mtime),
+			String::from_utf8_lossy (& header.name));
+
+	match header.typeflag {
+
+		tar::Type::Link =>
+			line.push_str (
+				& format! (
+					" link to {}",
+					String::from_utf8_lossy (& header.linkname))),
+
+		tar::Type::SymbolicLink =>
+			line.push_str (
+				& format! (
+					" -> {}",
+					String::from_utf8_lossy (& header.linkname))),
+
+		_ => (),
+
+	}
+
+	line
+
+}
+
+fn format_mode (
+	mode: u32,
+) -> String {
+
+	let mut string =
+		String::with_capacity (9);
+
+	for & (bit, character, special_bit, special_character) in [
+		(0o400, 'r', 0, ' '),
+		(0o200, 'w', 0, ' '),
+		(0o100, 'x', 0o4000, 's'),
+		(0o040, 'r', 0, ' '),
+		(0o020, 'w', 0, ' '),
+		(0o010, 'x', 0o2000, 's'),
+		(0o004, 'r', 0, ' '),
+		(0o002, 'w', 0, ' '),
+		(0o001, 'x', 0o1000, 't'),
+	].iter () {
+
+		string.push (
+			if mode & special_bit != 0 {
+				if mode & bit != 0 {
+					special_character
+				} else {
+					special_character.to_ascii_uppercase ()
+				}
+			} else if mode & bit != 0 {
+				character
+			} else {
+				'-'
+			});
+
+	}
+
+	string
+
+}
+
+// formats a unix time as utc, converting days to a date as in howard hinnant's
+// civil_from_days
+
+fn format_time (
+	time: u64,
+) -> String {
+
+	let days = (time / 86400) as i64 + 719468;
+	let seconds = time % 86400;
+
+	let era = days / 146097;
+	let day_of_era = days - era * 146097;
+
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524
+			- day_of_era / 146096) / 365;
+
+	let day_of_year =
+		day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+	let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	format! (
+		"{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+		year,
+		month,
+		day,
+		seconds / 3600,
+		seconds / 60 % 60,
+		seconds % 60)
+
+}
@@ -1,6 +1,7 @@
+use std::io;
 use std::io::Read;
-use std::io::Write;
 
+use checksum::*;
 use misc::*;
 
 use tar;
@@ -11,12 +12,53 @@ struct TarPacker <'a, 'b: 'a> {
 	packer: & 'a mut wbspack::Packer <'b>,
 	deferred: Vec <wbspack::Deferred>,
 	null_count: u64,
+	pax_size: Option <u64>,
+	offset: u64,
+	checksum: Crc32c,
+	lenient: bool,
+	anomalies: Vec <Anomaly>,
+}
+
+// the size and checksum of the input are those of the unpacked output, which
+// go in the pack's footer
+
+pub struct Packed {
+	pub anomalies: Vec <Anomaly>,
+	pub size: u64,
+	pub checksum: u32,
+}
+
+// a header which could not be read, in lenient mode, along with any blocks
+// directly after it which could not be read either. these are all kept
+// verbatim, so the archive still unpacks exactly as it was.
+
+pub struct Anomaly {
+	pub offset: u64,
+	pub name: Vec <u8>,
+	pub error: TfError,
+	pub blocks: u64,
+}
+
+// a line for the summary of what was kept verbatim in lenient mode
+
+pub fn format_anomaly (
+	anomaly: & Anomaly,
+) -> String {
+
+	format! (
+		"offset {} ({}): {}, {} blocks kept verbatim",
+		anomaly.offset,
+		String::from_utf8_lossy (& anomaly.name),
+		anomaly.error,
+		anomaly.blocks)
+
 }
 
 pub fn pack (
 	input: & mut Read,
 	packer: & mut wbspack::Packer,
-) -> Result <(), TfError> {
+	lenient: bool,
+) -> Result <Packed, TfError> {
 
 	let mut tar_packer =
 		TarPacker {
@@ -24,6 +66,11 @@ pub fn pack (
 			packer: packer,
 			deferred: vec! (),
 			null_count: 0,
+			pax_size: None,
+			offset: 0,
+			checksum: Crc32c::new (),
+			lenient: lenient,
+			anomalies: vec! (),
 		};
 
 	while try! (
@@ -37,7 +84,11 @@ pub fn pack (
 	try! (
 		tar_packer.write_nulls ());
 
-	Ok (())
+	Ok (Packed {
+		size: tar_packer.offset,
+		checksum: tar_packer.checksum.sum (),
+		anomalies: tar_packer.anomalies,
+	})
 
 }
 
@@ -67,10 +118,17 @@ impl <'a, 'b> TarPacker <'a, 'b> {
 
 		}
 
-		if self.null_count > 0 {
+		// nul blocks before another header are from concatenated archives, as
+		// read by tar --ignore-zeros, and are kept where they were
+
+		while self.null_count > 0 {
 
-			panic! (
-				"NUL in middle of archive");
+			self.deferred.push (
+				try! (
+					self.packer.defer (
+						vec! [0; 512])));
+
+			self.null_count -= 1;
 
 		}
 
@@ -88,25 +146,51 @@ impl <'a, 'b> TarPacker <'a, 'b> {
 		& mut self
 	) -> Result <Option <Vec <u8>>, TfError> {
 
-		let mut header_bytes =
-			Vec::with_capacity (
-				512);
+		let mut header_bytes: Vec <u8> =
+			vec! [0; 512];
+
+		// a pipe may return less than we asked for, so read until we have a
+		// whole block or reach the end
+
+		let mut bytes_read: usize =
+			0;
+
+		while bytes_read < 512 {
+
+			match self.input.read (
+				& mut header_bytes [bytes_read ..]) {
+
+				Ok (0) => break,
+
+				Ok (bytes) =>
+					bytes_read += bytes,
+
+				Err (ref error)
+					if error.kind () == io::ErrorKind::Interrupted => (),
+
+				Err (error) =>
+					return Err (error.into ()),
+
+			}
 
-		unsafe {
-			header_bytes.set_len (
-				512);
 		}
 
-		match try! (
-			self.input.read (
-				& mut header_bytes)) {
+		match bytes_read {
 
 			0 => Ok (None),
-			512 => Ok (Some (header_bytes)),
 
-			bytes_read => panic! (
-				"Read {} bytes, expected EOF or 512",
-				bytes_read),
+			512 => {
+				self.offset += 512;
+				self.checksum.update (& header_bytes);
+				Ok (Some (header_bytes))
+			},
+
+			bytes_read => Err (TfError {
+				error_message: format! (
+					"Truncated archive: {} bytes of header at offset {}",
+					bytes_read,
+					self.offset),
+			}),
 
 		}
 
@@ -117,10 +201,55 @@ impl <'a, 'b> TarPacker <'a, 'b> {
 		header_bytes: Vec <u8>,
 	) -> Result <(), TfError> {
 
-		let header = try! (
-			tar::Header::read (
-				& header_bytes)
-		);
+		let header_offset =
+			self.offset - 512;
+
+		let mut header =
+			match tar::verify_checksum (
+				& header_bytes,
+			).and_then (
+				|()| tar::Header::read (
+					& header_bytes)
+			) {
+
+			Ok (header) => header,
+
+			Err (error) => {
+
+				let name =
+					tar::header_name (
+						& header_bytes);
+
+				if ! self.lenient {
+
+					return Err (TfError {
+						error_message: format! (
+							"{} at offset {} ({})",
+							error,
+							header_offset,
+							String::from_utf8_lossy (& name)),
+					});
+
+				}
+
+				return self.record_bad_header (
+					header_offset,
+					name,
+					error,
+					header_bytes);
+
+			},
+
+		};
+
+		// a size from a pax extended header applies to the next entry only
+
+		if let Some (pax_size) = self.pax_size.take () {
+
+			header.size = pax_size;
+			header.blocks = tar::size_blocks (pax_size);
+
+		}
 
 		match header.typeflag {
 
@@ -130,7 +259,8 @@ impl <'a, 'b> TarPacker <'a, 'b> {
 			| tar::Type::CharacterSpecial
 			| tar::Type::BlockSpecial
 			| tar::Type::Directory
-			| tar::Type::Fifo => {
+			| tar::Type::Fifo
+			| tar::Type::GnuMultiVolume => {
 
 				// defer header
 
@@ -148,7 +278,10 @@ impl <'a, 'b> TarPacker <'a, 'b> {
 			},
 
 			  tar::Type::LongName
-			| tar::Type::LongLink => {
+			| tar::Type::LongLink
+			| tar::Type::PaxExtended
+			| tar::Type::PaxGlobal
+			| tar::Type::GnuDirectory => {
 
 				// defer header
 
@@ -160,18 +293,22 @@ impl <'a, 'b> TarPacker <'a, 'b> {
 				// defer content
 
 				let mut content_bytes: Vec <u8> =
-					Vec::with_capacity (
-						header.blocks as usize * 512);
-
-				unsafe {
-					content_bytes.set_len (
-						header.blocks as usize * 512);
-				}
+					vec! [0; header.blocks as usize * 512];
 
 				try! (
-					self.input.read_exact (
+					self.read_content (
 						& mut content_bytes));
 
+				if let tar::Type::PaxExtended = header.typeflag {
+
+					self.pax_size =
+						try! (
+							tar::pax_size (
+								& content_bytes [
+									0 .. header.size as usize]));
+
+				}
+
 				self.deferred.push (
 					try! (
 						self.packer.defer (
@@ -179,12 +316,208 @@ impl <'a, 'b> TarPacker <'a, 'b> {
 
 			},
 
+			tar::Type::GnuSparse => {
+
+				// defer header and any extension blocks
+
+				self.deferred.push (
+					try! (
+						self.packer.defer (
+							header_bytes)));
+
+				let mut sparse =
+					header.sparse.clone ();
+
+				let mut is_extended =
+					header.is_extended;
+
+				while is_extended {
+
+					let mut extension_bytes: Vec <u8> =
+						vec! [0; 512];
+
+					try! (
+						self.read_content (
+							& mut extension_bytes));
+
+					let (extension_sparse, extension_is_extended) =
+						try! (
+							tar::read_sparse_extension (
+								& extension_bytes));
+
+					sparse.extend (
+						extension_sparse);
+
+					is_extended =
+						extension_is_extended;
+
+					self.deferred.push (
+						try! (
+							self.packer.defer (
+								extension_bytes)));
+
+				}
+
+				// process data sections
+
+				try! (
+					self.process_sparse_file (
+						& header,
+						& sparse));
+
+			},
+
 		}
 
 		Ok (())
 
 	}
 
+	// each section of a sparse file's data is aligned separately, since it is
+	// the sections rather than the whole which match other copies of the file
+
+	fn process_sparse_file (
+		& mut self,
+		header: & tar::Header,
+		sparse: & [tar::SparseEntry],
+	) -> Result <(), TfError> {
+
+		let mut remaining =
+			header.blocks * 512;
+
+		if sparse.iter ().map (|entry| entry.size).sum::<u64> () > header.size {
+
+			return Err (TfError {
+				error_message: format! (
+					"Sparse map larger than data for {}",
+					String::from_utf8_lossy (& header.name)),
+			});
+
+		}
+
+		for entry in sparse.iter () {
+
+			if entry.size == 0 {
+				continue;
+			}
+
+			try! (
+				self.packer.align ());
+
+			let mut content_bytes: Vec <u8> =
+				vec! [0; entry.size as usize];
+
+			try! (
+				self.read_content (
+					& mut content_bytes));
+
+			try! (
+				self.packer.write (
+					& content_bytes));
+
+			remaining -=
+				entry.size;
+
+		}
+
+		// the padding to a whole block follows the last section
+
+		if remaining > 0 {
+
+			let mut padding_bytes: Vec <u8> =
+				vec! [0; remaining as usize];
+
+			try! (
+				self.read_content (
+					& mut padding_bytes));
+
+			try! (
+				self.packer.write (
+					& padding_bytes));
+
+		}
+
+		Ok (())
+
+	}
+
+	// there is no telling where a bad header's content ends, so like gnu tar we
+	// look for another header in the following block
+
+	fn record_bad_header (
+		& mut self,
+		header_offset: u64,
+		name: Vec <u8>,
+		error: TfError,
+		header_bytes: Vec <u8>,
+	) -> Result <(), TfError> {
+
+		self.deferred.push (
+			try! (
+				self.packer.defer (
+					header_bytes)));
+
+		if let Some (anomaly) = self.anomalies.last_mut () {
+
+			if anomaly.offset + anomaly.blocks * 512 == header_offset {
+
+				anomaly.blocks += 1;
+
+				return Ok (());
+
+			}
+
+		}
+
+		self.anomalies.push (
+			Anomaly {
+				offset: header_offset,
+				name: name,
+				error: error,
+				blocks: 1,
+			});
+
+		Ok (())
+
+	}
+
+	fn read_content (
+		& mut self,
+		content_bytes: & mut [u8],
+	) -> Result <(), TfError> {
+
+		match self.input.read_exact (
+			content_bytes) {
+
+			Ok (()) => (),
+
+			Err (ref error)
+				if error.kind () == io::ErrorKind::UnexpectedEof => {
+
+				return Err (TfError {
+					error_message: format! (
+						"Truncated archive: expected {} bytes at offset {}",
+						content_bytes.len (),
+						self.offset),
+				});
+
+			},
+
+			Err (error) =>
+				return Err (error.into ()),
+
+		}
+
+		self.offset +=
+			content_bytes.len () as u64;
+
+		self.checksum.update (
+			content_bytes);
+
+		Ok (())
+
+	}
+
 	fn process_one_file (
 		& mut self,
 		header: & tar::Header,
@@ -199,7 +532,7 @@ impl <'a, 'b> TarPacker <'a, 'b> {
 			vec! [0; 512 * header.blocks as usize];
 
 		try! (
-			self.input.read_exact (
+			self.read_content (
 				& mut content_bytes));
 
 		try! (
@@ -234,7 +567,13 @@ impl <'a, 'b> TarPacker <'a, 'b> {
 	) -> Result <(), TfError> {
 
 		if self.null_count < 2 {
-			panic! ();
+
+			return Err (TfError {
+				error_message: format! (
+					"Truncated archive: no end of archive marker at offset {}",
+					self.offset),
+			});
+
 		}
 
 		let null_bytes: [u8; 512] =
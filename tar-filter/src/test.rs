@@ -0,0 +1,987 @@
+use std::cmp;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process;
+
+use codec::*;
+use dirpack;
+use extract;
+use tar;
+use tarindex;
+use tarpack;
+use wbspack;
+
+// ######################################## harness
+
+// the golden tar is a small gnu archive with a long name, so it has deferred
+// content as well as headers. the packs were written by each version of the
+// packer, and the big-endian one is the version 0 pack with its numbers
+// swapped, as the old writer produced on a big-endian host. the ustar tar has
+// names split into a prefix, and the pax one a global header and a name too
+// long for either field. the sparse tar's map has more sections than fit in
+// its header, so it goes on in an extension block.
+
+const SIMPLE_TAR: & [u8] = include_bytes! ("../golden/simple.tar");
+const SIMPLE_V0_PACK: & [u8] = include_bytes! ("../golden/simple-v0.pack");
+const SIMPLE_V1_PACK: & [u8] = include_bytes! ("../golden/simple-v1.pack");
+const SIMPLE_V0_BIG_ENDIAN_PACK: & [u8] =
+	include_bytes! ("../golden/simple-v0-big-endian.pack");
+const USTAR_TAR: & [u8] = include_bytes! ("../golden/ustar.tar");
+const PAX_TAR: & [u8] = include_bytes! ("../golden/pax.tar");
+const SPARSE_TAR: & [u8] = include_bytes! ("../golden/sparse.tar");
+
+fn pack (
+	tar: & [u8],
+	alignment: u64,
+) -> Vec <u8> {
+
+	pack_with (& mut Cursor::new (tar), alignment, false).unwrap ().0
+
+}
+
+fn pack_with (
+	input: & mut Read,
+	alignment: u64,
+	lenient: bool,
+) -> Result <(Vec <u8>, Vec <tarpack::Anomaly>), String> {
+
+	let mut output: Vec <u8> =
+		vec! ();
+
+	let anomalies = {
+
+		let mut packer =
+			wbspack::Packer::new (
+				& mut output,
+				0,
+				alignment,
+			).unwrap ();
+
+		packer.write_header ().unwrap ();
+
+		let packed =
+			try! (
+				tarpack::pack (
+					input,
+					& mut packer,
+					lenient,
+				).map_err (
+					|error| error.error_message
+				));
+
+		packer.write_footer (
+			packed.size,
+			packed.checksum,
+		).unwrap ();
+
+		packed.anomalies
+
+	};
+
+	Ok ((output, anomalies))
+
+}
+
+// reads a few bytes at a time, as a pipe may
+
+struct ShortReader <'a> {
+	input: & 'a [u8],
+}
+
+impl <'a> Read for ShortReader <'a> {
+
+	fn read (
+		& mut self,
+		buffer: & mut [u8],
+	) -> io::Result <usize> {
+
+		let size =
+			cmp::min (cmp::min (buffer.len (), self.input.len ()), 7);
+
+		buffer [0 .. size].copy_from_slice (& self.input [0 .. size]);
+		self.input = & self.input [size ..];
+
+		Ok (size)
+
+	}
+
+}
+
+fn pack_dir (
+	path: & Path,
+	filter: & extract::Filter,
+) -> Vec <u8> {
+
+	let mut output: Vec <u8> =
+		vec! ();
+
+	{
+
+		let mut packer =
+			wbspack::Packer::new (
+				& mut output,
+				0,
+				0x10000,
+			).unwrap ();
+
+		packer.write_header ().unwrap ();
+
+		let packed =
+			dirpack::pack (
+				path,
+				& mut packer,
+				filter,
+				false,
+			).unwrap ();
+
+		assert_eq! (packed.problems.len (), 0);
+
+		packer.write_footer (
+			packed.size,
+			packed.checksum,
+		).unwrap ();
+
+	}
+
+	output
+
+}
+
+fn read_index (
+	pack: & [u8],
+) -> (wbspack::Footer, tarindex::Index) {
+
+	let mut input = Cursor::new (pack);
+	let footer = wbspack::read_pack (& mut input).unwrap ();
+
+	let index = {
+		let mut unpacked = wbspack::Unpacked::new (& mut input, & footer);
+		let unpacked_size = unpacked.size ();
+		tarindex::read_index (& mut unpacked, unpacked_size).unwrap ()
+	};
+
+	(footer, index)
+
+}
+
+// renames the entry with its header at an offset in a tar, updating the
+// checksum to match
+
+fn rename_entry (
+	tar: & mut [u8],
+	offset: usize,
+	name: & [u8],
+) {
+
+	for index in 0 .. 100 {
+		tar [offset + index] = * name.get (index).unwrap_or (& 0);
+	}
+
+	update_checksum (tar, offset);
+
+}
+
+fn update_checksum (
+	tar: & mut [u8],
+	offset: usize,
+) {
+
+	for index in 148 .. 156 {
+		tar [offset + index] = b' ';
+	}
+
+	let checksum: u32 =
+		tar [offset .. offset + 512].iter ().map (|byte| * byte as u32).sum ();
+
+	tar [offset + 148 .. offset + 156].copy_from_slice (
+		format! ("{:06o}\0 ", checksum).as_bytes ());
+
+}
+
+fn unpack (
+	pack: & [u8],
+) -> Result <Vec <u8>, String> {
+
+	let mut output: Vec <u8> =
+		vec! ();
+
+	wbspack::unpack (
+		& mut Cursor::new (pack),
+		& mut output,
+	).map_err (
+		|error| error.error_message
+	).map (
+		|()| output
+	)
+
+}
+
+// ######################################## tests
+
+#[test]
+fn codec_is_little_endian () {
+
+	let mut bytes: Vec <u8> = vec! ();
+
+	encode_u64 (0x0102030405060708, & mut bytes);
+	encode_u32 (0x090a0b0c, & mut bytes);
+
+	assert_eq! (bytes, vec! [
+		0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01,
+		0x0c, 0x0b, 0x0a, 0x09,
+	]);
+
+	assert_eq! (decode_u64 (& bytes [0 .. 8]), 0x0102030405060708);
+	assert_eq! (decode_u32 (& bytes [8 .. 12]), 0x090a0b0c);
+
+	let block_reference = BlockReference {
+		offset: 0x1234,
+		size: 0x200,
+		checksum: Some (0xe3069283),
+	};
+
+	assert_eq! (encode_block_reference (& block_reference, 1), vec! [
+		0x34, 0x12, 0, 0, 0, 0, 0, 0,
+		0x00, 0x02, 0, 0, 0, 0, 0, 0,
+		0x83, 0x92, 0x06, 0xe3, 0, 0, 0, 0,
+	]);
+
+	assert_eq! (
+		decode_block_reference (
+			& encode_block_reference (& block_reference, 1),
+			1),
+		block_reference);
+
+	assert_eq! (encode_block_reference (& block_reference, 0).len (), 16);
+
+}
+
+#[test]
+fn pack_matches_golden_file () {
+
+	assert_eq! (pack (SIMPLE_TAR, 1), SIMPLE_V1_PACK.to_vec ());
+
+}
+
+#[test]
+fn golden_packs_unpack_to_original_tar () {
+
+	assert_eq! (unpack (SIMPLE_V0_PACK), Ok (SIMPLE_TAR.to_vec ()));
+	assert_eq! (unpack (SIMPLE_V1_PACK), Ok (SIMPLE_TAR.to_vec ()));
+
+}
+
+#[test]
+fn ustar_and_pax_tars_unpack_to_original_tar () {
+
+	assert_eq! (unpack (& pack (USTAR_TAR, 1)), Ok (USTAR_TAR.to_vec ()));
+	assert_eq! (unpack (& pack (PAX_TAR, 1)), Ok (PAX_TAR.to_vec ()));
+
+	let directories: String =
+		(1 .. 11).map (
+			|index| format! ("directory{:02}/", index)
+		).collect ();
+
+	let (_footer, ustar_index) = read_index (& pack (USTAR_TAR, 1));
+
+	assert_eq! (ustar_index.entries.len (), 12);
+
+	assert_eq! (
+		ustar_index.entries [11].header.name,
+		format! ("ustar/{}prefixed.txt", directories).into_bytes ());
+
+	let (_footer, pax_index) = read_index (& pack (PAX_TAR, 1));
+
+	let names: Vec <String> =
+		pax_index.entries.iter ().map (
+			|entry| String::from_utf8_lossy (& entry.header.name).into_owned ()
+		).collect ();
+
+	assert_eq! (names, vec! [
+		String::from ("pax/"),
+		String::from ("pax/plain.txt"),
+		format! ("pax/{}.txt", "x".repeat (120)),
+	]);
+
+	assert_eq! (pax_index.entries [2].header.size, 13);
+
+}
+
+#[test]
+fn contiguous_files_are_regular_and_keep_their_typeflag () {
+
+	// hello.txt's header is the second block, after its directory's
+
+	let mut tar = SIMPLE_TAR.to_vec ();
+	tar [512 + 156] = b'7';
+	update_checksum (& mut tar, 512);
+
+	let header = tar::Header::read (& tar [512 .. 1024]).unwrap ();
+	assert_eq! (header.typeflag, tar::Type::Regular);
+
+	assert_eq! (header.write ().unwrap () [156], b'7');
+
+	assert_eq! (unpack (& pack (& tar, 1)), Ok (tar.clone ()));
+
+	let (_footer, index) = read_index (& pack (& tar, 1));
+	assert_eq! (index.entries [1].header.typeflag, tar::Type::Regular);
+
+}
+
+#[test]
+fn sparse_map_is_read_from_extension_blocks () {
+
+	let sparse_pack = pack (SPARSE_TAR, 1);
+
+	assert_eq! (unpack (& sparse_pack), Ok (SPARSE_TAR.to_vec ()));
+
+	let (footer, index) = read_index (& sparse_pack);
+
+	assert_eq! (index.damage.len (), 0);
+	assert_eq! (index.entries.len (), 2);
+
+	let entry = & index.entries [1];
+
+	assert_eq! (entry.header.typeflag, tar::Type::GnuSparse);
+	assert! (entry.header.is_extended);
+	assert_eq! (entry.header.sparse.len (), 4);
+	// gnu tar ends the map with an empty section at the end of the file
+
+	assert_eq! (entry.sparse.len (), 7);
+	assert_eq! (entry.sparse [6].offset, 6 * 65536);
+	assert_eq! (entry.sparse [6].size, 0);
+
+	assert_eq! (
+		tarindex::format_entry (entry),
+		"-rw-r--r--     393216 2020-01-01 00:00:00 sparse/holes.bin");
+
+	// each section holds a line at the start of a 64k run, with holes between
+
+	let mut expected: Vec <u8> = vec! [0; 6 * 65536];
+
+	for section in 0 .. 6 {
+		let line = format! ("data {:02}\n", section);
+		expected [section * 65536 .. section * 65536 + 8].copy_from_slice (
+			line.as_bytes ());
+	}
+
+	let mut input = Cursor::new (& sparse_pack);
+	let mut unpacked = wbspack::Unpacked::new (& mut input, & footer);
+	let mut content: Vec <u8> = vec! ();
+
+	tarindex::copy_entry_content (
+		& mut unpacked,
+		& index,
+		entry,
+		& mut content,
+	).unwrap ();
+
+	assert! (content == expected);
+
+}
+
+#[test]
+fn header_checksums_are_verified () {
+
+	// a name with a byte over 127 sums differently as signed bytes, as some
+	// old tars summed it, and either sum is accepted
+
+	let mut signed_tar = SIMPLE_TAR.to_vec ();
+	rename_entry (& mut signed_tar, 512, b"simple/h\xe9llo.txt");
+
+	let signed_checksum: i32 =
+		signed_tar [512 .. 1024].iter ().enumerate ().map (
+			|(index, byte)|
+			if index >= 148 && index < 156 { 32 } else { * byte as i8 as i32 }
+		).sum ();
+
+	signed_tar [512 + 148 .. 512 + 156].copy_from_slice (
+		format! ("{:06o}\0 ", signed_checksum).as_bytes ());
+
+	assert! (tar::verify_checksum (& signed_tar [512 .. 1024]).is_ok ());
+	assert_eq! (unpack (& pack (& signed_tar, 1)), Ok (signed_tar.clone ()));
+
+	// a header which matches neither is refused, naming where it was
+
+	let mut corrupt_tar = SIMPLE_TAR.to_vec ();
+	corrupt_tar [512 + 100] ^= 1;
+
+	let error = pack_with (& mut Cursor::new (& corrupt_tar), 1, false).err ().unwrap ();
+
+	assert! (error.starts_with ("Header checksum mismatch: stored "));
+	assert! (error.ends_with (" at offset 512 (simple/hello.txt)"));
+
+	// unless packing leniently, when it is kept verbatim along with its
+	// content, which can't be read as a header either
+
+	let (lenient_pack, anomalies) =
+		pack_with (& mut Cursor::new (& corrupt_tar), 1, true).unwrap ();
+
+	assert_eq! (anomalies.len (), 1);
+	assert_eq! (anomalies [0].offset, 512);
+	assert_eq! (anomalies [0].blocks, 2);
+
+	assert! (
+		tarpack::format_anomaly (& anomalies [0]).starts_with (
+			"offset 512 (simple/hello.txt): Header checksum mismatch: "));
+
+	assert! (
+		tarpack::format_anomaly (& anomalies [0]).ends_with (
+			", 2 blocks kept verbatim"));
+
+	assert_eq! (unpack (& lenient_pack), Ok (corrupt_tar));
+
+}
+
+#[test]
+fn short_reads_and_concatenated_archives_are_packed () {
+
+	let (short_pack, _anomalies) =
+		pack_with (
+			& mut ShortReader { input: SIMPLE_TAR },
+			1,
+			false,
+		).unwrap ();
+
+	assert_eq! (short_pack, SIMPLE_V1_PACK.to_vec ());
+
+	// the first archive's end of archive marker is kept between them, as tar
+	// --ignore-zeros reads it
+
+	let mut concatenated_tar = SIMPLE_TAR.to_vec ();
+	concatenated_tar.extend_from_slice (PAX_TAR);
+
+	let concatenated_pack = pack (& concatenated_tar, 1);
+
+	assert_eq! (unpack (& concatenated_pack), Ok (concatenated_tar));
+
+	let (_footer, index) = read_index (& concatenated_pack);
+
+	assert_eq! (index.damage.len (), 0);
+	assert_eq! (index.entries.len (), 9);
+	assert_eq! (index.entries [6].header.name, b"pax/".to_vec ());
+
+}
+
+#[test]
+fn truncated_and_unreadable_archives_are_errors () {
+
+	let truncated = |size: usize| {
+		pack_with (
+			& mut ShortReader { input: & SIMPLE_TAR [0 .. size] },
+			1,
+			false,
+		).err ().unwrap ()
+	};
+
+	assert_eq! (
+		truncated (700),
+		"Truncated archive: 188 bytes of header at offset 512");
+
+	assert_eq! (
+		truncated (1100),
+		"Truncated archive: expected 512 bytes at offset 1024");
+
+	assert_eq! (
+		truncated (5632),
+		"Truncated archive: no end of archive marker at offset 5632");
+
+	// gnu tar writes times before 1970 in negative base-256, which can't be
+	// packed, except leniently
+
+	let mut negative_tar = SIMPLE_TAR.to_vec ();
+
+	for index in 136 .. 148 {
+		negative_tar [512 + index] = 0xff;
+	}
+
+	update_checksum (& mut negative_tar, 512);
+
+	assert_eq! (
+		pack_with (& mut Cursor::new (& negative_tar), 1, false).err ().unwrap (),
+		"Negative number: -1 at offset 512 (simple/hello.txt)");
+
+	let (negative_pack, anomalies) =
+		pack_with (& mut Cursor::new (& negative_tar), 1, true).unwrap ();
+
+	assert_eq! (anomalies.len (), 1);
+	assert_eq! (anomalies [0].error.error_message, "Negative number: -1");
+	assert_eq! (unpack (& negative_pack), Ok (negative_tar));
+
+	assert! (tar::Header::read (& SIMPLE_TAR [0 .. 100]).is_err ());
+	assert! (tar::read_sparse_extension (& SIMPLE_TAR [0 .. 100]).is_err ());
+
+}
+
+#[test]
+fn aligned_pack_unpacks_to_original_tar () {
+
+	let aligned_pack = pack (SIMPLE_TAR, 0x1000);
+	let pack_size = aligned_pack.len ();
+
+	let blocks_location =
+		decode_blocks_location (& aligned_pack [pack_size - 48 .. pack_size - 32]);
+
+	assert_eq! (blocks_location.offset % 0x1000, 0);
+	assert_eq! (unpack (& aligned_pack), Ok (SIMPLE_TAR.to_vec ()));
+
+}
+
+#[test]
+fn big_endian_pack_is_rejected () {
+
+	assert_eq! (
+		unpack (SIMPLE_V0_BIG_ENDIAN_PACK),
+		Err (String::from (
+			"Pack was written in big-endian byte order by an older \
+			tar-filter, and can't be read")));
+
+}
+
+#[test]
+fn corrupt_pack_is_rejected () {
+
+	// the first file content, after the header
+
+	let mut corrupt_content = SIMPLE_V1_PACK.to_vec ();
+	corrupt_content [80] ^= 1;
+
+	assert! (
+		unpack (& corrupt_content).unwrap_err ().starts_with (
+			"Block 3 checksum mismatch at offset 80"));
+
+	// a block reference in the table
+
+	let mut corrupt_table = SIMPLE_V1_PACK.to_vec ();
+	let table_byte = corrupt_table.len () - 48 - 16 - 24 + 12;
+	corrupt_table [table_byte] ^= 1;
+
+	assert! (
+		unpack (& corrupt_table).unwrap_err ().starts_with (
+			"Pack table checksum mismatch"));
+
+	// the table location
+
+	let mut corrupt_location = SIMPLE_V1_PACK.to_vec ();
+	let location_byte = corrupt_location.len () - 48;
+	corrupt_location [location_byte] ^= 1;
+
+	assert! (
+		unpack (& corrupt_location).unwrap_err ().starts_with (
+			"Pack block table at offset"));
+
+}
+
+#[test]
+fn output_checksums_are_verified () {
+
+	// the stored size and checksum of the output are covered by the table
+	// checksum
+
+	let mut corrupt_output_checksum = SIMPLE_V1_PACK.to_vec ();
+	let checksum_byte = corrupt_output_checksum.len () - 32 + 8;
+	corrupt_output_checksum [checksum_byte] ^= 1;
+
+	assert! (
+		unpack (& corrupt_output_checksum).unwrap_err ().starts_with (
+			"Pack table checksum mismatch"));
+
+	// so they can only be wrong if written so, when the unpacked output is
+	// refused even though every block matches its own checksum
+
+	let packed_wrongly = |output_size: u64, output_checksum: u32| {
+
+		let mut output: Vec <u8> = vec! ();
+
+		{
+
+			let mut packer =
+				wbspack::Packer::new (& mut output, 0, 1).unwrap ();
+
+			packer.write_header ().unwrap ();
+
+			tarpack::pack (
+				& mut Cursor::new (SIMPLE_TAR),
+				& mut packer,
+				false,
+			).unwrap ();
+
+			packer.write_footer (output_size, output_checksum).unwrap ();
+
+		}
+
+		unpack (& output).unwrap_err ()
+
+	};
+
+	let (footer, _index) = read_index (SIMPLE_V1_PACK);
+	let output_size = footer.output_size.unwrap ();
+	let output_checksum = footer.output_checksum.unwrap ();
+
+	assert_eq! (
+		packed_wrongly (output_size + 512, output_checksum),
+		format! (
+			"Output size mismatch: expected {}, got {}",
+			output_size + 512,
+			output_size));
+
+	assert_eq! (
+		packed_wrongly (output_size, output_checksum ^ 1),
+		format! (
+			"Output checksum mismatch: stored {:08x}, calculated {:08x}",
+			output_checksum ^ 1,
+			output_checksum));
+
+	// version 0 packs have none of these, and are still read
+
+	let (v0_footer, v0_index) = read_index (SIMPLE_V0_PACK);
+
+	assert_eq! (v0_footer.version, 0);
+	assert_eq! (v0_footer.output_checksum, None);
+	assert! (
+		v0_footer.block_references.iter ().all (
+			|block| block.checksum.is_none ()));
+	assert_eq! (v0_index.entries.len (), 6);
+
+}
+
+#[test]
+fn index_is_read_from_deferred_headers () {
+
+	let (footer, index) = read_index (SIMPLE_V1_PACK);
+
+	assert_eq! (wbspack::check_blocks (& footer), Vec::<String>::new ());
+	assert_eq! (index.damage.len (), 0);
+
+	let names: Vec <String> =
+		index.entries.iter ().map (
+			|entry| String::from_utf8_lossy (& entry.header.name).into_owned ()
+		).collect ();
+
+	assert_eq! (names, vec! [
+		String::from ("simple/"),
+		String::from ("simple/hello.txt"),
+		String::from ("simple/link"),
+		format! ("simple/{}.txt", "long".repeat (30)),
+		String::from ("simple/sub/"),
+		String::from ("simple/sub/second.txt"),
+	]);
+
+	assert_eq! (index.entries [1].header.size, 12);
+	assert_eq! (index.entries [2].header.linkname, b"hello.txt".to_vec ());
+
+}
+
+#[test]
+fn damaged_headers_are_found_by_index () {
+
+	// version 0 has no checksums to stop the damaged header being read. the
+	// header of hello.txt is the third block, and its content the fourth.
+
+	let (footer, _index) = read_index (SIMPLE_V0_PACK);
+
+	let mut damaged_pack = SIMPLE_V0_PACK.to_vec ();
+	damaged_pack [footer.block_references [2].offset as usize + 10] ^= 1;
+
+	let (_footer, index) = read_index (& damaged_pack);
+
+	assert_eq! (index.entries.len (), 5);
+	assert_eq! (index.damage.len (), 1);
+	assert_eq! (index.damage [0].offset, 512);
+	assert_eq! (index.damage [0].name, b"simple/helmo.txt".to_vec ());
+	assert_eq! (index.damage [0].blocks, 2);
+
+}
+
+#[test]
+fn oversized_pax_size_is_found_by_index () {
+
+	// the pax header before the long name, at the sixth block, is given a size
+	// too large to be an offset in any archive
+
+	let mut tar = PAX_TAR.to_vec ();
+	let record = b"29 size=18446744073709551615\n";
+
+	for index in 0 .. 512 {
+		tar [3072 + index] = * record.get (index).unwrap_or (& 0);
+	}
+
+	tar [2560 + 124 .. 2560 + 136].copy_from_slice (b"00000000035\0");
+	update_checksum (& mut tar, 2560);
+
+	let tar_size = tar.len () as u64;
+	let index = tarindex::read_index (& mut Cursor::new (tar), tar_size).unwrap ();
+
+	assert_eq! (index.entries.len (), 2);
+	assert_eq! (index.damage.len (), 1);
+	assert_eq! (index.damage [0].offset, 3584);
+
+	assert_eq! (
+		index.damage [0].error.error_message,
+		"Content extends past the end of the archive");
+
+}
+
+#[test]
+fn blocks_outside_content_are_found () {
+
+	let (mut footer, _index) = read_index (SIMPLE_V1_PACK);
+
+	footer.block_references [3].offset = footer.table_offset - 1;
+	footer.block_references [3].size = 2;
+
+	assert_eq! (wbspack::check_blocks (& footer).len (), 1);
+
+}
+
+#[test]
+fn entries_are_listed_and_read_without_unpacking () {
+
+	let (footer, index) = read_index (SIMPLE_V1_PACK);
+
+	assert_eq! (
+		tarindex::format_entry (& index.entries [1]),
+		"-rw-r--r--         12 2020-01-01 00:00:00 simple/hello.txt");
+
+	assert_eq! (
+		tarindex::format_entry (& index.entries [2]),
+		"lrwxr-xr-x          0 2020-01-01 00:00:00 simple/link -> hello.txt");
+
+	let mut input = Cursor::new (SIMPLE_V1_PACK);
+	let mut unpacked = wbspack::Unpacked::new (& mut input, & footer);
+
+	let mut content: Vec <u8> = vec! ();
+
+	let entry =
+		tarindex::find_entry (& index, b"./simple/sub/second.txt").unwrap ();
+
+	tarindex::copy_entry_content (
+		& mut unpacked,
+		& index,
+		entry,
+		& mut content,
+	).unwrap ();
+
+	assert_eq! (content, b"second file\nwith two lines\n".to_vec ());
+
+	assert! (tarindex::find_entry (& index, b"simple/missing").is_none ());
+
+}
+
+#[test]
+fn globs_select_entries_to_extract () {
+
+	assert! (extract::glob_matches (b"*.txt", b"simple/hello.txt"));
+	assert! (extract::glob_matches (b"simple/?ello.[st]xt", b"simple/hello.txt"));
+	assert! (! extract::glob_matches (b"simple/[!h]*", b"simple/hello.txt"));
+	assert! (! extract::glob_matches (b"*.txt", b"simple/link"));
+
+	let filter = extract::Filter {
+		includes: vec! [b"simple/sub".to_vec (), b"*/link".to_vec ()],
+		excludes: vec! [b"second.*".to_vec ()],
+	};
+
+	assert! (filter.matches (b"simple/sub/"));
+	assert! (filter.matches (b"simple/link"));
+	assert! (filter.matches (b"simple/sub/third.txt"));
+	assert! (! filter.matches (b"simple/sub/second.txt"));
+	assert! (! filter.matches (b"simple/hello.txt"));
+
+}
+
+#[test]
+fn pack_is_extracted_to_directory () {
+
+	let target =
+		env::temp_dir ().join (
+			format! ("tar-filter-test-{}", process::id ()));
+
+	let (footer, index) = read_index (SIMPLE_V1_PACK);
+	let mut input = Cursor::new (SIMPLE_V1_PACK);
+	let mut unpacked = wbspack::Unpacked::new (& mut input, & footer);
+
+	let filter = extract::Filter {
+		includes: vec! (),
+		excludes: vec! [b"*long*".to_vec ()],
+	};
+
+	fs::create_dir (& target).unwrap ();
+
+	let problems =
+		extract::extract (& mut unpacked, & index, & target, & filter).unwrap ();
+
+	assert_eq! (problems.len (), 0);
+
+	assert_eq! (
+		fs::read (target.join ("simple/sub/second.txt")).unwrap (),
+		b"second file\nwith two lines\n".to_vec ());
+
+	assert_eq! (
+		fs::read_link (target.join ("simple/link")).unwrap (),
+		Path::new ("hello.txt"));
+
+	assert! (! target.join (format! ("simple/{}.txt", "long".repeat (30))).exists ());
+
+	let metadata = fs::metadata (target.join ("simple/sub")).unwrap ();
+	assert_eq! (metadata.mtime (), 1577836800);
+	assert_eq! (metadata.mode () & 0o7777, 0o755);
+
+	fs::remove_dir_all (& target).unwrap ();
+
+}
+
+#[test]
+fn paths_outside_target_are_refused () {
+
+	let target =
+		env::temp_dir ().join (
+			format! ("tar-filter-test-refused-{}", process::id ()));
+
+	let outside =
+		env::temp_dir ().join (
+			format! ("tar-filter-test-outside-{}", process::id ()));
+
+	// hello.txt's header is the second block, after its directory's
+
+	let mut tar = SIMPLE_TAR.to_vec ();
+	rename_entry (& mut tar, 512, b"simple/../../hello.txt");
+
+	// the first directory is replaced by a symlink to a directory outside,
+	// whose mode must not be set to the first one's when directories are
+	// finished. the symlink's header is the fourth block.
+
+	rename_entry (& mut tar, 0, b"victim/");
+	rename_entry (& mut tar, 1536, b"victim");
+
+	for index in 0 .. 100 {
+		tar [1536 + 157 + index] =
+			* outside.as_os_str ().as_bytes ().get (index).unwrap_or (& 0);
+	}
+
+	update_checksum (& mut tar, 1536);
+
+	let evil_pack = pack (& tar, 1);
+	let (footer, index) = read_index (& evil_pack);
+	let mut input = Cursor::new (& evil_pack);
+	let mut unpacked = wbspack::Unpacked::new (& mut input, & footer);
+
+	let filter = extract::Filter {
+		includes: vec! (),
+		excludes: vec! (),
+	};
+
+	fs::create_dir (& target).unwrap ();
+	fs::create_dir (& outside).unwrap ();
+	fs::set_permissions (& outside, fs::Permissions::from_mode (0o700)).unwrap ();
+
+	let problems =
+		extract::extract (& mut unpacked, & index, & target, & filter).unwrap ();
+
+	assert_eq! (problems.len (), 1);
+	assert_eq! (problems [0].name, b"simple/../../hello.txt".to_vec ());
+
+	assert! (
+		fs::symlink_metadata (target.join ("victim")).unwrap ()
+			.file_type ().is_symlink ());
+
+	assert_eq! (
+		fs::metadata (& outside).unwrap ().mode () & 0o7777,
+		0o700);
+
+	assert_eq! (
+		problems [0].error.error_message,
+		"Refusing path outside target: simple/../../hello.txt");
+
+	assert! (! target.join ("../hello.txt").exists ());
+	assert! (target.join ("simple/sub/second.txt").exists ());
+
+	fs::remove_dir_all (& target).unwrap ();
+	fs::remove_dir_all (& outside).unwrap ();
+
+}
+
+#[test]
+fn directory_is_packed_like_the_tar_it_came_from () {
+
+	let target =
+		env::temp_dir ().join (
+			format! ("tar-filter-test-pack-dir-{}", process::id ()));
+
+	let (footer, index) = read_index (SIMPLE_V1_PACK);
+	let mut input = Cursor::new (SIMPLE_V1_PACK);
+	let mut unpacked = wbspack::Unpacked::new (& mut input, & footer);
+
+	let filter = extract::Filter {
+		includes: vec! (),
+		excludes: vec! (),
+	};
+
+	fs::create_dir (& target).unwrap ();
+	extract::extract (& mut unpacked, & index, & target, & filter).unwrap ();
+
+	let dir_pack = pack_dir (& target.join ("simple"), & filter);
+
+	assert_eq! (pack_dir (& target.join ("simple"), & filter), dir_pack);
+
+	let (dir_footer, dir_index) = read_index (& dir_pack);
+
+	assert_eq! (dir_index.damage.len (), 0);
+	assert_eq! (dir_index.entries.len (), index.entries.len ());
+
+	// the golden tar is in name order, as pack-dir writes it, but symlink modes
+	// depend on the system
+
+	for (dir_entry, entry) in dir_index.entries.iter ().zip (index.entries.iter ()) {
+
+		assert_eq! (dir_entry.header.name, entry.header.name);
+		assert_eq! (dir_entry.header.linkname, entry.header.linkname);
+		assert_eq! (dir_entry.header.size, entry.header.size);
+		assert_eq! (dir_entry.header.mtime, entry.header.mtime);
+
+		assert_eq! (
+			format! ("{:?}", dir_entry.header.typeflag),
+			format! ("{:?}", entry.header.typeflag));
+
+		if ! dir_entry.header.linkname.is_empty () {
+			continue;
+		}
+
+		assert_eq! (dir_entry.header.mode, entry.header.mode);
+
+	}
+
+	let mut output: Vec <u8> = vec! ();
+	let mut dir_input = Cursor::new (& dir_pack);
+	let mut dir_unpacked = wbspack::Unpacked::new (& mut dir_input, & dir_footer);
+
+	tarindex::copy_entry_content (
+		& mut dir_unpacked,
+		& dir_index,
+		tarindex::find_entry (& dir_index, b"simple/sub/second.txt").unwrap (),
+		& mut output,
+	).unwrap ();
+
+	assert_eq! (output, b"second file\nwith two lines\n".to_vec ());
+
+	// excluded entries are left out, along with everything under them
+
+	let excluding_filter = extract::Filter {
+		includes: vec! (),
+		excludes: vec! [b"sub".to_vec ()],
+	};
+
+	let (_excluded_footer, excluded_index) =
+		read_index (& pack_dir (& target.join ("simple"), & excluding_filter));
+
+	assert_eq! (excluded_index.entries.len (), index.entries.len () - 2);
+
+	fs::remove_dir_all (& target).unwrap ();
+
+}
@@ -3,16 +3,46 @@ use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
-use std::mem;
 
+use checksum::*;
+use codec::*;
 use misc::*;
 
-#[ repr (C) ]
-#[ derive (Copy, Clone) ]
-pub struct BlockReference {
+// version 1 packs add a checksum to each block reference, and after the block
+// table the size and checksum of the whole unpacked output, then a checksum of
+// the header and everything in the footer before it. see codec for the layout.
 
-	pub offset: u64,
-	pub size: u64,
+pub const VERSION: u64 = 1;
+
+pub struct Footer {
+
+	pub version: u64,
+	pub content_offset: u64,
+	pub table_offset: u64,
+
+	pub block_references: Vec <BlockReference>,
+	pub output_size: Option <u64>,
+	pub output_checksum: Option <u32>,
+
+}
+
+// any pack which can be read from, such as a file or a backup in a zbackup
+// repository
+
+pub trait PackInput: Read + Seek {}
+
+impl <T: Read + Seek> PackInput for T {}
+
+// reads the unpacked output from a pack, seeking through the block table, so
+// that parts of it can be read without unpacking the rest
+
+pub struct Unpacked <'a, F: Read + Seek + ? Sized + 'a> {
+
+	input: & 'a mut F,
+	block_references: & 'a [BlockReference],
+	block_starts: Vec <u64>,
+	size: u64,
+	position: u64,
 
 }
 
@@ -23,6 +53,7 @@ pub struct Packer <'a> {
 	alignment: u64,
 
 	block_references: Vec <BlockReference>,
+	table_checksum: Crc32c,
 
 }
 
@@ -48,6 +79,7 @@ impl <'a> Packer <'a> {
 			alignment: alignment,
 
 			block_references: vec! (),
+			table_checksum: Crc32c::new (),
 
 		})
 
@@ -59,13 +91,14 @@ impl <'a> Packer <'a> {
 	) -> Result <(), TfError> {
 
 		try! (
-			self.output.write (
+			self.output.write_all (
 				data));
 
 		self.block_references.push (
 			BlockReference {
 				offset: self.offset,
 				size: data.len () as u64,
+				checksum: Some (crc32c (data)),
 			});
 
 		self.offset +=
@@ -90,6 +123,7 @@ impl <'a> Packer <'a> {
 			BlockReference {
 				offset: 0,
 				size: 0,
+				checksum: None,
 			});
 
 		Ok (deferred)
@@ -102,13 +136,14 @@ impl <'a> Packer <'a> {
 	) -> Result <(), TfError> {
 
 		try! (
-			self.output.write (
+			self.output.write_all (
 				& deferred.content));
 
 		self.block_references [deferred.index as usize] =
 			BlockReference {
 				offset: self.offset,
 				size: deferred.content.len () as u64,
+				checksum: Some (crc32c (& deferred.content)),
 			};
 
 		self.offset +=
@@ -134,7 +169,7 @@ impl <'a> Packer <'a> {
 				vec! [0; padding as usize];
 
 			try! (
-				self.output.write (
+				self.output.write_all (
 					& zeroes));
 
 			self.offset +=
@@ -146,24 +181,44 @@ impl <'a> Packer <'a> {
 
 	}
 
+	// writes part of the header or footer, which are covered by the table
+	// checksum
+
+	fn write_table (
+		& mut self,
+		data: & [u8],
+	) -> Result <(), TfError> {
+
+		try! (
+			self.output.write_all (
+				data));
+
+		self.table_checksum.update (
+			data);
+
+		self.offset +=
+			data.len () as u64;
+
+		Ok (())
+
+	}
+
 	pub fn write_header (
 		& mut self,
 	) -> Result <(), TfError> {
 
 		for header_line in [
-			b"WBS PACK\0\0\0\0\0\0\0\0",
-			b"HEADER START\0\0\0\0",
-			b"VERSION 0\0\0\0\0\0\0\0",
-			b"TARPACK 0\0\0\0\0\0\0\0",
-			b"HEADER END\0\0\0\0\0\0",
+			PACK_START,
+			HEADER_START,
+			try! (version_line (VERSION)),
+			TARPACK_0,
+			HEADER_END,
 		].iter () {
 
 			try! (
-				self.output.write (
+				self.write_table (
 					* header_line));
 
-			self.offset += 16;
-
 		}
 
 		Ok (())
@@ -172,81 +227,74 @@ impl <'a> Packer <'a> {
 
 	pub fn write_footer (
 		& mut self,
+		output_size: u64,
+		output_checksum: u32,
 	) -> Result <(), TfError> {
 
 		try! (
 			self.align ());
 
-		let blocks_offset =
-			self.offset;
+		let blocks_location =
+			BlocksLocation {
+				offset: self.offset,
+				length: self.block_references.len () as u64,
+			};
 
 		// write blocks
 
 		try! (
-			self.output.write (
-				b"BLOCKS START\0\0\0\0"));
-
-		self.offset += 16;
+			self.write_table (
+				BLOCKS_START));
 
-		for block_reference in self.block_references.iter () {
+		for block_index in 0 .. self.block_references.len () {
 
-			let binary_block_reference: & [u8; 16] =
-				unsafe {
-					mem::transmute::<& BlockReference, & [u8; 16]> (
-						block_reference)
-				};
+			let block_reference_bytes =
+				encode_block_reference (
+					& self.block_references [block_index],
+					VERSION);
 
 			try! (
-				self.output.write (
-					binary_block_reference));
-
-			self.offset +=
-				512;
+				self.write_table (
+					& block_reference_bytes));
 
 		}
 
 		try! (
-			self.output.write (
-				b"BLOCKS END\0\0\0\0\0\0"));
-
-		self.offset += 16;
-
-		// write blocks offset
+			self.write_table (
+				BLOCKS_END));
 
-		let binary_blocks_offset: & [u8; 8] = unsafe {
-			mem::transmute::<& u64, & [u8; 8]> (
-				& blocks_offset)
-		};
+		// write blocks location
 
 		try! (
-			self.output.write (
-				binary_blocks_offset));
+			self.write_table (
+				& encode_blocks_location (
+					& blocks_location)));
 
-		self.offset += 8;
+		// write output size and checksums, the last of which covers everything
+		// in the table before it
 
-		// write blocks length
+		try! (
+			self.write_table (
+				& encode_output_checksums (
+					output_size,
+					output_checksum)));
 
-		let blocks_length: u64 =
-			self.block_references.len () as u64;
+		let mut table_checksum_bytes: Vec <u8> =
+			vec! ();
 
-		let binary_blocks_length: & [u8; 8] = unsafe {
-			mem::transmute::<& u64, & [u8; 8]> (
-				& blocks_length)
-		};
+		encode_u32 (
+			self.table_checksum.sum (),
+			& mut table_checksum_bytes);
 
 		try! (
-			self.output.write (
-				binary_blocks_length));
-
-		self.offset += 8;
+			self.write_table (
+				& table_checksum_bytes));
 
 		// write end
 
 		try! (
-			self.output.write (
-				b"WBS PACK END\0\0\0\0"));
-
-		self.offset += 8;
+			self.write_table (
+				PACK_END));
 
 		Ok (())
 
@@ -254,20 +302,29 @@ impl <'a> Packer <'a> {
 
 }
 
-pub fn read_header (
-	input: & mut Read,
-) -> Result <(), TfError> {
+// reads the header, returning the pack's version along with the checksum of the
+// header so far, which the table checksum continues from
+
+pub fn read_header <F: Read + ? Sized> (
+	input: & mut F,
+) -> Result <(u64, Crc32c), TfError> {
 
 	let mut header_line: [u8; 16] =
 		[0; 16];
 
+	let mut table_checksum =
+		Crc32c::new ();
+
 	// read magic header
 
 	try! (
 		input.read_exact (
 			& mut header_line));
 
-	if header_line != * b"WBS PACK\0\0\0\0\0\0\0\0" {
+	table_checksum.update (
+		& header_line);
+
+	if header_line != * PACK_START {
 
 		return Err (TfError {
 			error_message: String::from (
@@ -282,7 +339,10 @@ pub fn read_header (
 		input.read_exact (
 			& mut header_line));
 
-	if header_line != * b"HEADER START\0\0\0\0" {
+	table_checksum.update (
+		& header_line);
+
+	if header_line != * HEADER_START {
 
 		return Err (TfError {
 			error_message: String::from (
@@ -293,8 +353,8 @@ pub fn read_header (
 
 	// read rest of header
 
-	let mut got_version_0 =
-		false;
+	let mut version: Option <u64> =
+		None;
 
 	loop {
 
@@ -302,15 +362,22 @@ pub fn read_header (
 			input.read_exact (
 				& mut header_line));
 
-		// read version 0
+		table_checksum.update (
+			& header_line);
+
+		// read version
 
-		if header_line == * b"VERSION 0\0\0\0\0\0\0\0" {
-			got_version_0 = true;
+		if header_line == * VERSION_0 {
+			version = Some (0);
+		}
+
+		if header_line == * VERSION_1 {
+			version = Some (1);
 		}
 
 		// read header end
 
-		if header_line == * b"HEADER END\0\0\0\0\0\0" {
+		if header_line == * HEADER_END {
 			break;
 		}
 
@@ -318,28 +385,50 @@ pub fn read_header (
 
 	// check version
 
-	if ! got_version_0 {
+	match version {
 
-		return Err (TfError {
+		Some (version) =>
+			Ok ((version, table_checksum)),
+
+		None => Err (TfError {
 			error_message: String::from (
 				"Unknown WBS pack file version"),
-		});
+		}),
 
 	}
 
-	Ok (())
-
 }
 
-pub fn read_footer <F: Read + Seek> (
+pub fn read_footer <F: Read + Seek + ? Sized> (
 	input: & mut F,
-) -> Result <Vec <BlockReference>, TfError> {
+	version: u64,
+	mut table_checksum: Crc32c,
+) -> Result <Footer, TfError> {
+
+	let content_offset =
+		try! (
+			input.seek (
+				SeekFrom::Current (0)));
 
 	let mut footer_line: [u8; 16] =
 		[0; 16];
 
 	// read magic footer
 
+	let pack_size =
+		try! (
+			input.seek (
+				SeekFrom::End (0)));
+
+	if pack_size < trailer_size (version) {
+
+		return Err (TfError {
+			error_message: String::from (
+				"Not a WBS pack file"),
+		});
+
+	}
+
 	try! (
 		input.seek (
 			SeekFrom::End (-16)));
@@ -348,7 +437,7 @@ pub fn read_footer <F: Read + Seek> (
 		input.read_exact (
 			& mut footer_line));
 
-	if footer_line != * b"WBS PACK END\0\0\0\0" {
+	if footer_line != * PACK_END {
 
 		return Err (TfError {
 			error_message: String::from (
@@ -357,20 +446,26 @@ pub fn read_footer <F: Read + Seek> (
 
 	}
 
-	// read blocks location
+	// read blocks location and output checksums
+
+	let mut trailer_bytes: Vec <u8> =
+		vec! [0; trailer_size (version) as usize - LINE_SIZE];
 
 	try! (
 		input.seek (
-			SeekFrom::End (-32)));
+			SeekFrom::End (
+				- (trailer_size (version) as i64))));
 
 	try! (
 		input.read_exact (
-			& mut footer_line));
+			& mut trailer_bytes));
 
-	let blocks_location = unsafe {
-		mem::transmute::<[u8; 16], BlockReference> (
-			footer_line)
-	};
+	let blocks_location =
+		try! (
+			check_blocks_location (
+				& trailer_bytes [0 .. LINE_SIZE],
+				version,
+				pack_size));
 
 	// read blocks start
 
@@ -383,7 +478,10 @@ pub fn read_footer <F: Read + Seek> (
 		input.read_exact (
 			& mut footer_line));
 
-	if footer_line != * b"BLOCKS START\0\0\0\0" {
+	table_checksum.update (
+		& footer_line);
+
+	if footer_line != * BLOCKS_START {
 
 		return Err (TfError {
 			error_message: String::from (
@@ -396,18 +494,24 @@ pub fn read_footer <F: Read + Seek> (
 
 	let mut block_references: Vec <BlockReference> =
 		Vec::with_capacity (
-			blocks_location.size as usize);
+			blocks_location.length as usize);
+
+	let mut block_reference_bytes: Vec <u8> =
+		vec! [0; block_reference_size (version) as usize];
 
-	for _block_index in 0 .. blocks_location.size {
+	for _block_index in 0 .. blocks_location.length {
 
 		try! (
 			input.read_exact (
-				& mut footer_line));
+				& mut block_reference_bytes));
 
-		block_references.push (unsafe {
-			mem::transmute::<[u8; 16], BlockReference> (
-				footer_line)
-		});
+		table_checksum.update (
+			& block_reference_bytes);
+
+		block_references.push (
+			decode_block_reference (
+				& block_reference_bytes,
+				version));
 
 	}
 
@@ -417,7 +521,10 @@ pub fn read_footer <F: Read + Seek> (
 		input.read_exact (
 			& mut footer_line));
 
-	if footer_line != * b"BLOCKS END\0\0\0\0\0\0" {
+	table_checksum.update (
+		& footer_line);
+
+	if footer_line != * BLOCKS_END {
 
 		return Err (TfError {
 			error_message: String::from (
@@ -426,17 +533,129 @@ pub fn read_footer <F: Read + Seek> (
 
 	}
 
-	Ok (block_references)
+	if version == 0 {
+
+		return Ok (Footer {
+			version: version,
+			content_offset: content_offset,
+			table_offset: blocks_location.offset,
+			block_references: block_references,
+			output_size: None,
+			output_checksum: None,
+		});
+
+	}
+
+	// check table checksum
+
+	let checksums =
+		decode_checksums (
+			& trailer_bytes [LINE_SIZE .. LINE_SIZE * 2]);
+
+	table_checksum.update (
+		& trailer_bytes [0 .. LINE_SIZE + 12]);
+
+	if table_checksum.sum () != checksums.table_checksum {
+
+		return Err (TfError {
+			error_message: format! (
+				"Pack table checksum mismatch: stored {:08x}, calculated {:08x}",
+				checksums.table_checksum,
+				table_checksum.sum ()),
+		});
+
+	}
+
+	Ok (Footer {
+		version: version,
+		content_offset: content_offset,
+		table_offset: blocks_location.offset,
+		block_references: block_references,
+		output_size: Some (checksums.output_size),
+		output_checksum: Some (checksums.output_checksum),
+	})
 
 }
 
-pub fn copy_blocks <Input: Read + Seek> (
+// the block table must end exactly where the trailer starts. earlier versions
+// wrote numbers in the host's byte order, so a pack from a big-endian host is
+// recognised by the table fitting only when they are read that way.
+
+fn check_blocks_location (
+	bytes: & [u8],
+	version: u64,
+	pack_size: u64,
+) -> Result <BlocksLocation, TfError> {
+
+	let table_end =
+		pack_size - trailer_size (version);
+
+	let fits = |blocks_location: & BlocksLocation| {
+
+		blocks_location.offset <= table_end
+			&& blocks_location.length <= table_end / block_reference_size (version)
+			&& blocks_location.offset
+				+ LINE_SIZE as u64 * 2
+				+ blocks_location.length * block_reference_size (version)
+			== table_end
+
+	};
+
+	let blocks_location =
+		decode_blocks_location (
+			bytes);
+
+	if fits (& blocks_location) {
+		return Ok (blocks_location);
+	}
+
+	let swapped_location =
+		BlocksLocation {
+			offset: blocks_location.offset.swap_bytes (),
+			length: blocks_location.length.swap_bytes (),
+		};
+
+	if fits (& swapped_location) {
+
+		return Err (TfError {
+			error_message: String::from (
+				"Pack was written in big-endian byte order by an older \
+				tar-filter, and can't be read"),
+		});
+
+	}
+
+	Err (TfError {
+		error_message: format! (
+			"Pack block table at offset {} with {} blocks does not end at the \
+			footer at offset {}",
+			blocks_location.offset,
+			blocks_location.length,
+			table_end),
+	})
+
+}
+
+// copies each block to the output, checking it as it goes where the pack has
+// checksums. the output is already written by the time a bad block is found,
+// so the caller must discard it on error.
+
+pub fn copy_blocks <Input: Read + Seek + ? Sized> (
 	input: & mut Input,
 	output: & mut Write,
-	blocks: & Vec <BlockReference>,
+	footer: & Footer,
 ) -> Result <(), TfError> {
 
-	for block in blocks {
+	let mut buffer: Vec <u8> =
+		vec! [0; 0x10000];
+
+	let mut output_checksum =
+		Crc32c::new ();
+
+	let mut output_size: u64 =
+		0;
+
+	for (block_index, block) in footer.block_references.iter ().enumerate () {
 
 		try! (
 			input.seek (
@@ -444,13 +663,112 @@ pub fn copy_blocks <Input: Read + Seek> (
 					block.offset)));
 
 		let mut block_reader =
-			input.take (
+			Read::take (
+				& mut * input,
 				block.size);
 
-		try! (
-			io::copy (
-				& mut block_reader,
-				output));
+		let mut block_checksum =
+			Crc32c::new ();
+
+		let mut block_size: u64 =
+			0;
+
+		loop {
+
+			let bytes_read =
+				match block_reader.read (
+					& mut buffer) {
+
+				Ok (0) => break,
+				Ok (bytes_read) => bytes_read,
+
+				Err (ref error)
+					if error.kind () == io::ErrorKind::Interrupted =>
+					continue,
+
+				Err (error) =>
+					return Err (error.into ()),
+
+			};
+
+			block_checksum.update (
+				& buffer [0 .. bytes_read]);
+
+			output_checksum.update (
+				& buffer [0 .. bytes_read]);
+
+			try! (
+				output.write_all (
+					& buffer [0 .. bytes_read]));
+
+			block_size +=
+				bytes_read as u64;
+
+		}
+
+		if block_size != block.size {
+
+			return Err (TfError {
+				error_message: format! (
+					"Block {} truncated: expected {} bytes at offset {}, got {}",
+					block_index,
+					block.size,
+					block.offset,
+					block_size),
+			});
+
+		}
+
+		if let Some (checksum) = block.checksum {
+
+			if block_checksum.sum () != checksum {
+
+				return Err (TfError {
+					error_message: format! (
+						"Block {} checksum mismatch at offset {}: stored {:08x}, \
+						calculated {:08x}",
+						block_index,
+						block.offset,
+						checksum,
+						block_checksum.sum ()),
+				});
+
+			}
+
+		}
+
+		output_size +=
+			block_size;
+
+	}
+
+	if let Some (expected_size) = footer.output_size {
+
+		if output_size != expected_size {
+
+			return Err (TfError {
+				error_message: format! (
+					"Output size mismatch: expected {}, got {}",
+					expected_size,
+					output_size),
+			});
+
+		}
+
+	}
+
+	if let Some (checksum) = footer.output_checksum {
+
+		if output_checksum.sum () != checksum {
+
+			return Err (TfError {
+				error_message: format! (
+					"Output checksum mismatch: stored {:08x}, calculated {:08x}",
+					checksum,
+					output_checksum.sum ()),
+			});
+
+		}
 
 	}
 
@@ -458,26 +776,209 @@ pub fn copy_blocks <Input: Read + Seek> (
 
 }
 
-pub fn unpack <F: Read + Seek> (
+pub fn read_pack <F: Read + Seek + ? Sized> (
+	input: & mut F,
+) -> Result <Footer, TfError> {
+
+	let (version, table_checksum) =
+		try! (
+			read_header (
+				input));
+
+	read_footer (
+		input,
+		version,
+		table_checksum)
+
+}
+
+pub fn unpack <F: Read + Seek + ? Sized> (
 	input: & mut F,
 	output: & mut Write,
 ) -> Result <(), TfError> {
 
-	try! (
-		read_header (
-			input));
-
-	let block_references =
+	let footer =
 		try! (
-			read_footer (
+			read_pack (
 				input));
 
 	try! (
 		copy_blocks (
 			input,
 			output,
-			& block_references));
+			& footer));
 
 	Ok (())
 
 }
+
+// checks that every block lies between the header and the block table, which
+// is all that can be checked without reading the blocks themselves
+
+pub fn check_blocks (
+	footer: & Footer,
+) -> Vec <String> {
+
+	let mut problems: Vec <String> =
+		vec! ();
+
+	for (block_index, block) in footer.block_references.iter ().enumerate () {
+
+		if block.offset < footer.content_offset
+			|| block.size > footer.table_offset
+			|| block.offset > footer.table_offset - block.size {
+
+			problems.push (
+				format! (
+					"Block {} at offset {} with size {} is outside the content \
+					between offsets {} and {}",
+					block_index,
+					block.offset,
+					block.size,
+					footer.content_offset,
+					footer.table_offset));
+
+		}
+
+	}
+
+	problems
+
+}
+
+impl <'a, F: Read + Seek + ? Sized> Unpacked <'a, F> {
+
+	pub fn new (
+		input: & 'a mut F,
+		footer: & 'a Footer,
+	) -> Unpacked <'a, F> {
+
+		let mut block_starts: Vec <u64> =
+			Vec::with_capacity (
+				footer.block_references.len ());
+
+		let mut size: u64 =
+			0;
+
+		for block in footer.block_references.iter () {
+
+			block_starts.push (
+				size);
+
+			size +=
+				block.size;
+
+		}
+
+		Unpacked {
+			input: input,
+			block_references: & footer.block_references,
+			block_starts: block_starts,
+			size: size,
+			position: 0,
+		}
+
+	}
+
+	pub fn size (
+		& self,
+	) -> u64 {
+
+		self.size
+
+	}
+
+}
+
+impl <'a, F: Read + Seek + ? Sized> Read for Unpacked <'a, F> {
+
+	fn read (
+		& mut self,
+		buffer: & mut [u8],
+	) -> io::Result <usize> {
+
+		if self.position >= self.size || buffer.is_empty () {
+			return Ok (0);
+		}
+
+		// find the last block starting at or before the position, skipping
+		// any empty ones which start there too
+
+		let mut block_index =
+			match self.block_starts.binary_search (
+				& self.position) {
+
+			Ok (block_index) => block_index,
+			Err (block_index) => block_index - 1,
+
+		};
+
+		while self.block_references [block_index].size == 0
+			|| self.block_starts [block_index]
+				+ self.block_references [block_index].size
+				<= self.position {
+
+			block_index += 1;
+
+		}
+
+		let block =
+			& self.block_references [block_index];
+
+		let block_position =
+			self.position - self.block_starts [block_index];
+
+		let bytes_wanted =
+			buffer.len ().min (
+				(block.size - block_position) as usize);
+
+		try! (
+			self.input.seek (
+				SeekFrom::Start (
+					block.offset + block_position)));
+
+		let bytes_read =
+			try! (
+				self.input.read (
+					& mut buffer [0 .. bytes_wanted]));
+
+		self.position +=
+			bytes_read as u64;
+
+		Ok (bytes_read)
+
+	}
+
+}
+
+impl <'a, F: Read + Seek + ? Sized> Seek for Unpacked <'a, F> {
+
+	fn seek (
+		& mut self,
+		position: SeekFrom,
+	) -> io::Result <u64> {
+
+		let new_position =
+			match position {
+				SeekFrom::Start (offset) => offset as i64,
+				SeekFrom::End (offset) => self.size as i64 + offset,
+				SeekFrom::Current (offset) => self.position as i64 + offset,
+			};
+
+		if new_position < 0 {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::InvalidInput,
+					"Seek before start of unpacked output"));
+
+		}
+
+		self.position =
+			new_position as u64;
+
+		Ok (self.position)
+
+	}
+
+}